@@ -0,0 +1,476 @@
+//! Writing files into ADF floppy images with the original (OFS) or fast
+//! (FFS) file system, so converted pictures end up on the disk a build
+//! boots from without a separate copy step. Directory caching disks are not
+//! supported
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BSIZE: usize = 512;
+const DD_BLOCKS: usize = 1760;
+const HD_BLOCKS: usize = 3520;
+
+const T_HEADER: u32 = 2;
+const T_DATA: u32 = 8;
+const T_LIST: u32 = 16;
+const ST_ROOT: u32 = 1;
+const ST_USERDIR: u32 = 2;
+const ST_FILE: u32 = -3i32 as u32;
+
+/// Entries in the hash tables of directories and the block tables of files
+const TABLE_SIZE: usize = BSIZE / 4 - 56;
+const TABLE: usize = 0x18;
+const HIGH_SEQ: usize = 0x08;
+const FIRST_DATA: usize = 0x10;
+const CHECKSUM: usize = 0x14;
+const BM_FLAG: usize = BSIZE - 200;
+const BM_PAGES: usize = BSIZE - 196;
+const BYTE_SIZE: usize = BSIZE - 188;
+const NAME: usize = BSIZE - 80;
+const HASH_CHAIN: usize = BSIZE - 16;
+const PARENT: usize = BSIZE - 12;
+const EXTENSION: usize = BSIZE - 8;
+const SEC_TYPE: usize = BSIZE - 4;
+/// OFS data blocks start with a header, FFS data blocks are all data
+const OFS_HEADER: usize = 24;
+const MAX_NAME: usize = 30;
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// `disk.adf[:path]` from the command line. Without a path, or with one
+/// ending in a slash, files keep the name of the output file
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdfTarget {
+    pub disk: PathBuf,
+    pub path: Option<String>,
+}
+
+impl AdfTarget {
+    /// Where `output` goes on the disk
+    pub fn path_for(&self, output: &Path) -> String {
+        let name = output
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match &self.path {
+            Some(path) if !path.is_empty() && !path.ends_with('/') => path.clone(),
+            Some(dir) => format!("{}{}", dir, name),
+            None => name,
+        }
+    }
+}
+
+impl FromStr for AdfTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // split after the extension so drive letters stay in the disk path
+        match s.to_ascii_lowercase().find(".adf:") {
+            Some(i) => Ok(AdfTarget {
+                disk: PathBuf::from(&s[..i + 4]),
+                path: Some(s[i + 5..].to_string()),
+            }),
+            None => Ok(AdfTarget {
+                disk: PathBuf::from(s),
+                path: None,
+            }),
+        }
+    }
+}
+
+/// A whole disk image held in memory
+pub struct Adf {
+    data: Vec<u8>,
+    ffs: bool,
+    /// International mode, which upper cases Latin-1 letters in names
+    intl: bool,
+}
+
+impl Adf {
+    /// An empty double density disk, like the Workbench formats it
+    pub fn format(ffs: bool, name: &str) -> Adf {
+        let mut adf = Adf {
+            data: vec![0; DD_BLOCKS * BSIZE],
+            ffs,
+            intl: false,
+        };
+        adf.data[..4].copy_from_slice(&[b'D', b'O', b'S', ffs as u8]);
+        let (root, bitmap) = (adf.root(), adf.root() + 1);
+        adf.set_long(root, 0, T_HEADER);
+        adf.set_long(root, 0x0c, TABLE_SIZE as u32);
+        adf.set_long(root, BM_FLAG, u32::MAX);
+        adf.set_long(root, BM_PAGES, bitmap as u32);
+        adf.set_name(root, name.as_bytes());
+        adf.set_long(root, SEC_TYPE, ST_ROOT);
+        adf.checksum(root);
+        for block in 2..DD_BLOCKS {
+            adf.set_free(block, block != root && block != bitmap);
+        }
+        adf.bitmap_checksum();
+        adf
+    }
+
+    /// Reads an ADF, or formats a new OFS disk named after the file if
+    /// there is none yet
+    pub fn load(path: &Path) -> IoResult<Adf> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                return Ok(Adf::format(false, &name));
+            }
+            Err(e) => return Err(e),
+        };
+        if data.len() != DD_BLOCKS * BSIZE && data.len() != HD_BLOCKS * BSIZE {
+            return Err(invalid("not a DD or HD ADF"));
+        }
+        let dos_type = match &data[..4] {
+            [b'D', b'O', b'S', dos_type] if *dos_type < 4 => *dos_type,
+            [b'D', b'O', b'S', 4..=5] => {
+                return Err(invalid("directory caching disks are not supported"))
+            }
+            _ => return Err(invalid("no AmigaDOS file system")),
+        };
+        let adf = Adf {
+            data,
+            ffs: dos_type & 1 != 0,
+            intl: dos_type & 2 != 0,
+        };
+        let root = adf.root();
+        if adf.long(root, 0) != T_HEADER || adf.long(root, SEC_TYPE) != ST_ROOT {
+            return Err(invalid("no root block"));
+        }
+        if !(2..adf.blocks()).contains(&adf.bitmap()) {
+            return Err(invalid("no bitmap block"));
+        }
+        if adf.long(root, BM_FLAG) != u32::MAX {
+            return Err(invalid("the bitmap is not valid, validate the disk first"));
+        }
+        Ok(adf)
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        fs::write(path, &self.data)
+    }
+
+    fn blocks(&self) -> usize {
+        self.data.len() / BSIZE
+    }
+
+    fn root(&self) -> usize {
+        self.blocks() / 2
+    }
+
+    fn long(&self, block: usize, offset: usize) -> u32 {
+        let i = block * BSIZE + offset;
+        u32::from_be_bytes([
+            self.data[i],
+            self.data[i + 1],
+            self.data[i + 2],
+            self.data[i + 3],
+        ])
+    }
+
+    fn set_long(&mut self, block: usize, offset: usize, value: u32) {
+        let i = block * BSIZE + offset;
+        self.data[i..i + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// The checksum of header, data and extension blocks makes the sum of
+    /// all longs 0
+    fn checksum(&mut self, block: usize) {
+        self.set_long(block, CHECKSUM, 0);
+        let sum = (0..BSIZE).step_by(4).fold(0u32, |sum, offset| {
+            sum.wrapping_add(self.long(block, offset))
+        });
+        self.set_long(block, CHECKSUM, sum.wrapping_neg());
+    }
+
+    fn bitmap(&self) -> usize {
+        self.long(self.root(), BM_PAGES) as usize
+    }
+
+    /// The bitmap checksum is its first long
+    fn bitmap_checksum(&mut self) {
+        let bitmap = self.bitmap();
+        let sum = (4..BSIZE).step_by(4).fold(0u32, |sum, offset| {
+            sum.wrapping_add(self.long(bitmap, offset))
+        });
+        self.set_long(bitmap, 0, sum.wrapping_neg());
+    }
+
+    /// Bitmap position of `block`: blocks 0 and 1 are not in it, and a set
+    /// bit marks a free block
+    fn bitmap_bit(&self, block: usize) -> (usize, u32) {
+        (4 + (block - 2) / 32 * 4, 1 << ((block - 2) % 32))
+    }
+
+    fn is_free(&self, block: usize) -> bool {
+        let (offset, bit) = self.bitmap_bit(block);
+        self.long(self.bitmap(), offset) & bit != 0
+    }
+
+    fn set_free(&mut self, block: usize, free: bool) {
+        let (offset, bit) = self.bitmap_bit(block);
+        let bitmap = self.bitmap();
+        let bits = self.long(bitmap, offset);
+        self.set_long(bitmap, offset, if free { bits | bit } else { bits & !bit });
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        (2..self.blocks()).filter(|&b| self.is_free(b)).count()
+    }
+
+    /// Takes the first free block after the root, wrapping around, the way
+    /// AmigaDOS does
+    fn allocate(&mut self) -> IoResult<usize> {
+        let root = self.root();
+        let block = (root + 1..self.blocks())
+            .chain(2..root)
+            .find(|&b| self.is_free(b))
+            .ok_or_else(|| IoError::other("the disk is full"))?;
+        self.set_free(block, false);
+        self.data[block * BSIZE..(block + 1) * BSIZE].fill(0);
+        Ok(block)
+    }
+
+    fn upper(&self, c: u8) -> u8 {
+        match c {
+            224..=254 if self.intl && c != 247 => c - 32,
+            _ => c.to_ascii_uppercase(),
+        }
+    }
+
+    fn hash(&self, name: &[u8]) -> usize {
+        let hash = name.iter().fold(name.len() as u32, |hash, &c| {
+            (hash * 13 + self.upper(c) as u32) & 0x7ff
+        });
+        hash as usize % TABLE_SIZE
+    }
+
+    fn name(&self, block: usize) -> &[u8] {
+        let i = block * BSIZE + NAME;
+        let len = (self.data[i] as usize).min(MAX_NAME);
+        &self.data[i + 1..i + 1 + len]
+    }
+
+    fn set_name(&mut self, block: usize, name: &[u8]) {
+        let i = block * BSIZE + NAME;
+        self.data[i] = name.len() as u8;
+        self.data[i + 1..i + 1 + name.len()].copy_from_slice(name);
+    }
+
+    /// The entry called `name` in directory `dir`, ignoring case
+    fn find(&self, dir: usize, name: &[u8]) -> Option<usize> {
+        let same = |other: &[u8]| {
+            other.len() == name.len()
+                && other
+                    .iter()
+                    .zip(name)
+                    .all(|(&a, &b)| self.upper(a) == self.upper(b))
+        };
+        let mut block = self.long(dir, TABLE + self.hash(name) * 4) as usize;
+        while block != 0 && block < self.blocks() {
+            if same(self.name(block)) {
+                return Some(block);
+            }
+            block = self.long(block, HASH_CHAIN) as usize;
+        }
+        None
+    }
+
+    /// Frees the blocks of file `header` and takes it out of `dir`
+    fn delete(&mut self, dir: usize, header: usize) {
+        let mut table = header;
+        while table != 0 && table < self.blocks() {
+            for i in 0..(self.long(table, HIGH_SEQ) as usize).min(TABLE_SIZE) {
+                let block = self.long(table, TABLE + (TABLE_SIZE - 1 - i) * 4) as usize;
+                if (2..self.blocks()).contains(&block) {
+                    self.set_free(block, true);
+                }
+            }
+            self.set_free(table, true);
+            table = self.long(table, EXTENSION) as usize;
+        }
+
+        let slot = TABLE + self.hash(self.name(header)) * 4;
+        let next = self.long(header, HASH_CHAIN);
+        let mut previous = dir;
+        let mut offset = slot;
+        while self.long(previous, offset) as usize != header {
+            previous = self.long(previous, offset) as usize;
+            offset = HASH_CHAIN;
+        }
+        self.set_long(previous, offset, next);
+        self.checksum(previous);
+    }
+
+    /// Writes `contents` to `path`, a file in the root directory or in
+    /// existing directories separated by slashes, replacing any file of the
+    /// same name. Nothing changes if it fails
+    pub fn write_file(&mut self, path: &str, contents: &[u8]) -> IoResult<()> {
+        let backup = self.data.clone();
+        let result = self.write_file_unchecked(path, contents);
+        if result.is_err() {
+            self.data = backup;
+        }
+        result
+    }
+
+    fn write_file_unchecked(&mut self, path: &str, contents: &[u8]) -> IoResult<()> {
+        // names are Latin-1
+        let latin1 = |part: &str| {
+            part.chars()
+                .map(|c| match c as u32 {
+                    0..=0xff if c != ':' => Ok(c as u8),
+                    _ => Err(invalid("names have to be Latin-1 without colons")),
+                })
+                .collect::<IoResult<Vec<u8>>>()
+        };
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        let name = latin1(parts.next_back().ok_or_else(|| invalid("no file name"))?)?;
+        if name.len() > MAX_NAME {
+            return Err(invalid("file names have at most 30 characters"));
+        }
+        let mut dir = self.root();
+        for part in parts {
+            dir = match self.find(dir, &latin1(part)?) {
+                Some(block) if self.long(block, SEC_TYPE) == ST_USERDIR => block,
+                _ => {
+                    return Err(IoError::new(
+                        ErrorKind::NotFound,
+                        format!("no directory {} on the disk", part),
+                    ))
+                }
+            };
+        }
+        if let Some(old) = self.find(dir, &name) {
+            if self.long(old, SEC_TYPE) != ST_FILE {
+                return Err(invalid("a directory of that name is in the way"));
+            }
+            self.delete(dir, old);
+        }
+
+        let per_block = if self.ffs { BSIZE } else { BSIZE - OFS_HEADER };
+        let data_blocks = contents.len().div_ceil(per_block);
+        let extensions = data_blocks.saturating_sub(TABLE_SIZE).div_ceil(TABLE_SIZE);
+        if 1 + data_blocks + extensions > self.free_blocks() {
+            return Err(IoError::other("the disk is full"));
+        }
+        let header = self.allocate()?;
+        let mut blocks = vec![];
+        for _ in 0..data_blocks {
+            blocks.push(self.allocate()?);
+        }
+        let mut tables = vec![header];
+        for _ in 0..extensions {
+            tables.push(self.allocate()?);
+        }
+
+        for (seq, (&block, chunk)) in blocks.iter().zip(contents.chunks(per_block)).enumerate() {
+            if self.ffs {
+                self.data[block * BSIZE..block * BSIZE + chunk.len()].copy_from_slice(chunk);
+                continue;
+            }
+            self.set_long(block, 0, T_DATA);
+            self.set_long(block, 4, header as u32);
+            self.set_long(block, 8, seq as u32 + 1);
+            self.set_long(block, 12, chunk.len() as u32);
+            self.set_long(block, 16, blocks.get(seq + 1).map_or(0, |&b| b as u32));
+            let start = block * BSIZE + OFS_HEADER;
+            self.data[start..start + chunk.len()].copy_from_slice(chunk);
+            self.checksum(block);
+        }
+
+        // the file header and its extension blocks hold the block table
+        for (i, &table) in tables.iter().enumerate() {
+            let entries = &blocks[(i * TABLE_SIZE).min(data_blocks)..]
+                [..TABLE_SIZE.min(data_blocks.saturating_sub(i * TABLE_SIZE))];
+            self.set_long(table, 4, table as u32);
+            self.set_long(table, HIGH_SEQ, entries.len() as u32);
+            for (j, &block) in entries.iter().enumerate() {
+                self.set_long(table, TABLE + (TABLE_SIZE - 1 - j) * 4, block as u32);
+            }
+            self.set_long(table, PARENT, header as u32);
+            let next = tables.get(i + 1).map_or(0, |&b| b as u32);
+            self.set_long(table, EXTENSION, next);
+            self.set_long(table, SEC_TYPE, ST_FILE);
+            self.set_long(table, 0, T_LIST);
+        }
+        self.set_long(header, 0, T_HEADER);
+        self.set_long(header, FIRST_DATA, blocks.first().map_or(0, |&b| b as u32));
+        self.set_long(header, BYTE_SIZE, contents.len() as u32);
+        self.set_name(header, &name);
+        self.set_long(header, PARENT, dir as u32);
+        let slot = TABLE + self.hash(&name) * 4;
+        let chain = self.long(dir, slot);
+        self.set_long(header, HASH_CHAIN, chain);
+        self.set_long(dir, slot, header as u32);
+        for &table in &tables {
+            self.checksum(table);
+        }
+        self.checksum(dir);
+        self.bitmap_checksum();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Follows the block tables of file `header`
+    fn read(adf: &Adf, header: usize) -> Vec<u8> {
+        let mut contents = vec![];
+        let mut table = header;
+        while table != 0 {
+            for i in 0..adf.long(table, HIGH_SEQ) as usize {
+                let block = adf.long(table, TABLE + (TABLE_SIZE - 1 - i) * 4) as usize;
+                let data = &adf.data[block * BSIZE..(block + 1) * BSIZE];
+                match adf.ffs {
+                    true => contents.extend_from_slice(data),
+                    false => {
+                        let len = adf.long(block, 12) as usize;
+                        contents.extend_from_slice(&data[OFS_HEADER..OFS_HEADER + len]);
+                    }
+                }
+            }
+            table = adf.long(table, EXTENSION) as usize;
+        }
+        contents.truncate(adf.long(header, BYTE_SIZE) as usize);
+        contents
+    }
+
+    #[test]
+    fn writes_and_replaces_files() {
+        for ffs in [false, true] {
+            let mut adf = Adf::format(ffs, "Empty");
+            let free = adf.free_blocks();
+            assert_eq!(free, DD_BLOCKS - 4);
+
+            // big enough for an extension block
+            let big = (0..50_000).map(|i| i as u8).collect::<Vec<_>>();
+            adf.write_file("pic.iff", &big).unwrap();
+            let header = adf.find(adf.root(), b"PIC.IFF").unwrap();
+            assert_eq!(read(&adf, header), big);
+            assert_ne!(adf.long(header, EXTENSION), 0);
+
+            adf.write_file("Pic.iff", b"small").unwrap();
+            let header = adf.find(adf.root(), b"pic.iff").unwrap();
+            assert_eq!(read(&adf, header), b"small");
+            assert_eq!(adf.free_blocks(), free - 2);
+
+            assert!(adf.write_file("gfx/pic.iff", b"").is_err());
+            assert!(adf.write_file("huge", &vec![0; DD_BLOCKS * BSIZE]).is_err());
+            assert_eq!(adf.free_blocks(), free - 2);
+        }
+
+        let target = "C:\\disks\\game.ADF:gfx/".parse::<AdfTarget>().unwrap();
+        assert_eq!(target.disk, PathBuf::from("C:\\disks\\game.ADF"));
+        assert_eq!(target.path_for(Path::new("out/title.iff")), "gfx/title.iff");
+    }
+}
@@ -0,0 +1,370 @@
+use crate::iffimage::{
+    check_indices, check_size, Color, ColorMap, IffConvertError, IffImage, IffLoadError,
+    MASK_HAS_TRANSPARENT_COLOR,
+};
+use std::io::Read;
+
+const HEADER_SIZE: usize = 128;
+const FRAME_HEADER_SIZE: usize = 16;
+const MAGIC: u16 = 0xa5e0;
+const FRAME_MAGIC: u16 = 0xf1fa;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_PALETTE: u16 = 0x2019;
+
+const LAYER_VISIBLE: u16 = 1;
+const LAYER_BACKGROUND: u16 = 8;
+const LAYER_TYPE_IMAGE: u16 = 0;
+
+const CEL_RAW: u16 = 0;
+const CEL_LINKED: u16 = 1;
+const CEL_COMPRESSED: u16 = 2;
+
+/// Little endian cursor over the file contents
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], IffConvertError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(IffConvertError::Malformed("truncated Aseprite file"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, IffConvertError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, IffConvertError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn i16(&mut self) -> Result<i16, IffConvertError> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> Result<u32, IffConvertError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, IffConvertError> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+struct Layer {
+    flags: u16,
+    layer_type: u16,
+    name: String,
+}
+
+struct Cel {
+    layer: usize,
+    x: i16,
+    y: i16,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+enum CelData {
+    Image(Cel),
+    Linked(usize, usize),
+}
+
+/// Parses the chunks of one frame, collecting layers, palette entries and cels
+fn read_frame(
+    cursor: &mut Cursor,
+    layers: &mut Vec<Layer>,
+    palette: &mut Vec<Color>,
+) -> Result<Vec<CelData>, IffConvertError> {
+    let frame_start = cursor.pos;
+    let frame_size = cursor.u32()? as usize;
+    if cursor.u16()? != FRAME_MAGIC {
+        return Err(IffConvertError::Malformed("bad Aseprite frame magic"));
+    }
+    let old_chunks = cursor.u16()? as usize;
+    cursor.bytes(4)?;
+    let new_chunks = cursor.u32()? as usize;
+    let num_chunks = if new_chunks == 0 {
+        old_chunks
+    } else {
+        new_chunks
+    };
+    cursor.pos = frame_start + FRAME_HEADER_SIZE;
+
+    let mut cels = vec![];
+    for _ in 0..num_chunks {
+        let chunk_start = cursor.pos;
+        let chunk_size = cursor.u32()? as usize;
+        if chunk_size < 6 {
+            return Err(IffConvertError::Malformed("bad Aseprite chunk size"));
+        }
+        let chunk_type = cursor.u16()?;
+        match chunk_type {
+            CHUNK_PALETTE => {
+                let size = cursor.u32()? as usize;
+                let first = cursor.u32()? as usize;
+                let last = cursor.u32()? as usize;
+                cursor.bytes(8)?;
+                if size > 256 || last < first || last >= size {
+                    return Err(IffConvertError::Malformed("bad Aseprite palette"));
+                }
+                palette.resize(size, Color::default());
+                for entry in palette.iter_mut().take(last + 1).skip(first) {
+                    let flags = cursor.u16()?;
+                    let rgba = cursor.bytes(4)?;
+                    *entry = Color {
+                        r: rgba[0],
+                        g: rgba[1],
+                        b: rgba[2],
+                    };
+                    if flags & 1 != 0 {
+                        cursor.string()?;
+                    }
+                }
+            }
+            // only used when there is no new palette chunk
+            CHUNK_OLD_PALETTE if palette.is_empty() => {
+                let packets = cursor.u16()?;
+                let mut index = 0;
+                for _ in 0..packets {
+                    index += cursor.u8()? as usize;
+                    let count = match cursor.u8()? {
+                        0 => 256,
+                        n => n as usize,
+                    };
+                    if index + count > 256 {
+                        return Err(IffConvertError::Malformed("bad Aseprite palette"));
+                    }
+                    if palette.len() < index + count {
+                        palette.resize(index + count, Color::default());
+                    }
+                    for entry in palette.iter_mut().skip(index).take(count) {
+                        let rgb = cursor.bytes(3)?;
+                        *entry = Color {
+                            r: rgb[0],
+                            g: rgb[1],
+                            b: rgb[2],
+                        };
+                    }
+                    index += count;
+                }
+            }
+            CHUNK_LAYER => {
+                let flags = cursor.u16()?;
+                let layer_type = cursor.u16()?;
+                cursor.bytes(2 + 4 + 2 + 1 + 3)?;
+                let name = cursor.string()?;
+                layers.push(Layer {
+                    flags,
+                    layer_type,
+                    name,
+                });
+            }
+            CHUNK_CEL => {
+                let layer = cursor.u16()? as usize;
+                let x = cursor.i16()?;
+                let y = cursor.i16()?;
+                cursor.u8()?;
+                let cel_type = cursor.u16()?;
+                cursor.bytes(2 + 5)?;
+                match cel_type {
+                    CEL_RAW | CEL_COMPRESSED => {
+                        let width = cursor.u16()? as usize;
+                        let height = cursor.u16()? as usize;
+                        let data = cursor.bytes(
+                            (chunk_start + chunk_size)
+                                .checked_sub(cursor.pos)
+                                .ok_or(IffConvertError::Malformed("truncated Aseprite cel"))?,
+                        )?;
+                        let pixels = if cel_type == CEL_COMPRESSED {
+                            inflate::inflate_bytes_zlib(data).map_err(|_| {
+                                IffConvertError::Malformed("bad compressed Aseprite cel")
+                            })?
+                        } else {
+                            data.to_vec()
+                        };
+                        if width == 0 || pixels.len() < width * height {
+                            return Err(IffConvertError::Malformed("truncated Aseprite cel"));
+                        }
+                        cels.push(CelData::Image(Cel {
+                            layer,
+                            x,
+                            y,
+                            width,
+                            height,
+                            pixels,
+                        }));
+                    }
+                    CEL_LINKED => cels.push(CelData::Linked(layer, cursor.u16()? as usize)),
+                    // tilemaps are not supported
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        cursor.pos = chunk_start + chunk_size;
+    }
+    cursor.pos = frame_start + frame_size;
+    Ok(cels)
+}
+
+impl IffImage {
+    /// Converts one frame of an indexed Aseprite file. All visible image
+    /// layers are flattened unless a single layer is picked by name
+    pub fn from_ase_reader<R: Read>(
+        mut r: R,
+        frame: usize,
+        layer: Option<&str>,
+    ) -> Result<IffImage, IffLoadError> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        IffImage::from_ase_bytes(&data, frame, layer)
+    }
+
+    pub fn from_ase_bytes(
+        data: &[u8],
+        frame: usize,
+        layer: Option<&str>,
+    ) -> Result<IffImage, IffLoadError> {
+        let mut cursor = Cursor { data, pos: 0 };
+
+        cursor.u32()?;
+        if cursor.u16()? != MAGIC {
+            return Err(From::from(IffConvertError::UnsupportedFormat));
+        }
+        let num_frames = cursor.u16()? as usize;
+        let width = cursor.u16()?;
+        let height = cursor.u16()?;
+        check_size(width as usize, height as usize)?;
+        let depth = cursor.u16()?;
+        cursor.bytes(4 + 2 + 8)?;
+        let transparent = cursor.u8()?;
+        if depth != 8 {
+            return Err(From::from(IffConvertError::UnsupportedBitDepth(
+                depth as u8,
+            )));
+        }
+        if frame >= num_frames {
+            return Err(From::from(IffConvertError::Malformed(
+                "frame does not exist",
+            )));
+        }
+        cursor.pos = HEADER_SIZE;
+
+        let mut layers = vec![];
+        let mut palette = vec![];
+        // layers and palette live in the first frame, cels may link to earlier frames
+        let mut frames = vec![];
+        for _ in 0..=frame {
+            frames.push(read_frame(&mut cursor, &mut layers, &mut palette)?);
+        }
+        if palette.is_empty() {
+            return Err(From::from(IffConvertError::NoPalette));
+        }
+
+        let selected = |index: usize| match layers.get(index) {
+            None => false,
+            Some(l) if l.layer_type != LAYER_TYPE_IMAGE => false,
+            Some(l) => match layer {
+                Some(name) => l.name == name,
+                None => l.flags & LAYER_VISIBLE != 0,
+            },
+        };
+        if let Some(name) = layer {
+            if !layers.iter().any(|l| l.name == name) {
+                return Err(From::from(IffConvertError::Malformed(
+                    "layer does not exist",
+                )));
+            }
+        }
+        let has_background =
+            (0..layers.len()).any(|i| selected(i) && layers[i].flags & LAYER_BACKGROUND != 0);
+
+        let mut cels = frames[frame]
+            .iter()
+            .filter_map(|cel| match cel {
+                CelData::Image(cel) => Some(cel),
+                CelData::Linked(layer, source) => frames.get(*source).and_then(|cels| {
+                    cels.iter().find_map(|cel| match cel {
+                        CelData::Image(cel) if cel.layer == *layer => Some(cel),
+                        _ => None,
+                    })
+                }),
+            })
+            .filter(|cel| selected(cel.layer))
+            .collect::<Vec<_>>();
+        cels.sort_by_key(|cel| cel.layer);
+
+        let (width_usize, height_usize) = (width as usize, height as usize);
+        let mut pixels = vec![transparent; width_usize * height_usize];
+        for cel in cels {
+            let opaque = layers[cel.layer].flags & LAYER_BACKGROUND != 0;
+            for (row_index, row) in cel.pixels.chunks(cel.width).take(cel.height).enumerate() {
+                let y = cel.y as isize + row_index as isize;
+                if y < 0 || y >= height_usize as isize {
+                    continue;
+                }
+                for (column, &index) in row.iter().enumerate() {
+                    let x = cel.x as isize + column as isize;
+                    if x < 0 || x >= width_usize as isize || (!opaque && index == transparent) {
+                        continue;
+                    }
+                    pixels[y as usize * width_usize + x as usize] = index;
+                }
+            }
+        }
+
+        let cmap = ColorMap { colors: palette };
+        check_indices(&pixels, width_usize, cmap.colors.len())?;
+        let mut image = IffImage::from_parts(width, height, cmap, pixels);
+        if !has_background {
+            image.bmhd.masking = MASK_HAS_TRANSPARENT_COLOR;
+            image.bmhd.transparent_color = transparent as u16;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(frame: usize, layer: Option<&str>) -> IffImage {
+        IffImage::from_ase_reader(
+            &include_bytes!("../testdata/layers.aseprite")[..],
+            frame,
+            layer,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flattens_visible_layers() {
+        let image = load(0, None);
+        assert_eq!(image.bmhd.width, 4);
+        assert_eq!(image.bmhd.height, 2);
+        assert_eq!(image.pixels, vec![1, 2, 2, 1, 1, 1, 3, 1]);
+        assert_eq!(image.bmhd.masking, 0);
+        assert_eq!(image.cmap.colors[3].g, 0xff);
+    }
+
+    #[test]
+    fn single_layer_and_linked_frame() {
+        let image = load(0, Some("top"));
+        assert_eq!(image.pixels, vec![0, 2, 2, 0, 0, 0, 3, 0]);
+        assert_eq!(image.bmhd.masking, MASK_HAS_TRANSPARENT_COLOR);
+        assert_eq!(load(1, None).pixels, load(0, None).pixels);
+    }
+}
@@ -0,0 +1,266 @@
+//! Atari ST screen data. The bitplanes of every group of 16 pixels are
+//! interleaved word by word, and palette entries are 0x0RGB words with 3
+//! bits per channel
+//!
+//! Degas Elite pictures (.PI1, .PI2 and .PI3) hold a full screen in one of
+//! the three ST resolutions: a resolution word, the palette, 32000 bytes of
+//! screen data and the color cycling table
+
+use crate::iffimage::{Color, ColorMap, IffConvertError, IffImage};
+use std::fmt;
+use std::str::FromStr;
+
+/// Colors of the ST palette
+pub const ST_COLORS: usize = 16;
+
+/// The ST palette word for `color`, keeping the top 3 bits per channel
+pub fn st_color(color: &Color) -> u16 {
+    (color.r as u16 >> 5) << 8 | (color.g as u16 >> 5) << 4 | color.b as u16 >> 5
+}
+
+/// All 16 palette words, unused entries black
+pub fn st_palette(cmap: &ColorMap) -> Vec<u8> {
+    (0..ST_COLORS)
+        .flat_map(|i| cmap.colors.get(i).map_or(0, st_color).to_be_bytes())
+        .collect()
+}
+
+/// Bitplanes needed for `image`. The ST knows 1, 2 and 4
+pub fn st_planes(image: &IffImage) -> Result<usize, IffConvertError> {
+    match image.num_colors() {
+        0..=2 => Ok(1),
+        3..=4 => Ok(2),
+        5..=ST_COLORS => Ok(4),
+        colors => Err(IffConvertError::TooManyColors(colors)),
+    }
+}
+
+/// Word-interleaved bitplanes of `image` with `planes` planes. Lines are
+/// padded to a multiple of 16 pixels with color 0
+pub fn planar(image: &IffImage, planes: usize) -> Vec<u8> {
+    let width = image.width() as usize;
+    let groups = width.div_ceil(16);
+    let mut out = Vec::with_capacity(groups * planes * 2 * image.height() as usize);
+    for row in image.pixels.chunks(width) {
+        for group in row.chunks(16) {
+            for plane in 0..planes {
+                let word = group.iter().enumerate().fold(0u16, |word, (x, &p)| {
+                    word | ((p as u16 >> plane) & 1) << (15 - x)
+                });
+                out.extend_from_slice(&word.to_be_bytes());
+            }
+        }
+    }
+    out
+}
+
+/// The ST palette followed by the planar picture, 1, 2 or 4 planes
+/// depending on the number of colors
+pub fn encode(image: &IffImage) -> Result<Vec<u8>, IffConvertError> {
+    let planes = st_planes(image)?;
+    let mut out = st_palette(image.palette());
+    out.extend(planar(image, planes));
+    Ok(out)
+}
+
+/// The screen modes of the ST
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    /// 320x200 with 16 colors
+    Low,
+    /// 640x200 with 4 colors
+    Medium,
+    /// 640x400 monochrome
+    High,
+}
+
+impl Resolution {
+    /// Screen width, height and bitplanes
+    pub fn screen(self) -> (u16, u16, usize) {
+        match self {
+            Resolution::Low => (320, 200, 4),
+            Resolution::Medium => (640, 200, 2),
+            Resolution::High => (640, 400, 1),
+        }
+    }
+
+    /// The lowest resolution that shows the colors of `image`
+    pub fn for_image(image: &IffImage) -> Result<Resolution, IffConvertError> {
+        Ok(match st_planes(image)? {
+            4 => Resolution::Low,
+            2 => Resolution::Medium,
+            _ => Resolution::High,
+        })
+    }
+
+    /// The Degas file extension
+    pub fn extension(self) -> &'static str {
+        match self {
+            Resolution::Low => "pi1",
+            Resolution::Medium => "pi2",
+            Resolution::High => "pi3",
+        }
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "low" | "pi1" => Ok(Resolution::Low),
+            "medium" | "pi2" => Ok(Resolution::Medium),
+            "high" | "pi3" => Ok(Resolution::High),
+            _ => Err(format!(
+                "Unknown resolution {}, expected low, medium or high",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Resolution::Low => "low",
+            Resolution::Medium => "medium",
+            Resolution::High => "high",
+        })
+    }
+}
+
+/// Writes `image` as an uncompressed Degas Elite picture. Smaller images are
+/// padded to the full screen with color 0, and the color cycling table is
+/// left disabled
+pub fn degas(image: &IffImage, resolution: Resolution) -> Result<Vec<u8>, IffConvertError> {
+    let (width, height, planes) = resolution.screen();
+    let colors = image.num_colors();
+    if colors > 1 << planes {
+        return Err(IffConvertError::TooManyColors(colors));
+    }
+    let screen = image.pad(width, height, 0)?;
+    let mut out = (resolution as u16).to_be_bytes().to_vec();
+    out.extend(st_palette(image.palette()));
+    out.extend(planar(&screen, planes));
+    // left and right color, direction and delay of 4 cycling ranges
+    out.resize(out.len() + 4 * 4 * 2, 0);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaves_planes_per_16_pixels() {
+        let colors = ColorMap {
+            colors: vec![
+                Color { r: 0, g: 0, b: 0 },
+                Color {
+                    r: 255,
+                    g: 128,
+                    b: 31,
+                },
+                Color::default(),
+            ],
+        };
+        let mut pixels = vec![0; 20];
+        pixels[0] = 1;
+        pixels[1] = 2;
+        pixels[16] = 3;
+        let image = IffImage::from_parts(20, 1, colors, pixels);
+        assert_eq!(st_color(&image.palette().colors[1]), 0x0740);
+
+        let data = encode(&image).unwrap();
+        assert_eq!(data.len(), 32 + 2 * 2 * 2);
+        assert_eq!(&data[2..4], &[0x07, 0x40]);
+        assert_eq!(&data[32..], &[0x80, 0, 0x40, 0, 0x80, 0, 0x80, 0]);
+
+        let pi2 = degas(&image, Resolution::Medium).unwrap();
+        assert_eq!(pi2.len(), 32066);
+        assert_eq!(&pi2[..2], &[0, 1]);
+        assert_eq!(&pi2[34..40], &[0x80, 0, 0x40, 0, 0x80, 0]);
+        assert!(degas(&image, Resolution::High).is_err());
+    }
+
+    /// The resolution, palette and pixels of a Degas Elite picture
+    fn read_degas(data: &[u8]) -> (u16, Vec<Color>, Vec<u8>) {
+        let word = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let channel = |v: u16| ((v & 7) << 5) as u8;
+        let palette = (0..ST_COLORS)
+            .map(|i| word(2 + i * 2))
+            .map(|w| Color {
+                r: channel(w >> 8),
+                g: channel(w >> 4),
+                b: channel(w),
+            })
+            .collect();
+        let resolution = word(0);
+        let mode = [Resolution::Low, Resolution::Medium, Resolution::High][resolution as usize];
+        let (width, height, planes) = mode.screen();
+        let screen = &data[34..34 + 32000];
+        let pixels = (0..width as usize * height as usize)
+            .map(|i| {
+                let (x, y) = (i % width as usize, i / width as usize);
+                let group = (y * width as usize / 16 + x / 16) * planes;
+                (0..planes).fold(0, |p, plane| {
+                    let w = u16::from_be_bytes([
+                        screen[(group + plane) * 2],
+                        screen[(group + plane) * 2 + 1],
+                    ]);
+                    p | (((w >> (15 - x % 16)) & 1) as u8) << plane
+                })
+            })
+            .collect();
+        (resolution, palette, pixels)
+    }
+
+    #[test]
+    fn degas_round_trips_every_resolution() {
+        for resolution in [Resolution::Low, Resolution::Medium, Resolution::High] {
+            let (screen_width, _, planes) = resolution.screen();
+            let colors = (0..1u8 << planes)
+                .map(|i| Color {
+                    r: i << 4,
+                    g: !(i << 4),
+                    b: 0x3f,
+                })
+                .collect::<Vec<_>>();
+            let (width, height) = (21, 3);
+            let pixels = (0..width * height)
+                .map(|i| (i * 7 % colors.len()) as u8)
+                .collect::<Vec<_>>();
+            let image = IffImage::from_parts(
+                width as u16,
+                height as u16,
+                ColorMap {
+                    colors: colors.clone(),
+                },
+                pixels.clone(),
+            );
+            let data = degas(&image, resolution).unwrap();
+            assert_eq!(data.len(), 2 + 32 + 32000 + 32);
+
+            let (read_resolution, palette, screen) = read_degas(&data);
+            assert_eq!(read_resolution, resolution as u16);
+            // the ST keeps the top 3 bits of every channel
+            for (read, color) in palette.iter().zip(&colors) {
+                assert_eq!(read.r, color.r & 0xe0, "{}", resolution);
+                assert_eq!(read.g, color.g & 0xe0, "{}", resolution);
+                assert_eq!(read.b, color.b & 0xe0, "{}", resolution);
+            }
+            assert!(palette[colors.len()..]
+                .iter()
+                .all(|c| *c == Color::default()));
+            for (y, row) in screen.chunks(screen_width as usize).enumerate() {
+                let expected = if y < height {
+                    &pixels[y * width..(y + 1) * width]
+                } else {
+                    &[][..]
+                };
+                assert_eq!(&row[..expected.len()], expected, "{} row {}", resolution, y);
+                assert!(row[expected.len()..].iter().all(|&p| p == 0));
+            }
+        }
+    }
+}
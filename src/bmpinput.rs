@@ -0,0 +1,252 @@
+use crate::iffimage::{
+    check_indices, check_size, unpack_samples, Color, ColorMap, IffConvertError, IffImage,
+    IffLoadError,
+};
+use std::io::Read;
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+
+fn le_u16(data: &[u8], offset: usize) -> Result<u16, IffConvertError> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(IffConvertError::Malformed("truncated BMP header"))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Result<u32, IffConvertError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(IffConvertError::Malformed("truncated BMP header"))
+}
+
+/// Decodes BI_RLE8 data into bottom-up rows of `width` indices
+fn decode_rle8(data: &[u8], width: usize, height: usize) -> Result<Vec<u8>, IffConvertError> {
+    let mut pixels = vec![0u8; width * height];
+    let (mut x, mut y) = (0usize, 0usize);
+    let mut i = 0;
+    let mut put = |x: usize, y: usize, index: u8| {
+        if x < width && y < height {
+            pixels[y * width + x] = index;
+        }
+    };
+    while i + 1 < data.len() {
+        let (count, value) = (data[i] as usize, data[i + 1]);
+        i += 2;
+        if count > 0 {
+            for _ in 0..count {
+                put(x, y, value);
+                x += 1;
+            }
+            continue;
+        }
+        match value {
+            // end of line
+            0 => {
+                x = 0;
+                y += 1;
+            }
+            // end of bitmap
+            1 => break,
+            // delta
+            2 => {
+                let delta = data
+                    .get(i..i + 2)
+                    .ok_or(IffConvertError::Malformed("truncated RLE8 delta"))?;
+                x += delta[0] as usize;
+                y += delta[1] as usize;
+                i += 2;
+            }
+            // absolute run, padded to a word
+            n => {
+                let run = data
+                    .get(i..i + n as usize)
+                    .ok_or(IffConvertError::Malformed("truncated RLE8 run"))?;
+                for &index in run {
+                    put(x, y, index);
+                    x += 1;
+                }
+                i += (n as usize + 1) & !1;
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+impl IffImage {
+    /// Converts 1, 4 or 8 bit paletted BMPs (uncompressed or RLE8)
+    pub fn from_bmp_reader<R: Read>(mut r: R) -> Result<IffImage, IffLoadError> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        IffImage::from_bmp_bytes(&data)
+    }
+
+    pub fn from_bmp_bytes(data: &[u8]) -> Result<IffImage, IffLoadError> {
+        if !data.starts_with(b"BM") {
+            return Err(From::from(IffConvertError::UnsupportedFormat));
+        }
+
+        let pixel_offset = le_u32(data, 10)? as usize;
+        let header_size = le_u32(data, 14)? as usize;
+        // OS/2 BITMAPCOREHEADER uses 16 bit dimensions and 3 byte palette entries
+        let (width, height, bit_count, compression, colors_used, entry_size) = if header_size == 12
+        {
+            (
+                le_u16(data, 18)? as i32,
+                le_u16(data, 20)? as i16 as i32,
+                le_u16(data, 24)?,
+                BI_RGB,
+                0,
+                3,
+            )
+        } else {
+            (
+                le_u32(data, 18)? as i32,
+                le_u32(data, 22)? as i32,
+                le_u16(data, 28)?,
+                le_u32(data, 30)?,
+                le_u32(data, 46)? as usize,
+                4,
+            )
+        };
+
+        if matches!(bit_count, 16 | 24 | 32) {
+            // left to the image crate, which quantizes truecolor
+            return Err(From::from(IffConvertError::NoPalette));
+        }
+        if !matches!(bit_count, 1 | 4 | 8) {
+            return Err(From::from(IffConvertError::UnsupportedBitDepth(
+                bit_count as u8,
+            )));
+        }
+        let top_down = height < 0;
+        let (width, height) = (
+            width.unsigned_abs() as usize,
+            height.unsigned_abs() as usize,
+        );
+        check_size(width, height)?;
+
+        let num_colors = if colors_used == 0 {
+            1 << bit_count
+        } else {
+            colors_used
+        };
+        if num_colors > 256 {
+            return Err(From::from(IffConvertError::TooManyColors(num_colors)));
+        }
+        let palette_offset = 14 + header_size;
+        let palette = data
+            .get(palette_offset..palette_offset + num_colors * entry_size)
+            .ok_or(IffConvertError::Malformed("truncated BMP palette"))?;
+        // entries are stored as BGR(X)
+        let cmap = ColorMap {
+            colors: palette
+                .chunks(entry_size)
+                .map(|c| Color {
+                    r: c[2],
+                    g: c[1],
+                    b: c[0],
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        let bitmap = data
+            .get(pixel_offset..)
+            .ok_or(IffConvertError::Malformed("pixel data offset out of range"))?;
+        let mut pixels = match compression {
+            BI_RGB => {
+                let line_size = (width * bit_count as usize).div_ceil(32) * 4;
+                let bitmap = bitmap
+                    .get(..line_size * height)
+                    .ok_or(IffConvertError::Malformed("truncated BMP pixel data"))?;
+                unpack_samples(bitmap, line_size, width, bit_count as u8, 1)
+            }
+            BI_RLE8 if bit_count == 8 => decode_rle8(bitmap, width, height)?,
+            _ => {
+                return Err(From::from(IffConvertError::Malformed(
+                    "unsupported BMP compression",
+                )))
+            }
+        };
+        if !top_down {
+            pixels = pixels
+                .chunks(width)
+                .rev()
+                .flat_map(|row| row.iter().cloned())
+                .collect();
+        }
+        check_indices(&pixels, width, cmap.colors.len())?;
+
+        let (width, height) = check_size(width, height)?;
+        Ok(IffImage::from_parts(width, height, cmap, pixels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bottom-up BMP with a BITMAPINFOHEADER and no palette
+    fn bmp_header(width: i32, height: i32, bit_count: u16) -> Vec<u8> {
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&bit_count.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data
+    }
+
+    #[test]
+    fn rle8_runs_and_absolute() {
+        // run of 3 x index 1, absolute run 2 3 4, end of line, run of 2 x 5, end of bitmap
+        let data = [3, 1, 0, 3, 2, 3, 4, 0, 0, 0, 2, 5, 0, 1];
+        let pixels = decode_rle8(&data, 6, 2).unwrap();
+        assert_eq!(pixels, vec![1, 1, 1, 2, 3, 4, 5, 5, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bottom_up_8bit_bmp() {
+        let image =
+            IffImage::from_bmp_reader(&include_bytes!("../testdata/indexed.bmp")[..]).unwrap();
+        assert_eq!(image.bmhd.width, 3);
+        assert_eq!(image.bmhd.height, 2);
+        assert_eq!(image.pixels, vec![0, 1, 2, 2, 1, 0]);
+        assert_eq!(image.cmap.colors[1].r, 0xff);
+        assert_eq!(image.cmap.colors[2].b, 0xff);
+    }
+
+    #[test]
+    fn rejects_bit_counts_without_a_palette_layout() {
+        for bit_count in [0, 2, 7] {
+            let mut data = bmp_header(1, 1, bit_count);
+            data.extend_from_slice(&[0; 8]);
+            assert!(matches!(
+                IffImage::from_bmp_bytes(&data),
+                Err(IffLoadError::Convert(IffConvertError::UnsupportedBitDepth(depth)))
+                    if depth as u16 == bit_count
+            ));
+        }
+    }
+
+    #[test]
+    fn truecolor_bmp_is_quantized() {
+        let mut data = bmp_header(2, 1, 24);
+        // BGR pixels, the row padded to 4 bytes
+        data.extend_from_slice(&[0, 0, 255, 255, 0, 0, 0, 0]);
+        assert!(matches!(
+            IffImage::from_bmp_bytes(&data),
+            Err(IffLoadError::Convert(IffConvertError::NoPalette))
+        ));
+        #[cfg(feature = "image")]
+        {
+            let options = crate::iffimage::LoadOptions::default();
+            let image = IffImage::from_bytes(&data, &options).unwrap();
+            assert_eq!(image.num_colors(), 2);
+            assert_eq!(image.cmap.colors[image.pixels[0] as usize].r, 255);
+            assert_eq!(image.cmap.colors[image.pixels[1] as usize].b, 255);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+
+/// Name of the cache file kept in the output directory
+pub const CACHE_FILE: &str = ".ipng2iff-cache";
+
+/// 64 bit FNV-1a. Stable across builds, unlike the std hashers
+pub fn fnv1a(data: &[u8], seed: u64) -> u64 {
+    data.iter().fold(seed, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Remembers a content+settings hash and the produced file per input file
+/// between batch runs
+#[derive(Debug, Default)]
+pub struct ConversionCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (u64, PathBuf)>,
+}
+
+impl ConversionCache {
+    /// Loads the cache from `dir`. A missing or unreadable cache is empty
+    pub fn load(dir: &Path) -> ConversionCache {
+        let path = dir.join(CACHE_FILE);
+        let entries = fs::read_to_string(&path)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(3, '\t');
+                        let hash = u64::from_str_radix(parts.next()?, 16).ok()?;
+                        let input = PathBuf::from(parts.next()?);
+                        Some((input, (hash, PathBuf::from(parts.next()?))))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        ConversionCache { path, entries }
+    }
+
+    /// The file converted from `input` last time, if nothing changed since
+    pub fn fresh_output(&self, input: &Path, hash: u64) -> Option<&Path> {
+        match self.entries.get(input) {
+            Some((cached, output)) if *cached == hash => Some(output),
+            _ => None,
+        }
+    }
+
+    /// The file converted from `input` in an earlier run, changed or not
+    pub fn output(&self, input: &Path) -> Option<&Path> {
+        self.entries.get(input).map(|(_, output)| output.as_path())
+    }
+
+    pub fn update(&mut self, input: &Path, hash: u64, output: &Path) {
+        self.entries
+            .insert(input.to_path_buf(), (hash, output.to_path_buf()));
+    }
+
+    pub fn save(&self) -> IoResult<()> {
+        let mut lines = self
+            .entries
+            .iter()
+            .map(|(input, (hash, output))| {
+                format!("{:016x}\t{}\t{}\n", hash, input.display(), output.display())
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        fs::write(&self.path, lines.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_reference_values() {
+        assert_eq!(fnv1a(b"", FNV_OFFSET), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a", FNV_OFFSET), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,91 @@
+//! Amiga display mode IDs as stored in the CAMG chunk. The low word holds
+//! the ViewPort mode flags, the high word the monitor, e.g. 0x00021000 for
+//! PAL. Modes can be given as a number or by the names of their flags
+
+use crate::playfield::{CAMG_DUALPF, CAMG_PF2PRI};
+use std::str::FromStr;
+
+pub const CAMG_GENLOCK_VIDEO: u32 = 0x0002;
+pub const CAMG_LACE: u32 = 0x0004;
+pub const CAMG_DOUBLESCAN: u32 = 0x0008;
+pub const CAMG_SUPERHIRES: u32 = 0x0020;
+pub const CAMG_EXTRA_HALFBRITE: u32 = 0x0080;
+pub const CAMG_GENLOCK_AUDIO: u32 = 0x0100;
+pub const CAMG_HAM: u32 = 0x0800;
+pub const CAMG_HIRES: u32 = 0x8000;
+pub const NTSC_MONITOR_ID: u32 = 0x0001_1000;
+pub const PAL_MONITOR_ID: u32 = 0x0002_1000;
+
+/// Flag names accepted by --camg-flags
+const FLAGS: [(&str, u32); 14] = [
+    ("GENLOCK", CAMG_GENLOCK_VIDEO),
+    ("LACE", CAMG_LACE),
+    ("DOUBLESCAN", CAMG_DOUBLESCAN),
+    ("SUPERHIRES", CAMG_SUPERHIRES),
+    ("PF2PRI", CAMG_PF2PRI),
+    ("EHB", CAMG_EXTRA_HALFBRITE),
+    ("HALFBRITE", CAMG_EXTRA_HALFBRITE),
+    ("GENLOCK_AUDIO", CAMG_GENLOCK_AUDIO),
+    ("DUALPF", CAMG_DUALPF),
+    ("HAM", CAMG_HAM),
+    ("HIRES", CAMG_HIRES),
+    ("LORES", 0),
+    ("NTSC", NTSC_MONITOR_ID),
+    ("PAL", PAL_MONITOR_ID),
+];
+
+/// A whole mode ID, given as hex with 0x or as decimal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModeId(pub u32);
+
+impl FromStr for ModeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        parsed
+            .map(ModeId)
+            .map_err(|_| format!("Invalid mode ID {:?}. Expected a number like 0x00029004", s))
+    }
+}
+
+/// Mode flags by name, comma separated like HAM,LACE,PAL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModeFlags(pub u32);
+
+impl FromStr for ModeFlags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mode = 0;
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let upper = name.to_ascii_uppercase();
+            let (_, flag) = FLAGS.iter().find(|(n, _)| *n == upper).ok_or_else(|| {
+                let names = FLAGS.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                format!("Unknown mode flag {}, expected {}", name, names.join(", "))
+            })?;
+            mode |= flag;
+        }
+        Ok(ModeFlags(mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids_and_flags() {
+        assert_eq!("0x00029004".parse(), Ok(ModeId(0x0002_9004)));
+        assert_eq!("32772".parse(), Ok(ModeId(0x8004)));
+        assert!("0xZZ".parse::<ModeId>().is_err());
+
+        assert_eq!("HAM,lace, HIRES".parse(), Ok(ModeFlags(0x8804)));
+        assert_eq!("ehb,pal".parse(), Ok(ModeFlags(0x0002_1080)));
+        assert!("HAM,SHAM".parse::<ModeFlags>().is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::iff::{form_chunks, Form, RawChunk};
+use crate::iffimage::{IffConvertError, IffImage, MASK_HAS_MASK};
+use crate::iffinput::parse_bmhd;
+use crate::palette::cmap_from_iff;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A four character chunk ID. Shorter IDs are padded with spaces
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkId(pub [u8; 4]);
+
+impl FromStr for ChunkId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 4 || !s.bytes().all(|b| (0x20..=0x7e).contains(&b)) {
+            return Err(format!(
+                "Invalid chunk ID {:?}. Expected up to 4 characters",
+                s
+            ));
+        }
+        let mut id = [b' '; 4];
+        id[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(ChunkId(id))
+    }
+}
+
+/// Chunk contents given as ID=text, ID=0xHEX or ID=@file
+#[derive(Debug)]
+pub struct ChunkValue {
+    pub id: ChunkId,
+    pub data: Vec<u8>,
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl FromStr for ChunkValue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid chunk {:?}. Expected ID=value", s))?;
+        let data = if let Some(path) = value.strip_prefix('@') {
+            fs::read(path).map_err(|e| format!("Can't read {:?}: {}", path, e))?
+        } else if let Some(hex) = value.strip_prefix("0x") {
+            parse_hex(hex).ok_or_else(|| format!("Invalid hex data {:?}", hex))?
+        } else {
+            value.as_bytes().to_vec()
+        };
+        Ok(ChunkValue {
+            id: id.parse()?,
+            data,
+        })
+    }
+}
+
+/// Chunk contents read from a file, given as ID:path
+#[derive(Debug)]
+pub struct ChunkFile {
+    pub id: ChunkId,
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+impl FromStr for ChunkFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, path) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid chunk {:?}. Expected ID:path", s))?;
+        let id = id.parse::<ChunkId>()?;
+        if [b"BMHD", b"CMAP", b"CAMG", b"BODY"].contains(&&id.0) {
+            return Err(format!(
+                "{} chunks are written by the converter",
+                String::from_utf8_lossy(&id.0)
+            ));
+        }
+        let data = fs::read(path).map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+        Ok(ChunkFile {
+            id,
+            path: PathBuf::from(path),
+            data,
+        })
+    }
+}
+
+/// Rewrites an ILBM with chunks replaced, inserted before the BODY or
+/// deleted. The BMHD and BODY can't be edited as they have to match
+pub fn edit_form(
+    data: &[u8],
+    set: &[ChunkValue],
+    delete: &[ChunkId],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let protected = |id: &ChunkId| &id.0 == b"BMHD" || &id.0 == b"BODY";
+    if set.iter().map(|c| &c.id).chain(delete).any(protected) {
+        return Err(From::from("BMHD and BODY chunks can't be edited"));
+    }
+
+    let mut chunks = vec![];
+    for (id, chunk) in form_chunks(data, b"ILBM")? {
+        if delete.contains(&ChunkId(id)) {
+            continue;
+        }
+        match set.iter().find(|c| c.id.0 == id) {
+            // the first chunk with the ID is replaced, any later ones dropped
+            Some(value) if !chunks.iter().any(|(i, _)| *i == id) => {
+                chunks.push((id, value.data.as_slice()))
+            }
+            Some(_) => {}
+            None => chunks.push((id, chunk)),
+        }
+    }
+    let mut body = chunks
+        .iter()
+        .position(|(id, _)| id == b"BODY")
+        .unwrap_or(chunks.len());
+    for value in set {
+        if !chunks.iter().any(|(id, _)| *id == value.id.0) {
+            chunks.insert(body, (value.id.0, value.data.as_slice()));
+            body += 1;
+        }
+    }
+
+    Ok(write_ilbm(&chunks))
+}
+
+/// Replaces the picture of an ILBM with `image`, keeping the CMAP and every
+/// other chunk. The pixels have to use colors of the existing palette
+pub fn rebody(data: &[u8], image: &IffImage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let chunks = form_chunks(data, b"ILBM")?;
+    let old = chunks
+        .iter()
+        .find(|(id, _)| id == b"BMHD")
+        .ok_or(IffConvertError::MissingChunk(*b"BMHD"))?;
+    let old = parse_bmhd(old.1)?;
+
+    let mut image = image.remap_to(&cmap_from_iff(data)?)?;
+    // fields describing the picture come from the new image, the rest stays
+    image.bmhd.bitplanes = image.bmhd.bitplanes.max(old.bitplanes);
+    image.bmhd.compression = old.compression;
+    image.bmhd.transparent_color = old.transparent_color;
+    image.bmhd.x_aspect = old.x_aspect;
+    image.bmhd.y_aspect = old.y_aspect;
+    image.bmhd.x = old.x;
+    image.bmhd.y = old.y;
+    if image.mask.is_none() && old.masking != MASK_HAS_MASK {
+        image.bmhd.masking = old.masking;
+    }
+
+    let bmhd = image.get_bmhd();
+    let body = image.get_body();
+    let chunks = chunks
+        .into_iter()
+        .map(|(id, chunk)| match &id {
+            b"BMHD" => (id, bmhd.as_slice()),
+            b"BODY" => (id, body.as_slice()),
+            _ => (id, chunk),
+        })
+        .collect::<Vec<_>>();
+    Ok(write_ilbm(&chunks))
+}
+
+fn write_ilbm(chunks: &[RawChunk]) -> Vec<u8> {
+    chunks
+        .iter()
+        .fold(Form::new(b"ILBM"), |form, (id, data)| form.chunk(id, *data))
+        .to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iff::chunks;
+    use crate::iffimage::{Color, ColorMap, IffImage};
+
+    #[test]
+    fn sets_and_deletes_chunks() {
+        let image = IffImage::from_parts(
+            16,
+            1,
+            ColorMap {
+                colors: vec![Color::default(); 2],
+            },
+            vec![1; 16],
+        );
+        let original = image.to_bytes();
+
+        let set = [
+            "CAMG=0x00000804".parse().unwrap(),
+            "ANNO=hi!".parse().unwrap(),
+        ];
+        let edited = edit_form(&original, &set, &["CMAP".parse().unwrap()]).unwrap();
+        let form_size = u32::from_be_bytes([edited[4], edited[5], edited[6], edited[7]]);
+        assert_eq!(form_size as usize, edited.len() - 8);
+        let ids = chunks(&edited[12..])
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![*b"BMHD", *b"CAMG", *b"ANNO", *b"BODY"]);
+        assert!(edit_form(&original, &[], &["BODY".parse().unwrap()]).is_err());
+        assert_eq!("CAT".parse::<ChunkId>().unwrap().0, *b"CAT ");
+    }
+
+    #[test]
+    fn rebody_keeps_palette_and_chunks() {
+        let colors = vec![
+            Color { r: 0, g: 0, b: 0 },
+            Color { r: 9, g: 9, b: 9 },
+            Color { r: 255, g: 0, b: 0 },
+        ];
+        let image = IffImage::from_parts(
+            16,
+            1,
+            ColorMap {
+                colors: colors.clone(),
+            },
+            vec![0; 16],
+        );
+        let original = image.to_bytes();
+        let original = edit_form(&original, &["CRNG=0x0000".parse().unwrap()], &[]).unwrap();
+
+        // same colors in a different order
+        let new = IffImage::from_parts(
+            16,
+            2,
+            ColorMap {
+                colors: vec![colors[2].clone(), colors[0].clone()],
+            },
+            vec![0; 32],
+        );
+        let rebodied = IffImage::from_iff_bytes(&rebody(&original, &new).unwrap()).unwrap();
+        assert_eq!(rebodied.bmhd.height, 2);
+        assert_eq!(rebodied.pixels, vec![2; 32]);
+        assert!(chunks(&rebody(&original, &new).unwrap()[12..])
+            .unwrap()
+            .iter()
+            .any(|(id, _)| id == b"CRNG"));
+
+        let foreign = IffImage::from_parts(
+            16,
+            1,
+            ColorMap {
+                colors: vec![Color { r: 1, g: 2, b: 3 }],
+            },
+            vec![0; 16],
+        );
+        assert!(rebody(&original, &foreign).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Config file picked up from the current directory
+pub const CONFIG_FILE: &str = "ipng2iff.toml";
+
+/// Named profiles and per-file overrides from the config file. Both are
+/// kept as extra command line arguments so every option can be set there
+/// and is validated exactly like on the command line:
+///
+/// ```toml
+/// [profiles.ocs]
+/// compress = "byterun1"
+/// colors = 32
+///
+/// [files."gfx/title.png"]
+/// crop = "0,0,320x200"
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    profiles: HashMap<String, Vec<OsString>>,
+    files: Vec<(PathBuf, Vec<OsString>)>,
+}
+
+/// Turns `key = value` pairs into `--key value` arguments
+fn table_args(table: &toml::Table) -> Result<Vec<OsString>, String> {
+    let mut args = vec![];
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        match value {
+            toml::Value::Boolean(true) => args.push(flag.into()),
+            toml::Value::Boolean(false) => {}
+            toml::Value::String(s) => {
+                args.push(flag.into());
+                args.push(s.into());
+            }
+            toml::Value::Integer(_) | toml::Value::Float(_) => {
+                args.push(flag.into());
+                args.push(value.to_string().into());
+            }
+            _ => return Err(format!("Unsupported value for {:?} in config", key)),
+        }
+    }
+    Ok(args)
+}
+
+fn tables<'a>(
+    config: &'a toml::Table,
+    section: &str,
+) -> Result<Vec<(&'a String, &'a toml::Table)>, String> {
+    match config.get(section) {
+        None => Ok(vec![]),
+        Some(toml::Value::Table(entries)) => entries
+            .iter()
+            .map(|(name, value)| match value {
+                toml::Value::Table(table) => Ok((name, table)),
+                _ => Err(format!("[{}.{:?}] in config is not a table", section, name)),
+            })
+            .collect(),
+        Some(_) => Err(format!("[{}] in config is not a table", section)),
+    }
+}
+
+/// Paths are compared without a leading ./
+fn normalize(path: &Path) -> &Path {
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let config = contents
+            .parse::<toml::Table>()
+            .map_err(|e| format!("Invalid config: {}", e))?;
+        let mut profiles = HashMap::new();
+        for (name, table) in tables(&config, "profiles")? {
+            profiles.insert(name.clone(), table_args(table)?);
+        }
+        let mut files = vec![];
+        for (path, table) in tables(&config, "files")? {
+            files.push((normalize(Path::new(path)).to_path_buf(), table_args(table)?));
+        }
+        Ok(Config { profiles, files })
+    }
+
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Config::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    pub fn profile_args(&self, name: &str) -> Result<&[OsString], String> {
+        self.profiles
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| format!("Unknown profile {:?}", name))
+    }
+
+    /// Every input with overrides, and the arguments overriding them
+    pub fn file_overrides(&self) -> impl Iterator<Item = (&Path, &[OsString])> {
+        self.files
+            .iter()
+            .map(|(path, args)| (path.as_path(), args.as_slice()))
+    }
+}
+
+/// Whether `input` is the file an override was written for
+pub fn matches(input: &Path, file: &Path) -> bool {
+    normalize(input) == file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_and_files_become_arguments() {
+        let config = Config::parse(
+            r#"
+            [profiles.ocs]
+            compress = "byterun1"
+            colors = 16
+            dry_run = true
+            mmap = false
+
+            [files."./gfx/title.png"]
+            crop = "0,0,32x32"
+            "#,
+        )
+        .unwrap();
+        let args = config.profile_args("ocs").unwrap();
+        assert_eq!(
+            args,
+            ["--colors", "16", "--compress", "byterun1", "--dry-run"]
+                .iter()
+                .map(OsString::from)
+                .collect::<Vec<_>>()
+                .as_slice()
+        );
+        assert!(config.profile_args("aga").is_err());
+        let (file, args) = config.file_overrides().next().unwrap();
+        assert!(matches(Path::new("./gfx/title.png"), file));
+        assert!(matches(Path::new("gfx/title.png"), file));
+        assert_eq!(args.len(), 2);
+    }
+}
@@ -0,0 +1,142 @@
+//! Pixels a conversion failed on: in colors the palette doesn't have. They
+//! can be listed with their position and color, or pointed out in a picture
+//! so they are easy to find in a paint program
+
+use crate::iffimage::{Color, ColorMap, IffImage};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Grays of the dimmed image in the highlight picture
+const GRAYS: usize = 64;
+
+/// One pixel that could not be converted
+#[derive(Clone, Debug, PartialEq)]
+pub struct Offender {
+    pub x: usize,
+    pub y: usize,
+    pub color: [u8; 3],
+}
+
+/// The offending pixels of an image, along with all the others for context
+#[derive(Debug)]
+pub struct PixelReport {
+    width: usize,
+    height: usize,
+    rgb: Vec<[u8; 3]>,
+    offending: Vec<bool>,
+}
+
+impl PixelReport {
+    pub(crate) fn new(
+        width: usize,
+        height: usize,
+        rgb: Vec<[u8; 3]>,
+        offending: Vec<bool>,
+    ) -> PixelReport {
+        PixelReport {
+            width,
+            height,
+            rgb,
+            offending,
+        }
+    }
+
+    /// Number of offending pixels
+    pub fn count(&self) -> usize {
+        self.offending.iter().filter(|&&o| o).count()
+    }
+
+    /// Number of different colors among the offending pixels
+    pub fn colors(&self) -> usize {
+        self.offenders()
+            .map(|o| o.color)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// The offending pixels, row by row from the top left
+    pub fn offenders(&self) -> impl Iterator<Item = Offender> + '_ {
+        let width = self.width.max(1);
+        self.offending
+            .iter()
+            .enumerate()
+            .filter(|(_, &o)| o)
+            .map(move |(i, _)| Offender {
+                x: i % width,
+                y: i / width,
+                color: self.rgb[i],
+            })
+    }
+
+    /// The image in dark grays with the offending pixels in bright red
+    pub fn highlight(&self) -> IffImage {
+        let mut colors = (0..GRAYS)
+            .map(|i| {
+                let v = (i * 2) as u8;
+                Color { r: v, g: v, b: v }
+            })
+            .collect::<Vec<_>>();
+        colors.push(Color { r: 255, g: 0, b: 0 });
+        let pixels = self
+            .rgb
+            .iter()
+            .zip(&self.offending)
+            .map(|(&[r, g, b], &offending)| match offending {
+                true => GRAYS as u8,
+                false => {
+                    let luma = (r as usize * 299 + g as usize * 587 + b as usize * 114) / 1000;
+                    (luma * GRAYS / 256) as u8
+                }
+            })
+            .collect();
+        IffImage::from_parts(
+            self.width as u16,
+            self.height as u16,
+            ColorMap { colors },
+            pixels,
+        )
+    }
+}
+
+impl fmt::Display for PixelReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} pixels in {} colors are not in the palette",
+            self.count(),
+            self.colors()
+        )?;
+        if let Some(first) = self.offenders().next() {
+            write!(f, ", the first at {},{}", first.x, first.y)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_and_highlights_offenders() {
+        let rgb = vec![[0, 0, 0], [9, 9, 9], [255, 255, 255], [9, 9, 9]];
+        let report = PixelReport::new(2, 2, rgb, vec![false, true, false, true]);
+        assert_eq!((report.count(), report.colors()), (2, 1));
+        assert_eq!(
+            report.offenders().last(),
+            Some(Offender {
+                x: 1,
+                y: 1,
+                color: [9, 9, 9]
+            })
+        );
+        assert_eq!(
+            report.to_string(),
+            "2 pixels in 1 colors are not in the palette, the first at 1,0"
+        );
+
+        let highlight = report.highlight();
+        assert_eq!(highlight.pixels, vec![0, 64, 63, 64]);
+        assert_eq!(highlight.palette().colors[64], Color { r: 255, g: 0, b: 0 });
+    }
+}
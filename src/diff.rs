@@ -0,0 +1,193 @@
+use crate::iff::form_chunks;
+use crate::iffimage::{IffImage, IffLoadError};
+use std::fmt;
+
+/// How a chunk differs between two ILBMs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkChange {
+    Added([u8; 4]),
+    Removed([u8; 4]),
+    Changed([u8; 4]),
+}
+
+impl fmt::Display for ChunkChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (id, what) = match self {
+            ChunkChange::Added(id) => (id, "added"),
+            ChunkChange::Removed(id) => (id, "removed"),
+            ChunkChange::Changed(id) => (id, "changed"),
+        };
+        f.write_fmt(format_args!("{} {}", String::from_utf8_lossy(id), what))
+    }
+}
+
+/// Differences between two ILBMs. Pixels are compared by their color, so a
+/// reordered palette alone does not make pixels differ
+#[derive(Debug, Default, PartialEq)]
+pub struct IffDiff {
+    /// Chunks besides the BODY that were added, removed or changed
+    pub chunks: Vec<ChunkChange>,
+    /// Both sizes if they differ, in which case pixels are not compared
+    pub size: Option<((u16, u16), (u16, u16))>,
+    /// Indices of palette entries that differ or exist in only one palette
+    pub palette: Vec<usize>,
+    pub pixels: usize,
+    pub total_pixels: usize,
+    /// Smallest rectangle holding all differing pixels as (x, y, width, height)
+    pub bounds: Option<(usize, usize, usize, usize)>,
+}
+
+impl IffDiff {
+    pub fn is_identical(&self) -> bool {
+        self.chunks.is_empty() && self.size.is_none() && self.palette.is_empty() && self.pixels == 0
+    }
+}
+
+impl fmt::Display for IffDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_identical() {
+            return f.write_str("identical\n");
+        }
+        if !self.chunks.is_empty() {
+            let chunks = self.chunks.iter().map(ToString::to_string);
+            writeln!(f, "chunks: {}", chunks.collect::<Vec<_>>().join(", "))?;
+        }
+        if !self.palette.is_empty() {
+            let indices = self.palette.iter().map(ToString::to_string);
+            writeln!(
+                f,
+                "palette: {} entries differ ({})",
+                self.palette.len(),
+                indices.collect::<Vec<_>>().join(", ")
+            )?;
+        }
+        match (self.size, self.bounds) {
+            (Some(((w1, h1), (w2, h2))), _) => writeln!(f, "size: {}x{} vs {}x{}", w1, h1, w2, h2),
+            (None, Some((x, y, width, height))) => writeln!(
+                f,
+                "pixels: {} of {} differ within {},{},{}x{}",
+                self.pixels, self.total_pixels, x, y, width, height
+            ),
+            (None, None) => writeln!(f, "pixels: identical"),
+        }
+    }
+}
+
+/// A chunk ID with how many chunks of that ID came before it
+type ChunkKey = ([u8; 4], usize);
+
+/// All chunks but the BODY, numbered per ID so repeated chunks are compared
+/// with their counterpart
+fn numbered_chunks(data: &[u8]) -> Result<Vec<(ChunkKey, &[u8])>, IffLoadError> {
+    let mut numbered: Vec<(ChunkKey, &[u8])> = vec![];
+    for (id, chunk) in form_chunks(data, b"ILBM")? {
+        if &id == b"BODY" {
+            continue;
+        }
+        let n = numbered.iter().filter(|((i, _), _)| *i == id).count();
+        numbered.push(((id, n), chunk));
+    }
+    Ok(numbered)
+}
+
+/// Compares two ILBM files
+pub fn diff(a: &[u8], b: &[u8]) -> Result<IffDiff, IffLoadError> {
+    let (image_a, image_b) = (IffImage::from_iff_bytes(a)?, IffImage::from_iff_bytes(b)?);
+    let (chunks_a, chunks_b) = (numbered_chunks(a)?, numbered_chunks(b)?);
+    let mut result = IffDiff::default();
+
+    for (key, data) in &chunks_a {
+        match chunks_b.iter().find(|(k, _)| k == key) {
+            None => result.chunks.push(ChunkChange::Removed(key.0)),
+            Some((_, other)) if other != data => result.chunks.push(ChunkChange::Changed(key.0)),
+            Some(_) => {}
+        }
+    }
+    for (key, _) in &chunks_b {
+        if !chunks_a.iter().any(|(k, _)| k == key) {
+            result.chunks.push(ChunkChange::Added(key.0));
+        }
+    }
+
+    let (colors_a, colors_b) = (&image_a.palette().colors, &image_b.palette().colors);
+    result.palette = (0..colors_a.len().max(colors_b.len()))
+        .filter(|&i| colors_a.get(i) != colors_b.get(i))
+        .collect();
+
+    let size_a = (image_a.width(), image_a.height());
+    let size_b = (image_b.width(), image_b.height());
+    if size_a != size_b {
+        result.size = Some((size_a, size_b));
+        return Ok(result);
+    }
+    let width = size_a.0 as usize;
+    result.total_pixels = image_a.pixels.len();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for (i, (&pa, &pb)) in image_a.pixels.iter().zip(&image_b.pixels).enumerate() {
+        // an index beyond its palette always counts as a difference
+        let same = match (colors_a.get(pa as usize), colors_b.get(pb as usize)) {
+            (Some(ca), Some(cb)) => ca == cb,
+            _ => false,
+        };
+        if !same {
+            let (x, y) = (i % width, i / width);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+            result.pixels += 1;
+        }
+    }
+    if result.pixels > 0 {
+        result.bounds = Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunkedit::edit_form;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn reports_chunks_palette_and_pixels() {
+        let colors = vec![Color { r: 0, g: 0, b: 0 }, Color { r: 9, g: 9, b: 9 }];
+        let a = IffImage::from_parts(
+            16,
+            2,
+            ColorMap {
+                colors: colors.clone(),
+            },
+            vec![0; 32],
+        );
+        let mut pixels = [0; 32];
+        pixels[18] = 1;
+        pixels[21] = 1;
+        // same colors swapped, so only the changed pixels count
+        let b = IffImage::from_parts(
+            16,
+            2,
+            ColorMap {
+                colors: vec![colors[1].clone(), colors[0].clone()],
+            },
+            pixels.iter().map(|p| 1 - p).collect(),
+        );
+        let b = edit_form(&b.to_bytes(), &["ANNO=hi".parse().unwrap()], &[]).unwrap();
+
+        let result = diff(&a.to_bytes(), &b).unwrap();
+        assert_eq!(
+            result.chunks,
+            vec![ChunkChange::Changed(*b"CMAP"), ChunkChange::Added(*b"ANNO")]
+        );
+        assert_eq!(result.palette, vec![0, 1]);
+        assert_eq!(result.pixels, 2);
+        assert_eq!(result.bounds, Some((2, 1, 4, 1)));
+        assert!(diff(&b, &b).unwrap().is_identical());
+
+        // pixels beyond a short CMAP are an error, not a panic
+        let mut short = IffImage::from_parts(16, 1, ColorMap { colors }, vec![1; 16]);
+        short.cmap.colors.truncate(1);
+        assert!(diff(&a.to_bytes(), &short.to_bytes()).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+//! Dithering onto a fixed palette. Error diffusion (Floyd-Steinberg) spreads
+//! the difference to the chosen color over the neighbouring pixels, ordered
+//! dithering shifts every pixel by the threshold of its position in a tiled
+//! matrix, which keeps flat areas regular and packs better. A blue noise
+//! matrix does the same without the cross-hatched look of Bayer. All can be
+//! toned down, full strength often looks noisy on lores screens
+
+use crate::iffimage::{ColorMap, ColorMetric, IffImage, Matcher, BAYER_4X4};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Side of the blue noise matrix
+const BLUE_NOISE_SIZE: usize = 64;
+/// Spread of the filter finding clusters and voids while making blue noise
+const BLUE_NOISE_SIGMA: f64 = 1.5;
+
+/// How colors missing from the palette are made up from the others
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DitherMethod {
+    /// Every pixel takes the closest color
+    None,
+    FloydSteinberg,
+    /// With the threshold matrix, 4x4 Bayer unless given
+    Ordered,
+    /// Ordered with a 64x64 blue noise matrix
+    BlueNoise,
+}
+
+impl FromStr for DitherMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DitherMethod::None),
+            "floyd-steinberg" | "fs" => Ok(DitherMethod::FloydSteinberg),
+            "ordered" | "bayer" => Ok(DitherMethod::Ordered),
+            "bluenoise" | "blue-noise" => Ok(DitherMethod::BlueNoise),
+            _ => Err(format!(
+                "Unknown dithering {}, expected none, floyd-steinberg, ordered or bluenoise",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DitherMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DitherMethod::None => "none",
+            DitherMethod::FloydSteinberg => "floyd-steinberg",
+            DitherMethod::Ordered => "ordered",
+            DitherMethod::BlueNoise => "bluenoise",
+        })
+    }
+}
+
+/// Thresholds tiled over the image for ordered dithering, given row by row
+/// like 0,2/3,1. A matrix of n entries should hold 0 to n-1 once each
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThresholdMatrix {
+    width: usize,
+    values: Vec<u16>,
+}
+
+impl ThresholdMatrix {
+    /// Threshold at `x`,`y` between 0 and 1
+    fn threshold(&self, x: usize, y: usize) -> f64 {
+        let height = self.values.len() / self.width;
+        let value = self.values[y % height * self.width + x % self.width];
+        (value as f64 + 0.5) / self.values.len() as f64
+    }
+}
+
+/// The set pixel with the most set pixels close by
+fn tightest_cluster(pattern: &[bool], energy: &[f64]) -> usize {
+    (0..pattern.len())
+        .filter(|&i| pattern[i])
+        .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        .expect("the pattern has set pixels")
+}
+
+/// The unset pixel with the fewest set pixels close by
+fn largest_void(pattern: &[bool], energy: &[f64]) -> usize {
+    (0..pattern.len())
+        .filter(|&i| !pattern[i])
+        .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        .expect("the pattern has unset pixels")
+}
+
+/// Makes a blue noise matrix with Ulichney's void-and-cluster method:
+/// a sparse pattern is evened out by moving pixels from clusters to voids,
+/// then pixels are ranked by taking them out of the tightest clusters and
+/// adding them to the largest voids. A fixed seed gives the same matrix
+/// every time
+fn void_and_cluster(size: usize) -> ThresholdMatrix {
+    let n = size * size;
+    // gaussian of the wrapped around distance, so the matrix tiles
+    let kernel = (0..n)
+        .map(|i| {
+            let d = |v: usize| v.min(size - v) as f64;
+            let squared = d(i % size).powi(2) + d(i / size).powi(2);
+            (-squared / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+    let update = |energy: &mut [f64], at: usize, sign: f64| {
+        let (ax, ay) = (at % size, at / size);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let dx = (i % size + size - ax) % size;
+            let dy = (i / size + size - ay) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+
+    let ones = n / 10;
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut state = 0x2545_f491u32;
+    let mut placed = 0;
+    while placed < ones {
+        // xorshift
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let i = state as usize % n;
+        if !pattern[i] {
+            pattern[i] = true;
+            update(&mut energy, i, 1.0);
+            placed += 1;
+        }
+    }
+    for _ in 0..n {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+    let (mut thinned, mut thinned_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..ones).rev() {
+        let cluster = tightest_cluster(&thinned, &thinned_energy);
+        thinned[cluster] = false;
+        update(&mut thinned_energy, cluster, -1.0);
+        ranks[cluster] = rank as u16;
+    }
+    for rank in ones..n {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank as u16;
+    }
+    ThresholdMatrix {
+        width: size,
+        values: ranks,
+    }
+}
+
+/// The blue noise matrix, made on first use
+fn blue_noise() -> &'static ThresholdMatrix {
+    static MATRIX: OnceLock<ThresholdMatrix> = OnceLock::new();
+    MATRIX.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE))
+}
+
+impl Default for ThresholdMatrix {
+    /// 4x4 Bayer
+    fn default() -> Self {
+        ThresholdMatrix {
+            width: 4,
+            values: BAYER_4X4.iter().flatten().copied().collect(),
+        }
+    }
+}
+
+impl FromStr for ThresholdMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid matrix {:?}. Expected rows like 0,2/3,1", s);
+        let rows = s
+            .split('/')
+            .map(|row| {
+                row.split(',')
+                    .map(|v| v.trim().parse::<u16>())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let width = rows[0].len();
+        if width == 0 || rows.iter().any(|row| row.len() != width) {
+            return Err(invalid());
+        }
+        Ok(ThresholdMatrix {
+            width,
+            values: rows.concat(),
+        })
+    }
+}
+
+/// Dithering settings
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dither {
+    pub method: DitherMethod,
+    /// 0 for none at all to 1 for full strength
+    pub strength: f64,
+    /// Diffuse errors along every other line from right to left, against
+    /// the diagonal streaks of always going the same way
+    pub serpentine: bool,
+    pub matrix: ThresholdMatrix,
+    /// How the closest palette color is picked
+    pub metric: ColorMetric,
+}
+
+impl Default for Dither {
+    /// Full strength Floyd-Steinberg
+    fn default() -> Self {
+        Dither {
+            method: DitherMethod::FloydSteinberg,
+            strength: 1.0,
+            serpentine: false,
+            matrix: ThresholdMatrix::default(),
+            metric: ColorMetric::Rgb,
+        }
+    }
+}
+
+impl IffImage {
+    /// Maps the image onto the colors of `cmap`, dithering as `dither` says
+    pub fn dither_with(&self, cmap: &ColorMap, dither: &Dither) -> IffImage {
+        let pixels = match dither.method {
+            DitherMethod::None => return self.remap_nearest_by(cmap, dither.metric),
+            DitherMethod::FloydSteinberg => self.diffuse(cmap, dither),
+            DitherMethod::Ordered => self.ordered(cmap, &dither.matrix, dither),
+            DitherMethod::BlueNoise => self.ordered(cmap, blue_noise(), dither),
+        };
+        self.with_pixels(cmap, pixels)
+    }
+
+    fn diffuse(&self, cmap: &ColorMap, dither: &Dither) -> Vec<u8> {
+        let width = self.bmhd.width as usize;
+        // errors of this and the next row times 16, one pixel of margin on
+        // both sides
+        let mut errors = vec![[0i32; 3]; 2 * (width + 2)];
+        let mut pixels = vec![0; self.pixels.len()];
+        let mut matcher = Matcher::new(cmap, dither.metric);
+        for (y, row) in self.pixels.chunks(width.max(1)).enumerate() {
+            let reverse = dither.serpentine && y % 2 == 1;
+            let (current, next) = errors.split_at_mut(width + 2);
+            for step in 0..row.len() {
+                let x = if reverse { row.len() - 1 - step } else { step };
+                // the margin shifts every position by one
+                let (behind, ahead) = if reverse { (x + 2, x) } else { (x, x + 2) };
+                let color = &self.cmap.colors[row[x] as usize];
+                let wanted = [color.r, color.g, color.b]
+                    .iter()
+                    .zip(current[x + 1])
+                    .map(|(&c, error)| (c as i32 + error / 16).clamp(0, 255))
+                    .collect::<Vec<_>>();
+                let nearest = matcher.nearest([wanted[0] as u8, wanted[1] as u8, wanted[2] as u8]);
+                pixels[y * width + x] = nearest;
+                let got = &cmap.colors[nearest as usize];
+                for (channel, &c) in [got.r, got.g, got.b].iter().enumerate() {
+                    let error = ((wanted[channel] - c as i32) as f64 * dither.strength) as i32;
+                    current[ahead][channel] += error * 7;
+                    next[behind][channel] += error * 3;
+                    next[x + 1][channel] += error * 5;
+                    next[ahead][channel] += error;
+                }
+            }
+            current.copy_from_slice(next);
+            next.fill([0; 3]);
+        }
+        pixels
+    }
+
+    fn ordered(&self, cmap: &ColorMap, matrix: &ThresholdMatrix, dither: &Dither) -> Vec<u8> {
+        let width = (self.bmhd.width as usize).max(1);
+        // about the distance between neighbouring colors of an evenly
+        // spread palette
+        let spread = 255.0 / (cmap.colors.len().max(1) as f64).cbrt() * dither.strength;
+        let mut matcher = Matcher::new(cmap, dither.metric);
+        self.pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &index)| {
+                let offset = (matrix.threshold(i % width, i / width) - 0.5) * spread;
+                let color = &self.cmap.colors[index as usize];
+                let shift = |c: u8| (c as f64 + offset).round().clamp(0.0, 255.0) as u8;
+                matcher.nearest([shift(color.r), shift(color.g), shift(color.b)])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::Color;
+
+    #[test]
+    fn strength_pattern_and_direction() {
+        let gray = ColorMap {
+            colors: vec![Color {
+                r: 128,
+                g: 128,
+                b: 128,
+            }],
+        };
+        let image = IffImage::from_parts(4, 4, gray, vec![0; 16]);
+        let bilevel = ColorMap::gray_ramp(2);
+        let white = |image: &IffImage| image.pixels.iter().filter(|&&p| p == 1).count();
+
+        let ordered = Dither {
+            method: DitherMethod::Ordered,
+            matrix: "0,2/3,1".parse().unwrap(),
+            ..Default::default()
+        };
+        let checkered = image.dither_with(&bilevel, &ordered);
+        assert_eq!(checkered.pixels[..8], [0, 1, 0, 1, 1, 0, 1, 0]);
+        let off = Dither {
+            strength: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(white(&image.dither_with(&bilevel, &off)), 16);
+        assert_eq!(white(&image.dither_with(&bilevel, &Dither::default())), 8);
+
+        let serpentine = Dither {
+            serpentine: true,
+            ..Default::default()
+        };
+        let ramp = ColorMap::gray_curve(8, 1.0, false);
+        let gradient = IffImage::from_parts(8, 4, ramp, (0..32).map(|i| i % 8).collect());
+        let dithered = gradient.dither_with(&bilevel, &serpentine);
+        assert_eq!(
+            dithered.pixels[..8],
+            gradient.dither_to(&bilevel).pixels[..8]
+        );
+        assert_ne!(dithered.pixels, gradient.dither_to(&bilevel).pixels);
+
+        assert!("0,1/2".parse::<ThresholdMatrix>().is_err());
+
+        // every threshold once, evenly spread out
+        let noise = blue_noise();
+        let mut values = noise.values.clone();
+        values.sort_unstable();
+        assert!(values.iter().enumerate().all(|(i, &v)| v as usize == i));
+        // the darkest sixteenth has about 4 pixels in every 8x8 block
+        for block in 0..64 {
+            let (bx, by) = (block % 8 * 8, block / 8 * 8);
+            let dark = (0..64)
+                .filter(|i| noise.values[(by + i / 8) * 64 + bx + i % 8] < 256)
+                .count();
+            assert!((2..=6).contains(&dark), "{} dark pixels", dark);
+        }
+        assert!("".parse::<ThresholdMatrix>().is_err());
+    }
+
+    #[test]
+    fn blue_noise_output_is_fixed() {
+        let gray = ColorMap {
+            colors: vec![Color {
+                r: 128,
+                g: 128,
+                b: 128,
+            }],
+        };
+        let image = IffImage::from_parts(64, 64, gray, vec![0; 64 * 64]);
+        let blue = Dither {
+            method: DitherMethod::BlueNoise,
+            ..Default::default()
+        };
+        let bilevel = ColorMap::gray_ramp(2);
+        let dithered = image.dither_with(&bilevel, &blue);
+        assert_eq!(
+            dithered.pixels[..16],
+            [1, 0, 1, 1, 0, 1, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1]
+        );
+        let white = dithered.pixels.iter().filter(|&&p| p == 1).count();
+        assert!((2000..2100).contains(&white), "{} white pixels", white);
+
+        // the seeded matrix comes out the same when made again
+        let ordered = Dither {
+            method: DitherMethod::Ordered,
+            matrix: void_and_cluster(BLUE_NOISE_SIZE),
+            ..Default::default()
+        };
+        assert_eq!(
+            image.dither_with(&bilevel, &ordered).pixels,
+            dithered.pixels
+        );
+    }
+}
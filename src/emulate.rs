@@ -0,0 +1,220 @@
+//! What an ILBM looks like on an Amiga screen. OCS and ECS only have 4 bits
+//! per channel, extra halfbrite shows the upper 32 colors as the lower ones
+//! at half brightness, HAM holds and modifies the color to its left, and
+//! hires and interlaced pixels are not square. The screen is rendered as
+//! truecolor, with every pixel repeated until it is about square
+
+use crate::camg::{CAMG_EXTRA_HALFBRITE, CAMG_HAM, CAMG_HIRES, CAMG_LACE, CAMG_SUPERHIRES};
+use crate::ham::{self, HamMode};
+use crate::iffimage::{Color, IffImage};
+use png::{BitDepth, ColorType, EncodingError};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Colors in extra halfbrite mode: 32 set ones and 32 halved ones
+const EHB_COLORS: usize = 32;
+
+/// The Amiga chipsets, by how they show colors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chipset {
+    /// OCS and ECS: 12 bit colors, up to 6 bitplanes
+    Ocs,
+    /// AGA: 24 bit colors, up to 8 bitplanes
+    Aga,
+}
+
+impl Chipset {
+    /// The oldest chipset able to show `image`
+    pub fn for_image(image: &IffImage) -> Chipset {
+        match image.bitplanes() {
+            0..=6 => Chipset::Ocs,
+            _ => Chipset::Aga,
+        }
+    }
+
+    /// A palette color as the chipset shows it. OCS drops the low 4 bits
+    fn show(self, color: &Color) -> Color {
+        let channel = |v: u8| match self {
+            Chipset::Ocs => (v >> 4) * 17,
+            Chipset::Aga => v,
+        };
+        Color {
+            r: channel(color.r),
+            g: channel(color.g),
+            b: channel(color.b),
+        }
+    }
+
+    /// A palette color at half brightness, from the bits the chipset has
+    fn halve(self, color: &Color) -> Color {
+        let channel = |v: u8| match self {
+            Chipset::Ocs => (v >> 5) * 17,
+            Chipset::Aga => v >> 1,
+        };
+        Color {
+            r: channel(color.r),
+            g: channel(color.g),
+            b: channel(color.b),
+        }
+    }
+}
+
+impl FromStr for Chipset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ocs" | "ecs" => Ok(Chipset::Ocs),
+            "aga" => Ok(Chipset::Aga),
+            _ => Err(format!("Unknown chipset {}, expected ocs, ecs or aga", s)),
+        }
+    }
+}
+
+impl fmt::Display for Chipset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Chipset::Ocs => "ocs",
+            Chipset::Aga => "aga",
+        })
+    }
+}
+
+/// A rendered screen
+#[derive(Clone, Debug, PartialEq)]
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    /// Colors row by row from the top left
+    pub pixels: Vec<Color>,
+}
+
+impl Screen {
+    /// Writes an 8 bit RGB PNG
+    pub fn to_png<W: Write>(&self, w: W) -> Result<(), EncodingError> {
+        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+        encoder.set_color(ColorType::RGB);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let data = self
+            .pixels
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b])
+            .collect::<Vec<_>>();
+        writer.write_image_data(&data)
+    }
+}
+
+/// How often every pixel is repeated across and down to look about square,
+/// a lores line being as tall as a lores pixel is wide
+fn pixel_repeat(camg: u32) -> (usize, usize) {
+    // in quarters of a lores pixel
+    let width = match camg {
+        m if m & CAMG_SUPERHIRES != 0 => 1,
+        m if m & CAMG_HIRES != 0 => 2,
+        _ => 4,
+    };
+    let height = match camg & CAMG_LACE {
+        0 => 4,
+        _ => 2,
+    };
+    let unit = width.min(height);
+    (width / unit, height / unit)
+}
+
+/// Renders `image` as `chipset` shows it, going by its CAMG
+pub fn render(image: &IffImage, chipset: Chipset) -> Screen {
+    let camg = image.camg().unwrap_or(0);
+    let colors = &image.palette().colors;
+    let mut palette = colors.iter().map(|c| chipset.show(c)).collect::<Vec<_>>();
+    let shown = match image.bitplanes() {
+        6 | 8 if camg & CAMG_HAM != 0 => {
+            let mode = match image.bitplanes() {
+                6 => HamMode::Ham6,
+                _ => HamMode::Ham8,
+            };
+            palette.truncate(mode.base_colors());
+            ham::decode(image, mode, &palette)
+        }
+        planes => {
+            if planes == 6 && camg & CAMG_EXTRA_HALFBRITE != 0 {
+                palette.resize(EHB_COLORS, Color::default());
+                let halved = (0..EHB_COLORS)
+                    .map(|i| {
+                        colors
+                            .get(i)
+                            .map_or_else(Color::default, |c| chipset.halve(c))
+                    })
+                    .collect::<Vec<_>>();
+                palette.extend(halved);
+            }
+            image
+                .pixels
+                .iter()
+                .map(|&p| palette.get(p as usize).cloned().unwrap_or_default())
+                .collect()
+        }
+    };
+
+    let (across, down) = pixel_repeat(camg);
+    let width = image.width() as usize;
+    let mut pixels = Vec::with_capacity(shown.len() * across * down);
+    for row in shown.chunks(width.max(1)) {
+        let wide = row
+            .iter()
+            .flat_map(|c| std::iter::repeat_n(c.clone(), across))
+            .collect::<Vec<_>>();
+        for _ in 0..down {
+            pixels.extend_from_slice(&wide);
+        }
+    }
+    Screen {
+        width: width * across,
+        height: image.height() as usize * down,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::ColorMap;
+
+    #[test]
+    fn shows_colors_and_pixels_like_the_hardware() {
+        let color = |r, g, b| Color { r, g, b };
+        let colors = vec![color(0x12, 0x34, 0xff), color(0xff, 0x88, 0x40)];
+        let mut image = IffImage::from_parts(2, 1, ColorMap { colors }, vec![0, 1]);
+        assert_eq!(Chipset::for_image(&image), Chipset::Ocs);
+        let screen = render(&image, Chipset::Ocs);
+        assert_eq!(
+            screen.pixels,
+            vec![color(0x11, 0x33, 0xff), color(0xff, 0x88, 0x44)]
+        );
+        assert_eq!(
+            render(&image, Chipset::Aga).pixels[0],
+            color(0x12, 0x34, 0xff)
+        );
+
+        // hires pixels are half as wide as they are tall
+        image.set_camg(Some(CAMG_HIRES));
+        let screen = render(&image, Chipset::Ocs);
+        assert_eq!((screen.width, screen.height), (2, 2));
+        assert_eq!(screen.pixels[1], screen.pixels[3]);
+
+        // index 33 is color 1 at half brightness
+        image.set_camg(Some(CAMG_EXTRA_HALFBRITE | CAMG_LACE));
+        image.bmhd.bitplanes = 6;
+        image.pixels = vec![33, 0];
+        let screen = render(&image, Chipset::Ocs);
+        assert_eq!((screen.width, screen.height), (4, 1));
+        assert_eq!(screen.pixels[0], color(0x77, 0x44, 0x22));
+        assert_eq!(screen.pixels[3], color(0x11, 0x33, 0xff));
+
+        let mut png = vec![];
+        screen.to_png(&mut png).unwrap();
+        let (info, _) = png::Decoder::new(&png[..]).read_info().unwrap();
+        assert_eq!((info.width, info.color_type), (4, ColorType::RGB));
+    }
+}
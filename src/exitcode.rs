@@ -0,0 +1,138 @@
+use ipng2iff::iffimage::{IffConvertError, IffLoadError};
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+
+/// Bad arguments, refused overwrites and anything not covered below
+pub const FAILURE: i32 = 1;
+/// Reading an input or writing an output failed
+pub const IO: i32 = 2;
+/// The input is not a supported format or could not be decoded
+pub const DECODE: i32 = 3;
+/// The palette is missing, too large or does not cover every pixel
+pub const PALETTE: i32 = 4;
+/// The image does not fit the requested operation (crop, size)
+pub const VALIDATION: i32 = 5;
+
+/// Some files of a batch failed. Carries the exit code of the first failure
+#[derive(Debug)]
+pub struct BatchError {
+    pub failed: usize,
+    pub total: usize,
+    pub code: i32,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "{} of {} files failed to convert",
+            self.failed, self.total
+        ))
+    }
+}
+
+impl Error for BatchError {}
+
+/// The outputs take more bytes than --max-size allows
+#[derive(Debug)]
+pub struct OverBudget {
+    pub size: u64,
+    pub max: u64,
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Output takes {} bytes, {} more than the budget of {}",
+            self.size,
+            self.size - self.max,
+            self.max
+        ))
+    }
+}
+
+impl Error for OverBudget {}
+
+/// A conversion step that would lose information, under --policy strict
+#[derive(Debug)]
+pub struct LossyOperation(pub String);
+
+impl fmt::Display for LossyOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Lossy step refused by --policy strict: {}",
+            self.0
+        ))
+    }
+}
+
+impl Error for LossyOperation {}
+
+fn for_convert_error(error: &IffConvertError) -> i32 {
+    match error {
+        IffConvertError::NoPalette
+        | IffConvertError::EmptyPalette
+        | IffConvertError::TooManyColors(_)
+        | IffConvertError::InvalidPixel { .. }
+        | IffConvertError::UnmappedPixels(_)
+        | IffConvertError::InvalidIndex { .. }
+        | IffConvertError::KeyColorNotInPalette(_)
+        | IffConvertError::PartialAlpha { .. } => PALETTE,
+        IffConvertError::CropOutOfBounds
+        | IffConvertError::InvalidColorCount(_)
+        | IffConvertError::InvalidSize(..)
+        | IffConvertError::EmptyImage(..)
+        | IffConvertError::TooLarge(..) => VALIDATION,
+        IffConvertError::Cancelled => FAILURE,
+        _ => DECODE,
+    }
+}
+
+/// Picks the exit code for an error bubbling up to main
+pub fn for_error(error: &(dyn Error + 'static)) -> i32 {
+    if let Some(batch) = error.downcast_ref::<BatchError>() {
+        batch.code
+    } else if error.is::<OverBudget>() || error.is::<LossyOperation>() {
+        VALIDATION
+    } else if error.is::<IoError>() {
+        IO
+    } else if let Some(error) = error.downcast_ref::<IffConvertError>() {
+        for_convert_error(error)
+    } else if let Some(error) = error.downcast_ref::<IffLoadError>() {
+        match error {
+            IffLoadError::Io(_) | IffLoadError::File { .. } => IO,
+            IffLoadError::Convert(e) => for_convert_error(e),
+            _ => DECODE,
+        }
+    } else {
+        FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn classifies_errors() {
+        let missing = IffLoadError::from(IoError::from(ErrorKind::NotFound));
+        assert_eq!(for_error(&missing), IO);
+        assert_eq!(for_error(&IffConvertError::TooManyColors(300)), PALETTE);
+        assert_eq!(for_error(&IffConvertError::UnsupportedFormat), DECODE);
+        assert_eq!(for_error(&IffConvertError::CropOutOfBounds), VALIDATION);
+        assert_eq!(
+            for_error(&IffConvertError::InvalidColorCount(0)),
+            VALIDATION
+        );
+        let over = OverBudget {
+            size: 901121,
+            max: 901120,
+        };
+        assert_eq!(for_error(&over), VALIDATION);
+        let lossy = LossyOperation("scaling to 64x64".to_string());
+        assert_eq!(for_error(&lossy), VALIDATION);
+        let refused: Box<dyn Error> = From::from("already exists");
+        assert_eq!(for_error(refused.as_ref()), FAILURE);
+    }
+}
@@ -0,0 +1,331 @@
+//! Amiga bitmap fonts from a glyph sheet. A font is a `name.font` contents
+//! file listing the sizes, plus one loadable hunk file per size in the
+//! `name` directory holding a DiskFontHeader with the TextFont. The glyphs
+//! of a TextFont sit side by side in one bitplane, the strike, and CharLoc
+//! gives each glyph's bit offset and width. Proportional fonts also have
+//! CharKern, added to the position before drawing, and CharSpace, added
+//! after it
+
+use crate::iffimage::{IffConvertError, IffImage};
+
+const FCH_ID: u16 = 0x0f00;
+const DFH_ID: u16 = 0x0f80;
+const NT_FONT: u8 = 12;
+const FPF_DISKFONT: u8 = 0x02;
+const FPF_PROPORTIONAL: u8 = 0x20;
+const FPF_DESIGNED: u8 = 0x40;
+/// Length of the font file names in the contents file
+const MAX_FONT_PATH: usize = 256;
+const MAX_FONT_NAME: usize = 32;
+
+const HUNK_HEADER: u32 = 0x3f3;
+const HUNK_CODE: u32 = 0x3e9;
+const HUNK_RELOC32: u32 = 0x3ec;
+const HUNK_END: u32 = 0x3f2;
+
+/// Offsets in the code hunk, which starts with `moveq #-1,d0; rts` in case
+/// somebody runs it
+const DFH_NAME: usize = 26;
+const TEXT_FONT: usize = 58;
+const TF_DATA: usize = 110;
+
+/// The printable ASCII characters, the default character map
+pub const ASCII: &str = " !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~";
+
+/// How the glyph sheet is laid out and the font described
+#[derive(Clone, Debug)]
+pub struct FontOptions {
+    /// The font name without `.font`
+    pub name: String,
+    /// Size of the cells holding one glyph each, left to right and top to
+    /// bottom. The cell height is the font height
+    pub cell_width: u16,
+    pub cell_height: u16,
+    /// The characters of the cells in order, Latin-1 only
+    pub chars: String,
+    /// Rows from the top of a cell to the baseline [default: the last row]
+    pub baseline: Option<u16>,
+    /// Blank columns after every glyph of a proportional font
+    pub spacing: u16,
+    /// Every glyph as wide as a cell instead of trimmed to its pixels
+    pub fixed: bool,
+}
+
+/// One character cut out of the sheet
+struct Glyph {
+    /// Bits from the top row down, `width` per row
+    bits: Vec<bool>,
+    width: usize,
+    kern: i16,
+    space: i16,
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn words(values: impl Iterator<Item = i16>) -> Vec<u8> {
+    values.flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// Reads the glyph in cell `cell`. Pixels of any color but 0 are set.
+/// Proportional glyphs are trimmed to their set columns, blank columns on
+/// the left becoming the kerning
+fn cut_glyph(sheet: &IffImage, cell: usize, options: &FontOptions) -> Glyph {
+    let (cw, ch) = (options.cell_width as usize, options.cell_height as usize);
+    let width = sheet.width() as usize;
+    let columns = width / cw;
+    let (x0, y0) = (cell % columns * cw, cell / columns * ch);
+    let set = |x: usize, y: usize| sheet.pixels[(y0 + y) * width + x0 + x] != 0;
+
+    let mut used = (0..cw).filter(|&x| (0..ch).any(|y| set(x, y)));
+    let (first, last) = match (used.clone().next(), used.next_back()) {
+        _ if options.fixed => (0, cw - 1),
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            return Glyph {
+                bits: vec![],
+                width: 0,
+                kern: 0,
+                space: cw.div_ceil(2) as i16,
+            }
+        }
+    };
+    let glyph_width = last - first + 1;
+    Glyph {
+        bits: (0..ch)
+            .flat_map(|y| (first..=last).map(move |x| (x, y)))
+            .map(|(x, y)| set(x, y))
+            .collect(),
+        width: glyph_width,
+        kern: first as i16,
+        space: (glyph_width + options.spacing as usize) as i16,
+    }
+}
+
+/// Makes a font from the glyphs in `sheet`. Returns the `name.font`
+/// contents file and the font file to store as `name/<cell height>`
+pub fn encode(
+    sheet: &IffImage,
+    options: &FontOptions,
+) -> Result<(Vec<u8>, Vec<u8>), IffConvertError> {
+    let (cw, ch) = (options.cell_width as usize, options.cell_height as usize);
+    let name = format!("{}.font", options.name);
+    if options.name.is_empty() || name.len() >= MAX_FONT_NAME || !name.is_ascii() {
+        return Err(IffConvertError::Unsupported(
+            "font names are ASCII with at most 26 characters",
+        ));
+    }
+    let cells = match (cw, ch) {
+        (0, _) | (_, 0) => 0,
+        _ => (sheet.width() as usize / cw) * (sheet.height() as usize / ch),
+    };
+    if cells == 0 || cells < options.chars.chars().count() {
+        return Err(IffConvertError::InvalidSize(
+            sheet.width() as u32,
+            sheet.height() as u32,
+        ));
+    }
+    let mut glyphs = (0..256).map(|_| None).collect::<Vec<Option<Glyph>>>();
+    for (cell, c) in options.chars.chars().enumerate() {
+        let code = match c as u32 {
+            code @ 0..=0xff => code as usize,
+            _ => return Err(IffConvertError::Unsupported("characters outside Latin-1")),
+        };
+        if glyphs[code].is_none() {
+            glyphs[code] = Some(cut_glyph(sheet, cell, options));
+        }
+    }
+    let lo = glyphs.iter().position(Option::is_some).unwrap_or(0);
+    let hi = glyphs.iter().rposition(Option::is_some).unwrap_or(0);
+
+    // characters without a glyph, and the extra one drawn for characters
+    // outside lo..=hi, are blank
+    let blank = || Glyph {
+        bits: vec![],
+        width: 0,
+        kern: 0,
+        space: cw.div_ceil(2) as i16,
+    };
+    let glyphs = glyphs
+        .into_iter()
+        .skip(lo)
+        .take(hi - lo + 1)
+        .map(|glyph| glyph.unwrap_or_else(blank))
+        .chain(std::iter::once(blank()))
+        .collect::<Vec<_>>();
+
+    let strike_width = glyphs.iter().map(|g| g.width).sum::<usize>();
+    let modulo = strike_width.div_ceil(16) * 2;
+    if modulo > u16::MAX as usize {
+        return Err(IffConvertError::Unsupported("glyph strike too wide"));
+    }
+    let mut strike = vec![0u8; modulo * ch];
+    let mut char_loc = vec![];
+    let mut offset = 0;
+    for glyph in &glyphs {
+        for (i, _) in glyph.bits.iter().enumerate().filter(|(_, &bit)| bit) {
+            let (x, y) = (offset + i % glyph.width, i / glyph.width);
+            strike[y * modulo + x / 8] |= 0x80 >> (x % 8);
+        }
+        push_u16(&mut char_loc, offset as u16);
+        push_u16(&mut char_loc, glyph.width as u16);
+        offset += glyph.width;
+    }
+    let char_space = words(glyphs.iter().map(|g| g.space));
+    let char_kern = words(glyphs.iter().map(|g| g.kern));
+
+    let flags = FPF_DISKFONT | FPF_DESIGNED | if options.fixed { 0 } else { FPF_PROPORTIONAL };
+    let x_size = match options.fixed {
+        true => cw,
+        false => glyphs
+            .iter()
+            .map(|g| (g.kern + g.space).max(0) as usize)
+            .max()
+            .unwrap_or(cw),
+    };
+    let baseline = options.baseline.unwrap_or(ch as u16 - 1);
+
+    let mut hunk = vec![0x70, 0xff, 0x4e, 0x75];
+    // DiskFontHeader: Node, FileID, Revision, Segment, Name
+    push_u32(&mut hunk, 0);
+    push_u32(&mut hunk, 0);
+    hunk.extend_from_slice(&[NT_FONT, 0]);
+    push_u32(&mut hunk, DFH_NAME as u32);
+    push_u16(&mut hunk, DFH_ID);
+    push_u16(&mut hunk, 0);
+    push_u32(&mut hunk, 0);
+    let mut dfh_name = name.clone().into_bytes();
+    dfh_name.resize(MAX_FONT_NAME, 0);
+    hunk.extend(dfh_name);
+
+    // TextFont: Message, sizes and the pointers to the tables
+    let char_data = TF_DATA;
+    let loc = char_data + strike.len();
+    let (space, kern) = match options.fixed {
+        true => (0, 0),
+        false => (
+            loc + char_loc.len(),
+            loc + char_loc.len() + char_space.len(),
+        ),
+    };
+    push_u32(&mut hunk, 0);
+    push_u32(&mut hunk, 0);
+    hunk.extend_from_slice(&[NT_FONT, 0]);
+    push_u32(&mut hunk, DFH_NAME as u32);
+    push_u32(&mut hunk, 0);
+    let length = TF_DATA - TEXT_FONT
+        + strike.len()
+        + char_loc.len()
+        + if options.fixed {
+            0
+        } else {
+            2 * char_space.len()
+        };
+    push_u16(&mut hunk, length as u16);
+    push_u16(&mut hunk, ch as u16);
+    hunk.extend_from_slice(&[0, flags]);
+    push_u16(&mut hunk, x_size as u16);
+    push_u16(&mut hunk, baseline);
+    // BoldSmear, Accessors
+    push_u16(&mut hunk, 1);
+    push_u16(&mut hunk, 0);
+    hunk.extend_from_slice(&[lo as u8, hi as u8]);
+    push_u32(&mut hunk, char_data as u32);
+    push_u16(&mut hunk, modulo as u16);
+    push_u32(&mut hunk, loc as u32);
+    push_u32(&mut hunk, space as u32);
+    push_u32(&mut hunk, kern as u32);
+    debug_assert_eq!(hunk.len(), TF_DATA);
+    hunk.extend(strike);
+    hunk.extend(char_loc);
+    if !options.fixed {
+        hunk.extend(char_space);
+        hunk.extend(char_kern);
+    }
+    hunk.resize(hunk.len().div_ceil(4) * 4, 0);
+
+    // pointers to relocate by the address the hunk is loaded at
+    let mut relocs = vec![14, 68, 92, 98];
+    if !options.fixed {
+        relocs.extend_from_slice(&[102, 106]);
+    }
+    let mut font = vec![];
+    for value in [HUNK_HEADER, 0, 1, 0, 0, hunk.len() as u32 / 4] {
+        push_u32(&mut font, value);
+    }
+    push_u32(&mut font, HUNK_CODE);
+    push_u32(&mut font, hunk.len() as u32 / 4);
+    font.extend(hunk);
+    for value in [HUNK_RELOC32, relocs.len() as u32, 0] {
+        push_u32(&mut font, value);
+    }
+    for reloc in relocs {
+        push_u32(&mut font, reloc);
+    }
+    push_u32(&mut font, 0);
+    push_u32(&mut font, HUNK_END);
+
+    let mut contents = vec![];
+    push_u16(&mut contents, FCH_ID);
+    push_u16(&mut contents, 1);
+    let mut path = format!("{}/{}", options.name, ch).into_bytes();
+    path.resize(MAX_FONT_PATH, 0);
+    contents.extend(path);
+    push_u16(&mut contents, ch as u16);
+    contents.extend_from_slice(&[0, flags]);
+    Ok((contents, font))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn glyph_strike_and_tables() {
+        // two 4x2 cells: "A" with a blank column on the left, then a blank
+        #[rustfmt::skip]
+        let pixels = vec![
+            0, 1, 1, 0,  0, 0, 0, 0,
+            0, 1, 0, 1,  0, 0, 0, 0,
+        ];
+        let colors = ColorMap {
+            colors: vec![Color::default(); 2],
+        };
+        let sheet = IffImage::from_parts(8, 2, colors, pixels);
+        let mut options = FontOptions {
+            name: "tiny".to_string(),
+            cell_width: 4,
+            cell_height: 2,
+            chars: "A ".to_string(),
+            baseline: None,
+            spacing: 1,
+            fixed: false,
+        };
+        let (contents, font) = encode(&sheet, &options).unwrap();
+        assert_eq!(&contents[..10], b"\x0f\x00\x00\x01tiny/2");
+        assert_eq!(contents.len(), 4 + 260);
+
+        let hunk = &font[32..];
+        assert_eq!(&hunk[DFH_NAME..DFH_NAME + 10], b"tiny.font\0");
+        // lo ' ', hi 'A', modulo 2, strike rows 110 and 112
+        assert_eq!(&hunk[90..92], b" A");
+        assert_eq!(&hunk[96..98], &[0, 2]);
+        assert_eq!(&hunk[110..114], &[0xc0, 0, 0xa0, 0]);
+        // CharLoc of 'A', the 34th entry, and its space and kern
+        let entries = (b'A' - b' ') as usize + 2;
+        let loc = 114 + 4 * (b'A' - b' ') as usize;
+        assert_eq!(&hunk[loc..loc + 4], &[0, 0, 0, 3]);
+        let space = 114 + 4 * entries + 2 * (b'A' - b' ') as usize;
+        assert_eq!(&hunk[space..space + 2], &[0, 4]);
+        assert_eq!(&hunk[space + 2 * entries..space + 2 * entries + 2], &[0, 1]);
+
+        options.chars = "ABC".to_string();
+        assert!(encode(&sheet, &options).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::iffimage::{
+    check_indices, check_size, Color, ColorMap, IffConvertError, IffImage, IffLoadError,
+    MASK_HAS_TRANSPARENT_COLOR,
+};
+use std::io::Read;
+
+impl IffImage {
+    /// Converts the first frame of a GIF. Frames smaller than the logical
+    /// screen are placed on a background filled with the background color
+    pub fn from_gif_reader<R: Read>(r: R) -> Result<IffImage, IffLoadError> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(r)?;
+
+        let width = decoder.width();
+        let height = decoder.height();
+        check_size(width as usize, height as usize)?;
+        let background = decoder.bg_color().unwrap_or(0) as u8;
+        let global_palette = decoder.global_palette().map(|p| p.to_vec());
+
+        let frame = match decoder.read_next_frame()? {
+            None => {
+                return Err(From::from(IffConvertError::EmptyImage(
+                    width as u32,
+                    height as u32,
+                )))
+            }
+            Some(frame) => frame,
+        };
+
+        let palette = match frame.palette.as_ref().or(global_palette.as_ref()) {
+            None => return Err(From::from(IffConvertError::NoPalette)),
+            Some(palette) => palette,
+        };
+        if palette.is_empty() {
+            return Err(From::from(IffConvertError::EmptyPalette));
+        }
+        let cmap = ColorMap {
+            colors: palette
+                .chunks(3)
+                .map(|c| Color {
+                    r: c[0],
+                    g: c[1],
+                    b: c[2],
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        let mut pixels = vec![background; width as usize * height as usize];
+        for (row_index, row) in frame.buffer.chunks(frame.width as usize).enumerate() {
+            let y = frame.top as usize + row_index;
+            if y >= height as usize {
+                break;
+            }
+            for (column, &index) in row.iter().enumerate() {
+                let x = frame.left as usize + column;
+                if x < width as usize {
+                    pixels[y * width as usize + x] = index;
+                }
+            }
+        }
+        check_indices(&pixels, width as usize, cmap.colors.len())?;
+
+        let transparent = frame.transparent;
+        let mut image = IffImage::from_parts(width, height, cmap, pixels);
+        if let Some(index) = transparent {
+            image.bmhd.masking = MASK_HAS_TRANSPARENT_COLOR;
+            image.bmhd.transparent_color = index as u16;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transparent_gif() {
+        let image =
+            IffImage::from_gif_reader(&include_bytes!("../testdata/transparent.gif")[..]).unwrap();
+        assert_eq!(image.bmhd.width, 4);
+        assert_eq!(image.bmhd.height, 2);
+        assert_eq!(image.bmhd.bitplanes, 2);
+        assert_eq!(image.pixels, vec![0, 1, 2, 3, 3, 2, 1, 0]);
+        assert_eq!(image.bmhd.masking, MASK_HAS_TRANSPARENT_COLOR);
+        assert_eq!(image.bmhd.transparent_color, 3);
+    }
+}
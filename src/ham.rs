@@ -0,0 +1,361 @@
+//! Hold-and-modify: every pixel either shows one of the base colors or
+//! holds the color of the pixel to its left and changes one channel of it.
+//! HAM6 has 16 base colors and 4 bit channels, with the control bits in the
+//! two top planes. HAM8 has 64 base colors and sets the upper 6 bits of a
+//! channel, with the control bits in the two bottom planes. Each line starts
+//! out from color 0. Converting picks the closest of those choices pixel by
+//! pixel, and colors changing along a line in more than one channel smear
+//! into the next pixels ("fringes")
+
+use crate::camg::CAMG_HAM;
+use crate::iffimage::{
+    CancelToken, Color, ColorMap, ColorMetric, IffConvertError, IffImage, PaletteOrder,
+};
+use crate::quantize::quantize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Control bits: take a base color, or modify blue, red or green
+const BASE: u8 = 0;
+const BLUE: u8 = 1;
+const RED: u8 = 2;
+const GREEN: u8 = 3;
+/// Delta E from which a pixel at a color boundary counts as a fringe
+const FRINGE_DELTA_E: f64 = 10.0;
+/// Delta E shown at full brightness in the heat map
+const HEAT_MAP_MAX_DELTA_E: f64 = 30.0;
+
+/// The two HAM variants
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HamMode {
+    /// OCS/ECS: 16 base colors with 12 bit colors
+    Ham6,
+    /// AGA: 64 base colors with 24 bit colors
+    Ham8,
+}
+
+impl HamMode {
+    pub fn bitplanes(self) -> u8 {
+        match self {
+            HamMode::Ham6 => 6,
+            HamMode::Ham8 => 8,
+        }
+    }
+
+    pub fn base_colors(self) -> usize {
+        match self {
+            HamMode::Ham6 => 16,
+            HamMode::Ham8 => 64,
+        }
+    }
+
+    /// A base color as the hardware shows it
+    fn base_color(self, color: &Color) -> [u8; 3] {
+        let channel = |v: u8| match self {
+            HamMode::Ham6 => (v as u16 + 8) / 17 * 17,
+            HamMode::Ham8 => v as u16,
+        } as u8;
+        [channel(color.r), channel(color.g), channel(color.b)]
+    }
+
+    /// The value for a channel modified from `held` towards `wanted`, and
+    /// what it then shows
+    fn modify(self, held: u8, wanted: u8) -> (u8, u8) {
+        let value = match self {
+            HamMode::Ham6 => ((wanted as u16 + 8) / 17) as u8,
+            HamMode::Ham8 => ((wanted as u16 + 2) / 4).min(63) as u8,
+        };
+        (value, self.modified(held, value))
+    }
+
+    /// What a channel holding `held` shows once modified with `value`
+    fn modified(self, held: u8, value: u8) -> u8 {
+        match self {
+            HamMode::Ham6 => value * 17,
+            HamMode::Ham8 => value << 2 | held & 3,
+        }
+    }
+
+    /// The pixel value of `control` with `value`
+    fn code(self, control: u8, value: u8) -> u8 {
+        match self {
+            HamMode::Ham6 => control << 4 | value,
+            HamMode::Ham8 => value << 2 | control,
+        }
+    }
+
+    /// The control bits and value of a pixel
+    fn split(self, code: u8) -> (u8, u8) {
+        match self {
+            HamMode::Ham6 => (code >> 4 & 3, code & 0x0f),
+            HamMode::Ham8 => (code & 3, code >> 2),
+        }
+    }
+}
+
+impl FromStr for HamMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ham6" => Ok(HamMode::Ham6),
+            "ham8" => Ok(HamMode::Ham8),
+            _ => Err(format!("Unknown HAM mode {}, expected ham6 or ham8", s)),
+        }
+    }
+}
+
+impl fmt::Display for HamMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HamMode::Ham6 => "ham6",
+            HamMode::Ham8 => "ham8",
+        })
+    }
+}
+
+/// A HAM picture and the colors it shows on screen
+pub struct Ham {
+    /// The ILBM with the base colors as CMAP and the HAM CAMG
+    pub image: IffImage,
+    /// Color of every pixel on screen
+    pub shown: Vec<Color>,
+}
+
+/// Base colors for `image`: the image is quantized to as many colors as
+/// `mode` has, the most used first
+pub fn base_palette(image: &IffImage, mode: HamMode) -> Result<ColorMap, IffConvertError> {
+    let colors = &image.palette().colors;
+    let rgba = image
+        .pixels
+        .iter()
+        .flat_map(|&p| {
+            let c = &colors[p as usize];
+            [c.r, c.g, c.b, 0xff]
+        })
+        .collect::<Vec<_>>();
+    let (cmap, _) = quantize(
+        &rgba,
+        mode.base_colors(),
+        PaletteOrder::Popularity,
+        ColorMetric::Rgb,
+        &CancelToken::default(),
+    )?;
+    Ok(cmap)
+}
+
+/// Converts `image` to HAM with the colors of `base`, which may have as
+/// many colors as `mode` has base colors
+pub fn encode(image: &IffImage, mode: HamMode, base: &ColorMap) -> Result<Ham, IffConvertError> {
+    if base.colors.is_empty() {
+        return Err(IffConvertError::EmptyPalette);
+    }
+    if base.colors.len() > mode.base_colors() {
+        return Err(IffConvertError::TooManyColors(base.colors.len()));
+    }
+    let base = base
+        .colors
+        .iter()
+        .map(|c| mode.base_color(c))
+        .collect::<Vec<_>>();
+    let distance = |a: [u8; 3], b: [u8; 3]| {
+        a.iter()
+            .zip(&b)
+            .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+            .sum::<u32>()
+    };
+
+    let colors = &image.palette().colors;
+    let width = image.width() as usize;
+    let mut codes = Vec::with_capacity(image.pixels.len());
+    let mut shown = Vec::with_capacity(image.pixels.len());
+    for row in image.pixels.chunks(width.max(1)) {
+        let mut held = base[0];
+        for &index in row {
+            let c = &colors[index as usize];
+            let wanted = [c.r, c.g, c.b];
+            // (distance, pixel value, color shown), the first one wins ties
+            let mut best = (u32::MAX, 0, held);
+            let mut consider = |code: u8, color: [u8; 3]| {
+                let d = distance(color, wanted);
+                if d < best.0 {
+                    best = (d, code, color);
+                }
+            };
+            for (i, &color) in base.iter().enumerate() {
+                consider(mode.code(BASE, i as u8), color);
+            }
+            for (control, channel) in [(BLUE, 2), (RED, 0), (GREEN, 1)] {
+                let (value, modified) = mode.modify(held[channel], wanted[channel]);
+                let mut color = held;
+                color[channel] = modified;
+                consider(mode.code(control, value), color);
+            }
+            codes.push(best.1);
+            shown.push(Color {
+                r: best.2[0],
+                g: best.2[1],
+                b: best.2[2],
+            });
+            held = best.2;
+        }
+    }
+
+    let cmap = ColorMap {
+        colors: base.iter().map(|&[r, g, b]| Color { r, g, b }).collect(),
+    };
+    let mut ham = IffImage::from_parts(image.width(), image.height(), cmap, codes);
+    ham.bmhd.bitplanes = mode.bitplanes();
+    ham.bmhd.compression = image.bmhd.compression;
+    ham.camg = Some(image.camg().unwrap_or(0) | CAMG_HAM);
+    Ok(Ham { image: ham, shown })
+}
+
+/// The colors a HAM picture shows on screen, with `base` its base colors
+/// as the hardware shows them. Missing base colors show black
+pub fn decode(image: &IffImage, mode: HamMode, base: &[Color]) -> Vec<Color> {
+    let black = Color::default();
+    let base_color = |index: u8| base.get(index as usize).unwrap_or(&black).clone();
+    let width = (image.width() as usize).max(1);
+    let mut shown = Vec::with_capacity(image.pixels.len());
+    for row in image.pixels.chunks(width) {
+        let mut held = base_color(0);
+        for &code in row {
+            let (control, value) = mode.split(code);
+            match control {
+                BASE => held = base_color(value),
+                BLUE => held.b = mode.modified(held.b, value),
+                RED => held.r = mode.modified(held.r, value),
+                _ => held.g = mode.modified(held.g, value),
+            }
+            shown.push(held.clone());
+        }
+    }
+    shown
+}
+
+/// How far a HAM picture is off from the image it was made from
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Metrics {
+    /// Mean delta E over all pixels
+    pub mean_delta_e: f64,
+    /// The line with the highest mean delta E, and that delta E
+    pub worst_row: usize,
+    pub worst_row_delta_e: f64,
+    /// Pixels where the image changes color and the HAM picture is
+    /// visibly off
+    pub fringes: usize,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mean delta E {:.2}, worst line {} ({:.2}), {} fringe pixels",
+            self.mean_delta_e, self.worst_row, self.worst_row_delta_e, self.fringes
+        )
+    }
+}
+
+/// Delta E of every pixel between `image` and the colors `shown` for it
+fn errors(image: &IffImage, shown: &[Color]) -> Vec<f64> {
+    let colors = &image.palette().colors;
+    image
+        .pixels
+        .iter()
+        .zip(shown)
+        .map(|(&p, shown)| colors[p as usize].delta_e(shown))
+        .collect()
+}
+
+/// Compares the colors `shown` by a HAM picture with `image`
+pub fn metrics(image: &IffImage, shown: &[Color]) -> Metrics {
+    let errors = errors(image, shown);
+    let width = (image.width() as usize).max(1);
+    let (worst_row, worst_row_delta_e) = errors
+        .chunks(width)
+        .map(|row| row.iter().sum::<f64>() / row.len() as f64)
+        .enumerate()
+        .fold(
+            (0, 0.0),
+            |worst, (y, e)| if e > worst.1 { (y, e) } else { worst },
+        );
+    let colors = &image.palette().colors;
+    let fringes = (0..errors.len())
+        .filter(|&i| {
+            let boundary = i % width > 0
+                && colors[image.pixels[i] as usize] != colors[image.pixels[i - 1] as usize];
+            boundary && errors[i] > FRINGE_DELTA_E
+        })
+        .count();
+    Metrics {
+        mean_delta_e: errors.iter().sum::<f64>() / errors.len().max(1) as f64,
+        worst_row,
+        worst_row_delta_e,
+        fringes,
+    }
+}
+
+/// The error of every pixel from black over red and yellow to white
+pub fn heat_map(image: &IffImage, shown: &[Color]) -> IffImage {
+    let colors = (0..256)
+        .map(|i| {
+            let channel = |offset: i32| (i * 3 - offset).clamp(0, 255) as u8;
+            Color {
+                r: channel(0),
+                g: channel(255),
+                b: channel(510),
+            }
+        })
+        .collect();
+    let pixels = errors(image, shown)
+        .iter()
+        .map(|e| (e / HEAT_MAP_MAX_DELTA_E * 255.0).min(255.0) as u8)
+        .collect();
+    IffImage::from_parts(image.width(), image.height(), ColorMap { colors }, pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_and_modifies_channels() {
+        let color = |r, g, b| Color { r, g, b };
+        let colors = vec![
+            color(0, 0, 0),
+            color(255, 0, 0),
+            color(255, 0, 255),
+            color(255, 255, 255),
+            color(255, 255, 0),
+        ];
+        let image = IffImage::from_parts(4, 2, ColorMap { colors }, vec![0, 1, 2, 3, 0, 4, 4, 4]);
+        let base = ColorMap {
+            colors: vec![color(0, 0, 0), color(255, 255, 255)],
+        };
+        let ham = encode(&image, HamMode::Ham6, &base).unwrap();
+        assert_eq!(ham.image.bitplanes(), 6);
+        assert_eq!(ham.image.camg(), Some(CAMG_HAM));
+        // base 0, red 15, blue 15, base 1, then yellow takes two pixels
+        assert_eq!(
+            ham.image.pixels,
+            vec![0x00, 0x2f, 0x1f, 0x01, 0x00, 0x01, 0x10, 0x10]
+        );
+        assert_eq!(ham.shown[5], color(255, 255, 255));
+        let base = &ham.image.palette().colors;
+        assert_eq!(decode(&ham.image, HamMode::Ham6, base), ham.shown);
+
+        let metrics = metrics(&image, &ham.shown);
+        assert_eq!(metrics.worst_row, 1);
+        assert_eq!(metrics.fringes, 1);
+        assert!(metrics.mean_delta_e > 0.0);
+        let heat_map = heat_map(&image, &ham.shown);
+        assert_eq!(heat_map.pixels[..5], [0; 5]);
+        assert_eq!(heat_map.pixels[5], 255);
+
+        assert_eq!(HamMode::Ham8.code(RED, 63), 0xfe);
+        assert_eq!(HamMode::Ham8.modify(0x03, 0x80), (32, 0x83));
+        assert!(encode(&image, HamMode::Ham6, &ColorMap::default()).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! Classic Workbench icons (.info files): a DiskObject with its gadget,
+//! drawer data for disks and drawers, one or two images and the default
+//! tool and tool type strings. Pointers in the structures only flag whether
+//! the data follows, their values do not matter. Icons have no palette, the
+//! pixels are Workbench pens
+
+use crate::iffimage::{IffConvertError, IffImage};
+use std::fmt;
+use std::str::FromStr;
+
+const WB_DISKMAGIC: u16 = 0xe310;
+const WB_DISKVERSION: u16 = 1;
+/// Any non-null pointer value
+const PRESENT: u32 = 1;
+/// No fixed position in the drawer window
+const NO_ICON_POSITION: u32 = 0x8000_0000;
+
+/// Gadget flags: highlight by complementing, or with the selected image
+const GADGHCOMP: u16 = 0x0000;
+const GADGHIMAGE: u16 = 0x0002;
+const GADGIMAGE: u16 = 0x0004;
+/// Gadget activation: RELVERIFY and GADGIMMEDIATE
+const ACTIVATION: u16 = 0x0003;
+const BOOLGADGET: u16 = 0x0001;
+
+/// What double clicking the icon opens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IconType {
+    Disk = 1,
+    Drawer = 2,
+    Tool = 3,
+    Project = 4,
+    Garbage = 5,
+}
+
+impl IconType {
+    /// Disks, drawers and the trashcan open a window
+    fn has_drawer(self) -> bool {
+        matches!(self, IconType::Disk | IconType::Drawer | IconType::Garbage)
+    }
+}
+
+impl FromStr for IconType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "disk" => Ok(IconType::Disk),
+            "drawer" => Ok(IconType::Drawer),
+            "tool" => Ok(IconType::Tool),
+            "project" => Ok(IconType::Project),
+            "garbage" => Ok(IconType::Garbage),
+            _ => Err(format!(
+                "Unknown icon type {}, expected disk, drawer, tool, project or garbage",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IconType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IconType::Disk => "disk",
+            IconType::Drawer => "drawer",
+            IconType::Tool => "tool",
+            IconType::Project => "project",
+            IconType::Garbage => "garbage",
+        })
+    }
+}
+
+/// The DiskObject fields besides the images
+#[derive(Clone, Debug)]
+pub struct IconOptions {
+    pub icon_type: IconType,
+    /// Program opening a project
+    pub default_tool: Option<String>,
+    /// Tool types as KEY=value strings
+    pub tool_types: Vec<String>,
+    /// Stack size for tools
+    pub stack_size: u32,
+}
+
+impl Default for IconOptions {
+    /// A tool with the usual 4 KB stack
+    fn default() -> Self {
+        IconOptions {
+            icon_type: IconType::Tool,
+            default_tool: None,
+            tool_types: vec![],
+            stack_size: 4096,
+        }
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// A length prefixed, null terminated string
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    push_u32(out, s.len() as u32 + 1);
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+/// The Image structure followed by its planes, one after the other with
+/// word aligned rows
+fn push_image(out: &mut Vec<u8>, image: &IffImage) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let depth = image.bitplanes();
+    for value in [0, 0, width as u16, height as u16, depth as u16] {
+        push_u16(out, value);
+    }
+    push_u32(out, PRESENT);
+    // PlanePick and PlaneOnOff
+    out.push(((1u16 << depth) - 1) as u8);
+    out.push(0);
+    push_u32(out, 0);
+
+    let row_bytes = width.div_ceil(16) * 2;
+    for plane in 0..depth {
+        for y in 0..height {
+            let mut row = vec![0u8; row_bytes];
+            for (x, &p) in image.pixels[y * width..(y + 1) * width].iter().enumerate() {
+                row[x / 8] |= (p >> plane & 1) << (7 - x % 8);
+            }
+            out.extend(row);
+        }
+    }
+}
+
+/// The drawer window of disks and drawers: a NewWindow followed by the
+/// scroll position
+fn push_drawer_data(out: &mut Vec<u8>) {
+    for value in [50, 50, 400, 100] {
+        push_u16(out, value);
+    }
+    // DetailPen, BlockPen, IDCMPFlags, Flags and 5 pointers
+    out.extend_from_slice(&[0xff, 0xff]);
+    out.resize(out.len() + 7 * 4, 0);
+    // MinWidth, MinHeight, MaxWidth, MaxHeight, Type (WBENCHSCREEN)
+    for value in [90, 40, 0xffff, 0xffff, 1] {
+        push_u16(out, value);
+    }
+    push_u32(out, 0);
+    push_u32(out, 0);
+}
+
+/// Writes a .info icon showing `normal`, and `selected` when clicked.
+/// Without a selected image Workbench complements the normal one
+pub fn encode(
+    normal: &IffImage,
+    selected: Option<&IffImage>,
+    options: &IconOptions,
+) -> Result<Vec<u8>, IffConvertError> {
+    for image in std::iter::once(normal).chain(selected) {
+        if image.width() > i16::MAX as u16 || image.height() > i16::MAX as u16 {
+            return Err(IffConvertError::InvalidSize(
+                image.width() as u32,
+                image.height() as u32,
+            ));
+        }
+    }
+    let present = |flag: bool| if flag { PRESENT } else { 0 };
+
+    let mut out = vec![];
+    push_u16(&mut out, WB_DISKMAGIC);
+    push_u16(&mut out, WB_DISKVERSION);
+    // Gadget: NextGadget, LeftEdge, TopEdge, Width, Height
+    push_u32(&mut out, 0);
+    for value in [0, 0, normal.width(), normal.height()] {
+        push_u16(&mut out, value);
+    }
+    let highlight = if selected.is_some() {
+        GADGHIMAGE
+    } else {
+        GADGHCOMP
+    };
+    for value in [GADGIMAGE | highlight, ACTIVATION, BOOLGADGET] {
+        push_u16(&mut out, value);
+    }
+    push_u32(&mut out, PRESENT);
+    push_u32(&mut out, present(selected.is_some()));
+    // GadgetText, MutualExclude, SpecialInfo, GadgetID, UserData
+    out.resize(out.len() + 3 * 4 + 2 + 4, 0);
+
+    out.push(options.icon_type as u8);
+    out.push(0);
+    push_u32(&mut out, present(options.default_tool.is_some()));
+    push_u32(&mut out, present(!options.tool_types.is_empty()));
+    push_u32(&mut out, NO_ICON_POSITION);
+    push_u32(&mut out, NO_ICON_POSITION);
+    push_u32(&mut out, present(options.icon_type.has_drawer()));
+    push_u32(&mut out, 0);
+    push_u32(&mut out, options.stack_size);
+
+    if options.icon_type.has_drawer() {
+        push_drawer_data(&mut out);
+    }
+    push_image(&mut out, normal);
+    if let Some(selected) = selected {
+        push_image(&mut out, selected);
+    }
+    if let Some(tool) = &options.default_tool {
+        push_string(&mut out, tool);
+    }
+    if !options.tool_types.is_empty() {
+        // the size of the pointer array, including the terminating null
+        push_u32(&mut out, (options.tool_types.len() as u32 + 1) * 4);
+        for tool_type in &options.tool_types {
+            push_string(&mut out, tool_type);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn disk_object_with_images_and_strings() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let normal = IffImage::from_parts(3, 1, colors.clone(), vec![1, 2, 3]);
+        let selected = IffImage::from_parts(3, 1, colors, vec![3, 0, 0]);
+        let options = IconOptions {
+            default_tool: Some("SYS:Utilities/MultiView".to_string()),
+            tool_types: vec!["DONOTWAIT".to_string()],
+            icon_type: IconType::Project,
+            ..Default::default()
+        };
+        let info = encode(&normal, Some(&selected), &options).unwrap();
+        assert_eq!(&info[..4], &[0xe3, 0x10, 0, 1]);
+        assert_eq!(&info[12..14], &[0, 3]);
+        assert_eq!(&info[16..18], &[0, 0x06]);
+        assert_eq!(info[48], 4);
+        assert_eq!(info.len(), 78 + 2 * (20 + 4) + 4 + 24 + 4 + 4 + 10);
+
+        // normal image: header, then plane 0 and plane 1
+        assert_eq!(&info[78 + 8..78 + 10], &[0, 2]);
+        assert_eq!(info[78 + 14], 3);
+        assert_eq!(&info[98..102], &[0xa0, 0, 0x60, 0]);
+        assert!(info.ends_with(b"\0\0\0\x0aDONOTWAIT\0"));
+
+        let tool = encode(&normal, None, &IconOptions::default()).unwrap();
+        assert_eq!(&tool[16..18], &[0, 0x04]);
+        assert_eq!(tool.len(), 78 + 24);
+    }
+}
@@ -0,0 +1,314 @@
+//! Generic IFF 85 building blocks: chunks, FORMs nesting them, CAT and LIST
+//! containers, and the readers splitting a FORM up again. ILBM is built on
+//! top of these, other FORM types (8SVX, custom ones) can be written the
+//! same way
+//!
+//! ```
+//! use ipng2iff::iff::Form;
+//!
+//! let form = Form::new(b"8SVX")
+//!     .chunk(b"VHDR", vec![0; 20])
+//!     .chunk(b"NAME", "beep")
+//!     .chunk(b"BODY", vec![0x40, 0xc0, 0x40]);
+//! assert_eq!(form.to_bytes().len(), form.size());
+//! ```
+
+use crate::iffimage::IffConvertError;
+use std::convert::TryFrom;
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::Result as IoResult;
+use std::io::Write;
+
+/// A chunk ID with the chunk data, borrowed from a parsed file
+pub type RawChunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Size of a chunk including its header and the pad byte for odd lengths
+pub fn padded_chunk_size(len: usize) -> usize {
+    8 + len + (len & 1)
+}
+
+/// Writes a chunk header. `len` excludes the header and pad byte. Sizes
+/// beyond the 32 bit size field are refused instead of wrapping around
+pub fn write_header(writer: &mut dyn Write, id: &[u8; 4], len: usize) -> IoResult<()> {
+    let len = u32::try_from(len).map_err(|_| {
+        IoError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} chunk of {} bytes exceeds the 4 GB IFF limit",
+                String::from_utf8_lossy(id),
+                len
+            ),
+        )
+    })?;
+    writer.write_all(id)?;
+    writer.write_all(&len.to_be_bytes())
+}
+
+/// Writes one chunk, padding it to an even length as IFF requires
+pub fn write_chunk(writer: &mut dyn Write, id: &[u8; 4], data: &[u8]) -> IoResult<()> {
+    write_header(writer, id, data.len())?;
+    writer.write_all(data)?;
+    if data.len() & 1 != 0 {
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Splits the contents of a FORM into its chunks
+pub fn chunks(data: &[u8]) -> Result<Vec<RawChunk<'_>>, IffConvertError> {
+    let mut chunks = vec![];
+    let mut i = 0;
+    while i + 8 <= data.len() {
+        let id = [data[i], data[i + 1], data[i + 2], data[i + 3]];
+        let len = u32::from_be_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        let chunk = data
+            .get(i + 8..i + 8 + len)
+            .ok_or(IffConvertError::Malformed("truncated IFF chunk"))?;
+        chunks.push((id, chunk));
+        i += 8 + len + (len & 1);
+    }
+    Ok(chunks)
+}
+
+/// Returns the chunks inside a FORM of type `form_type`
+pub fn form_chunks<'a>(
+    data: &'a [u8],
+    form_type: &[u8; 4],
+) -> Result<Vec<RawChunk<'a>>, IffConvertError> {
+    if data.len() < 12 || &data[0..4] != b"FORM" {
+        return Err(IffConvertError::UnsupportedFormat);
+    }
+    if &data[8..12] != form_type {
+        return Err(IffConvertError::Malformed("unexpected FORM type"));
+    }
+    let len = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let form = data
+        .get(12..8 + len.max(4))
+        .ok_or(IffConvertError::Malformed("truncated FORM"))?;
+    chunks(form)
+}
+
+/// One entry of a FORM
+#[derive(Clone, Debug, PartialEq)]
+pub enum Chunk {
+    /// A plain chunk with its ID and data
+    Data([u8; 4], Vec<u8>),
+    /// A FORM, or another group chunk, nested inside a group
+    Form(Form),
+}
+
+impl Chunk {
+    pub fn new(id: &[u8; 4], data: impl Into<Vec<u8>>) -> Chunk {
+        Chunk::Data(*id, data.into())
+    }
+
+    pub fn id(&self) -> [u8; 4] {
+        match self {
+            Chunk::Data(id, _) => *id,
+            Chunk::Form(form) => form.id,
+        }
+    }
+
+    /// Bytes the chunk takes up in a file, header and padding included
+    pub fn size(&self) -> usize {
+        match self {
+            Chunk::Data(_, data) => padded_chunk_size(data.len()),
+            Chunk::Form(form) => form.size(),
+        }
+    }
+
+    pub fn write(&self, writer: &mut dyn Write) -> IoResult<()> {
+        match self {
+            Chunk::Data(id, data) => write_chunk(writer, id, data),
+            Chunk::Form(form) => form.write(writer),
+        }
+    }
+}
+
+/// A FORM of chunks and nested FORMs, built up in order and written with all
+/// sizes and padding worked out. CAT, LIST and PROP share the layout of a
+/// FORM, only with another ID
+#[derive(Clone, Debug, PartialEq)]
+pub struct Form {
+    id: [u8; 4],
+    form_type: [u8; 4],
+    chunks: Vec<Chunk>,
+}
+
+impl Form {
+    pub fn new(form_type: &[u8; 4]) -> Form {
+        Form {
+            id: *b"FORM",
+            form_type: *form_type,
+            chunks: vec![],
+        }
+    }
+
+    /// Properties shared by the FORMs of `form_type` in a LIST
+    pub fn prop(form_type: &[u8; 4]) -> Form {
+        Form {
+            id: *b"PROP",
+            ..Form::new(form_type)
+        }
+    }
+
+    /// A CAT concatenating `forms`. `contents_type` hints at the type of
+    /// all of them, or is four spaces for mixed contents
+    pub fn cat(contents_type: &[u8; 4], forms: Vec<Form>) -> Form {
+        Form {
+            id: *b"CAT ",
+            form_type: *contents_type,
+            chunks: forms.into_iter().map(Chunk::Form).collect(),
+        }
+    }
+
+    /// A LIST of `forms` of one type. Chunks with an ID in `shared` that are
+    /// identical in all forms are stored once in a PROP instead
+    pub fn list(form_type: &[u8; 4], forms: Vec<Form>, shared: &[[u8; 4]]) -> Form {
+        let mut prop = Form::prop(form_type);
+        let mut forms = forms;
+        if forms.iter().all(|form| form.form_type == *form_type) {
+            for id in shared {
+                let first = forms.first().and_then(|form| form.data_chunk(id));
+                let first = match first {
+                    Some(chunk) => chunk.clone(),
+                    None => continue,
+                };
+                if forms.iter().all(|form| form.data_chunk(id) == Some(&first)) {
+                    for form in &mut forms {
+                        form.chunks.retain(|chunk| chunk != &first);
+                    }
+                    prop.push(first);
+                }
+            }
+        }
+
+        let mut chunks = vec![];
+        if !prop.chunks.is_empty() {
+            chunks.push(Chunk::Form(prop));
+        }
+        chunks.extend(forms.into_iter().map(Chunk::Form));
+        Form {
+            id: *b"LIST",
+            form_type: *form_type,
+            chunks,
+        }
+    }
+
+    fn data_chunk(&self, id: &[u8; 4]) -> Option<&Chunk> {
+        self.chunks
+            .iter()
+            .find(|chunk| matches!(chunk, Chunk::Data(i, _) if i == id))
+    }
+
+    /// Appends a chunk with `data`
+    pub fn chunk(mut self, id: &[u8; 4], data: impl Into<Vec<u8>>) -> Form {
+        self.push(Chunk::new(id, data));
+        self
+    }
+
+    /// Appends a nested FORM
+    pub fn form(mut self, form: Form) -> Form {
+        self.push(Chunk::Form(form));
+        self
+    }
+
+    pub fn push(&mut self, chunk: Chunk) {
+        self.chunks.push(chunk);
+    }
+
+    /// FORM, CAT , LIST or PROP
+    pub fn id(&self) -> [u8; 4] {
+        self.id
+    }
+
+    pub fn form_type(&self) -> [u8; 4] {
+        self.form_type
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// The size stored in the FORM header: the form type plus all chunks
+    pub fn content_size(&self) -> usize {
+        4 + self.chunks.iter().map(Chunk::size).sum::<usize>()
+    }
+
+    /// Bytes the whole FORM takes up, header included
+    pub fn size(&self) -> usize {
+        8 + self.content_size()
+    }
+
+    pub fn write(&self, writer: &mut dyn Write) -> IoResult<()> {
+        write_header(writer, &self.id, self.content_size())?;
+        writer.write_all(&self.form_type)?;
+        for chunk in &self.chunks {
+            chunk.write(writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.size());
+        self.write(&mut out)
+            .expect("writing to a Vec only fails beyond 4 GB");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_forms_are_sized_and_padded() {
+        let inner = Form::new(b"ILBM").chunk(b"ANNO", "odd");
+        let form = Form::new(b"TEST")
+            .chunk(b"NAME", "ab")
+            .form(inner.clone())
+            .chunk(b"DATA", vec![1, 2, 3]);
+        let bytes = form.to_bytes();
+        assert_eq!(bytes.len(), form.size());
+        assert_eq!(form.size(), 12 + 10 + (12 + 12) + 12);
+
+        let chunks = form_chunks(&bytes, b"TEST").unwrap();
+        let ids = chunks.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![*b"NAME", *b"FORM", *b"DATA"]);
+        assert_eq!(chunks[1].1.len(), inner.content_size());
+        assert_eq!(chunks[2].1, &[1, 2, 3]);
+        let nested = [
+            &b"FORM"[..],
+            &(chunks[1].1.len() as u32).to_be_bytes(),
+            chunks[1].1,
+        ]
+        .concat();
+        assert_eq!(nested, inner.to_bytes());
+        assert!(form_chunks(&bytes, b"ILBM").is_err());
+
+        if let Some(len) = (u32::MAX as usize).checked_add(1) {
+            assert!(write_header(&mut vec![], b"BODY", len).is_err());
+        }
+    }
+
+    #[test]
+    fn list_moves_shared_chunks_into_a_prop() {
+        let forms = vec![
+            Form::new(b"ILBM").chunk(b"CMAP", "rgb").chunk(b"BODY", "a"),
+            Form::new(b"ILBM").chunk(b"CMAP", "rgb").chunk(b"BODY", "b"),
+        ];
+        let list = Form::list(b"ILBM", forms.clone(), &[*b"CMAP", *b"CAMG"]);
+        let ids = list.chunks().iter().map(Chunk::id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![*b"PROP", *b"FORM", *b"FORM"]);
+        assert_eq!(&list.to_bytes()[..4], b"LIST");
+        match &list.chunks()[1] {
+            Chunk::Form(form) => assert_eq!(form.chunks(), &[Chunk::new(b"BODY", "a")]),
+            _ => unreachable!(),
+        }
+
+        let cat = Form::cat(b"ILBM", forms);
+        assert_eq!(&cat.to_bytes()[..4], b"CAT ");
+        assert_eq!(cat.size(), 12 + 2 * (12 + 12 + 10));
+    }
+}
@@ -1,203 +1,2231 @@
+use crate::diagnostics::PixelReport;
+use crate::dither::Dither;
+use crate::iff::{padded_chunk_size, write_chunk, write_header, Form};
+#[cfg(feature = "image")]
+use crate::imageinput;
+use crate::pngcolor;
+use crate::vdat;
+use gif::DecodingError as GifDecodeError;
+use log::{debug, trace};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 use png::ColorType;
 use png::DecodingError as PngDecodeError;
-use std::error::Error;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs::File;
+use std::io::BufReader;
 use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::io::Result as IoResult;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-#[derive(Debug)]
+/// Colors an ILBM can have with 8 bitplanes
+pub const MAX_COLORS: usize = 256;
+
+/// Why an image can't be represented as an ILBM
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum IffConvertError {
-    WrongColorType(ColorType),
+    #[error("No palette found")]
     NoPalette,
+    #[error("Palette found, but is empty :S")]
     EmptyPalette,
+    #[error("Too many colors: {0}")]
     TooManyColors(usize),
-    InvalidPixel([u8; 3]),
+    #[error("Can't quantize to {0} colors, expected 1 to {max}", max = MAX_COLORS)]
+    InvalidColorCount(usize),
+    #[error("Pixel color {color:?} at {x},{y} is not in the palette")]
+    InvalidPixel { color: [u8; 3], x: usize, y: usize },
+    #[error("{0}")]
+    UnmappedPixels(Box<PixelReport>),
+    #[error("Crop region exceeds the image")]
+    CropOutOfBounds,
+    #[error("Invalid image size {0}x{1}")]
+    InvalidSize(u32, u32),
+    #[error("Image is empty: {0}x{1} pixels")]
+    EmptyImage(u32, u32),
+    #[error("Image size {0}x{1} exceeds the IFF limit of 65535x65535 pixels")]
+    TooLarge(u32, u32),
+    #[error("Unsupported bit depth: {0}")]
+    UnsupportedBitDepth(u8),
+    #[error("Pixel index {index} at {x},{y} is outside of the palette")]
+    InvalidIndex { index: u8, x: usize, y: usize },
+    #[error("Unsupported input file format")]
+    UnsupportedFormat,
+    #[error("Conversion cancelled")]
+    Cancelled,
+    #[error("Unsupported input: {0}")]
+    Unsupported(&'static str),
+    #[error("Malformed input file: {0}")]
+    Malformed(&'static str),
+    #[error("No {} chunk found", String::from_utf8_lossy(.0))]
+    MissingChunk([u8; 4]),
+    #[error("Malformed {} chunk: {reason}", String::from_utf8_lossy(chunk))]
+    MalformedChunk {
+        chunk: [u8; 4],
+        reason: &'static str,
+    },
+    #[error("Key color {0} is not in the palette")]
+    KeyColorNotInPalette(KeyColor),
+    #[error("Pixel at {x},{y} is partially transparent (alpha {alpha})")]
+    PartialAlpha { alpha: u8, x: usize, y: usize },
+}
+
+/// Why loading an image failed. The underlying decoder error is available
+/// through `source()`
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum IffLoadError {
+    #[error("I/O error")]
+    Io(#[from] IoError),
+    #[error("Can't read {}", path.display())]
+    File {
+        path: PathBuf,
+        #[source]
+        source: IoError,
+    },
+    #[error("Can't decode PNG")]
+    Png(#[from] PngDecodeError),
+    #[error("Can't decode GIF")]
+    Gif(#[from] GifDecodeError),
+    #[cfg(feature = "image")]
+    #[error("Can't decode image")]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Convert(#[from] IffConvertError),
+}
+
+/// Something that did not stop a conversion but might not be what was
+/// intended
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "kebab-case"))]
+#[non_exhaustive]
+pub enum Warning {
+    /// Truecolor input had more colors than allowed and was quantized
+    Quantized { colors: usize },
+    /// Colors of an input with another gamma were converted to sRGB
+    GammaCorrected { gamma: f64 },
+    /// An embedded ICC profile was not applied to the colors
+    IccProfileIgnored,
+    /// Partially transparent pixels were converted as if they were opaque
+    AlphaIgnored,
+    /// Pixels in colors missing from the palette took the closest entry
+    Remapped { pixels: usize, colors: usize },
+    /// Samples of more than 8 bits were brought down to 8 bits
+    DepthReduced,
+    /// Compression made the BODY larger than storing it uncompressed
+    CompressionGrewBody {
+        compression: Compression,
+        body_size: usize,
+        raw_body_size: usize,
+    },
 }
 
-impl fmt::Display for IffConvertError {
+impl Warning {
+    /// Whether the converted image lost colors or detail of the input
+    pub fn is_lossy(&self) -> bool {
+        matches!(
+            self,
+            Warning::Quantized { .. }
+                | Warning::AlphaIgnored
+                | Warning::Remapped { .. }
+                | Warning::DepthReduced
+        )
+    }
+}
+
+impl fmt::Display for Warning {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            IffConvertError::WrongColorType(c) => f.write_fmt(format_args!(
-                "Invalid ColorType {:?}. Can only work with indexed!",
-                c
-            )),
-            IffConvertError::NoPalette => f.write_str("No palette found"),
-            IffConvertError::EmptyPalette => f.write_str("Palette found, but is empty :S"),
-            IffConvertError::TooManyColors(c) => {
-                f.write_fmt(format_args!("Too many colors: {}", c))
-            }
-            IffConvertError::InvalidPixel(c) => {
-                f.write_fmt(format_args!("Too many colors: {:?}", c))
+        match self {
+            Warning::Quantized { colors } => {
+                f.write_fmt(format_args!("Quantized to {} colors", colors))
             }
+            Warning::GammaCorrected { gamma } => f.write_fmt(format_args!(
+                "Colors converted from gamma {:.5} to sRGB",
+                gamma
+            )),
+            Warning::IccProfileIgnored => f.write_str("Embedded ICC profile ignored"),
+            Warning::AlphaIgnored => f.write_str("Alpha channel ignored, pixels treated as opaque"),
+            Warning::Remapped { pixels, colors } => f.write_fmt(format_args!(
+                "{} pixels in {} colors not in the palette mapped to the nearest entry",
+                pixels, colors
+            )),
+            Warning::DepthReduced => f.write_str("Samples reduced to 8 bits"),
+            Warning::CompressionGrewBody {
+                compression,
+                body_size,
+                raw_body_size,
+            } => f.write_fmt(format_args!(
+                "{} made the BODY larger ({} instead of {} bytes)",
+                compression, body_size, raw_body_size
+            )),
         }
     }
 }
 
+/// How far a conversion got, passed to progress callbacks
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Progress {
+    /// Scanlines of the BODY packed and written so far, planes with VDAT
+    Rows { done: usize, total: usize },
+}
+
+/// Lets another thread abort a running conversion. Clones share the flag
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails with `IffConvertError::Cancelled` once cancelled
+    pub(crate) fn check(&self) -> Result<(), IffConvertError> {
+        match self.is_cancelled() {
+            true => Err(IffConvertError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// What happens to the alpha channel of inputs that have one
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AlphaMode {
+    /// All pixels are opaque, with a warning if any were not
+    #[default]
+    Ignore,
+    /// Pixels with at least this alpha are opaque, the others are masked
+    Threshold(u8),
+    /// Fully transparent pixels are masked, partial alpha is an error
+    Strict,
+}
+
+/// Settings for formats that hold more than a single image or need quantizing
 #[derive(Debug)]
-pub enum IffLoadError {
-    IoError(IoError),
-    PngDecodeError(PngDecodeError),
-    IffConvertError(IffConvertError),
+pub struct LoadOptions {
+    /// Frame to convert from animated inputs
+    pub frame: usize,
+    /// Only convert this layer instead of flattening all visible ones
+    pub layer: Option<String>,
+    /// Memory-map the input instead of reading it through a buffer. Needs
+    /// the mmap feature, without it the input is always buffered
+    pub mmap: bool,
+    /// Palette size when truecolor input has to be quantized, 1 to
+    /// `MAX_COLORS`
+    #[cfg_attr(not(feature = "image"), allow(dead_code))]
+    pub max_colors: usize,
+    /// Order of palettes built for truecolor input
+    #[cfg_attr(not(feature = "image"), allow(dead_code))]
+    pub palette_order: PaletteOrder,
+    /// Checked between decoding and quantizing steps
+    pub cancel: CancelToken,
+    /// Turns the alpha channel into a mask plane
+    pub alpha: AlphaMode,
+    /// Converts PNGs with a gAMA other than sRGB to sRGB colors
+    pub color_management: bool,
+    /// Dithers 16 bit samples down to 8 bits instead of rounding them
+    pub dither_16bit: bool,
+    /// Pixels in colors the palette doesn't have
+    pub remap: Remap,
+    /// How the closest colors are found when quantizing and remapping
+    pub color_metric: ColorMetric,
 }
 
-impl From<IoError> for IffLoadError {
-    fn from(error: IoError) -> Self {
-        IffLoadError::IoError(error)
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            frame: 0,
+            layer: None,
+            mmap: false,
+            max_colors: 32,
+            palette_order: PaletteOrder::FirstAppearance,
+            cancel: CancelToken::default(),
+            alpha: AlphaMode::Ignore,
+            color_management: true,
+            dither_16bit: false,
+            remap: Remap::Exact,
+            color_metric: ColorMetric::Rgb,
+        }
     }
 }
 
-impl From<PngDecodeError> for IffLoadError {
-    fn from(error: PngDecodeError) -> Self {
-        IffLoadError::PngDecodeError(error)
+#[derive(Clone, Default)]
+pub struct IffImage {
+    pub(crate) bmhd: BitmapHeader,
+    pub(crate) cmap: ColorMap,
+    pub(crate) pixels: Vec<u8>,
+    /// Amiga display mode, written as a CAMG chunk when set
+    pub(crate) camg: Option<u32>,
+    /// Leaves out the CMAP, for programs setting the palette themselves
+    pub(crate) omit_cmap: bool,
+    /// User supplied chunks written around the BODY
+    pub(crate) extra_chunks: Vec<([u8; 4], Vec<u8>, ChunkPlacement)>,
+    /// One byte per pixel, 0 where the pixel is see-through. Written as an
+    /// extra plane after the bitplanes of every row
+    pub(crate) mask: Option<Vec<u8>>,
+    /// Alpha of every pixel, kept by decoders when not all are opaque
+    pub(crate) alpha: Option<Vec<u8>>,
+    pub(crate) warnings: Vec<Warning>,
+}
+
+/// Where a user supplied chunk goes in the FORM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkPlacement {
+    BeforeBody,
+    AfterBody,
+}
+
+/// BMHD compression byte for uncompressed rows
+pub(crate) const COMPRESSION_NONE: u8 = 0;
+/// BMHD compression byte for ByteRun1 packed rows
+pub(crate) const COMPRESSION_BYTERUN1: u8 = 1;
+/// BMHD compression byte for VDAT packed planes
+pub(crate) const COMPRESSION_VDAT: u8 = 2;
+
+/// BODY compression
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Compression {
+    None,
+    ByteRun1,
+    /// ByteRun1 unless that makes the BODY larger, as it does for noisy or
+    /// dithered images
+    Auto,
+    /// Vertical run-length packed planes as used on the Atari ST, which
+    /// suits images with large vertical areas of one color
+    Vdat,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "byterun1" => Ok(Compression::ByteRun1),
+            "auto" => Ok(Compression::Auto),
+            "vdat" => Ok(Compression::Vdat),
+            _ => Err(format!(
+                "Invalid compression {:?}. Expected none, byterun1, auto or vdat",
+                s
+            )),
+        }
     }
 }
 
-impl From<IffConvertError> for IffLoadError {
-    fn from(error: IffConvertError) -> Self {
-        IffLoadError::IffConvertError(error)
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::ByteRun1 => f.write_str("byterun1"),
+            Compression::Auto => f.write_str("auto"),
+            Compression::Vdat => f.write_str("vdat"),
+        }
     }
 }
 
-impl fmt::Display for IffLoadError {
+/// BMHD masking technique: no transparency
+pub(crate) const MASK_NONE: u8 = 0;
+/// BMHD masking technique: an extra plane per row holds the mask
+pub(crate) const MASK_HAS_MASK: u8 = 1;
+/// BMHD masking technique: the color at `transparent_color` is see-through
+pub(crate) const MASK_HAS_TRANSPARENT_COLOR: u8 = 2;
+/// BMHD masking technique: like the transparent color, but only where it
+/// can be reached from the outside, as the DPaint lasso does
+pub(crate) const MASK_LASSO: u8 = 3;
+
+/// A color given as #RRGGBB or as a palette index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyColor {
+    Rgb([u8; 3]),
+    Index(u8),
+}
+
+impl FromStr for KeyColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid color {:?}. Expected #RRGGBB or a palette index", s);
+        match s.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => {
+                let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                let [_, r, g, b] = rgb.to_be_bytes();
+                Ok(KeyColor::Rgb([r, g, b]))
+            }
+            Some(_) => Err(invalid()),
+            None => s.parse().map(KeyColor::Index).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for KeyColor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            IffLoadError::IoError(e) => f.write_fmt(format_args!("IoError {}", e)),
-            IffLoadError::PngDecodeError(e) => f.write_fmt(format_args!("PngDecodeError {}", e)),
-            IffLoadError::IffConvertError(e) => f.write_fmt(format_args!("IffConvertError {}", e)),
+            KeyColor::Rgb([r, g, b]) => f.write_fmt(format_args!("#{:02x}{:02x}{:02x}", r, g, b)),
+            KeyColor::Index(index) => f.write_fmt(format_args!("index {}", index)),
         }
     }
 }
 
-impl Error for IffLoadError {}
+/// Order of the palette entries when the converter builds the palette
+/// itself, by quantizing or indexing truecolor input. Either way the same
+/// input always gives the same indices
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum PaletteOrder {
+    /// The color of the top left pixel first, then row by row
+    #[default]
+    FirstAppearance,
+    /// The most used color first, ties by first appearance
+    Popularity,
+}
 
-#[derive(Default)]
-pub struct IffImage {
-    bmhd: BitmapHeader,
-    cmap: ColorMap,
-    pixels: Vec<u8>,
+impl FromStr for PaletteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "first-appearance" | "appearance" => Ok(PaletteOrder::FirstAppearance),
+            "popularity" => Ok(PaletteOrder::Popularity),
+            _ => Err(format!(
+                "Unknown palette order {}, expected first-appearance or popularity",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PaletteOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PaletteOrder::FirstAppearance => "first-appearance",
+            PaletteOrder::Popularity => "popularity",
+        })
+    }
+}
+
+/// How an existing palette is sorted, e.g. to line up color cycling ranges
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum PaletteSort {
+    /// Keep the palette as it is
+    #[default]
+    None,
+    /// Darkest first, by CIE L*
+    Luminance,
+    /// Grays first, then around the color wheel from red, each by luminance
+    Hue,
+    /// The most used color first
+    Popularity,
+}
+
+impl FromStr for PaletteSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(PaletteSort::None),
+            "luminance" => Ok(PaletteSort::Luminance),
+            "hue" => Ok(PaletteSort::Hue),
+            "popularity" => Ok(PaletteSort::Popularity),
+            _ => Err(format!(
+                "Unknown palette sort {}, expected luminance, hue, popularity or none",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PaletteSort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PaletteSort::None => "none",
+            PaletteSort::Luminance => "luminance",
+            PaletteSort::Hue => "hue",
+            PaletteSort::Popularity => "popularity",
+        })
+    }
+}
+
+/// What happens to pixels whose color is not in the palette
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Remap {
+    /// Fail with the first such pixel
+    #[default]
+    Exact,
+    /// Take the closest palette color
+    Nearest,
+}
+
+impl FromStr for Remap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "exact" => Ok(Remap::Exact),
+            "nearest" => Ok(Remap::Nearest),
+            _ => Err(format!("Unknown remap {}, expected exact or nearest", s)),
+        }
+    }
+}
+
+impl fmt::Display for Remap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Remap::Exact => "exact",
+            Remap::Nearest => "nearest",
+        })
+    }
+}
+
+/// How color differences are measured when looking for the closest color
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ColorMetric {
+    /// Euclidean distance of the sRGB values
+    #[default]
+    Rgb,
+    /// CIEDE2000 delta E, the closest to how people judge differences
+    Ciede2000,
+    /// Euclidean distance in OKLab, nearly as good and a lot cheaper
+    Oklab,
+}
+
+impl ColorMetric {
+    /// The coordinates of `color` that `difference` compares
+    fn coordinates(self, color: &Color) -> [f64; 3] {
+        match self {
+            ColorMetric::Rgb => [color.r as f64, color.g as f64, color.b as f64],
+            ColorMetric::Ciede2000 => color.to_lab(),
+            ColorMetric::Oklab => color.to_oklab(),
+        }
+    }
+
+    /// Difference between two colors' coordinates, only comparable with
+    /// other differences of the same metric
+    fn difference(self, a: &[f64; 3], b: &[f64; 3]) -> f64 {
+        match self {
+            ColorMetric::Ciede2000 => ciede2000(a, b),
+            _ => a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum(),
+        }
+    }
+}
+
+impl FromStr for ColorMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(ColorMetric::Rgb),
+            "ciede2000" | "de2000" => Ok(ColorMetric::Ciede2000),
+            "oklab" => Ok(ColorMetric::Oklab),
+            _ => Err(format!(
+                "Unknown color metric {}, expected rgb, ciede2000 or oklab",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ColorMetric::Rgb => "rgb",
+            ColorMetric::Ciede2000 => "ciede2000",
+            ColorMetric::Oklab => "oklab",
+        })
+    }
+}
+
+/// CIEDE2000 delta E between two L*a*b* colors, after Sharma, Wu and Dalal
+fn ciede2000(lab1: &[f64; 3], lab2: &[f64; 3]) -> f64 {
+    let ([l1, a1, b1], [l2, a2, b2]) = (*lab1, *lab2);
+    let pow25_7 = 25f64.powi(7);
+    let c_mean = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + pow25_7)).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f64, b: f64| match a == 0.0 && b == 0.0 {
+        true => 0.0,
+        false => b.atan2(a).to_degrees().rem_euclid(360.0),
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+    let chromatic = c1 * c2 != 0.0;
+
+    let dh = match h2 - h1 {
+        _ if !chromatic => 0.0,
+        d if d > 180.0 => d - 360.0,
+        d if d < -180.0 => d + 360.0,
+        d => d,
+    };
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_mean = (l1 + l2) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = match (h1 + h2, (h1 - h2).abs()) {
+        (sum, _) if !chromatic => sum,
+        (sum, d) if d <= 180.0 => sum / 2.0,
+        (sum, _) if sum < 360.0 => (sum + 360.0) / 2.0,
+        (sum, _) => (sum - 360.0) / 2.0,
+    };
+    let cos = |degrees: f64| degrees.to_radians().cos();
+    let t =
+        1.0 - 0.17 * cos(h_mean - 30.0) + 0.24 * cos(2.0 * h_mean) + 0.32 * cos(3.0 * h_mean + 6.0)
+            - 0.20 * cos(4.0 * h_mean - 63.0);
+    let theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let rc = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + pow25_7)).sqrt();
+    let sl = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let sc = 1.0 + 0.045 * c_mean;
+    let sh = 1.0 + 0.015 * c_mean * t;
+    let rt = -(2.0 * theta).to_radians().sin() * rc;
+    let (l, c, h) = (dl / sl, dc / sc, dh / sh);
+    (l * l + c * c + h * h + rt * c * h).sqrt()
+}
+
+/// An sRGB sample in linear light, 0 to 1
+fn srgb_to_linear(v: u8) -> f64 {
+    let v = v as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A linear light value back as an sRGB sample
+fn linear_to_srgb(v: f64) -> u8 {
+    let v = v.clamp(0.0, 1.0);
+    let v = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round() as u8
+}
+
+/// How transparency is stored, the BMHD masking
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Masking {
+    None,
+    /// A mask plane, keyed on the transparent color unless the input had one
+    Mask,
+    TransparentColor,
+    Lasso,
+}
+
+impl FromStr for Masking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Masking::None),
+            "mask" => Ok(Masking::Mask),
+            "transparent-color" => Ok(Masking::TransparentColor),
+            "lasso" => Ok(Masking::Lasso),
+            _ => Err(format!(
+                "Invalid masking {:?}. Expected none, mask, transparent-color or lasso",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Masking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Masking::None => f.write_str("none"),
+            Masking::Mask => f.write_str("mask"),
+            Masking::TransparentColor => f.write_str("transparent-color"),
+            Masking::Lasso => f.write_str("lasso"),
+        }
+    }
+}
+
+/// The BMHD chunk, as written
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BitmapHeader {
+    pub width: u16,
+    pub height: u16,
+    pub x: i16,
+    pub y: i16,
+    pub bitplanes: u8,
+    pub masking: u8,
+    pub compression: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) _pad1: u8,
+    pub transparent_color: u16,
+    pub x_aspect: u8,
+    pub y_aspect: u8,
+    pub page_width: u16,
+    pub page_height: u16,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    /// CIE L*a*b* coordinates of the sRGB color, for a D65 white point
+    pub fn to_lab(&self) -> [f64; 3] {
+        let (r, g, b) = (
+            srgb_to_linear(self.r),
+            srgb_to_linear(self.g),
+            srgb_to_linear(self.b),
+        );
+        let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.950_47;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.088_83;
+        let f = |t: f64| {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+    }
+
+    /// Björn Ottosson's OKLab coordinates of the sRGB color
+    pub fn to_oklab(&self) -> [f64; 3] {
+        let (r, g, b) = (
+            srgb_to_linear(self.r),
+            srgb_to_linear(self.g),
+            srgb_to_linear(self.b),
+        );
+        let l = (0.412_221_470_8 * r + 0.536_332_536_3 * g + 0.051_445_992_9 * b).cbrt();
+        let m = (0.211_903_498_2 * r + 0.680_699_545_1 * g + 0.107_396_956_6 * b).cbrt();
+        let s = (0.088_302_461_9 * r + 0.281_718_837_6 * g + 0.629_978_700_5 * b).cbrt();
+        [
+            0.210_454_255_3 * l + 0.793_617_785_0 * m - 0.004_072_046_8 * s,
+            1.977_998_495_1 * l - 2.428_592_205_0 * m + 0.450_593_709_9 * s,
+            0.025_904_037_1 * l + 0.782_771_766_2 * m - 0.808_675_766_0 * s,
+        ]
+    }
+
+    /// The sRGB color closest to OKLab coordinates
+    pub fn from_oklab([lightness, a, b]: [f64; 3]) -> Color {
+        let l = (lightness + 0.396_337_777_4 * a + 0.215_803_757_3 * b).powi(3);
+        let m = (lightness - 0.105_561_345_8 * a - 0.063_854_172_8 * b).powi(3);
+        let s = (lightness - 0.089_484_177_5 * a - 1.291_485_548_0 * b).powi(3);
+        Color {
+            r: linear_to_srgb(4.076_741_662_1 * l - 3.307_711_591_3 * m + 0.230_969_929_2 * s),
+            g: linear_to_srgb(-1.268_438_004_6 * l + 2.609_757_401_1 * m - 0.341_319_396_5 * s),
+            b: linear_to_srgb(-0.004_196_086_3 * l - 0.703_418_614_7 * m + 1.707_614_701_0 * s),
+        }
+    }
+
+    /// Hue in degrees from red, None for grays
+    pub fn hue(&self) -> Option<f64> {
+        let (r, g, b) = (self.r as f64, self.g as f64, self.b as f64);
+        let max = r.max(g).max(b);
+        let range = max - r.min(g).min(b);
+        if range == 0.0 {
+            return None;
+        }
+        let sector = if max == r {
+            (g - b) / range
+        } else if max == g {
+            (b - r) / range + 2.0
+        } else {
+            (r - g) / range + 4.0
+        };
+        Some((sector * 60.0).rem_euclid(360.0))
+    }
+
+    /// Perceptual distance to `other` (CIE76 delta E). Around 2.3 is the
+    /// smallest difference most people notice
+    pub fn delta_e(&self, other: &Color) -> f64 {
+        let (a, b) = (self.to_lab(), other.to_lab());
+        a.iter()
+            .zip(&b)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Perceptual distance to `other` by CIEDE2000, which corrects CIE76
+    /// for blues and saturated colors
+    pub fn delta_e_2000(&self, other: &Color) -> f64 {
+        ciede2000(&self.to_lab(), &other.to_lab())
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid color {:?}. Expected #RRGGBB", s);
+        match s.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => {
+                let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                let [_, r, g, b] = rgb.to_be_bytes();
+                Ok(Color { r, g, b })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The CMAP chunk
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ColorMap {
+    pub colors: Vec<Color>,
+}
+
+impl ColorMap {
+    /// Maps every color to its first palette index, for constant time lookups
+    pub(crate) fn lookup_table(&self) -> HashMap<[u8; 3], u8> {
+        let mut lookup = HashMap::with_capacity(self.colors.len());
+        for (index, color) in self.colors.iter().enumerate() {
+            lookup
+                .entry([color.r, color.g, color.b])
+                .or_insert(index as u8);
+        }
+        lookup
+    }
+
+    /// The index of the closest color by RGB distance, the first one on ties
+    pub fn nearest(&self, rgb: [u8; 3]) -> u8 {
+        let distance = |c: &Color| {
+            let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+            d(c.r, rgb[0]) + d(c.g, rgb[1]) + d(c.b, rgb[2])
+        };
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| distance(c))
+            .map_or(0, |(index, _)| index as u8)
+    }
+
+    /// The index of the closest color by `metric`, the first one on ties
+    pub fn nearest_by(&self, rgb: [u8; 3], metric: ColorMetric) -> u8 {
+        Matcher::new(self, metric).nearest(rgb)
+    }
+
+    /// `levels` grays from black to white, evenly spaced once raised to
+    /// `gamma`: 1.0 gives even steps in value, 2.2 even steps in linear
+    /// light. `white_first` puts white at index 0
+    pub fn gray_curve(levels: usize, gamma: f64, white_first: bool) -> ColorMap {
+        let last = levels.saturating_sub(1).max(1) as f64;
+        let mut colors = (0..levels)
+            .map(|i| {
+                let v = ((i as f64 / last).powf(1.0 / gamma) * 255.0).round() as u8;
+                Color { r: v, g: v, b: v }
+            })
+            .collect::<Vec<_>>();
+        if white_first {
+            colors.reverse();
+        }
+        ColorMap { colors }
+    }
+
+    pub(crate) fn gray_ramp(levels: usize) -> ColorMap {
+        ColorMap {
+            colors: (0..levels)
+                .map(|i| {
+                    let v = (i * 255 / (levels - 1)) as u8;
+                    Color { r: v, g: v, b: v }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Looks up closest palette colors by a metric, with the palette converted
+/// once and every color looked up only once
+pub(crate) struct Matcher<'a> {
+    cmap: &'a ColorMap,
+    metric: ColorMetric,
+    coordinates: Vec<[f64; 3]>,
+    found: HashMap<[u8; 3], u8>,
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(cmap: &'a ColorMap, metric: ColorMetric) -> Matcher<'a> {
+        let coordinates = match metric {
+            // plain RGB goes through ColorMap::nearest
+            ColorMetric::Rgb => vec![],
+            _ => cmap.colors.iter().map(|c| metric.coordinates(c)).collect(),
+        };
+        Matcher {
+            cmap,
+            metric,
+            coordinates,
+            found: HashMap::new(),
+        }
+    }
+
+    pub fn nearest(&mut self, rgb: [u8; 3]) -> u8 {
+        if self.metric == ColorMetric::Rgb {
+            return self.cmap.nearest(rgb);
+        }
+        let (metric, coordinates) = (self.metric, &self.coordinates);
+        *self.found.entry(rgb).or_insert_with(|| {
+            let [r, g, b] = rgb;
+            let wanted = metric.coordinates(&Color { r, g, b });
+            let differences = coordinates.iter().map(|c| metric.difference(c, &wanted));
+            differences
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(0, |(index, _)| index as u8)
+        })
+    }
 }
 
-#[derive(Default)]
-struct BitmapHeader {
-    width: u16,
-    height: u16,
-    x: i16,
-    y: i16,
-    bitplanes: u8,
-    masking: u8,
-    compression: u8,
-    _pad1: u8,
-    transparent_color: u16,
-    x_aspect: u8,
-    y_aspect: u8,
-    page_width: u16,
-    page_height: u16,
+/// Unpacks 1, 2, 4 or 8 bit samples into one byte per sample. Each row of
+/// `line_size` bytes carries `width * samples` samples
+pub(crate) fn unpack_samples(
+    buf: &[u8],
+    line_size: usize,
+    width: usize,
+    bit_depth: u8,
+    samples: usize,
+) -> Vec<u8> {
+    let per_row = width * samples;
+    if bit_depth == 8 {
+        return buf
+            .chunks(line_size)
+            .flat_map(|row| row[..per_row].iter().cloned())
+            .collect();
+    }
+
+    let per_byte = 8 / bit_depth as usize;
+    let mask = (1u8 << bit_depth) - 1;
+    buf.chunks(line_size)
+        .flat_map(|row| {
+            (0..per_row).map(move |i| {
+                let shift = 8 - bit_depth as usize * (i % per_byte + 1);
+                (row[i / per_byte] >> shift) & mask
+            })
+        })
+        .collect()
 }
 
-#[derive(Default)]
-struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
+/// Gathers bit `bpl` of 8 chunky pixels packed into a u64 (leftmost pixel in
+/// the most significant byte) into one planar byte, leftmost pixel in bit 7
+#[inline]
+fn plane_byte(chunky: u64, bpl: usize) -> u8 {
+    (((chunky >> bpl) & 0x0101_0101_0101_0101).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u8
 }
 
-#[derive(Default)]
-struct ColorMap {
-    colors: Vec<Color>,
+/// ByteRun1 packs one row: `n` in 0..=127 copies the next n + 1 bytes
+/// literally, `-n` in -127..=-1 repeats the next byte n + 1 times
+pub(crate) fn byterun1(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push((1 - run as i8 as i16) as u8);
+            out.push(row[i]);
+            i += run;
+            continue;
+        }
+
+        // literal until the next run of at least 2 equal bytes
+        let start = i;
+        while i < row.len() && i - start < 128 && (i + 1 >= row.len() || row[i] != row[i + 1]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&row[start..i]);
+    }
+}
+
+/// Size of the BMHD chunk data
+const BMHD_SIZE: usize = 20;
+
+/// Makes sure every pixel addresses an existing palette entry
+pub(crate) fn check_indices(
+    pixels: &[u8],
+    width: usize,
+    num_colors: usize,
+) -> Result<(), IffConvertError> {
+    match pixels
+        .iter()
+        .position(|&index| index as usize >= num_colors)
+    {
+        Some(i) => Err(IffConvertError::InvalidIndex {
+            index: pixels[i],
+            x: i % width,
+            y: i / width,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// 4x4 Bayer matrix for dithering 16 bit samples
+pub(crate) const BAYER_4X4: [[u16; 4]; 4] =
+    [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Brings a 16 bit sample at `x`,`y` down to 8 bits. Dithering adds an
+/// ordered threshold below one 8 bit step instead of rounding, so smooth
+/// gradients don't band
+pub(crate) fn reduce_sample(value: u16, x: usize, y: usize, dither: bool) -> u8 {
+    let threshold = match dither {
+        true => BAYER_4X4[y % 4][x % 4] * 16 + 8,
+        false => 128,
+    };
+    ((value as u32 + threshold as u32) / 257).min(255) as u8
+}
+
+/// Reads the next PNG frame, returning the samples and the bytes per line.
+/// 16 bit samples come back as 8 bit ones, alpha is never dithered
+fn read_png_frame<R: Read>(
+    reader: &mut png::Reader<R>,
+    info: &png::OutputInfo,
+    dither: bool,
+) -> Result<(Vec<u8>, usize), IffLoadError> {
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+    if info.bit_depth != png::BitDepth::Sixteen {
+        return Ok((buf, info.line_size));
+    }
+
+    let channels = info.color_type.samples();
+    let has_alpha = matches!(info.color_type, ColorType::GrayscaleAlpha | ColorType::RGBA);
+    let mut out = Vec::with_capacity(buf.len() / 2);
+    for (y, line) in buf.chunks(info.line_size).enumerate() {
+        for (i, sample) in line.chunks_exact(2).enumerate() {
+            let alpha = has_alpha && i % channels == channels - 1;
+            let value = u16::from_be_bytes([sample[0], sample[1]]);
+            out.push(reduce_sample(value, i / channels, y, dither && !alpha));
+        }
+    }
+    Ok((out, info.line_size / 2))
+}
+
+/// Checks decoded dimensions against the 16 bit BMHD fields instead of
+/// truncating them
+pub(crate) fn check_size(width: usize, height: usize) -> Result<(u16, u16), IffConvertError> {
+    if width == 0 || height == 0 {
+        return Err(IffConvertError::EmptyImage(width as u32, height as u32));
+    }
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(IffConvertError::TooLarge(width as u32, height as u32)),
+    }
+}
+
+/// Bitplanes needed for `colors` palette entries. Even a single color
+/// takes one plane, a BODY without planes cannot be loaded
+pub(crate) fn bitplanes_for(colors: usize) -> u8 {
+    (usize::BITS - colors.saturating_sub(1).leading_zeros()).max(1) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Png,
+    Gif,
+    Bmp,
+    Aseprite,
+    Tga,
+    Iff,
+}
+
+fn sniff_format(magic: &[u8], path: &Path) -> Option<Format> {
+    if magic.starts_with(b"\x89PNG") {
+        Some(Format::Png)
+    } else if magic.starts_with(b"GIF8") {
+        Some(Format::Gif)
+    } else if magic.starts_with(b"BM") {
+        Some(Format::Bmp)
+    } else if magic.starts_with(b"FORM") {
+        Some(Format::Iff)
+    } else if magic.get(4..6) == Some(&[0xe0, 0xa5]) {
+        Some(Format::Aseprite)
+    } else if has_extension(path, "tga") {
+        // TGA has no magic number at the start of the file
+        Some(Format::Tga)
+    } else {
+        None
+    }
+}
+
+/// File extensions picked up when converting whole directories
+const SUPPORTED_EXTENSIONS: &[&str] = &["png", "gif", "bmp", "tga", "ase", "aseprite"];
+/// Additional extensions decoded through the image crate
+#[cfg(feature = "image")]
+const IMAGE_CRATE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "webp", "tif", "tiff"];
+
+/// Whether a file looks like something we can convert, going by its extension
+pub fn is_supported_file<P: AsRef<Path>>(path: P) -> bool {
+    let supported = SUPPORTED_EXTENSIONS.iter();
+    #[cfg(feature = "image")]
+    let supported = supported.chain(IMAGE_CRATE_EXTENSIONS.iter());
+    supported
+        .into_iter()
+        .any(|extension| has_extension(&path, extension))
+}
+
+fn has_extension<P: AsRef<Path>>(path: P, extension: &str) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(extension))
 }
 
 impl IffImage {
-    pub fn from_png_file<P: AsRef<Path>>(path: P) -> Result<IffImage, IffLoadError> {
-        let decoder = png::Decoder::new(File::open(path)?);
+    /// Decodes a PNG from any reader. Adam7 interlaced images are
+    /// deinterlaced into a full frame before conversion
+    pub fn from_png_reader<R: Read>(r: R) -> Result<IffImage, IffLoadError> {
+        IffImage::from_png(r, &LoadOptions::default())
+    }
+
+    /// Decodes a PNG, with color management converting the palette to sRGB
+    /// as the gAMA and sRGB chunks ask for
+    fn from_png<R: Read>(mut r: R, options: &LoadOptions) -> Result<IffImage, IffLoadError> {
+        let (consumed, color_info) = pngcolor::scan(&mut r)?;
+        let mut decoder = png::Decoder::new(consumed.as_slice().chain(r));
+        // we unpack the raw samples ourselves so sub-byte depths keep their indices
+        decoder.set_transformations(png::Transformations::IDENTITY);
         let (info, mut reader) = decoder.read_info()?;
 
         let frame_info = reader.info();
-        if frame_info.color_type != ColorType::Indexed {
-            return Err(From::from(IffConvertError::WrongColorType(
-                frame_info.color_type,
-            )));
+        // 16 bit samples are brought down to 8 bits when reading the frame
+        let bit_depth = (frame_info.bit_depth as u8).min(8);
+        let depth_reduced = frame_info.bit_depth == png::BitDepth::Sixteen;
+        // refuse before allocating anything for the frame
+        check_size(info.width as usize, info.height as usize)?;
+        let width = info.width as usize;
+        let mut warnings = vec![];
+        let mut alpha = None;
+        let (cmap, pixels) = match frame_info.color_type {
+            ColorType::Indexed | ColorType::RGB | ColorType::RGBA => {
+                // truecolor images may carry a suggested palette we can map onto
+                let palette = match &frame_info.palette {
+                    None => return Err(From::from(IffConvertError::NoPalette)),
+                    Some(palette) => {
+                        if palette.is_empty() {
+                            return Err(From::from(IffConvertError::EmptyPalette));
+                        }
+                        palette
+                    }
+                };
+
+                let num_colors = palette.len() / 3;
+                if num_colors > MAX_COLORS {
+                    return Err(From::from(IffConvertError::TooManyColors(num_colors)));
+                }
+
+                let cmap = ColorMap {
+                    colors: palette
+                        .chunks(3)
+                        .map(|c| Color {
+                            r: c[0],
+                            g: c[1],
+                            b: c[2],
+                        })
+                        .collect::<Vec<_>>(),
+                };
+                let color_type = frame_info.color_type;
+
+                let (buf, line_size) = read_png_frame(&mut reader, &info, options.dither_16bit)?;
+
+                if color_type == ColorType::Indexed {
+                    // the indices are taken as they are so duplicate palette
+                    // entries and deliberate index assignments survive
+                    let pixels = unpack_samples(&buf, line_size, width, bit_depth, 1);
+                    check_indices(&pixels, width, cmap.colors.len())?;
+                    (cmap, pixels)
+                } else {
+                    let samples = color_type.samples();
+                    let rgb = unpack_samples(&buf, line_size, width, bit_depth, samples);
+                    if samples == 4 && rgb.chunks(4).any(|pixel| pixel[3] != 0xff) {
+                        warnings.push(Warning::AlphaIgnored);
+                        alpha = Some(rgb.chunks(4).map(|pixel| pixel[3]).collect());
+                    }
+
+                    let mut lookup = cmap.lookup_table();
+                    let mut matcher = Matcher::new(&cmap, options.color_metric);
+                    let mut remapped = 0;
+                    let colors = lookup.len();
+                    let pixels = rgb
+                        .chunks(samples)
+                        .map(|pixel| {
+                            let rgb = [pixel[0], pixel[1], pixel[2]];
+                            match (lookup.get(&rgb), options.remap) {
+                                (Some(&index), _) => Some(index),
+                                (None, Remap::Nearest) => {
+                                    remapped += 1;
+                                    Some(*lookup.entry(rgb).or_insert_with(|| matcher.nearest(rgb)))
+                                }
+                                (None, Remap::Exact) => None,
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    if pixels.contains(&None) {
+                        // all of them, so they can be fixed in one go
+                        let report = PixelReport::new(
+                            width,
+                            info.height as usize,
+                            rgb.chunks(samples).map(|p| [p[0], p[1], p[2]]).collect(),
+                            pixels.iter().map(Option::is_none).collect(),
+                        );
+                        return Err(From::from(IffConvertError::UnmappedPixels(Box::new(
+                            report,
+                        ))));
+                    }
+                    let pixels = pixels.into_iter().flatten().collect::<Vec<_>>();
+                    if remapped > 0 {
+                        warnings.push(Warning::Remapped {
+                            pixels: remapped,
+                            colors: lookup.len() - colors,
+                        });
+                    }
+                    (cmap, pixels)
+                }
+            }
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => {
+                // one palette entry per representable gray level. alpha is ignored
+                let levels = 1usize << bit_depth;
+                let samples = if frame_info.color_type == ColorType::GrayscaleAlpha {
+                    2
+                } else {
+                    1
+                };
+                let cmap = ColorMap::gray_ramp(levels);
+
+                let (buf, line_size) = read_png_frame(&mut reader, &info, options.dither_16bit)?;
+
+                // the raw gray level is the index into the ramp
+                let samples = unpack_samples(&buf, line_size, width, bit_depth, samples)
+                    .chunks(samples)
+                    .map(|pixel| (pixel[0], pixel.get(1).copied()))
+                    .collect::<Vec<_>>();
+                if samples
+                    .iter()
+                    .any(|(_, alpha)| alpha.is_some_and(|a| a != 0xff))
+                {
+                    warnings.push(Warning::AlphaIgnored);
+                    alpha = Some(samples.iter().map(|(_, a)| a.unwrap_or(0xff)).collect());
+                }
+                let pixels = samples.iter().map(|(gray, _)| *gray).collect::<Vec<_>>();
+                (cmap, pixels)
+            }
+        };
+
+        let mut cmap = cmap;
+        if options.color_management {
+            if let Some(table) = color_info.srgb_table() {
+                for color in &mut cmap.colors {
+                    color.r = table[color.r as usize];
+                    color.g = table[color.g as usize];
+                    color.b = table[color.b as usize];
+                }
+                warnings.push(Warning::GammaCorrected {
+                    gamma: color_info.gamma.unwrap_or_default(),
+                });
+            }
+            if color_info.icc && !color_info.srgb {
+                warnings.push(Warning::IccProfileIgnored);
+            }
+        }
+
+        if depth_reduced {
+            warnings.push(Warning::DepthReduced);
         }
 
-        let palette = match &frame_info.palette {
-            None => return Err(From::from(IffConvertError::NoPalette)),
-            Some(palette) => {
-                if palette.len() == 0 {
-                    return Err(From::from(IffConvertError::EmptyPalette));
+        let (width, height) = check_size(width, info.height as usize)?;
+        let mut image = IffImage::from_parts(width, height, cmap, pixels);
+        image.warnings = warnings;
+        image.alpha = alpha;
+        Ok(image)
+    }
+
+    /// Sniffs the file contents and picks the matching decoder
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<IffImage, IffLoadError> {
+        options.cancel.check()?;
+        let file_error = |source| IffLoadError::File {
+            path: path.as_ref().to_path_buf(),
+            source,
+        };
+        let mut file = File::open(&path).map_err(file_error)?;
+        let mut magic = [0u8; 8];
+        let len = file.read(&mut magic).map_err(file_error)?;
+        file.seek(SeekFrom::Start(0)).map_err(file_error)?;
+
+        let format = sniff_format(&magic[..len], path.as_ref());
+        debug!("{}: detected format {:?}", path.as_ref().display(), format);
+        let result = match format {
+            None => Err(From::from(IffConvertError::UnsupportedFormat)),
+            #[cfg(feature = "mmap")]
+            Some(format) if options.mmap => {
+                // Safety: the mapping is only read while decoding. Like every
+                // mmap user we rely on nobody truncating the file meanwhile
+                let data = unsafe { Mmap::map(&file).map_err(file_error)? };
+                IffImage::from_format_bytes(&data, format, options)
+            }
+            Some(format) => IffImage::from_format_reader(BufReader::new(file), format, options),
+        };
+
+        // truecolor pictures without a palette get quantized instead
+        #[cfg(feature = "image")]
+        let result = match result {
+            Err(IffLoadError::Convert(
+                IffConvertError::UnsupportedFormat | IffConvertError::NoPalette,
+            )) => {
+                debug!(
+                    "{}: falling back to the image crate",
+                    path.as_ref().display()
+                );
+                options.cancel.check()?;
+                let opened = image::open(&path)?;
+                let color = opened.color();
+                let depth_reduced = color.bytes_per_pixel() > color.channel_count();
+                let mut decoded = imageinput::reduce_16bit(opened, options.dither_16bit);
+                let mut color_info = pngcolor::ColorInfo::default();
+                if options.color_management && format == Some(Format::Png) {
+                    let mut file = File::open(&path).map_err(file_error)?;
+                    color_info = pngcolor::scan(&mut file).map_err(file_error)?.1;
+                }
+                let table = color_info.srgb_table();
+                if let Some(table) = table {
+                    let mut rgba = decoded.to_rgba8();
+                    for pixel in rgba.pixels_mut() {
+                        for sample in &mut pixel.0[..3] {
+                            *sample = table[*sample as usize];
+                        }
+                    }
+                    decoded = image::DynamicImage::ImageRgba8(rgba);
                 }
-                palette
+                IffImage::from_dynamic_image(
+                    decoded,
+                    options.max_colors,
+                    options.palette_order,
+                    options.color_metric,
+                    &options.cancel,
+                )
+                .map(|mut image| {
+                    if depth_reduced {
+                        image.warnings.push(Warning::DepthReduced);
+                    }
+                    if table.is_some() {
+                        image.warnings.push(Warning::GammaCorrected {
+                            gamma: color_info.gamma.unwrap_or_default(),
+                        });
+                    }
+                    if color_info.icc && !color_info.srgb {
+                        image.warnings.push(Warning::IccProfileIgnored);
+                    }
+                    image
+                })
+                .map_err(From::from)
             }
+            result => result,
+        };
+        let mut image = result?;
+        image.apply_alpha(options.alpha)?;
+        Ok(image)
+    }
+
+    /// Sniffs the contents of an image file already in memory and picks the
+    /// matching decoder. TGA can't be told apart by its contents and is only
+    /// recognized by `from_file`
+    pub fn from_bytes(data: &[u8], options: &LoadOptions) -> Result<IffImage, IffLoadError> {
+        let format = sniff_format(data, Path::new(""));
+        debug!("detected format {:?}", format);
+        let result = match format {
+            None => Err(From::from(IffConvertError::UnsupportedFormat)),
+            Some(format) => IffImage::from_format_bytes(data, format, options),
         };
 
-        // hmmm always RGB?
-        let num_colors = palette.len() / 3;
-        if num_colors > 255 {
-            return Err(From::from(IffConvertError::TooManyColors(num_colors)));
+        #[cfg(feature = "image")]
+        let result = match result {
+            Err(IffLoadError::Convert(
+                IffConvertError::UnsupportedFormat | IffConvertError::NoPalette,
+            )) => {
+                debug!("falling back to the image crate");
+                options.cancel.check()?;
+                IffImage::from_dynamic_image(
+                    image::load_from_memory(data)?,
+                    options.max_colors,
+                    options.palette_order,
+                    options.color_metric,
+                    &options.cancel,
+                )
+                .map_err(From::from)
+            }
+            result => result,
+        };
+        let mut image = result?;
+        image.apply_alpha(options.alpha)?;
+        Ok(image)
+    }
+
+    /// Decodes from memory, which avoids copying for the formats that are
+    /// parsed as a whole
+    fn from_format_bytes(
+        data: &[u8],
+        format: Format,
+        options: &LoadOptions,
+    ) -> Result<IffImage, IffLoadError> {
+        match format {
+            Format::Png => IffImage::from_png(data, options),
+            Format::Gif => IffImage::from_gif_reader(data),
+            Format::Bmp => IffImage::from_bmp_bytes(data),
+            Format::Aseprite => {
+                IffImage::from_ase_bytes(data, options.frame, options.layer.as_deref())
+            }
+            Format::Tga => IffImage::from_tga_bytes(data),
+            Format::Iff => IffImage::from_iff_bytes(data),
         }
+    }
 
-        let bitplanes = (num_colors as f32).log2().ceil() as u8;
-        let cmap = ColorMap {
-            colors: palette
-                .chunks(3)
-                .map(|c| Color {
-                    r: c[0],
-                    g: c[1],
-                    b: c[2],
+    fn from_format_reader<R: Read>(
+        r: R,
+        format: Format,
+        options: &LoadOptions,
+    ) -> Result<IffImage, IffLoadError> {
+        match format {
+            Format::Png => IffImage::from_png(r, options),
+            Format::Gif => IffImage::from_gif_reader(r),
+            Format::Bmp => IffImage::from_bmp_reader(r),
+            Format::Aseprite => {
+                IffImage::from_ase_reader(r, options.frame, options.layer.as_deref())
+            }
+            Format::Tga => IffImage::from_tga_reader(r),
+            Format::Iff => IffImage::from_iff_reader(r),
+        }
+    }
+
+    pub(crate) fn from_parts(width: u16, height: u16, cmap: ColorMap, pixels: Vec<u8>) -> IffImage {
+        let bitplanes = bitplanes_for(cmap.colors.len());
+
+        IffImage {
+            bmhd: BitmapHeader {
+                width,
+                height,
+                bitplanes,
+                page_width: width,
+                page_height: height,
+                ..Default::default()
+            },
+            cmap,
+            pixels,
+            camg: None,
+            omit_cmap: false,
+            extra_chunks: vec![],
+            mask: None,
+            alpha: None,
+            warnings: vec![],
+        }
+    }
+
+    /// Sets the mask plane, one byte per pixel with 0 for see-through
+    /// pixels, and the BMHD masking to match. `None` removes the mask
+    pub fn set_mask(&mut self, mask: Option<Vec<u8>>) -> Result<(), IffConvertError> {
+        match &mask {
+            Some(mask) if mask.len() != self.pixels.len() => {
+                return Err(IffConvertError::Malformed(
+                    "mask size differs from the image",
+                ))
+            }
+            Some(_) => self.bmhd.masking = MASK_HAS_MASK,
+            None if self.bmhd.masking == MASK_HAS_MASK => self.bmhd.masking = MASK_NONE,
+            None => {}
+        }
+        self.mask = mask;
+        Ok(())
+    }
+
+    pub fn mask(&self) -> Option<&[u8]> {
+        self.mask.as_deref()
+    }
+
+    /// Selects the BMHD masking. A mask plane is keyed on the transparent
+    /// color when there is none yet, the other techniques drop it
+    pub fn set_masking(&mut self, masking: Masking) {
+        if masking != Masking::Mask {
+            self.mask = None;
+        }
+        self.bmhd.masking = match masking {
+            Masking::None => MASK_NONE,
+            Masking::Mask => {
+                if self.mask.is_none() {
+                    let transparent = self.bmhd.transparent_color;
+                    let mask = self
+                        .pixels
+                        .iter()
+                        .map(|&p| (p as u16 != transparent) as u8)
+                        .collect();
+                    self.mask = Some(mask);
+                }
+                MASK_HAS_MASK
+            }
+            Masking::TransparentColor => MASK_HAS_TRANSPARENT_COLOR,
+            Masking::Lasso => MASK_LASSO,
+        };
+    }
+
+    /// Builds the mask plane by keying on `key`: pixels of that color, by any
+    /// palette entry holding it, become see-through. The first such entry
+    /// is also made the transparent color
+    pub fn set_mask_from_color(&mut self, key: KeyColor) -> Result<(), IffConvertError> {
+        let keyed = self
+            .cmap
+            .colors
+            .iter()
+            .enumerate()
+            .map(|(index, c)| match key {
+                KeyColor::Rgb(rgb) => rgb == [c.r, c.g, c.b],
+                KeyColor::Index(key) => index == key as usize,
+            })
+            .collect::<Vec<_>>();
+        let first = keyed
+            .iter()
+            .position(|&keyed| keyed)
+            .ok_or(IffConvertError::KeyColorNotInPalette(key))?;
+        let mask = self
+            .pixels
+            .iter()
+            .map(|&p| !keyed.get(p as usize).copied().unwrap_or(false) as u8)
+            .collect();
+        self.bmhd.transparent_color = first as u16;
+        self.set_mask(Some(mask))
+    }
+
+    /// The palette index most common along the border of the image, taken
+    /// to be the background. Ties go to the lower index
+    pub fn background_index(&self) -> u8 {
+        let (width, height) = (self.bmhd.width as usize, self.bmhd.height as usize);
+        let mut counts = [0usize; 256];
+        for y in 0..height {
+            let row = &self.pixels[y * width..(y + 1) * width];
+            if y == 0 || y == height - 1 {
+                row.iter().for_each(|&p| counts[p as usize] += 1);
+            } else if let (Some(&first), Some(&last)) = (row.first(), row.last()) {
+                counts[first as usize] += 1;
+                if width > 1 {
+                    counts[last as usize] += 1;
+                }
+            }
+        }
+        // max_by_key keeps the last maximum, so look from the top down
+        (0..=255u8)
+            .rev()
+            .max_by_key(|&index| counts[index as usize])
+            .unwrap_or(0)
+    }
+
+    /// Makes palette entry `index` see-through, keeping a mask plane keyed
+    /// on the old transparent color in sync
+    pub fn set_transparent_color(&mut self, index: u8) {
+        self.bmhd.transparent_color = index as u16;
+        match self.masking() {
+            Masking::Mask => {
+                self.mask = None;
+                self.set_masking(Masking::Mask);
+            }
+            Masking::None => self.bmhd.masking = MASK_HAS_TRANSPARENT_COLOR,
+            Masking::TransparentColor | Masking::Lasso => {}
+        }
+    }
+
+    /// Turns the alpha channel the decoder found, if any, into a mask plane.
+    /// With `AlphaMode::Ignore` all pixels stay opaque
+    pub fn apply_alpha(&mut self, mode: AlphaMode) -> Result<(), IffConvertError> {
+        let alpha = match (&self.alpha, mode) {
+            (None, _) | (_, AlphaMode::Ignore) => return Ok(()),
+            (Some(alpha), _) => alpha,
+        };
+        let threshold = match mode {
+            AlphaMode::Threshold(threshold) => threshold,
+            _ => {
+                let width = self.bmhd.width as usize;
+                if let Some(i) = alpha.iter().position(|&a| a != 0 && a != 0xff) {
+                    return Err(IffConvertError::PartialAlpha {
+                        alpha: alpha[i],
+                        x: i % width,
+                        y: i / width,
+                    });
+                }
+                0xff
+            }
+        };
+        let mask = alpha.iter().map(|&a| (a >= threshold) as u8).collect();
+        self.set_mask(Some(mask))?;
+        self.alpha = None;
+        self.warnings.retain(|w| *w != Warning::AlphaIgnored);
+        Ok(())
+    }
+
+    /// The BMHD masking. Unknown values count as none
+    pub fn masking(&self) -> Masking {
+        match self.bmhd.masking {
+            MASK_HAS_MASK => Masking::Mask,
+            MASK_HAS_TRANSPARENT_COLOR => Masking::TransparentColor,
+            MASK_LASSO => Masking::Lasso,
+            _ => Masking::None,
+        }
+    }
+
+    /// Selects the BODY compression. `Auto` packs the BODY once with
+    /// ByteRun1, only counting its size, and keeps it uncompressed unless
+    /// that is smaller
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.bmhd.compression = match compression {
+            Compression::None => COMPRESSION_NONE,
+            Compression::ByteRun1 => COMPRESSION_BYTERUN1,
+            Compression::Vdat => COMPRESSION_VDAT,
+            Compression::Auto => {
+                self.bmhd.compression = COMPRESSION_BYTERUN1;
+                if self.body_size() < self.raw_body_size() {
+                    COMPRESSION_BYTERUN1
+                } else {
+                    COMPRESSION_NONE
+                }
+            }
+        };
+    }
+
+    /// The compression of the BODY as written, never `Auto`
+    pub fn compression(&self) -> Compression {
+        match self.bmhd.compression {
+            COMPRESSION_BYTERUN1 => Compression::ByteRun1,
+            COMPRESSION_VDAT => Compression::Vdat,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.bmhd.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.bmhd.height
+    }
+
+    pub fn bitplanes(&self) -> u8 {
+        self.bmhd.bitplanes
+    }
+
+    pub fn header(&self) -> &BitmapHeader {
+        &self.bmhd
+    }
+
+    /// The Amiga display mode written as CAMG chunk, if any
+    pub fn camg(&self) -> Option<u32> {
+        self.camg
+    }
+
+    /// Everything noteworthy that happened while loading and transforming
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn palette(&self) -> &ColorMap {
+        &self.cmap
+    }
+
+    pub fn num_colors(&self) -> usize {
+        self.cmap.colors.len()
+    }
+
+    /// Re-expresses the image in terms of `cmap`. Pixels keep their index
+    /// where both palettes agree on the color, others take the first entry
+    /// with the same color. Colors missing from `cmap` are an error
+    pub(crate) fn remap_to(&self, cmap: &ColorMap) -> Result<IffImage, IffConvertError> {
+        let lookup = cmap.lookup_table();
+        let table = self
+            .cmap
+            .colors
+            .iter()
+            .enumerate()
+            .map(|(index, color)| {
+                let rgb = [color.r, color.g, color.b];
+                if cmap.colors.get(index) == Some(color) {
+                    Ok(index as u8)
+                } else {
+                    lookup.get(&rgb).copied().ok_or(rgb)
+                }
+            })
+            .collect::<Vec<_>>();
+        let width = self.bmhd.width as usize;
+        let pixels = self
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &index)| {
+                table[index as usize].map_err(|color| IffConvertError::InvalidPixel {
+                    color,
+                    x: i % width,
+                    y: i / width,
                 })
-                .collect::<Vec<_>>(),
-        };
-
-        // Allocate the output buffer.
-        let mut buf = vec![0; info.buffer_size()];
-        reader.next_frame(&mut buf)?;
-
-        // hmm this nested result is really suboptimal...need an early return
-        let pixels =
-            buf.chunks(3).map(|pixel| {
-                match cmap.colors.iter().position(|color| {
-                    pixel[0] == color.r && pixel[1] == color.g && pixel[2] == color.b
-                }) {
-                    None => {
-                        return Err(IffConvertError::InvalidPixel([
-                            pixel[0], pixel[1], pixel[2],
-                        ]))
-                    }
-                    Some(index) => Ok(index as u8),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.with_pixels(cmap, pixels))
+    }
+
+    /// The image with other pixels in another palette, keeping everything
+    /// else
+    pub(crate) fn with_pixels(&self, cmap: &ColorMap, pixels: Vec<u8>) -> IffImage {
+        let mut image =
+            IffImage::from_parts(self.bmhd.width, self.bmhd.height, cmap.clone(), pixels);
+        // position, aspect and page size stay, the palette may need other planes
+        image.bmhd = BitmapHeader {
+            bitplanes: image.bmhd.bitplanes,
+            ..self.bmhd.clone()
+        };
+        image.camg = self.camg;
+        image.omit_cmap = self.omit_cmap;
+        image.extra_chunks = self.extra_chunks.clone();
+        image.mask = self.mask.clone();
+        image.warnings = self.warnings.clone();
+        image
+    }
+
+    /// Maps the image onto the colors of `cmap`, spreading the difference to
+    /// the nearest color over the neighbouring pixels (Floyd-Steinberg)
+    pub fn dither_to(&self, cmap: &ColorMap) -> IffImage {
+        self.dither_with(cmap, &Dither::default())
+    }
+
+    /// Number of pixels using each palette entry
+    pub fn histogram(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.cmap.colors.len().max(256)];
+        for &index in &self.pixels {
+            counts[index as usize] += 1;
+        }
+        counts.truncate(self.cmap.colors.len());
+        counts
+    }
+
+    /// Whether every palette entry is a gray, as for grayscale inputs
+    pub fn is_grayscale(&self) -> bool {
+        self.cmap.colors.iter().all(|c| c.r == c.g && c.g == c.b)
+    }
+
+    /// Maps every pixel to the closest color of `cmap`, unlike `remap_to`
+    /// accepting colors `cmap` lacks
+    pub fn remap_nearest(&self, cmap: &ColorMap) -> IffImage {
+        self.remap_nearest_by(cmap, ColorMetric::Rgb)
+    }
+
+    /// `remap_nearest` with the closest colors by `metric`
+    pub fn remap_nearest_by(&self, cmap: &ColorMap, metric: ColorMetric) -> IffImage {
+        let mut matcher = Matcher::new(cmap, metric);
+        let table = self
+            .cmap
+            .colors
+            .iter()
+            .map(|c| matcher.nearest([c.r, c.g, c.b]))
+            .collect::<Vec<_>>();
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        let mut image = self.with_pixels(cmap, pixels);
+        if let Some(&transparent) = table.get(self.bmhd.transparent_color as usize) {
+            image.bmhd.transparent_color = transparent as u16;
+        }
+        image
+    }
+
+    /// The image as a single bitplane with a 2 color CMAP if it is a
+    /// grayscale picture using at most two grays, like a scanned page or a
+    /// stencil saved with a full gray ramp. None if it isn't, or if it
+    /// already is that small. Masked images are left alone so the
+    /// transparent color keeps its meaning
+    pub fn bilevel(&self) -> Option<IffImage> {
+        if self.cmap.colors.len() <= 2 || self.masking() != Masking::None || !self.is_grayscale() {
+            return None;
+        }
+        let mut used = [false; 256];
+        for &index in &self.pixels {
+            used[index as usize] = true;
+        }
+        let mut colors: Vec<Color> = vec![];
+        let mut table = [0u8; 256];
+        for (index, color) in self.cmap.colors.iter().enumerate() {
+            if !used[index] {
+                continue;
+            }
+            table[index] = match colors.iter().position(|c| c == color) {
+                Some(position) => position as u8,
+                None if colors.len() < 2 => {
+                    colors.push(color.clone());
+                    colors.len() as u8 - 1
+                }
+                None => return None,
+            };
+        }
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        Some(self.with_pixels(&ColorMap { colors }, pixels))
+    }
+
+    /// Folds palette entries less than `distance` (delta E) away from an
+    /// earlier entry into that one, e.g. colors an editor's scaling or
+    /// antialiasing made up. The remaining entries keep their order. A
+    /// masked image's transparent color is never merged
+    pub fn merge_colors(&self, distance: f64) -> IffImage {
+        let transparent = match self.masking() {
+            Masking::None => None,
+            _ => Some(self.bmhd.transparent_color as usize),
+        };
+        let labs = self
+            .cmap
+            .colors
+            .iter()
+            .map(Color::to_lab)
+            .collect::<Vec<_>>();
+        let close = |a: usize, b: usize| {
+            let d = labs[a].iter().zip(&labs[b]).map(|(a, b)| (a - b).powi(2));
+            d.sum::<f64>().sqrt() < distance
+        };
+
+        let mut kept: Vec<usize> = vec![];
+        let mut table = vec![0u8; labs.len()];
+        for (index, entry) in table.iter_mut().enumerate() {
+            let target = match transparent == Some(index) {
+                true => None,
+                false => kept
+                    .iter()
+                    .position(|&k| transparent != Some(k) && close(k, index)),
+            };
+            *entry = match target {
+                Some(position) => position as u8,
+                None => {
+                    kept.push(index);
+                    kept.len() as u8 - 1
+                }
+            };
+        }
+        let colors = kept.iter().map(|&i| self.cmap.colors[i].clone()).collect();
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        let mut image = self.with_pixels(&ColorMap { colors }, pixels);
+        if let Some(&transparent) = table.get(self.bmhd.transparent_color as usize) {
+            image.bmhd.transparent_color = transparent as u16;
+        }
+        image
+    }
+
+    /// Drops the palette entries no pixel uses, keeping the order of the
+    /// others. The transparent color stays as long as the image is masked
+    pub fn prune_colors(&self) -> IffImage {
+        let mut used = vec![false; self.cmap.colors.len()];
+        for &index in &self.pixels {
+            used[index as usize] = true;
+        }
+        let transparent = self.bmhd.transparent_color as usize;
+        if self.masking() != Masking::None && transparent < used.len() {
+            used[transparent] = true;
+        }
+
+        let mut table = vec![0u8; used.len()];
+        let mut colors = vec![];
+        for (index, color) in self.cmap.colors.iter().enumerate() {
+            if used[index] {
+                table[index] = colors.len() as u8;
+                colors.push(color.clone());
+            }
+        }
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        let mut image = self.with_pixels(&ColorMap { colors }, pixels);
+        if transparent < table.len() {
+            image.bmhd.transparent_color = table[transparent] as u16;
+        }
+        image
+    }
+
+    /// Sorts the palette and remaps the pixels and the transparent color to
+    /// the new indices. Ties keep their old order
+    pub fn sort_palette(&self, sort: PaletteSort) -> IffImage {
+        if sort == PaletteSort::None {
+            return self.clone();
+        }
+        let colors = &self.cmap.colors;
+        let mut counts = vec![0usize; colors.len()];
+        for &p in &self.pixels {
+            counts[p as usize] += 1;
+        }
+        let luminance = |i: usize| colors[i].to_lab()[0];
+        let mut sorted = (0..colors.len()).collect::<Vec<_>>();
+        match sort {
+            PaletteSort::None => {}
+            PaletteSort::Luminance => {
+                sorted.sort_by(|&a, &b| luminance(a).total_cmp(&luminance(b)))
+            }
+            PaletteSort::Hue => sorted.sort_by(|&a, &b| {
+                let hue = |i: usize| colors[i].hue().unwrap_or(-1.0);
+                hue(a)
+                    .total_cmp(&hue(b))
+                    .then(luminance(a).total_cmp(&luminance(b)))
+            }),
+            PaletteSort::Popularity => sorted.sort_by_key(|&i| std::cmp::Reverse(counts[i])),
+        }
+
+        let mut table = vec![0u8; colors.len()];
+        for (new, &old) in sorted.iter().enumerate() {
+            table[old] = new as u8;
+        }
+        let colors = sorted.iter().map(|&i| colors[i].clone()).collect();
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        let mut image = self.with_pixels(&ColorMap { colors }, pixels);
+        if let Some(&transparent) = table.get(self.bmhd.transparent_color as usize) {
+            image.bmhd.transparent_color = transparent as u16;
+        }
+        image
+    }
+
+    /// Moves the colors of `pins` to their indices and the other palette
+    /// entries to the free slots, in their order. Pinned colors the image
+    /// doesn't have are added, gaps filled with black
+    pub fn pin_colors(&self, pins: &[(Color, u8)]) -> Result<IffImage, IffConvertError> {
+        let mut slots: Vec<Option<Color>> = vec![None; 256];
+        for (color, index) in pins {
+            slots[*index as usize] = Some(color.clone());
+        }
+        let mut free = (0..slots.len())
+            .filter(|&i| slots[i].is_none())
+            .collect::<Vec<_>>();
+        free.reverse();
+
+        let pinned = |color: &Color| pins.iter().find(|(pinned, _)| pinned == color);
+        let unpinned = self.cmap.colors.iter().filter(|c| pinned(c).is_none());
+        let needed = pins.len() + unpinned.count();
+
+        let mut table = vec![0u8; self.cmap.colors.len()];
+        for (entry, color) in table.iter_mut().zip(&self.cmap.colors) {
+            *entry = match pinned(color) {
+                Some((_, index)) => *index,
+                None => {
+                    let index = free.pop().ok_or(IffConvertError::TooManyColors(needed))?;
+                    slots[index] = Some(color.clone());
+                    index as u8
+                }
+            };
+        }
+        let len = slots.iter().rposition(Option::is_some).map_or(0, |i| i + 1);
+        let colors = slots[..len]
+            .iter()
+            .map(|slot| slot.clone().unwrap_or_default())
+            .collect();
+        let pixels = self.pixels.iter().map(|&p| table[p as usize]).collect();
+        let mut image = self.with_pixels(&ColorMap { colors }, pixels);
+        if let Some(&transparent) = table.get(self.bmhd.transparent_color as usize) {
+            image.bmhd.transparent_color = transparent as u16;
+        }
+        Ok(image)
+    }
+
+    /// Lowers the bitplanes to what the highest palette index in use needs,
+    /// so a 256 color palette of which only 7 are used takes 3 planes. A
+    /// transparent color the masking refers to counts as used
+    pub fn minimize_bitplanes(&mut self) {
+        let mut used = self
+            .pixels
+            .iter()
+            .max()
+            .map_or(1, |&index| index as usize + 1);
+        if matches!(self.bmhd.masking, MASK_HAS_TRANSPARENT_COLOR | MASK_LASSO) {
+            used = used.max(self.bmhd.transparent_color as usize + 1);
+        }
+        self.bmhd.bitplanes = bitplanes_for(used).min(self.bmhd.bitplanes);
+    }
+
+    /// Whether the written FORM gets a CMAP. Without it the file only keeps
+    /// the pixels, the palette is up to the program showing them
+    pub fn set_cmap_chunk(&mut self, write: bool) {
+        self.omit_cmap = !write;
+    }
+
+    /// Adds a chunk the converter knows nothing about, e.g. engine specific
+    /// metadata, written in the order added
+    pub fn add_chunk(&mut self, id: [u8; 4], data: Vec<u8>, placement: ChunkPlacement) {
+        self.extra_chunks.push((id, data, placement));
+    }
+
+    fn extra_chunks(&self, placement: ChunkPlacement) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+        self.extra_chunks
+            .iter()
+            .filter(move |(_, _, p)| *p == placement)
+            .map(|(id, data, _)| (id, data.as_slice()))
+    }
+
+    /// Sets or removes the display mode written as CAMG chunk
+    pub fn set_camg(&mut self, camg: Option<u32>) {
+        self.camg = camg;
+    }
+
+    /// Makes the CMAP exactly as long as the bitplanes address, adding
+    /// `fill` entries or dropping ones no pixel can reach. Otherwise the
+    /// whole palette is written, whatever its length
+    pub fn pad_cmap(&mut self, fill: &Color) {
+        let len = 1 << self.bmhd.bitplanes;
+        self.cmap.colors.resize(len, fill.clone());
+    }
+
+    pub fn crop(
+        &self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<IffImage, IffConvertError> {
+        if width == 0
+            || height == 0
+            || x as u32 + width as u32 > self.bmhd.width as u32
+            || y as u32 + height as u32 > self.bmhd.height as u32
+        {
+            return Err(IffConvertError::CropOutOfBounds);
+        }
+
+        let crop = |samples: &[u8]| {
+            samples
+                .chunks(self.bmhd.width as usize)
+                .skip(y as usize)
+                .take(height as usize)
+                .flat_map(|row| row[x as usize..(x + width) as usize].iter().cloned())
+                .collect::<Vec<_>>()
+        };
+
+        Ok(IffImage {
+            bmhd: BitmapHeader {
+                width,
+                height,
+                page_width: width,
+                page_height: height,
+                ..self.bmhd.clone()
+            },
+            cmap: self.cmap.clone(),
+            pixels: crop(&self.pixels),
+            camg: self.camg,
+            omit_cmap: self.omit_cmap,
+            extra_chunks: self.extra_chunks.clone(),
+            mask: self.mask.as_deref().map(crop),
+            alpha: self.alpha.as_deref().map(crop),
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    /// Nearest-neighbor resize. Never introduces new colors.
+    pub fn resize(&self, width: u16, height: u16) -> Result<IffImage, IffConvertError> {
+        if width == 0 || height == 0 {
+            return Err(IffConvertError::InvalidSize(width as u32, height as u32));
+        }
+
+        let (src_width, src_height) = (self.bmhd.width as usize, self.bmhd.height as usize);
+        let resize = |samples: &[u8]| {
+            let mut resized = Vec::with_capacity(width as usize * height as usize);
+            for y in 0..height as usize {
+                let src_y = y * src_height / height as usize;
+                for x in 0..width as usize {
+                    let src_x = x * src_width / width as usize;
+                    resized.push(samples[src_y * src_width + src_x]);
                 }
+            }
+            resized
+        };
+
+        Ok(IffImage {
+            bmhd: BitmapHeader {
+                width,
+                height,
+                page_width: width,
+                page_height: height,
+                ..self.bmhd.clone()
+            },
+            cmap: self.cmap.clone(),
+            pixels: resize(&self.pixels),
+            camg: self.camg,
+            omit_cmap: self.omit_cmap,
+            extra_chunks: self.extra_chunks.clone(),
+            mask: self.mask.as_deref().map(resize),
+            alpha: self.alpha.as_deref().map(resize),
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    /// Grows the image to `width` x `height` by adding columns on the right
+    /// and rows at the bottom filled with palette entry `fill`. Padding is
+    /// see-through in the mask
+    pub fn pad(&self, width: u16, height: u16, fill: u8) -> Result<IffImage, IffConvertError> {
+        if width < self.bmhd.width || height < self.bmhd.height {
+            return Err(IffConvertError::InvalidSize(width as u32, height as u32));
+        }
+        if fill as usize >= self.cmap.colors.len() {
+            return Err(IffConvertError::InvalidIndex {
+                index: fill,
+                x: self.bmhd.width as usize,
+                y: 0,
             });
-        match pixels.clone().find(|p| p.is_err()) {
-            Some(e) => return Err(From::from(e.err().unwrap())),
-            None => (),
         }
 
-        let pixels = pixels.map(|pixel| pixel.unwrap()).collect::<Vec<_>>();
+        let src_width = self.bmhd.width as usize;
+        let pad = |samples: &[u8], fill: u8| {
+            let mut padded = vec![fill; width as usize * height as usize];
+            for (y, row) in samples.chunks(src_width).enumerate() {
+                let start = y * width as usize;
+                padded[start..start + src_width].copy_from_slice(row);
+            }
+            padded
+        };
+
+        Ok(IffImage {
+            bmhd: BitmapHeader {
+                width,
+                height,
+                page_width: width,
+                page_height: height,
+                ..self.bmhd.clone()
+            },
+            cmap: self.cmap.clone(),
+            pixels: pad(&self.pixels, fill),
+            camg: self.camg,
+            omit_cmap: self.omit_cmap,
+            extra_chunks: self.extra_chunks.clone(),
+            mask: self.mask.as_deref().map(|mask| pad(mask, 0)),
+            alpha: self.alpha.as_deref().map(|alpha| pad(alpha, 0)),
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    /// Writes the FORM straight to `writer`. All chunk sizes are known up
+    /// front so nothing besides the BODY has to be buffered. The same image
+    /// always gives the same bytes: chunks come in a fixed order, nothing
+    /// depends on time or hashing, and parallel packing keeps scanline order
+    pub fn write(&self, writer: &mut dyn Write) -> IoResult<()> {
+        self.write_with_progress(writer, &mut |_| {}, &CancelToken::default())
+    }
+
+    /// Like `write`, reporting every written scanline to `progress`. Packing
+    /// stops with an `Interrupted` error once `cancel` is cancelled
+    pub fn write_with_progress(
+        &self,
+        writer: &mut dyn Write,
+        progress: &mut dyn FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> IoResult<()> {
+        let cancelled = || IoError::new(ErrorKind::Interrupted, IffConvertError::Cancelled);
+        let bmhd = self.get_bmhd();
+        let cmap = self.get_cmap();
+        // compressed rows are packed twice, once for the size and once for writing
+        let mut body_size = 0;
+        self.for_each_body_row(|row| match cancel.is_cancelled() {
+            true => Err(cancelled()),
+            false => {
+                body_size += row.len();
+                Ok(())
+            }
+        })?;
+        debug!(
+            "packing {} bitplanes of {}x{} into a {} byte BODY ({})",
+            self.bmhd.bitplanes,
+            self.bmhd.width,
+            self.bmhd.height,
+            body_size,
+            self.compression()
+        );
+
+        let form_size = self.form_size(body_size);
+        write_header(writer, b"FORM", form_size)?;
+        writer.write_all(b"ILBM")?;
+        write_chunk(writer, b"BMHD", &bmhd)?;
+        if !self.omit_cmap {
+            write_chunk(writer, b"CMAP", &cmap)?;
+        }
+        if let Some(camg) = self.camg {
+            write_chunk(writer, b"CAMG", &camg.to_be_bytes())?;
+        }
+        for (id, data) in self.extra_chunks(ChunkPlacement::BeforeBody) {
+            write_chunk(writer, id, data)?;
+        }
+        write_header(writer, b"BODY", body_size)?;
+        let total = match self.bmhd.compression {
+            COMPRESSION_VDAT => self.stored_planes(),
+            _ => self.bmhd.height as usize,
+        };
+        let mut done = 0;
+        self.for_each_body_row(|row| {
+            if cancel.is_cancelled() {
+                return Err(cancelled());
+            }
+            writer.write_all(row)?;
+            done += 1;
+            progress(Progress::Rows { done, total });
+            Ok(())
+        })?;
+        if body_size & 1 != 0 {
+            writer.write_all(&[0])?;
+        }
+        for (id, data) in self.extra_chunks(ChunkPlacement::AfterBody) {
+            write_chunk(writer, id, data)?;
+        }
+        Ok(())
+    }
+
+    /// The ILBM as a generic FORM, e.g. to put it into a CAT or LIST
+    pub fn to_form(&self) -> Form {
+        let mut form = Form::new(b"ILBM").chunk(b"BMHD", self.get_bmhd());
+        if !self.omit_cmap {
+            form = form.chunk(b"CMAP", self.get_cmap());
+        }
+        if let Some(camg) = self.camg {
+            form = form.chunk(b"CAMG", camg.to_be_bytes());
+        }
+        for (id, data) in self.extra_chunks(ChunkPlacement::BeforeBody) {
+            form = form.chunk(id, data);
+        }
+        form = form.chunk(b"BODY", self.get_body());
+        for (id, data) in self.extra_chunks(ChunkPlacement::AfterBody) {
+            form = form.chunk(id, data);
+        }
+        form
+    }
 
-        Ok(IffImage {
-            bmhd: BitmapHeader {
-                width: info.width as u16,
-                height: info.height as u16,
-                bitplanes,
-                page_width: info.width as u16,
-                page_height: info.height as u16,
-                ..Default::default()
-            },
-            cmap,
-            pixels,
-            ..Default::default()
-        })
+    /// The complete FORM as written by `write`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write(&mut out)
+            .expect("writing to a Vec only fails beyond 4 GB");
+        out
     }
 
-    pub fn write(&self, writer: &mut dyn Write) -> IoResult<()> {
-        let ilbm = self.get_ilbm();
-        writer.write(b"FORM")?;
-        writer.write(&(ilbm.len() as u32).to_be_bytes())?;
-        writer.write(&ilbm)?;
-        Ok(())
+    /// FORM size for a BODY of `body_size` bytes, excluding the FORM header
+    pub(crate) fn form_size(&self, body_size: usize) -> usize {
+        4 + padded_chunk_size(BMHD_SIZE)
+            + if self.omit_cmap {
+                0
+            } else {
+                padded_chunk_size(self.cmap.colors.len() * 3)
+            }
+            + self.camg.map_or(0, |_| padded_chunk_size(4))
+            + self
+                .extra_chunks
+                .iter()
+                .map(|(_, data, _)| padded_chunk_size(data.len()))
+                .sum::<usize>()
+            + padded_chunk_size(body_size)
     }
 
-    fn get_bmhd(&self) -> Vec<u8> {
+    pub(crate) fn get_bmhd(&self) -> Vec<u8> {
         let mut v = vec![];
         v.extend_from_slice(&self.bmhd.width.to_be_bytes());
         v.extend_from_slice(&self.bmhd.height.to_be_bytes());
@@ -215,6 +2243,8 @@ impl IffImage {
         v
     }
 
+    /// The palette as is, see `pad_cmap` for loaders wanting exactly one
+    /// entry per color the bitplanes address
     fn get_cmap(&self) -> Vec<u8> {
         self.cmap.colors.iter().fold(vec![], |mut v, color| {
             v.push(color.r);
@@ -224,57 +2254,197 @@ impl IffImage {
         })
     }
 
-    fn get_body(&self) -> Vec<u8> {
-        let mut row = vec![0u8; (self.bmhd.width / 8) as usize];
-        let mut v = vec![];
+    /// Splits scanline `y` into its uncompressed bitplane rows, followed by
+    /// the mask row if there is a mask
+    fn fill_planes(&self, y: usize, planes: &mut [u8]) {
+        let width = self.bmhd.width as usize;
+        let row_bytes = self.row_bytes();
+        let bitplanes = self.bmhd.bitplanes as usize;
+        let line = &self.pixels[y * width..(y + 1) * width];
 
-        let mut row_pixel_index = 0;
-        // UFF...SMEEELLLLLSSSS!!!
-        for _y in 0..self.bmhd.height {
-            for bpl in 0..self.bmhd.bitplanes {
-                let mut pixel_index = row_pixel_index;
-                for row_index in 0..self.bmhd.width / 8 {
-                    let mut value = 0u8;
-                    for bit in 0..8 {
-                        // println!(
-                        //     "pixel: {} {} {}",
-                        //     self.pixels[pixel_index],
-                        //     2i32.pow(bpl as u32),
-                        //     ((self.pixels[pixel_index]) & (2 ^ (bpl + 1)))
-                        // );
-                        if ((self.pixels[pixel_index]) & 2i32.pow(bpl as u32) as u8) != 0 {
-                            // 7-bit because of big endian
-                            value |= 1 << (7 - bit);
-                        }
-                        // println!("Value: {:b}", value);
-                        pixel_index += 1;
-                    }
-                    row[row_index as usize] = value;
+        // the padding byte of rows ending on an odd byte stays clear
+        planes.fill(0);
+        // 8 chunky pixels at a time, the leftmost one in the top byte
+        for (byte_index, group) in line.chunks(8).enumerate() {
+            let mut chunky = [0u8; 8];
+            chunky[..group.len()].copy_from_slice(group);
+            let chunky = u64::from_be_bytes(chunky);
+            for (bpl, row) in planes.chunks_mut(row_bytes).take(bitplanes).enumerate() {
+                row[byte_index] = plane_byte(chunky, bpl);
+            }
+        }
+
+        if let Some(mask) = &self.mask {
+            let row = &mut planes[bitplanes * row_bytes..(bitplanes + 1) * row_bytes];
+            for (x, &opaque) in mask[y * width..(y + 1) * width].iter().enumerate() {
+                if opaque != 0 {
+                    row[x / 8] |= 0x80 >> (x % 8);
                 }
-                v.extend_from_slice(&row);
             }
-            row_pixel_index += self.bmhd.width as usize;
         }
-        v
     }
 
-    fn get_ilbm(&self) -> Vec<u8> {
-        let mut v = vec![];
-        v.extend_from_slice(b"ILBM");
-        v.extend_from_slice(b"BMHD");
-        let bmhd = self.get_bmhd();
-        v.extend_from_slice(&(bmhd.len() as u32).to_be_bytes());
-        v.extend_from_slice(&bmhd);
+    /// Packs all bitplane rows of scanline `y` into `out`, compressed if
+    /// requested. `planes` is scratch space for the uncompressed plane rows
+    fn encode_scanline(&self, y: usize, planes: &mut [u8], out: &mut Vec<u8>) {
+        out.clear();
+        let row_bytes = self.row_bytes();
+        if row_bytes == 0 {
+            return;
+        }
+        self.fill_planes(y, planes);
 
-        v.extend_from_slice(b"CMAP");
-        let cmap = self.get_cmap();
-        v.extend_from_slice(&(cmap.len() as u32).to_be_bytes());
-        v.extend_from_slice(&cmap);
+        for row in planes.chunks(row_bytes) {
+            if self.bmhd.compression == COMPRESSION_BYTERUN1 {
+                byterun1(row, out);
+            } else {
+                out.extend_from_slice(row);
+            }
+        }
+    }
+
+    /// Bytes per plane row, which ILBM pads to whole words
+    fn row_bytes(&self) -> usize {
+        (self.bmhd.width as usize).div_ceil(16) * 2
+    }
+
+    /// Rows stored per scanline: the bitplanes and the mask, if any
+    fn stored_planes(&self) -> usize {
+        self.bmhd.bitplanes as usize + self.mask.is_some() as usize
+    }
+
+    /// All stored planes as VDAT columns: words of 16 pixels from the top to
+    /// the bottom line, one column after the other
+    fn vdat_planes(&self) -> Vec<Vec<u16>> {
+        let (row_bytes, height) = (self.row_bytes(), self.bmhd.height as usize);
+        let mut planes = vec![vec![0u16; row_bytes.div_ceil(2) * height]; self.stored_planes()];
+        if row_bytes == 0 {
+            return planes;
+        }
+        let mut rows = vec![0u8; row_bytes * self.stored_planes()];
+        for y in 0..height {
+            self.fill_planes(y, &mut rows);
+            for (plane, row) in planes.iter_mut().zip(rows.chunks(row_bytes)) {
+                for (column, word) in row.chunks(2).enumerate() {
+                    let low = word.get(1).copied().unwrap_or(0);
+                    plane[column * height + y] = u16::from_be_bytes([word[0], low]);
+                }
+            }
+        }
+        planes
+    }
+
+    /// Feeds the BODY to `f`, a scanline at a time or, with VDAT, a VDAT
+    /// chunk per plane
+    fn for_each_body_row<F>(&self, mut f: F) -> IoResult<()>
+    where
+        F: FnMut(&[u8]) -> IoResult<()>,
+    {
+        if self.bmhd.compression != COMPRESSION_VDAT {
+            return self.for_each_scanline(f);
+        }
+        for plane in self.vdat_planes() {
+            let mut chunk = vec![];
+            write_chunk(&mut chunk, b"VDAT", &vdat::pack(&plane))?;
+            f(&chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Feeds the BODY to `f` one scanline at a time so the whole planar
+    /// image never has to be in memory
+    #[cfg(not(feature = "parallel"))]
+    fn for_each_scanline<F>(&self, mut f: F) -> IoResult<()>
+    where
+        F: FnMut(&[u8]) -> IoResult<()>,
+    {
+        let mut planes = vec![0u8; self.row_bytes() * self.stored_planes()];
+        let mut out = vec![];
+        for y in 0..self.bmhd.height as usize {
+            trace!("packing scanline {}", y);
+            self.encode_scanline(y, &mut planes, &mut out);
+            f(&out)?;
+        }
+        Ok(())
+    }
+
+    /// Feeds the BODY to `f` one scanline at a time. Batches of scanlines are
+    /// packed in parallel, which keeps memory bounded by the batch size
+    #[cfg(feature = "parallel")]
+    fn for_each_scanline<F>(&self, mut f: F) -> IoResult<()>
+    where
+        F: FnMut(&[u8]) -> IoResult<()>,
+    {
+        use rayon::prelude::*;
+
+        const BATCH: usize = 256;
+        let height = self.bmhd.height as usize;
+        for start in (0..height).step_by(BATCH) {
+            let scanlines = (start..(start + BATCH).min(height))
+                .into_par_iter()
+                .map_init(
+                    || vec![0u8; self.row_bytes() * self.stored_planes()],
+                    |planes, y| {
+                        let mut out = vec![];
+                        self.encode_scanline(y, planes, &mut out);
+                        out
+                    },
+                )
+                .collect::<Vec<_>>();
+            trace!("packed scanlines {}..{}", start, start + scanlines.len());
+            for scanline in scanlines {
+                f(&scanline)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of the BODY as written, after compression
+    pub fn body_size(&self) -> usize {
+        let mut size = 0;
+        self.for_each_body_row(|row| {
+            size += row.len();
+            Ok(())
+        })
+        .expect("counting never fails");
+        size
+    }
+
+    /// Size of the BODY without compression
+    pub fn raw_body_size(&self) -> usize {
+        self.row_bytes() * self.stored_planes() * self.bmhd.height as usize
+    }
+
+    /// Size of every bitplane over the whole image when packed with
+    /// ByteRun1, whatever the compression is set to, followed by the mask
+    /// plane if there is one. Uncompressed, every plane takes up the same
+    /// share of `raw_body_size()`
+    pub fn packed_plane_sizes(&self) -> Vec<usize> {
+        let row_bytes = self.row_bytes();
+        let mut sizes = vec![0; self.stored_planes()];
+        if row_bytes == 0 {
+            return sizes;
+        }
+        let mut planes = vec![0u8; row_bytes * sizes.len()];
+        let mut out = vec![];
+        for y in 0..self.bmhd.height as usize {
+            self.fill_planes(y, &mut planes);
+            for (size, row) in sizes.iter_mut().zip(planes.chunks(row_bytes)) {
+                out.clear();
+                byterun1(row, &mut out);
+                *size += out.len();
+            }
+        }
+        sizes
+    }
 
-        v.extend_from_slice(b"BODY");
-        let body = self.get_body();
-        v.extend_from_slice(&(body.len() as u32).to_be_bytes());
-        v.extend_from_slice(&body);
+    pub(crate) fn get_body(&self) -> Vec<u8> {
+        let mut v = vec![];
+        self.for_each_body_row(|row| {
+            v.extend_from_slice(row);
+            Ok(())
+        })
+        .expect("writing to a Vec never fails");
         v
     }
 }
@@ -307,8 +2477,7 @@ mod tests {
             ..Default::default()
         };
         let body = image.get_body();
-        assert_eq!(body.len(), 1);
-        assert_eq!(body[0], 0b1010101);
+        assert_eq!(body, vec![0b1010101, 0]);
     }
 
     #[test]
@@ -333,8 +2502,776 @@ mod tests {
             ..Default::default()
         };
         let body = image.get_body();
-        assert_eq!(body.len(), 2);
-        assert_eq!(body[0], 0b01001001);
-        assert_eq!(body[1], 0b00110000);
+        assert_eq!(body, vec![0b01001001, 0, 0b00110000, 0]);
+    }
+
+    #[test]
+    fn rows_are_padded_to_words() {
+        for (width, row_bytes) in [(8, 2), (16, 2), (24, 4)] {
+            let cmap = ColorMap {
+                colors: vec![Color::default(); 4],
+            };
+            let image = IffImage::from_parts(width, 11, cmap, vec![3; width as usize * 11]);
+            assert_eq!(image.row_bytes(), row_bytes);
+            let body = image.get_body();
+            assert_eq!(body.len(), row_bytes * 2 * 11);
+            // the padding byte is clear
+            assert_eq!(body[row_bytes - 1], (width % 16 == 0) as u8 * 0xff);
+        }
+    }
+
+    #[test]
+    fn crop_region() {
+        let image = IffImage {
+            bmhd: BitmapHeader {
+                width: 4,
+                height: 3,
+                bitplanes: 2,
+                ..Default::default()
+            },
+            pixels: vec![0, 1, 2, 3, 1, 2, 3, 0, 2, 3, 0, 1],
+            cmap: ColorMap {
+                colors: vec![Color::default(); 4],
+            },
+            ..Default::default()
+        };
+        let cropped = image.crop(1, 1, 2, 2).unwrap();
+        assert_eq!(cropped.bmhd.width, 2);
+        assert_eq!(cropped.bmhd.height, 2);
+        assert_eq!(cropped.pixels, vec![2, 3, 3, 0]);
+        assert!(image.crop(3, 0, 2, 1).is_err());
+    }
+
+    #[test]
+    fn resize_nearest_neighbor() {
+        let image = IffImage {
+            bmhd: BitmapHeader {
+                width: 4,
+                height: 2,
+                bitplanes: 2,
+                ..Default::default()
+            },
+            pixels: vec![0, 0, 1, 1, 2, 2, 3, 3],
+            cmap: ColorMap {
+                colors: vec![Color::default(); 4],
+            },
+            ..Default::default()
+        };
+        let half = image.resize(2, 1).unwrap();
+        assert_eq!(half.pixels, vec![0, 1]);
+        let double = half.resize(4, 2).unwrap();
+        assert_eq!(double.pixels, vec![0, 0, 1, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn gray_ramp_palette() {
+        let cmap = ColorMap::gray_ramp(4);
+        let values = cmap.colors.iter().map(|c| c.r).collect::<Vec<_>>();
+        assert_eq!(values, vec![0, 0x55, 0xaa, 0xff]);
+    }
+
+    #[test]
+    fn unpack_sub_byte_samples() {
+        // two rows of 3 two-bit samples, each row padded to a full byte
+        let buf = [0b0001_1000, 0b1110_0100];
+        assert_eq!(unpack_samples(&buf, 1, 3, 2, 1), vec![0, 1, 2, 3, 2, 1]);
+        let buf = [0b1010_0000];
+        assert_eq!(unpack_samples(&buf, 1, 3, 1, 1), vec![1, 0, 1]);
+        let buf = [0x12, 0x30];
+        assert_eq!(unpack_samples(&buf, 2, 3, 4, 1), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn interlaced_png() {
+        let interlaced =
+            IffImage::from_png_reader(&include_bytes!("../testdata/interlaced.png")[..]).unwrap();
+        let progressive =
+            IffImage::from_png_reader(&include_bytes!("../testdata/progressive.png")[..]).unwrap();
+        assert_eq!(interlaced.bmhd.width, 13);
+        assert_eq!(interlaced.bmhd.height, 11);
+        assert_eq!(interlaced.pixels, progressive.pixels);
+    }
+
+    #[test]
+    fn duplicate_palette_entries_keep_indices() {
+        // 8 pixels 0..3 twice over a palette where entries 0 and 2 are both black
+        let image =
+            IffImage::from_png_reader(&include_bytes!("../testdata/duplicates.png")[..]).unwrap();
+        assert_eq!(image.pixels, vec![0, 1, 2, 3, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn lookup_table_prefers_first_index() {
+        let cmap = ColorMap {
+            colors: vec![
+                Color { r: 1, g: 2, b: 3 },
+                Color { r: 0, g: 0, b: 0 },
+                Color { r: 1, g: 2, b: 3 },
+            ],
+        };
+        let lookup = cmap.lookup_table();
+        assert_eq!(lookup.len(), 2);
+        assert_eq!(lookup[&[1, 2, 3]], 0);
+        assert_eq!(lookup[&[0, 0, 0]], 1);
+    }
+
+    #[test]
+    fn odd_chunks_are_padded() {
+        let image = IffImage::from_parts(
+            16,
+            1,
+            ColorMap {
+                colors: vec![Color::default(); 3],
+            },
+            vec![0; 16],
+        );
+        let out = image.to_bytes();
+        let form_size = u32::from_be_bytes([out[4], out[5], out[6], out[7]]) as usize;
+        assert_eq!(form_size, out.len() - 8);
+        // BMHD chunk, then CMAP with 9 bytes of colors plus a pad byte
+        let cmap = 12 + 8 + 20;
+        assert_eq!(&out[cmap..cmap + 4], b"CMAP");
+        assert_eq!(&out[cmap + 4..cmap + 8], &9u32.to_be_bytes());
+        assert_eq!(&out[cmap + 8 + 10..cmap + 8 + 14], b"BODY");
+    }
+
+    #[test]
+    fn cmap_can_be_left_out() {
+        let mut image = IffImage::from_parts(16, 1, ColorMap::gray_ramp(2), vec![0; 16]);
+        image.set_camg(Some(0x8000));
+        image.set_cmap_chunk(false);
+        let out = image.to_bytes();
+        let form_size = u32::from_be_bytes([out[4], out[5], out[6], out[7]]) as usize;
+        assert_eq!(form_size, out.len() - 8);
+        assert_eq!(&out[40..44], b"CAMG");
+        assert_eq!(image.to_form().to_bytes(), out);
+
+        image.set_camg(None);
+        let out = image.to_bytes();
+        assert_eq!(out.len(), 12 + 28 + 8 + 2);
+        assert_eq!(&out[40..44], b"BODY");
+
+        image.add_chunk(*b"GAME", vec![1, 2, 3], ChunkPlacement::AfterBody);
+        image.add_chunk(*b"NAME", b"hero".to_vec(), ChunkPlacement::BeforeBody);
+        let out = image.to_bytes();
+        let form_size = u32::from_be_bytes([out[4], out[5], out[6], out[7]]) as usize;
+        assert_eq!(form_size, out.len() - 8);
+        assert_eq!(&out[40..44], b"NAME");
+        assert_eq!(&out[52..56], b"BODY");
+        assert_eq!(&out[62..], b"GAME\0\0\0\x03\x01\x02\x03\0");
+        assert_eq!(image.to_form().to_bytes(), out);
+    }
+
+    #[test]
+    fn byterun1_packing() {
+        let mut out = vec![];
+        byterun1(&[1, 1, 1, 2, 3, 4, 4], &mut out);
+        assert_eq!(out, vec![0xfe, 1, 1, 2, 3, 0xff, 4]);
+
+        out.clear();
+        byterun1(&[7; 130], &mut out);
+        assert_eq!(out, vec![0x81, 7, 0xff, 7]);
+
+        out.clear();
+        let literal = (0..130).map(|i| i as u8).collect::<Vec<_>>();
+        byterun1(&literal, &mut out);
+        assert_eq!(out[0], 127);
+        assert_eq!(out[129], 1);
+        assert_eq!(out.len(), 132);
+    }
+
+    #[test]
+    fn compressed_body_size_matches() {
+        let mut image = IffImage::from_parts(
+            32,
+            2,
+            ColorMap {
+                colors: vec![Color::default(); 4],
+            },
+            vec![1; 64],
+        );
+        image.set_compression(Compression::ByteRun1);
+        let out = image.to_bytes();
+        let form_size = u32::from_be_bytes([out[4], out[5], out[6], out[7]]) as usize;
+        assert_eq!(form_size, out.len() - 8);
+        // each plane row of 4 bytes packs into a single run
+        assert_eq!(image.body_size(), 2 * 2 * 2);
+
+        let mut rows = vec![];
+        image
+            .write_with_progress(
+                &mut vec![],
+                &mut |progress| rows.push(progress),
+                &CancelToken::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                Progress::Rows { done: 1, total: 2 },
+                Progress::Rows { done: 2, total: 2 }
+            ]
+        );
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let error = image
+            .write_with_progress(&mut vec![], &mut |_| {}, &cancel)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn plane_byte_gathers_bits() {
+        let chunky = u64::from_be_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(plane_byte(chunky, 0), 0b0101_0101);
+        assert_eq!(plane_byte(chunky, 1), 0b0011_0011);
+        assert_eq!(plane_byte(chunky, 2), 0b0000_1111);
+        assert_eq!(plane_byte(u64::MAX, 7), 0xff);
+    }
+
+    #[test]
+    fn sniffs_formats() {
+        let path = Path::new("image.tga");
+        assert_eq!(sniff_format(b"\x89PNG\r\n\x1a\n", path), Some(Format::Png));
+        assert_eq!(sniff_format(b"GIF89a", path), Some(Format::Gif));
+        assert_eq!(sniff_format(b"BM", path), Some(Format::Bmp));
+        assert_eq!(
+            sniff_format(&[0, 0, 0, 0, 0xe0, 0xa5], path),
+            Some(Format::Aseprite)
+        );
+        assert_eq!(sniff_format(b"FORM\0\0\0\x04ILBM", path), Some(Format::Iff));
+        assert_eq!(sniff_format(&[0, 1, 9], path), Some(Format::Tga));
+        assert_eq!(sniff_format(&[0, 1, 9], Path::new("image.raw")), None);
+        assert!(is_supported_file("gfx/Sprite.ASE"));
+        let png = include_bytes!("../testdata/progressive.png");
+        let image = IffImage::from_bytes(png, &LoadOptions::default()).unwrap();
+        assert_eq!((image.width(), image.height()), (13, 11));
+        assert!(!is_supported_file("gfx/readme.txt"));
+    }
+
+    #[test]
+    fn output_is_deterministic() {
+        // more scanlines than the parallel packing does in one batch
+        let pixels = (0..64 * 600).map(|i| (i * 7 % 31) as u8).collect();
+        let mut image = IffImage::from_parts(
+            64,
+            600,
+            ColorMap {
+                colors: vec![Color::default(); 32],
+            },
+            pixels,
+        );
+        image.set_compression(Compression::ByteRun1);
+        let mut written = vec![];
+        image.write(&mut written).unwrap();
+        assert_eq!(written, image.to_bytes());
+
+        let mut planes = vec![0; image.row_bytes() * 5];
+        let (mut out, mut serial) = (vec![], vec![]);
+        for y in 0..600 {
+            image.encode_scanline(y, &mut planes, &mut out);
+            serial.extend_from_slice(&out);
+        }
+        assert_eq!(image.get_body(), serial);
+    }
+
+    #[test]
+    fn auto_compression_picks_the_smaller_body() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 2],
+        };
+        let mut flat = IffImage::from_parts(64, 4, colors.clone(), vec![1; 256]);
+        flat.set_compression(Compression::Auto);
+        assert_eq!(flat.compression(), Compression::ByteRun1);
+        assert!(flat.body_size() < flat.raw_body_size());
+
+        // alternating bytes only grow with ByteRun1
+        let noise = (0..256).map(|i| (i / 8 % 2) as u8).collect();
+        let mut noisy = IffImage::from_parts(64, 4, colors, noise);
+        noisy.set_compression(Compression::Auto);
+        assert_eq!(noisy.compression(), Compression::None);
+
+        noisy.set_compression(Compression::ByteRun1);
+        let sizes = noisy.packed_plane_sizes();
+        assert_eq!(sizes.len(), 1);
+        assert_eq!(sizes.iter().sum::<usize>(), noisy.body_size());
+    }
+
+    #[test]
+    fn mask_plane_follows_the_bitplanes() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let pixels = (0..64).map(|i| (i % 4) as u8).collect();
+        let mut image = IffImage::from_parts(16, 4, colors, pixels);
+        let mask = (0..64).map(|i| (i % 16 >= 8) as u8).collect::<Vec<_>>();
+        assert!(image.set_mask(Some(vec![1; 3])).is_err());
+        image.set_mask(Some(mask.clone())).unwrap();
+        assert_eq!(image.bmhd.masking, MASK_HAS_MASK);
+        let body = image.get_body();
+        assert_eq!(body.len(), image.raw_body_size());
+        assert_eq!(&body[4..6], &[0x00, 0xff]);
+
+        image.set_compression(Compression::ByteRun1);
+        let read = IffImage::from_iff_bytes(&image.to_bytes()).unwrap();
+        assert_eq!(read.mask(), Some(mask.as_slice()));
+        assert_eq!(read.pixels, image.pixels);
+        assert_eq!(image.crop(8, 1, 8, 2).unwrap().mask(), Some(&[1; 16][..]));
+
+        image.set_mask(None).unwrap();
+        assert_eq!(image.bmhd.masking, MASK_NONE);
+    }
+
+    #[test]
+    fn masking_modes() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let mut image = IffImage::from_parts(4, 1, colors, vec![0, 1, 2, 3]);
+        image.bmhd.transparent_color = 2;
+        image.set_masking(Masking::Mask);
+        assert_eq!(image.mask(), Some(&[1, 1, 0, 1][..]));
+        assert_eq!(image.masking(), Masking::Mask);
+
+        image.set_masking(Masking::Lasso);
+        assert_eq!(image.mask(), None);
+        assert_eq!(image.get_bmhd()[9], MASK_LASSO);
+        assert_eq!(image.bmhd.transparent_color, 2);
+        image.set_masking(Masking::None);
+        assert_eq!(image.masking(), Masking::None);
+        assert_eq!("transparent-color".parse(), Ok(Masking::TransparentColor));
+    }
+
+    #[test]
+    fn pad_with_fill_index() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let mut image = IffImage::from_parts(2, 2, colors, vec![1, 2, 3, 1]);
+        image.set_mask(Some(vec![1; 4])).unwrap();
+        let padded = image.pad(3, 3, 2).unwrap();
+        assert_eq!(padded.pixels, vec![1, 2, 2, 3, 1, 2, 2, 2, 2]);
+        assert_eq!(padded.mask(), Some(&[1, 1, 0, 1, 1, 0, 0, 0, 0][..]));
+        assert!(image.pad(1, 3, 0).is_err());
+        assert!(image.pad(4, 4, 4).is_err());
+    }
+
+    #[test]
+    fn background_from_the_border() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        #[rustfmt::skip]
+        let pixels = vec![
+            3, 2, 2, 1,
+            2, 1, 1, 2,
+            1, 1, 1, 1,
+        ];
+        let mut image = IffImage::from_parts(4, 3, colors, pixels);
+        assert_eq!(image.background_index(), 1);
+        image.set_transparent_color(image.background_index());
+        assert_eq!(image.masking(), Masking::TransparentColor);
+        assert_eq!(image.bmhd.transparent_color, 1);
+
+        let mut tie = image.crop(0, 0, 2, 1).unwrap();
+        assert_eq!(tie.background_index(), 2);
+        tie.set_masking(Masking::Mask);
+        tie.set_transparent_color(3);
+        assert_eq!(tie.mask(), Some(&[0, 1][..]));
+    }
+
+    #[test]
+    fn mask_from_key_color() {
+        let magenta = Color {
+            r: 255,
+            g: 0,
+            b: 255,
+        };
+        let colors = ColorMap {
+            colors: vec![Color::default(), magenta.clone(), magenta],
+        };
+        let mut image = IffImage::from_parts(4, 1, colors, vec![0, 1, 2, 0]);
+        image
+            .set_mask_from_color("#FF00ff".parse().unwrap())
+            .unwrap();
+        assert_eq!(image.mask(), Some(&[1, 0, 0, 1][..]));
+        assert_eq!(image.bmhd.transparent_color, 1);
+        image.set_mask_from_color("2".parse().unwrap()).unwrap();
+        assert_eq!(image.mask(), Some(&[1, 1, 0, 1][..]));
+
+        assert!(image.set_mask_from_color(KeyColor::Index(7)).is_err());
+        assert!(image.set_mask_from_color(KeyColor::Rgb([1, 2, 3])).is_err());
+        assert!("#12345".parse::<KeyColor>().is_err());
+    }
+
+    #[test]
+    fn dithers_onto_another_palette() {
+        let gray = ColorMap {
+            colors: vec![Color {
+                r: 128,
+                g: 128,
+                b: 128,
+            }],
+        };
+        let bilevel = ColorMap::gray_ramp(2);
+        assert_eq!(bilevel.nearest([200, 90, 200]), 1);
+
+        let image = IffImage::from_parts(8, 8, gray, vec![0; 64]);
+        let dithered = image.dither_to(&bilevel);
+        assert_eq!(dithered.num_colors(), 2);
+        let white = dithered.pixels.iter().filter(|&&p| p == 1).count();
+        assert!((28..=36).contains(&white), "{} white pixels", white);
+        // no two neighbours on a line alike, as with a checkerboard
+        assert!(dithered.pixels[..8].windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn single_color_takes_one_bitplane() {
+        assert_eq!(bitplanes_for(1), 1);
+        assert_eq!(bitplanes_for(2), 1);
+        assert_eq!(bitplanes_for(3), 2);
+        assert_eq!(bitplanes_for(32), 5);
+        assert_eq!(bitplanes_for(256), 8);
+
+        let colors = ColorMap {
+            colors: vec![Color::default()],
+        };
+        let image = IffImage::from_parts(16, 2, colors, vec![0; 32]);
+        assert_eq!(image.bitplanes(), 1);
+        assert_eq!(image.get_body().len(), 2 * 2);
+    }
+
+    #[test]
+    fn bitplanes_follow_the_used_indices() {
+        let colors = (0..=255).map(|i| Color { r: i, g: i, b: i }).collect();
+        let pixels = vec![6, 2, 200, 2];
+        let mut image = IffImage::from_parts(4, 1, ColorMap { colors }, pixels);
+        image.set_transparent_color(9);
+        image.minimize_bitplanes();
+        assert_eq!(image.bitplanes(), 8);
+
+        let mut pruned = image.prune_colors();
+        assert_eq!(pruned.num_colors(), 4);
+        assert_eq!(pruned.pixels, vec![1, 0, 3, 0]);
+        assert_eq!(pruned.bmhd.transparent_color, 2);
+        assert_eq!(pruned.palette().colors[2].r, 9);
+        pruned.minimize_bitplanes();
+        assert_eq!(pruned.bitplanes(), 2);
+
+        image.pixels[2] = 6;
+        image.minimize_bitplanes();
+        // transparent color 9 needs a fourth plane
+        assert_eq!(image.bitplanes(), 4);
+        image.set_masking(Masking::None);
+        image.minimize_bitplanes();
+        assert_eq!(image.bitplanes(), 3);
+        image.pad_cmap(&Color::default());
+        assert_eq!(image.num_colors(), 8);
+        pruned.pad_cmap(&"#ff8000".parse().unwrap());
+        assert_eq!(pruned.num_colors(), 4);
+
+        let mut two = IffImage::from_parts(2, 1, ColorMap::gray_ramp(3), vec![0, 2]);
+        two.pad_cmap(&"#ff8000".parse().unwrap());
+        assert_eq!(two.num_colors(), 4);
+        assert_eq!(
+            two.palette().colors[3],
+            Color {
+                r: 255,
+                g: 128,
+                b: 0
+            }
+        );
+        assert!("ff8000".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn sixteen_bit_png_is_reduced() {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 3, 1);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[0, 0, 0x80, 0x80, 0xff, 0xff])
+            .unwrap();
+        drop(writer);
+
+        let image = IffImage::from_png_reader(&png[..]).unwrap();
+        assert_eq!(image.num_colors(), 256);
+        assert_eq!(image.pixels, vec![0, 128, 255]);
+        assert_eq!(image.warnings(), &[Warning::DepthReduced]);
+        assert!(image.warnings()[0].is_lossy());
+
+        // a dithered flat area averages out to the exact level
+        let value = 100 * 257 + 64;
+        let sum = (0..16)
+            .map(|i| reduce_sample(value, i % 4, i / 4, true) as u32)
+            .sum::<u32>();
+        assert_eq!(sum, 100 * 16 + 4);
+        assert_eq!(reduce_sample(value, 0, 0, false), 100);
+    }
+
+    #[test]
+    fn off_palette_pixels_remapped_to_nearest() {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 3, 1);
+        encoder.set_color(ColorType::RGB);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_chunk(*b"PLTE", &[0, 0, 0, 255, 255, 255])
+            .unwrap();
+        writer
+            .write_image_data(&[0, 0, 0, 250, 250, 250, 2, 1, 0])
+            .unwrap();
+        drop(writer);
+
+        match IffImage::from_png(&png[..], &LoadOptions::default()) {
+            Err(IffLoadError::Convert(IffConvertError::UnmappedPixels(report))) => {
+                assert_eq!((report.count(), report.colors()), (2, 2))
+            }
+            _ => panic!("off palette pixels accepted"),
+        }
+        let options = LoadOptions {
+            remap: Remap::Nearest,
+            ..Default::default()
+        };
+        let image = IffImage::from_png(&png[..], &options).unwrap();
+        assert_eq!(image.pixels, vec![0, 1, 0]);
+        assert_eq!(
+            image.warnings(),
+            &[Warning::Remapped {
+                pixels: 2,
+                colors: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn gray_ramp_with_curve() {
+        let linear = ColorMap::gray_curve(4, 1.0, false);
+        let values = |cmap: &ColorMap| cmap.colors.iter().map(|c| c.r).collect::<Vec<_>>();
+        assert_eq!(values(&linear), vec![0, 85, 170, 255]);
+        assert_eq!(
+            values(&ColorMap::gray_curve(3, 2.2, true)),
+            vec![255, 186, 0]
+        );
+
+        let image = IffImage::from_parts(4, 1, ColorMap::gray_ramp(256), vec![0, 40, 100, 250]);
+        assert!(image.is_grayscale());
+        let four = image.remap_nearest(&linear);
+        assert_eq!(four.pixels, vec![0, 0, 1, 3]);
+        assert_eq!(four.num_colors(), 4);
+    }
+
+    #[test]
+    fn bilevel_grayscale_takes_one_plane() {
+        let scan = IffImage::from_parts(4, 1, ColorMap::gray_ramp(256), vec![255, 0, 0, 255]);
+        let bilevel = scan.bilevel().unwrap();
+        assert_eq!(bilevel.bitplanes(), 1);
+        assert_eq!(bilevel.num_colors(), 2);
+        assert_eq!(bilevel.pixels, vec![1, 0, 0, 1]);
+        assert_eq!(bilevel.palette().colors[1].r, 255);
+
+        let gray = IffImage::from_parts(3, 1, ColorMap::gray_ramp(256), vec![255, 0, 128]);
+        assert!(gray.bilevel().is_none());
+        assert!(bilevel.bilevel().is_none());
+    }
+
+    #[test]
+    fn merges_near_duplicate_colors() {
+        let color = |r, g, b| Color { r, g, b };
+        let colors = vec![
+            color(0, 0, 0),
+            color(200, 40, 40),
+            color(1, 1, 1),
+            color(201, 41, 40),
+            color(0, 0, 255),
+        ];
+        assert!(colors[0].delta_e(&colors[2]) < 1.0);
+        assert!(colors[0].delta_e(&colors[4]) > 50.0);
+
+        let image = IffImage::from_parts(5, 1, ColorMap { colors }, vec![0, 1, 2, 3, 4]);
+        let merged = image.merge_colors(2.0);
+        assert_eq!(merged.num_colors(), 3);
+        assert_eq!(merged.pixels, vec![0, 1, 0, 1, 2]);
+        assert_eq!(image.merge_colors(0.0).num_colors(), 5);
+
+        let mut masked = image.merge_colors(0.0);
+        masked.set_transparent_color(2);
+        let merged = masked.merge_colors(2.0);
+        assert_eq!(merged.num_colors(), 4);
+        assert_eq!(merged.bmhd.transparent_color, 2);
+    }
+
+    #[test]
+    fn sorts_the_palette() {
+        let color = |r, g, b| Color { r, g, b };
+        let colors = vec![
+            color(255, 255, 255),
+            color(0, 0, 255),
+            color(0, 0, 0),
+            color(255, 0, 0),
+        ];
+        let mut image = IffImage::from_parts(5, 1, ColorMap { colors }, vec![0, 1, 2, 3, 3]);
+        image.set_transparent_color(2);
+
+        let by_luminance = image.sort_palette(PaletteSort::Luminance);
+        assert_eq!(by_luminance.cmap.colors[0], color(0, 0, 0));
+        assert_eq!(by_luminance.pixels, vec![3, 1, 0, 2, 2]);
+        assert_eq!(by_luminance.bmhd.transparent_color, 0);
+
+        let by_hue = image.sort_palette(PaletteSort::Hue);
+        assert_eq!(by_hue.pixels, vec![1, 3, 0, 2, 2]);
+        let by_popularity = image.sort_palette(PaletteSort::Popularity);
+        assert_eq!(by_popularity.pixels, vec![1, 2, 3, 0, 0]);
+        assert_eq!(image.sort_palette(PaletteSort::None).pixels, image.pixels);
+
+        // read ILBMs keep their position, aspect and page size
+        image.bmhd.x = 5;
+        image.bmhd.x_aspect = 10;
+        image.bmhd.y_aspect = 11;
+        image.bmhd.page_width = 320;
+        let sorted = image.sort_palette(PaletteSort::Luminance);
+        assert_eq!(sorted.bmhd.x, 5);
+        assert_eq!((sorted.bmhd.x_aspect, sorted.bmhd.y_aspect), (10, 11));
+        assert_eq!(sorted.bmhd.page_width, 320);
+        image.bmhd.bitplanes = 5;
+        assert_eq!(image.sort_palette(PaletteSort::None).bmhd.bitplanes, 5);
+    }
+
+    #[test]
+    fn oversized_png_is_refused() {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 70000, 1);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0; 8750]).unwrap();
+        drop(writer);
+
+        match IffImage::from_png_reader(&png[..]) {
+            Err(IffLoadError::Convert(IffConvertError::TooLarge(70000, 1))) => {}
+            other => panic!("unexpected {:?}", other.map(|image| image.width())),
+        }
+        assert!(matches!(
+            check_size(0, 10),
+            Err(IffConvertError::EmptyImage(0, 10))
+        ));
+        assert_eq!(check_size(65535, 1).unwrap(), (65535, 1));
+    }
+
+    #[test]
+    fn perceptual_color_metrics() {
+        // from Sharma, Wu and Dalal's test data
+        let pair = ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]);
+        assert!((ciede2000(&pair.0, &pair.1) - 2.0425).abs() < 1e-4);
+        let gray = Color {
+            r: 60,
+            g: 60,
+            b: 60,
+        };
+        assert_eq!(gray.delta_e_2000(&gray), 0.0);
+        let orange = Color {
+            r: 250,
+            g: 140,
+            b: 20,
+        };
+        assert_eq!(Color::from_oklab(orange.to_oklab()), orange);
+
+        // a dark gray is closer to black in RGB, but looks closer to mid gray
+        let cmap = ColorMap {
+            colors: vec![
+                Color::default(),
+                Color {
+                    r: 128,
+                    g: 128,
+                    b: 128,
+                },
+            ],
+        };
+        assert_eq!(cmap.nearest_by([60, 60, 60], ColorMetric::Rgb), 0);
+        assert_eq!(cmap.nearest_by([60, 60, 60], ColorMetric::Oklab), 1);
+        // and a dark blue-gray to blue
+        let blue = ColorMap {
+            colors: vec![Color::default(), Color { r: 0, g: 0, b: 255 }],
+        };
+        assert_eq!(blue.nearest_by([40, 40, 100], ColorMetric::Rgb), 0);
+        assert_eq!(blue.nearest_by([40, 40, 100], ColorMetric::Ciede2000), 1);
+        assert_eq!("OKLab".parse(), Ok(ColorMetric::Oklab));
+        assert!("lab".parse::<ColorMetric>().is_err());
+    }
+
+    #[test]
+    fn alpha_becomes_a_mask() {
+        let with_alpha = |alpha: Vec<u8>| {
+            let mut image = IffImage::from_parts(2, 2, ColorMap::gray_ramp(2), vec![0, 1, 1, 0]);
+            image.alpha = Some(alpha);
+            image.warnings.push(Warning::AlphaIgnored);
+            image
+        };
+
+        let mut image = with_alpha(vec![0, 0x80, 0x7f, 0xff]);
+        image.apply_alpha(AlphaMode::Threshold(0x80)).unwrap();
+        assert_eq!(image.masking(), Masking::Mask);
+        assert_eq!(image.mask(), Some(&[0, 1, 0, 1][..]));
+        assert!(image.alpha.is_none());
+        assert!(image.warnings().is_empty());
+
+        let mut image = with_alpha(vec![0, 0xff, 0xff, 0x40]);
+        assert!(matches!(
+            image.apply_alpha(AlphaMode::Strict),
+            Err(IffConvertError::PartialAlpha {
+                alpha: 0x40,
+                x: 1,
+                y: 1
+            })
+        ));
+        let mut image = with_alpha(vec![0, 0xff, 0xff, 0]);
+        image.apply_alpha(AlphaMode::Strict).unwrap();
+        assert_eq!(image.mask(), Some(&[0, 1, 1, 0][..]));
+
+        let mut image = with_alpha(vec![0, 0x40, 0xff, 0]);
+        image.apply_alpha(AlphaMode::Ignore).unwrap();
+        assert_eq!(image.masking(), Masking::None);
+        assert_eq!(image.mask(), None);
+        assert_eq!(image.warnings(), &[Warning::AlphaIgnored]);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn truecolor_palette_follows_the_order() {
+        let (red, green, blue) = ([255, 0, 0], [0, 255, 0], [0, 0, 255]);
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 6, 1);
+        encoder.set_color(ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&[red, blue, blue, green, blue, green].concat())
+            .unwrap();
+        drop(writer);
+
+        for (order, pixels, transparent) in [
+            (PaletteOrder::FirstAppearance, vec![0, 1, 1, 2, 1, 2], 2),
+            (PaletteOrder::Popularity, vec![2, 0, 0, 1, 0, 1], 1),
+        ] {
+            let options = LoadOptions {
+                palette_order: order,
+                ..LoadOptions::default()
+            };
+            let mut image = IffImage::from_bytes(&png, &options).unwrap();
+            assert_eq!(image.pixels, pixels, "{}", order);
+            image.set_mask_from_color(KeyColor::Rgb(green)).unwrap();
+            assert_eq!(image.bmhd.transparent_color, transparent, "{}", order);
+        }
+    }
+
+    #[test]
+    fn missing_input_names_the_file() {
+        let path = Path::new("testdata/missing.png");
+        match IffImage::from_file(path, &LoadOptions::default()) {
+            Err(IffLoadError::File { path: failed, .. }) => assert_eq!(failed, path),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
     }
 }
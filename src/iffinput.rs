@@ -0,0 +1,311 @@
+use crate::iff::form_chunks;
+use crate::iffimage::{
+    check_indices, check_size, BitmapHeader, Color, ColorMap, IffConvertError, IffImage,
+    IffLoadError, COMPRESSION_BYTERUN1, COMPRESSION_NONE, COMPRESSION_VDAT, MASK_HAS_MASK,
+};
+use crate::vdat;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// CAMG flag for hold-and-modify
+pub(crate) const CAMG_HAM: u32 = 0x800;
+/// CAMG flag for extra half-brite: indices 32-63 are 0-31 at half brightness
+pub(crate) const CAMG_EHB: u32 = 0x80;
+
+fn be_u16(data: &[u8], offset: usize) -> Result<u16, IffConvertError> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(IffConvertError::MalformedChunk {
+            chunk: *b"BMHD",
+            reason: "truncated",
+        })
+}
+
+pub(crate) fn parse_bmhd(data: &[u8]) -> Result<BitmapHeader, IffConvertError> {
+    if data.len() < 20 {
+        return Err(IffConvertError::MalformedChunk {
+            chunk: *b"BMHD",
+            reason: "truncated",
+        });
+    }
+    Ok(BitmapHeader {
+        width: be_u16(data, 0)?,
+        height: be_u16(data, 2)?,
+        x: be_u16(data, 4)? as i16,
+        y: be_u16(data, 6)? as i16,
+        bitplanes: data[8],
+        masking: data[9],
+        compression: data[10],
+        _pad1: 0,
+        transparent_color: be_u16(data, 12)?,
+        x_aspect: data[14],
+        y_aspect: data[15],
+        page_width: be_u16(data, 16)?,
+        page_height: be_u16(data, 18)?,
+    })
+}
+
+/// ByteRun1 is only used for BODY data
+const TRUNCATED_BYTERUN1: IffConvertError = IffConvertError::MalformedChunk {
+    chunk: *b"BODY",
+    reason: "truncated ByteRun1 data",
+};
+
+/// Unpacks ByteRun1 data until `out` holds `len` bytes
+pub(crate) fn unpack_byterun1(data: &[u8], len: usize) -> Result<Vec<u8>, IffConvertError> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while out.len() < len {
+        let n = *data.get(i).ok_or(TRUNCATED_BYTERUN1)? as i8;
+        i += 1;
+        match n {
+            0..=127 => {
+                let literal = data.get(i..i + n as usize + 1).ok_or(TRUNCATED_BYTERUN1)?;
+                out.extend_from_slice(literal);
+                i += literal.len();
+            }
+            -127..=-1 => {
+                let value = *data.get(i).ok_or(TRUNCATED_BYTERUN1)?;
+                out.extend(std::iter::repeat_n(value, (1 - n as i16) as usize));
+                i += 1;
+            }
+            // -128 is a no-op
+            _ => {}
+        }
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+impl IffImage {
+    /// Reads an ILBM file. Besides the BODY (raw, ByteRun1 or VDAT) the CMAP and
+    /// CAMG are taken over, extra half-brite palettes are expanded to 64
+    /// colors. HAM and deep (24 bit) images are not supported
+    pub fn from_iff_file<P: AsRef<Path>>(path: P) -> Result<IffImage, IffLoadError> {
+        let data = fs::read(&path).map_err(|source| IffLoadError::File {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
+        IffImage::from_iff_bytes(&data)
+    }
+
+    /// Reads an ILBM back, e.g. to preview or re-convert it
+    pub fn from_iff_reader<R: Read>(mut r: R) -> Result<IffImage, IffLoadError> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        IffImage::from_iff_bytes(&data)
+    }
+
+    pub fn from_iff_bytes(data: &[u8]) -> Result<IffImage, IffLoadError> {
+        let chunks = form_chunks(data, b"ILBM")?;
+        let find = |id: &[u8; 4]| chunks.iter().find(|(c, _)| c == id).map(|(_, d)| *d);
+
+        let bmhd = parse_bmhd(find(b"BMHD").ok_or(IffConvertError::MissingChunk(*b"BMHD"))?)?;
+        check_size(bmhd.width as usize, bmhd.height as usize)?;
+        if bmhd.bitplanes == 0 {
+            return Err(From::from(IffConvertError::MalformedChunk {
+                chunk: *b"BMHD",
+                reason: "no bitplanes",
+            }));
+        }
+        if bmhd.bitplanes > 8 {
+            return Err(From::from(IffConvertError::UnsupportedBitDepth(
+                bmhd.bitplanes,
+            )));
+        }
+        let mut camg = match find(b"CAMG") {
+            Some(&[a, b, c, d, ..]) => Some(u32::from_be_bytes([a, b, c, d])),
+            Some(_) => {
+                return Err(From::from(IffConvertError::MalformedChunk {
+                    chunk: *b"CAMG",
+                    reason: "truncated",
+                }))
+            }
+            None => None,
+        };
+        if camg.is_some_and(|camg| camg & CAMG_HAM != 0) {
+            return Err(From::from(IffConvertError::Unsupported("HAM images")));
+        }
+        let mut cmap = ColorMap {
+            colors: find(b"CMAP")
+                .ok_or(IffConvertError::NoPalette)?
+                .chunks_exact(3)
+                .map(|c| Color {
+                    r: c[0],
+                    g: c[1],
+                    b: c[2],
+                })
+                .collect(),
+        };
+        if cmap.colors.is_empty() {
+            return Err(From::from(IffConvertError::EmptyPalette));
+        }
+        if let Some(flags) = camg.filter(|camg| camg & CAMG_EHB != 0) {
+            // the halved colors become regular palette entries
+            cmap.colors.resize(32, Color::default());
+            let halves = cmap
+                .colors
+                .iter()
+                .map(|c| Color {
+                    r: c.r >> 1,
+                    g: c.g >> 1,
+                    b: c.b >> 1,
+                })
+                .collect::<Vec<_>>();
+            cmap.colors.extend(halves);
+            camg = Some(flags & !CAMG_EHB);
+        }
+
+        let (width, height) = (bmhd.width as usize, bmhd.height as usize);
+        let row_bytes = width.div_ceil(16) * 2;
+        let planes = bmhd.bitplanes as usize;
+        let stored_planes = planes + (bmhd.masking == MASK_HAS_MASK) as usize;
+        let body_len = row_bytes * stored_planes * height;
+        let body = find(b"BODY").ok_or(IffConvertError::MissingChunk(*b"BODY"))?;
+        let body = match bmhd.compression {
+            COMPRESSION_NONE => body
+                .get(..body_len)
+                .ok_or(IffConvertError::MalformedChunk {
+                    chunk: *b"BODY",
+                    reason: "truncated",
+                })?
+                .to_vec(),
+            COMPRESSION_BYTERUN1 => unpack_byterun1(body, body_len)?,
+            COMPRESSION_VDAT => vdat::unpack_body(body, row_bytes, stored_planes, height)?,
+            _ => {
+                return Err(From::from(IffConvertError::MalformedChunk {
+                    chunk: *b"BMHD",
+                    reason: "unsupported compression",
+                }))
+            }
+        };
+
+        let mut pixels = vec![0u8; width * height];
+        let mut mask = vec![0u8; width * height * (stored_planes - planes)];
+        for (y, line) in body.chunks(row_bytes * stored_planes).enumerate() {
+            for (bpl, row) in line.chunks(row_bytes).enumerate() {
+                // the mask plane, if any, comes last
+                let (samples, bit_shift) = match bpl < planes {
+                    true => (&mut pixels, bpl),
+                    false => (&mut mask, 0),
+                };
+                for x in 0..width {
+                    let bit = (row[x / 8] >> (7 - x % 8)) & 1;
+                    samples[y * width + x] |= bit << bit_shift;
+                }
+            }
+        }
+        // a CMAP may be shorter than the bitplanes can address
+        check_indices(&pixels, width, cmap.colors.len())?;
+
+        Ok(IffImage {
+            bmhd: BitmapHeader {
+                compression: COMPRESSION_NONE,
+                ..bmhd
+            },
+            cmap,
+            pixels,
+            camg,
+            omit_cmap: false,
+            extra_chunks: vec![],
+            mask: (stored_planes > planes).then_some(mask),
+            alpha: None,
+            warnings: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::Compression;
+
+    #[test]
+    fn round_trips_written_ilbm() {
+        // rows of an odd number of bytes are padded to words in the BODY
+        for width in [1, 8, 13, 24, 32] {
+            let mut image = IffImage::from_parts(
+                width,
+                3,
+                ColorMap {
+                    colors: vec![Color::default(); 5],
+                },
+                (0..width as usize * 3).map(|i| (i % 5) as u8).collect(),
+            );
+            let mask = (0..width as usize * 3).map(|i| (i % 3 > 0) as u8).collect();
+            image.set_mask(Some(mask)).unwrap();
+            for compression in [Compression::None, Compression::ByteRun1, Compression::Vdat] {
+                image.set_compression(compression);
+                let out = image.to_bytes();
+                let read = IffImage::from_iff_bytes(&out).unwrap();
+                assert_eq!(read.bmhd.width, width);
+                assert_eq!(read.bmhd.bitplanes, 3);
+                assert_eq!(read.pixels, image.pixels, "width {}", width);
+                assert_eq!(read.mask(), image.mask(), "width {}", width);
+            }
+        }
+    }
+
+    #[test]
+    fn expands_extra_half_brite_and_keeps_camg() {
+        let mut colors = vec![Color::default(); 32];
+        colors[8] = Color {
+            r: 0xf0,
+            g: 0x80,
+            b: 0x22,
+        };
+        let mut image = IffImage::from_parts(16, 1, ColorMap { colors }, vec![40; 16]);
+        image.bmhd.bitplanes = 6;
+        image.camg = Some(CAMG_EHB | 0x8000);
+        let read = IffImage::from_iff_bytes(&image.to_bytes()).unwrap();
+        assert_eq!(read.cmap.colors.len(), 64);
+        assert_eq!(
+            read.cmap.colors[40],
+            Color {
+                r: 0x78,
+                g: 0x40,
+                b: 0x11
+            }
+        );
+        assert_eq!(read.camg, Some(0x8000));
+        assert_eq!(read.to_bytes().len(), read.form_size(read.body_size()) + 8);
+
+        image.camg = Some(CAMG_HAM);
+        assert!(IffImage::from_iff_bytes(&image.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_indices_beyond_cmap_and_zero_bitplanes() {
+        let colors = vec![Color::default(); 4];
+        let mut image = IffImage::from_parts(16, 1, ColorMap { colors }, vec![3; 16]);
+        image.cmap.colors.truncate(2);
+        assert!(matches!(
+            IffImage::from_iff_bytes(&image.to_bytes()),
+            Err(IffLoadError::Convert(IffConvertError::InvalidIndex {
+                index: 3,
+                x: 0,
+                y: 0
+            }))
+        ));
+
+        image.bmhd.bitplanes = 0;
+        assert!(matches!(
+            IffImage::from_iff_bytes(&image.to_bytes()),
+            Err(IffLoadError::Convert(IffConvertError::MalformedChunk {
+                reason: "no bitplanes",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn byterun1_unpacking() {
+        let data = [0xfe, 1, 1, 2, 3, 0x80, 0xff, 4];
+        assert_eq!(
+            unpack_byterun1(&data, 7).unwrap(),
+            vec![1, 1, 1, 2, 3, 4, 4]
+        );
+        assert!(unpack_byterun1(&data[..3], 7).is_err());
+    }
+}
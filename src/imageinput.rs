@@ -0,0 +1,195 @@
+use crate::iffimage::{
+    check_size, reduce_sample, CancelToken, ColorMetric, IffConvertError, IffImage, IffLoadError,
+    PaletteOrder, Warning,
+};
+use crate::quantize::quantize;
+use std::convert::TryFrom;
+use std::path::Path;
+
+impl IffImage {
+    /// Loads anything the image crate can decode and quantizes it down to
+    /// `max_colors` colors
+    pub fn from_image_file<P: AsRef<Path>>(
+        path: P,
+        max_colors: usize,
+    ) -> Result<IffImage, IffLoadError> {
+        Ok(IffImage::from_dynamic_image(
+            image::open(path)?,
+            max_colors,
+            PaletteOrder::default(),
+            ColorMetric::default(),
+            &CancelToken::default(),
+        )?)
+    }
+
+    /// Like `from_image_file`, with the file contents already in memory
+    pub fn from_image_bytes(data: &[u8], max_colors: usize) -> Result<IffImage, IffLoadError> {
+        Ok(IffImage::from_dynamic_image(
+            image::load_from_memory(data)?,
+            max_colors,
+            PaletteOrder::default(),
+            ColorMetric::default(),
+            &CancelToken::default(),
+        )?)
+    }
+
+    pub(crate) fn from_dynamic_image(
+        image: image::DynamicImage,
+        max_colors: usize,
+        order: PaletteOrder,
+        metric: ColorMetric,
+        cancel: &CancelToken,
+    ) -> Result<IffImage, IffConvertError> {
+        let rgba = image.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let (width, height) = check_size(width as usize, height as usize)?;
+
+        let (cmap, pixels) = quantize(rgba.as_raw(), max_colors, order, metric, cancel)?;
+        let mut warnings = vec![];
+        let mut alpha = None;
+        if rgba.pixels().any(|pixel| pixel[3] != 0xff) {
+            warnings.push(Warning::AlphaIgnored);
+            alpha = Some(rgba.pixels().map(|pixel| pixel[3]).collect());
+        }
+        let lossy = rgba.pixels().zip(&pixels).any(|(pixel, &index)| {
+            let c = &cmap.colors[index as usize];
+            [c.r, c.g, c.b] != pixel.0[..3]
+        });
+        if lossy {
+            warnings.push(Warning::Quantized {
+                colors: cmap.colors.len(),
+            });
+        }
+
+        let mut image = IffImage::from_parts(width, height, cmap, pixels);
+        image.warnings = warnings;
+        image.alpha = alpha;
+        Ok(image)
+    }
+}
+
+/// Brings images with 16 bit channels down to 8 bits, dithered instead of
+/// rounded if `dither` is set. Other images are returned as they are
+pub(crate) fn reduce_16bit(image: image::DynamicImage, dither: bool) -> image::DynamicImage {
+    let color = image.color();
+    if !dither || color.bytes_per_pixel() != 2 * color.channel_count() {
+        return image;
+    }
+    let rgba = image.to_rgba16();
+    let data = rgba
+        .enumerate_pixels()
+        .flat_map(|(x, y, pixel)| {
+            let [r, g, b, a] = pixel.0;
+            let (x, y) = (x as usize, y as usize);
+            [
+                reduce_sample(r, x, y, true),
+                reduce_sample(g, x, y, true),
+                reduce_sample(b, x, y, true),
+                reduce_sample(a, x, y, false),
+            ]
+        })
+        .collect();
+    let reduced = image::RgbaImage::from_raw(rgba.width(), rgba.height(), data)
+        .expect("one sample per channel and pixel");
+    image::DynamicImage::ImageRgba8(reduced)
+}
+
+/// Images with up to 256 colors are indexed exactly, others are quantized
+/// down to 256 colors
+impl TryFrom<image::DynamicImage> for IffImage {
+    type Error = IffConvertError;
+
+    fn try_from(image: image::DynamicImage) -> Result<Self, Self::Error> {
+        IffImage::from_dynamic_image(
+            image,
+            256,
+            PaletteOrder::default(),
+            ColorMetric::default(),
+            &CancelToken::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::AlphaMode;
+
+    #[test]
+    fn warns_about_quantizing_and_alpha() {
+        let rgba = image::RgbaImage::from_fn(8, 8, |x, y| {
+            image::Rgba([(x * 32) as u8, (y * 32) as u8, 0, 0xff])
+        });
+        let image = IffImage::try_from(image::DynamicImage::from(rgba.clone())).unwrap();
+        assert!(image.warnings().is_empty());
+        assert_eq!(image.num_colors(), 64);
+        let image = IffImage::from_dynamic_image(
+            rgba.clone().into(),
+            4,
+            PaletteOrder::default(),
+            ColorMetric::default(),
+            &CancelToken::default(),
+        )
+        .unwrap();
+        assert_eq!(image.warnings(), &[Warning::Quantized { colors: 4 }]);
+
+        let mut translucent = rgba;
+        translucent.put_pixel(0, 0, image::Rgba([0, 0, 0, 0x80]));
+        let cancel = CancelToken::default();
+        let image = IffImage::from_dynamic_image(
+            translucent.clone().into(),
+            64,
+            PaletteOrder::default(),
+            ColorMetric::default(),
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(image.warnings(), &[Warning::AlphaIgnored]);
+        assert_eq!(image.crop(0, 0, 2, 2).unwrap().warnings().len(), 1);
+
+        let mut masked = image.crop(0, 0, 2, 1).unwrap();
+        assert!(matches!(
+            masked.apply_alpha(AlphaMode::Strict),
+            Err(IffConvertError::PartialAlpha {
+                alpha: 0x80,
+                x: 0,
+                y: 0
+            })
+        ));
+        masked.apply_alpha(AlphaMode::Threshold(0x81)).unwrap();
+        assert_eq!(masked.mask(), Some(&[0, 1][..]));
+        assert!(masked.warnings().is_empty());
+
+        cancel.cancel();
+        assert!(matches!(
+            IffImage::from_dynamic_image(
+                translucent.into(),
+                64,
+                PaletteOrder::default(),
+                ColorMetric::default(),
+                &cancel
+            ),
+            Err(IffConvertError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn try_from_checks_the_size_and_quantizes_large_palettes() {
+        let wide = image::RgbaImage::new(70000, 1);
+        assert!(matches!(
+            IffImage::try_from(image::DynamicImage::from(wide)),
+            Err(IffConvertError::TooLarge(70000, 1))
+        ));
+        let empty = image::RgbaImage::new(0, 4);
+        assert!(matches!(
+            IffImage::try_from(image::DynamicImage::from(empty)),
+            Err(IffConvertError::EmptyImage(0, 4))
+        ));
+
+        // more colors than an ILBM palette holds
+        let colorful = image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let image = IffImage::try_from(image::DynamicImage::from(colorful)).unwrap();
+        assert_eq!(image.num_colors(), 256);
+        assert_eq!(image.warnings(), &[Warning::Quantized { colors: 256 }]);
+    }
+}
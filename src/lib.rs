@@ -0,0 +1,40 @@
+//! Converts indexed images to Amiga IFF ILBM files. The `ipng2iff` binary is
+//! a thin command line frontend on top of this library
+
+mod aseinput;
+pub mod atarist;
+mod bmpinput;
+pub mod camg;
+pub mod chunkedit;
+pub mod diagnostics;
+pub mod diff;
+pub mod dither;
+pub mod emulate;
+pub mod font;
+mod gifinput;
+pub mod ham;
+pub mod icon;
+pub mod iff;
+pub mod iffimage;
+mod iffinput;
+#[cfg(feature = "image")]
+mod imageinput;
+pub mod palette;
+pub mod playfield;
+mod pngcolor;
+mod pngoutput;
+pub mod preview;
+mod quantize;
+mod rawinput;
+pub mod report;
+pub mod rgbn;
+pub mod sprite;
+pub mod template;
+mod tgainput;
+mod vdat;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use iffimage::{
+    AlphaMode, Compression, IffConvertError, IffImage, IffLoadError, KeyColor, LoadOptions, Masking,
+};
@@ -0,0 +1,46 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Prints log records to stderr, keeping stdout free for --json
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("Error: {}", record.args()),
+            Level::Warn => eprintln!("Warning: {}", record.args()),
+            Level::Info => eprintln!("{}", record.args()),
+            Level::Debug | Level::Trace => {
+                eprintln!("[{}] {}", record.target(), record.args())
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Maps -q / -v / -vv to a log level. Without either, progress messages are
+/// shown but no per-stage details
+pub fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Off,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+pub fn init(verbose: u8, quiet: bool) {
+    // only fails when a logger is already installed
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level(verbose, quiet));
+    }
+}
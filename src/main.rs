@@ -1,24 +1,1840 @@
+use log::{debug, error, info, log_enabled, warn, Level};
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-mod iffimage;
+use ipng2iff::{
+    atarist, camg, chunkedit, diff, dither, emulate, font, ham, icon, iff, iffimage, palette,
+    playfield, preview, report, rgbn, sprite, template,
+};
+
+mod adf;
+mod cache;
+mod config;
+mod exitcode;
+mod logger;
+mod manifest;
+mod progress;
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+struct Size {
+    width: u16,
+    height: u16,
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid size {:?}. Expected WxH", s);
+        let mut parts = s.splitn(2, 'x');
+        match (parts.next(), parts.next()) {
+            (Some(w), Some(h)) => Ok(Size {
+                width: w.trim().parse().map_err(|_| invalid())?,
+                height: h.trim().parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Scale factor given as `Nx` (enlarge) or `1/Nx` (shrink)
+#[derive(Debug)]
+struct Scale {
+    numerator: u32,
+    denominator: u32,
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid scale {:?}. Expected Nx or 1/Nx", s);
+        let factor = s.trim().trim_end_matches('x');
+        let mut parts = factor.splitn(2, '/');
+        let numerator = parts
+            .next()
+            .and_then(|n| n.trim().parse().ok())
+            .ok_or_else(invalid)?;
+        let denominator = match parts.next() {
+            Some(d) => d.trim().parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        if numerator == 0 || denominator == 0 {
+            return Err(invalid());
+        }
+        Ok(Scale {
+            numerator,
+            denominator,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Crop {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid crop region {:?}. Expected X,Y,WxH", s);
+        let parts = s.split(',').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        let size = parts[2].parse::<Size>().map_err(|_| invalid())?;
+        Ok(Crop {
+            x: parts[0].trim().parse().map_err(|_| invalid())?,
+            y: parts[1].trim().parse().map_err(|_| invalid())?,
+            width: size.width,
+            height: size.height,
+        })
+    }
+}
+
+/// Number of bytes, optionally with a K or M suffix (1024 based), e.g. 880K
+/// for a double density floppy
+#[derive(Debug)]
+struct ByteSize(u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid size {:?}. Expected bytes like 901120 or 880K", s);
+        let (number, unit) = match s.trim().to_ascii_uppercase() {
+            s if s.ends_with('K') => (s.trim_end_matches('K').to_string(), 1 << 10),
+            s if s.ends_with('M') => (s.trim_end_matches('M').to_string(), 1 << 20),
+            s => (s, 1),
+        };
+        let number = number.trim().parse::<u64>().map_err(|_| invalid())?;
+        number.checked_mul(unit).map(ByteSize).ok_or_else(invalid)
+    }
+}
+
+/// Palette size for quantizing, 1 to 256 colors
+#[derive(Clone, Copy, Debug)]
+struct ColorCount(usize);
+
+impl FromStr for ColorCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse() {
+            Ok(colors) if (1..=iffimage::MAX_COLORS).contains(&colors) => Ok(ColorCount(colors)),
+            _ => Err(format!(
+                "Invalid number of colors {:?}. Expected 1 to {}",
+                s,
+                iffimage::MAX_COLORS
+            )),
+        }
+    }
+}
+
+/// Whether conversion steps that lose information may run
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Policy {
+    /// Quantize, remap, reduce, scale and pad as needed
+    Lossy,
+    /// Fail instead, for builds that must reproduce the art exactly
+    Strict,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lossy" => Ok(Policy::Lossy),
+            "strict" => Ok(Policy::Strict),
+            _ => Err(format!("Invalid policy {:?}. Expected strict or lossy", s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum PaletteCommand {
+    /// Write the palette of an IFF (or any other input) to a palette file.
+    /// .gpl, .pal and .act are recognized, anything else gets raw RGB triplets
+    Extract {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+}
 
 #[derive(StructOpt, Debug)]
-#[structopt(about = "A command line utility to convert indexed PNGs to Amiga readable IFF files")]
+enum Command {
+    /// Show the converted image, or an existing IFF, in a truecolor terminal.
+    /// Conversion options go before the subcommand
+    Preview {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Shrink the image to at most this many columns
+        #[structopt(long, default_value = "80")]
+        columns: u16,
+    },
+    /// Insert, replace or delete chunks of an existing ILBM. The BODY is
+    /// copied as it is
+    Edit {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Set a chunk to ID=text, ID=0xHEX or ID=@file, replacing an existing
+        /// one. New chunks go before the BODY
+        #[structopt(long)]
+        set: Vec<chunkedit::ChunkValue>,
+        /// Delete all chunks with this ID
+        #[structopt(long)]
+        delete: Vec<chunkedit::ChunkId>,
+        /// Write the result here instead of back into the file
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Replace the picture of an existing ILBM, keeping its palette, CAMG,
+    /// CRNG, ANNO and all other chunks. The image may only use colors of the
+    /// existing palette. Conversion options go before the subcommand
+    Rebody {
+        #[structopt(parse(from_os_str))]
+        iff: PathBuf,
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// Write the result here instead of back into the IFF
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Split an image into the two playfields of a dual playfield screen by
+    /// palette ranges: the first block of colors goes to playfield 1, the
+    /// next one to playfield 2. Conversion options go before the subcommand
+    DualPlayfield {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        pf1: PathBuf,
+        #[structopt(parse(from_os_str))]
+        pf2: PathBuf,
+        /// Colors per playfield, 8 on OCS/ECS or 16 on AGA
+        #[structopt(long, default_value = "8")]
+        playfield_colors: usize,
+        /// Show playfield 2 in front of playfield 1
+        #[structopt(long)]
+        pf2_front: bool,
+    },
+    /// Write an image using colors 0 to 3 as raw sprite data, with control
+    /// words and the terminating null words. Conversion options go before
+    /// the subcommand
+    Sprite {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// 16, or 32 and 64 for the AGA sprite fetch modes
+        #[structopt(long, default_value = "16")]
+        sprite_width: usize,
+        /// First display line of the sprite
+        #[structopt(long, default_value = "44")]
+        vstart: u16,
+        /// Horizontal position in low resolution pixels
+        #[structopt(long, default_value = "128")]
+        hstart: u16,
+        /// Line after the last one shown, padding the sprite with transparent
+        /// lines [default: right below the image]
+        #[structopt(long)]
+        vstop: Option<u16>,
+        /// Leave out the null control words ending the sprite, to chain more
+        /// sprites for the same channel
+        #[structopt(long)]
+        no_terminator: bool,
+        /// Write an attached pair for images with up to 15 colors plus
+        /// transparency, shown with color registers 17 to 31
+        #[structopt(long)]
+        attached: bool,
+    },
+    /// Write an image with up to 16 colors as Atari ST screen data: the
+    /// palette as 16 ST color words followed by the word-interleaved
+    /// bitplanes. Conversion options go before the subcommand
+    AtariSt {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Write an image as a Degas Elite picture, padded to the full ST screen.
+    /// Conversion options go before the subcommand
+    Degas {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// low (.PI1, 16 colors), medium (.PI2, 4 colors) or high (.PI3, 2
+        /// colors) [default: from the output extension or the colors]
+        #[structopt(long)]
+        resolution: Option<atarist::Resolution>,
+    },
+    /// Write an image as a true color FORM RGBN (12 bit) or RGB8 (24 bit)
+    /// for Impulse Turbo Silver and other ray-tracing and video software.
+    /// Conversion options go before the subcommand
+    Rgb {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// rgbn or rgb8
+        #[structopt(long, default_value = "rgb8")]
+        format: rgbn::RgbFormat,
+    },
+    /// Write an image as a HAM6 or HAM8 ILBM with base colors picked from
+    /// the image, and report how far the result is off. Conversion options
+    /// go before the subcommand, truecolor input wants --colors 256
+    Ham {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// ham6 (OCS/ECS, 16 base colors) or ham8 (AGA, 64 base colors)
+        #[structopt(long, default_value = "ham6")]
+        mode: ham::HamMode,
+        /// Take the base colors from this palette file (.gpl, .pal, .act or
+        /// raw RGB triplets) instead of picking them, e.g. to share color
+        /// registers with other parts of the screen
+        #[structopt(long, parse(from_os_str))]
+        ham_base: Option<PathBuf>,
+        /// Write a PNG showing the color error of every pixel, from black
+        /// over red and yellow to white
+        #[structopt(long, parse(from_os_str))]
+        heat_map: Option<PathBuf>,
+    },
+    /// Make a Workbench icon (.info) from an image and optionally a second
+    /// one shown while the icon is selected. Pixels are Workbench pens, the
+    /// palette is not stored. Conversion options go before the subcommand
+    Icon {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// Image shown while selected [default: the image complemented]
+        #[structopt(long, parse(from_os_str))]
+        selected: Option<PathBuf>,
+        /// disk, drawer, tool, project or garbage
+        #[structopt(long, default_value = "tool")]
+        icon_type: icon::IconType,
+        /// Program opening a project icon
+        #[structopt(long)]
+        default_tool: Option<String>,
+        /// Tool type string, e.g. DONOTWAIT. Can be given multiple times
+        #[structopt(long)]
+        tool_type: Vec<String>,
+        /// Stack size of a tool
+        #[structopt(long, default_value = "4096")]
+        stack: u32,
+    },
+    /// Make an Amiga bitmap font from a sheet of glyphs in equally sized
+    /// cells, writing OUTPUT (name.font) and the font itself as
+    /// name/<height> next to it. Pixels of any color but 0 are set.
+    /// Conversion options go before the subcommand
+    Font {
+        #[structopt(parse(from_os_str))]
+        sheet: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// Size of the cells, the height being the font height
+        #[structopt(long)]
+        cell: Size,
+        /// Text file with the characters of the cells in order, left to
+        /// right and top to bottom. Line breaks are skipped [default:
+        /// printable ASCII from space to ~]
+        #[structopt(long, parse(from_os_str))]
+        chars: Option<PathBuf>,
+        /// Rows from the top of a cell to the baseline [default: the last row]
+        #[structopt(long)]
+        baseline: Option<u16>,
+        /// Blank columns after every glyph
+        #[structopt(long, default_value = "1")]
+        spacing: u16,
+        /// Make a fixed width font with glyphs as wide as the cells
+        #[structopt(long)]
+        fixed: bool,
+    },
+    /// Compare two ILBMs: chunks, palette and pixels. Exits with 1 if they
+    /// differ
+    Diff {
+        #[structopt(parse(from_os_str))]
+        a: PathBuf,
+        #[structopt(parse(from_os_str))]
+        b: PathBuf,
+    },
+    /// Palette tools
+    Palette(PaletteCommand),
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    setting = structopt::clap::AppSettings::SubcommandsNegateReqs,
+    setting = structopt::clap::AppSettings::AllArgsOverrideSelf,
+    about = "A command line utility to convert indexed PNG, GIF, BMP, TGA and Aseprite images to Amiga readable IFF files",
+    after_help = "EXIT CODES:\n    0  success\n    1  invalid arguments or refused to overwrite\n    2  I/O error\n    3  unsupported or undecodable input\n    4  palette or color error\n    5  image does not fit the requested crop or size"
+)]
 struct Opt {
-    #[structopt(parse(from_os_str))]
-    infile: PathBuf,
-    #[structopt(parse(from_os_str))]
-    outfile: PathBuf,
+    /// Input and output file. The output defaults to the input with an .iff
+    /// extension. With --out-dir, --cat or --list all files are inputs, which
+    /// may be glob patterns like gfx/**/*.png or directories to convert
+    /// recursively
+    #[structopt(parse(from_os_str), required = true)]
+    files: Vec<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// Config file with profiles and per-file overrides [default: ipng2iff.toml]
+    #[structopt(long, env = "IPNG2IFF_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Apply the options of this profile from the config file. Options on the
+    /// command line take precedence
+    #[structopt(long, env = "IPNG2IFF_PROFILE")]
+    profile: Option<String>,
+    /// Options of inputs with overrides in the config file
+    #[structopt(skip)]
+    overrides: Vec<(PathBuf, Opt)>,
+    /// Convert all inputs into this directory
+    #[structopt(long, env = "IPNG2IFF_OUT_DIR", parse(from_os_str))]
+    out_dir: Option<PathBuf>,
+    /// Name outputs after a template like {stem}_{bitplanes}bpl.iff. Also
+    /// knows {width}, {height} and {colors}
+    #[structopt(long, env = "IPNG2IFF_OUT_TEMPLATE", requires = "out-dir")]
+    out_template: Option<template::OutputTemplate>,
+    /// Write all inputs as ILBMs into this one IFF CAT file
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["out-dir", "watch", "list"]
+    )]
+    cat: Option<PathBuf>,
+    /// Like --cat but writes a LIST, storing a palette shared by all images
+    /// once in a PROP
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["out-dir", "watch"])]
+    list: Option<PathBuf>,
+    /// Skip inputs that did not change since the last run into --out-dir
+    #[structopt(long, requires = "out-dir")]
+    incremental: bool,
+    /// After a batch run, write a JSON manifest with the SHA-256, size and
+    /// conversion settings of every output file
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["watch", "dry-run", "cat", "list"])]
+    manifest: Option<PathBuf>,
+    /// Also write every output into an ADF disk image, at a path on the disk
+    /// given like disk.adf:gfx/ or disk.adf:gfx/title.iff. A missing ADF is
+    /// created as an empty OFS disk
+    #[structopt(long, conflicts_with_all = &["watch", "dry-run", "cat", "list"])]
+    into_adf: Option<adf::AdfTarget>,
+    /// Keep running and reconvert inputs whenever they change
+    #[structopt(long, conflicts_with = "incremental")]
+    watch: bool,
+    /// Only convert a sub-rectangle of the image (X,Y,WxH)
+    #[structopt(long)]
+    crop: Option<Crop>,
+    /// Nearest-neighbor scale factor applied after cropping (e.g. 2x or 1/3x)
+    #[structopt(long, conflicts_with = "resize")]
+    scale: Option<Scale>,
+    /// Nearest-neighbor resize to an exact size applied after cropping (WxH)
+    #[structopt(long)]
+    resize: Option<Size>,
+    /// Pad the height to a multiple of this many lines
+    #[structopt(long)]
+    pad_height: Option<u16>,
+    /// Pad the width to a multiple of this many pixels, e.g. 16 or 32 for
+    /// blitter routines
+    #[structopt(long)]
+    align_rows: Option<u16>,
+    /// Palette entry filling the padding of --pad-height and --align-rows
+    #[structopt(long, default_value = "0")]
+    pad_index: u8,
+    /// Frame to convert from Aseprite files
+    #[structopt(long, default_value = "0")]
+    frame: usize,
+    /// Convert a single Aseprite layer instead of all visible layers
+    #[structopt(long)]
+    layer: Option<String>,
+    /// Maximum number of colors when truecolor input needs quantizing
+    #[structopt(long, env = "IPNG2IFF_COLORS", default_value = "32")]
+    colors: ColorCount,
+    /// Fold palette entries closer than this perceptual distance (delta E,
+    /// about 2.3 is just noticeable) into the first of them
+    #[structopt(long)]
+    merge_colors: Option<f64>,
+    /// Palette order for truecolor input: first-appearance (row by row from
+    /// the top left) or popularity. Both give the same indices every run
+    #[structopt(long, default_value = "first-appearance")]
+    palette_order: iffimage::PaletteOrder,
+    /// Sort the palette afterwards, remapping the pixels: luminance, hue,
+    /// popularity or none. Sorted palettes make color cycling ranges and
+    /// copper gradients easier to set up
+    #[structopt(long, default_value = "none")]
+    sort_palette: iffimage::PaletteSort,
+    /// Pixels in colors missing from the palette: exact fails, nearest takes
+    /// the closest palette color and warns how many were changed
+    #[structopt(long, default_value = "exact")]
+    remap: iffimage::Remap,
+    /// CAMG display mode ID to write, e.g. 0x00029004 for PAL hires
+    /// interlaced. Combined with --camg-flags
+    #[structopt(long, conflicts_with = "minimal")]
+    camg: Option<camg::ModeId>,
+    /// CAMG display mode by flag names, e.g. HAM,LACE,HIRES or EHB,PAL
+    #[structopt(long, conflicts_with = "minimal")]
+    camg_flags: Option<camg::ModeFlags>,
+    /// Dithering when remapping onto --preset or --gray-levels: none,
+    /// floyd-steinberg, ordered or bluenoise, which is less patterned than
+    /// ordered and still packs well [default: floyd-steinberg for --preset,
+    /// none for --gray-levels]
+    #[structopt(long)]
+    dither: Option<dither::DitherMethod>,
+    /// Dithering strength in percent, lower is calmer
+    #[structopt(long, default_value = "100")]
+    dither_strength: f64,
+    /// Diffuse errors along every other line from right to left
+    #[structopt(long)]
+    serpentine: bool,
+    /// Threshold matrix for ordered dithering, rows like 0,2/3,1 [default:
+    /// 4x4 Bayer]. Picks ordered dithering unless --dither is given
+    #[structopt(long)]
+    dither_matrix: Option<dither::ThresholdMatrix>,
+    /// How the closest colors are found when quantizing, remapping and
+    /// dithering: rgb, ciede2000 or oklab. The perceptual ones give better
+    /// small palettes, oklab at a fraction of the cost of ciede2000
+    #[structopt(long, default_value = "rgb")]
+    color_metric: iffimage::ColorMetric,
+    /// PNG showing the result as the Amiga displays it: 12 bit colors on
+    /// OCS, extra halfbrite and HAM decoded, hires and interlaced pixels
+    /// stretched to about square
+    #[structopt(long, parse(from_os_str))]
+    preview_out: Option<PathBuf>,
+    /// Chipset for --preview-out: ocs, ecs or aga [default: aga for more
+    /// than 6 bitplanes, ocs otherwise]
+    #[structopt(long, requires = "preview-out")]
+    preview_chipset: Option<emulate::Chipset>,
+    /// lossy runs steps that lose information (quantizing, nearest color
+    /// remapping, 16 bit reduction, scaling, padding) as needed, strict
+    /// makes them errors
+    #[structopt(long, default_value = "lossy")]
+    policy: Policy,
+    /// How many pixels in colors missing from the palette to list when a
+    /// conversion fails on them
+    #[structopt(long, default_value = "10")]
+    report_pixels: usize,
+    /// Directory to write a PNG to for every input failing on colors missing
+    /// from the palette, showing those pixels in red
+    #[structopt(long, parse(from_os_str))]
+    diagnostics_dir: Option<PathBuf>,
+    /// TOML file pinning colors to palette indices, as `"#RRGGBB" = index`
+    /// lines. The other colors fill the remaining slots
+    #[structopt(long)]
+    index_map: Option<palette::IndexMap>,
+    /// Treat the input as raw 8 bit chunky pixels of the given size (WxH)
+    #[structopt(long, requires = "raw-palette")]
+    input_raw: Option<Size>,
+    /// Palette of raw RGB triplets for --input-raw
+    #[structopt(long, parse(from_os_str))]
+    raw_palette: Option<PathBuf>,
+    /// BODY compression: none, byterun1, auto, which picks whichever of the
+    /// two gives the smaller BODY, or vdat (vertical RLE, Atari ST)
+    #[structopt(long, env = "IPNG2IFF_COMPRESS", default_value = "none")]
+    compress: iffimage::Compression,
+    /// BMHD masking: none, mask (a mask plane keyed on the transparent color),
+    /// transparent-color or lasso. Defaults to what the input specifies
+    #[structopt(long)]
+    masking: Option<iffimage::Masking>,
+    /// Mask pixels whose alpha is below this, 0-255, instead of ignoring the
+    /// alpha channel
+    #[structopt(long, conflicts_with_all = &["masking", "mask-from-color"])]
+    alpha_threshold: Option<u8>,
+    /// Mask fully transparent pixels and fail on partially transparent ones
+    #[structopt(long, conflicts_with_all = &["masking", "mask-from-color", "alpha-threshold"])]
+    strict_alpha: bool,
+    /// Make the color most common along the image border transparent
+    #[structopt(long, conflicts_with = "mask-from-color")]
+    auto_transparent: bool,
+    /// Build a mask plane keyed on this color, #RRGGBB or a palette index,
+    /// for art marking transparency with a background color
+    #[structopt(long, conflicts_with = "masking")]
+    mask_from_color: Option<iffimage::KeyColor>,
+    /// Memory-map the input file instead of buffered reading
+    #[structopt(long)]
+    mmap: bool,
+    /// Overwrite existing output files
+    #[structopt(long, conflicts_with = "backup")]
+    force: bool,
+    /// Rename existing output files to *.bak instead of refusing to overwrite
+    #[structopt(long)]
+    backup: bool,
+    /// Remap onto a standard palette with dithering: workbench13 (4 colors)
+    /// or workbench31 (8 colors), for pictures shown with the Workbench
+    #[structopt(long)]
+    preset: Option<palette::Preset>,
+    /// Drop palette entries no pixel uses before picking the bitplanes,
+    /// which otherwise follow the highest palette index in use
+    #[structopt(long)]
+    prune_colors: bool,
+    /// Keep PNG colors as stored instead of converting files with a gAMA
+    /// chunk other than sRGB to sRGB
+    #[structopt(long)]
+    no_color_management: bool,
+    /// Dither 16 bit per channel images down to 8 bits instead of rounding
+    #[structopt(long)]
+    dither_16bit: bool,
+    /// Map grayscale images onto a ramp of this many grays (2 to 256)
+    #[structopt(long)]
+    gray_levels: Option<usize>,
+    /// Spacing of the --gray-levels ramp: 1.0 for even steps in value, 2.2
+    /// for even steps in linear light
+    #[structopt(long, default_value = "1.0")]
+    gray_gamma: f64,
+    /// Put white at index 0 of the --gray-levels ramp instead of black
+    #[structopt(long, requires = "gray-levels")]
+    gray_white_first: bool,
+    /// Write exactly one CMAP entry per color the bitplanes address, as some
+    /// loaders expect. Otherwise the whole palette is written, however long
+    #[structopt(long)]
+    pad_cmap: bool,
+    /// Color of the entries --pad-cmap adds, as #RRGGBB
+    #[structopt(long, default_value = "#000000")]
+    cmap_fill: iffimage::Color,
+    /// Leave out the CMAP, for programs setting the palette at runtime
+    #[structopt(long, conflicts_with = "pad-cmap")]
+    no_cmap: bool,
+    /// Write only the BMHD and BODY chunks, leaving out the CMAP and CAMG
+    #[structopt(long, conflicts_with = "pad-cmap")]
+    minimal: bool,
+    /// Byte budget for all outputs together, like 880K for a floppy. Going
+    /// over fails once everything is converted, use --dry-run to check first
+    #[structopt(long)]
+    max_size: Option<ByteSize>,
+    /// Only warn when --max-size is exceeded
+    #[structopt(long, requires = "max-size")]
+    warn_size: bool,
+    /// Embed the contents of a file as an extra chunk before the BODY, given
+    /// as ID:path. Can be repeated
+    #[structopt(long = "chunk", number_of_values = 1)]
+    chunks: Vec<chunkedit::ChunkFile>,
+    /// Like --chunk, but the chunk goes after the BODY
+    #[structopt(long = "chunk-after-body", number_of_values = 1)]
+    chunks_after_body: Vec<chunkedit::ChunkFile>,
+    /// Print a JSON report line per file to stdout
+    #[structopt(long)]
+    json: bool,
+    /// Show details of every conversion stage, -vv for even more
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Only report errors through the exit code
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print a report for every file. colors lists the pixel count of each
+    /// palette entry and flags unused and near-duplicate ones
+    #[structopt(long)]
+    report: Option<report::ReportKind>,
+    /// Only print the palette of every input as color swatches, IFF files
+    /// included, instead of converting
+    #[structopt(long)]
+    show_palette: bool,
+    /// Do the whole conversion but only report what would be written
+    #[structopt(long, conflicts_with = "watch")]
+    dry_run: bool,
+    /// Byte-identical output for identical inputs and options. This is
+    /// always the case, the flag is accepted for scripts asking for it
+    #[structopt(long)]
+    #[allow(dead_code)] // nothing to switch on
+    deterministic: bool,
+    /// Never show the batch progress bar, which is only drawn on a terminal
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// Names and content hashes of --chunk files, so editing one counts as a
+/// settings change without putting its bytes into the manifest
+fn chunk_settings(chunks: &[chunkedit::ChunkFile]) -> Vec<String> {
+    chunks
+        .iter()
+        .map(|chunk| {
+            format!(
+                "{}:{}:{:016x}",
+                String::from_utf8_lossy(&chunk.id.0),
+                chunk.path.display(),
+                cache::fnv1a(&chunk.data, cache::FNV_OFFSET)
+            )
+        })
+        .collect()
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let opt = Opt::from_args();
-    let iff = iffimage::IffImage::from_png_file(opt.infile)?;
-    let mut buffer = File::create(opt.outfile)?;
-    iff.write(&mut buffer)?;
+impl Opt {
+    /// Parses the command line on top of the selected profile. Inputs with
+    /// overrides in the config file get their own options
+    fn parse() -> Result<Opt, Box<dyn Error>> {
+        let args = env::args_os().collect::<Vec<_>>();
+        let opt = Opt::from_iter(&args);
+        let path = match &opt.config {
+            Some(path) => path.clone(),
+            None if Path::new(config::CONFIG_FILE).exists() => PathBuf::from(config::CONFIG_FILE),
+            None if opt.profile.is_some() => {
+                return Err(From::from(format!(
+                    "--profile needs a config file, but {} does not exist",
+                    config::CONFIG_FILE
+                )))
+            }
+            None => return Ok(opt),
+        };
+        let config = config::Config::load(&path)?;
 
+        let profile_args = match &opt.profile {
+            Some(name) => config.profile_args(name)?,
+            None => &[],
+        };
+        let with_args = |extra: &[OsString]| {
+            let args = args[..1]
+                .iter()
+                .chain(profile_args)
+                .chain(extra)
+                .chain(&args[1..]);
+            Opt::from_iter_safe(args)
+        };
+        let mut opt = with_args(&[])?;
+        for (file, file_args) in config.file_overrides() {
+            opt.overrides
+                .push((file.to_path_buf(), with_args(file_args)?));
+        }
+        Ok(opt)
+    }
+
+    /// The options for converting `infile`
+    fn for_file(&self, infile: &Path) -> &Opt {
+        self.overrides
+            .iter()
+            .find(|(file, _)| config::matches(infile, file))
+            .map_or(self, |(_, opt)| opt)
+    }
+
+    /// Everything that influences the produced file, for the incremental cache
+    /// and the manifest
+    fn settings(&self) -> String {
+        format!(
+            "out_template={:?} crop={:?} scale={:?} resize={:?} frame={} layer={:?} colors={} input_raw={:?} raw_palette={:?} compress={:?} masking={:?} mask_from_color={:?} alpha_threshold={:?} strict_alpha={} auto_transparent={} pad_height={:?} align_rows={:?} pad_index={} preset={:?} prune_colors={} pad_cmap={} cmap_fill={:?} no_cmap={} minimal={} chunks={:?} chunks_after_body={:?} no_color_management={} dither_16bit={} gray_levels={:?} gray_gamma={} gray_white_first={} palette_order={} merge_colors={:?} sort_palette={} index_map={:?} remap={} policy={:?} camg={:?} camg_flags={:?} dither={:?} dither_strength={} serpentine={} dither_matrix={:?} color_metric={} preview_out={:?} preview_chipset={:?}",
+            self.out_template,
+            self.crop,
+            self.scale,
+            self.resize,
+            self.frame,
+            self.layer,
+            self.colors.0,
+            self.input_raw,
+            self.raw_palette,
+            self.compress,
+            self.masking,
+            self.mask_from_color,
+            self.alpha_threshold,
+            self.strict_alpha,
+            self.auto_transparent,
+            self.pad_height,
+            self.align_rows,
+            self.pad_index,
+            self.preset,
+            self.prune_colors,
+            self.pad_cmap,
+            self.cmap_fill,
+            self.no_cmap,
+            self.minimal,
+            chunk_settings(&self.chunks),
+            chunk_settings(&self.chunks_after_body),
+            self.no_color_management,
+            self.dither_16bit,
+            self.gray_levels,
+            self.gray_gamma,
+            self.gray_white_first,
+            self.palette_order,
+            self.merge_colors,
+            self.sort_palette,
+            self.index_map,
+            self.remap,
+            self.policy,
+            self.camg,
+            self.camg_flags,
+            self.dither,
+            self.dither_strength,
+            self.serpentine,
+            self.dither_matrix,
+            self.color_metric,
+            self.preview_out,
+            self.preview_chipset
+        )
+    }
+}
+
+/// Makes room for `outfile`. Existing files are only replaced with --force or
+/// when we wrote them ourselves (`previous`), --backup moves them aside
+fn clear_output(outfile: &Path, opt: &Opt, previous: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if opt.force || previous == Some(outfile) || !outfile.exists() {
+        return Ok(());
+    }
+    if opt.backup {
+        let mut backup = outfile.as_os_str().to_owned();
+        backup.push(".bak");
+        if !opt.dry_run {
+            fs::rename(outfile, backup)?;
+        }
+        return Ok(());
+    }
+    Err(From::from(format!(
+        "{} already exists. Use --force to overwrite it or --backup to keep a copy",
+        outfile.display()
+    )))
+}
+
+/// Writes `contents` to `output` once `clear_output` made room for it.
+/// Nothing is written with --dry-run
+fn write_output(
+    output: &Path,
+    contents: &[u8],
+    opt: &Opt,
+    previous: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    clear_output(output, opt, previous)?;
+    if !opt.dry_run {
+        fs::write(output, contents)?;
+    }
     Ok(())
 }
+
+/// Decodes `infile` and applies cropping, scaling and compression
+fn load(infile: &Path, opt: &Opt) -> Result<iffimage::IffImage, Box<dyn Error>> {
+    let opt = opt.for_file(infile);
+    let load_options = iffimage::LoadOptions {
+        frame: opt.frame,
+        layer: opt.layer.clone(),
+        mmap: opt.mmap,
+        max_colors: opt.colors.0,
+        palette_order: opt.palette_order,
+        color_management: !opt.no_color_management,
+        dither_16bit: opt.dither_16bit,
+        remap: opt.remap,
+        color_metric: opt.color_metric,
+        alpha: match (opt.alpha_threshold, opt.strict_alpha) {
+            (Some(threshold), _) => iffimage::AlphaMode::Threshold(threshold),
+            (None, true) => iffimage::AlphaMode::Strict,
+            (None, false) => iffimage::AlphaMode::Ignore,
+        },
+        ..Default::default()
+    };
+    let mut iff = match (&opt.input_raw, &opt.raw_palette) {
+        (Some(size), Some(palette)) => {
+            iffimage::IffImage::from_raw_files(infile, palette, size.width, size.height)?
+        }
+        _ => iffimage::IffImage::from_file(infile, &load_options)
+            .inspect_err(|e| report_unmapped(infile, e, opt))?,
+    };
+    debug!(
+        "{}: decoded {}x{} with {} colors",
+        infile.display(),
+        iff.width(),
+        iff.height(),
+        iff.num_colors()
+    );
+    for warning in iff.warnings().iter().filter(|w| w.is_lossy()) {
+        allow_lossy(opt, warning.to_string())?;
+    }
+    if let Some(distance) = opt.merge_colors {
+        let colors = iff.num_colors();
+        iff = iff.merge_colors(distance);
+        if iff.num_colors() < colors {
+            allow_lossy(opt, "merging near duplicate colors".to_string())?;
+        }
+        debug!(
+            "{}: merged {} near duplicate colors",
+            infile.display(),
+            colors - iff.num_colors()
+        );
+    }
+    if let Some(crop) = &opt.crop {
+        iff = iff.crop(crop.x, crop.y, crop.width, crop.height)?;
+    }
+    if let Some(scale) = &opt.scale {
+        let scaled = |v: u16| {
+            (v as u64)
+                .checked_mul(scale.numerator as u64)
+                .map_or(u64::MAX, |v| v / scale.denominator as u64)
+        };
+        let (width, height) = (scaled(iff.width()), scaled(iff.height()));
+        if width > u16::MAX as u64 || height > u16::MAX as u64 {
+            let reported = |v: u64| v.min(u32::MAX as u64) as u32;
+            return Err(From::from(iffimage::IffConvertError::TooLarge(
+                reported(width),
+                reported(height),
+            )));
+        }
+        allow_lossy(opt, format!("scaling to {}x{}", width, height))?;
+        iff = iff.resize(width as u16, height as u16)?;
+    }
+    if let Some(size) = &opt.resize {
+        allow_lossy(opt, format!("resizing to {}x{}", size.width, size.height))?;
+        iff = iff.resize(size.width, size.height)?;
+    }
+    if !(0.0..=100.0).contains(&opt.dither_strength) {
+        return Err(From::from("--dither-strength takes 0 to 100 percent"));
+    }
+    let method = match (opt.dither, &opt.dither_matrix) {
+        (Some(method), _) => Some(method),
+        (None, Some(_)) => Some(dither::DitherMethod::Ordered),
+        (None, None) => None,
+    };
+    let dithering = |default: dither::DitherMethod| dither::Dither {
+        method: method.unwrap_or(default),
+        strength: opt.dither_strength / 100.0,
+        serpentine: opt.serpentine,
+        matrix: opt.dither_matrix.clone().unwrap_or_default(),
+        metric: opt.color_metric,
+    };
+    if let Some(levels) = opt.gray_levels {
+        if !(2..=256).contains(&levels) || opt.gray_gamma <= 0.0 {
+            return Err(From::from(
+                "--gray-levels takes 2 to 256 levels and --gray-gamma a positive gamma",
+            ));
+        }
+        if iff.is_grayscale() {
+            let ramp = iffimage::ColorMap::gray_curve(levels, opt.gray_gamma, opt.gray_white_first);
+            allow_lossy(opt, format!("remapping to {} gray levels", levels))?;
+            iff = iff.dither_with(&ramp, &dithering(dither::DitherMethod::None));
+        } else {
+            warn!("{}: not grayscale, --gray-levels ignored", infile.display());
+        }
+    }
+    if let Some(preset) = opt.preset {
+        allow_lossy(opt, format!("dithering to the {} palette", preset))?;
+        iff = iff.dither_with(
+            &preset.palette(),
+            &dithering(dither::DitherMethod::FloydSteinberg),
+        );
+    }
+    if opt.pad_height.is_some() || opt.align_rows.is_some() {
+        let round_up = |v: u16, multiple: Option<u16>| match multiple {
+            Some(multiple) if multiple > 0 => {
+                (v as u32).div_ceil(multiple as u32) * multiple as u32
+            }
+            _ => v as u32,
+        };
+        let width = round_up(iff.width(), opt.align_rows);
+        let height = round_up(iff.height(), opt.pad_height);
+        if width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(From::from(iffimage::IffConvertError::TooLarge(
+                width, height,
+            )));
+        }
+        if (width, height) != (iff.width() as u32, iff.height() as u32) {
+            allow_lossy(opt, format!("padding to {}x{}", width, height))?;
+        }
+        iff = iff.pad(width as u16, height as u16, opt.pad_index)?;
+    }
+    if opt.auto_transparent {
+        let index = iff.background_index();
+        iff.set_transparent_color(index);
+        let color = &iff.palette().colors[index as usize];
+        info!(
+            "{}: background color {} (#{:02x}{:02x}{:02x}) is transparent",
+            infile.display(),
+            index,
+            color.r,
+            color.g,
+            color.b
+        );
+    }
+    if let Some(masking) = opt.masking {
+        iff.set_masking(masking);
+    }
+    if let Some(key) = opt.mask_from_color {
+        iff.set_mask_from_color(key)?;
+    }
+    if let Some(bilevel) = iff.bilevel() {
+        debug!("{}: black and white, using one bitplane", infile.display());
+        iff = bilevel;
+    }
+    if opt.prune_colors {
+        iff = iff.prune_colors();
+    }
+    iff = iff.sort_palette(opt.sort_palette);
+    match &opt.index_map {
+        // pinned indices have to stay reachable even if no pixel uses them
+        Some(index_map) => iff = iff.pin_colors(&index_map.pins)?,
+        None => iff.minimize_bitplanes(),
+    }
+    if opt.pad_cmap {
+        iff.pad_cmap(&opt.cmap_fill);
+    }
+    if opt.no_cmap || opt.minimal {
+        iff.set_cmap_chunk(false);
+    }
+    if opt.minimal {
+        iff.set_camg(None);
+    }
+    if opt.camg.is_some() || opt.camg_flags.is_some() {
+        let mode = opt.camg.map_or(0, |m| m.0) | opt.camg_flags.map_or(0, |m| m.0);
+        let planes = match mode {
+            m if m & camg::CAMG_HAM != 0 => &[6, 8][..],
+            m if m & camg::CAMG_EXTRA_HALFBRITE != 0 => &[6][..],
+            _ => &[],
+        };
+        if !planes.is_empty() && !planes.contains(&iff.bitplanes()) {
+            warn!(
+                "{}: CAMG {:#010x} does not suit {} bitplanes",
+                infile.display(),
+                mode,
+                iff.bitplanes()
+            );
+        }
+        iff.set_camg(Some(mode));
+    }
+    for (chunks, placement) in [
+        (&opt.chunks, iffimage::ChunkPlacement::BeforeBody),
+        (&opt.chunks_after_body, iffimage::ChunkPlacement::AfterBody),
+    ] {
+        for chunk in chunks {
+            iff.add_chunk(chunk.id.0, chunk.data.clone(), placement);
+        }
+    }
+    iff.set_compression(opt.compress);
+    Ok(iff)
+}
+
+/// Converts `infile` and reports what was written. The output path differs
+/// from `outfile` when --out-template names it. `previous` is the file an
+/// earlier conversion of `infile` wrote, which may be replaced without --force
+fn convert(
+    infile: &Path,
+    outfile: &Path,
+    opt: &Opt,
+    previous: Option<&Path>,
+    progress: &mut dyn FnMut(iffimage::Progress),
+) -> Result<report::Conversion, Box<dyn Error>> {
+    let opt = opt.for_file(infile);
+    let iff = load(infile, opt)?;
+    let outfile = match &opt.out_template {
+        Some(template) => {
+            let stem = infile.file_stem().unwrap_or_default().to_string_lossy();
+            outfile.with_file_name(template.render(&stem, &iff))
+        }
+        None => outfile.to_path_buf(),
+    };
+    clear_output(&outfile, opt, previous)?;
+    // converting the same input again replaces the preview written before
+    let preview_previous = previous.and(opt.preview_out.as_deref());
+    if let Some(path) = &opt.preview_out {
+        clear_output(path, opt, preview_previous)?;
+    }
+    if !opt.dry_run {
+        if let Some(parent) = outfile.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut buffer = BufWriter::new(File::create(&outfile)?);
+        iff.write_with_progress(&mut buffer, progress, &Default::default())?;
+        // flushing on drop would swallow a failing last write
+        buffer.flush()?;
+        debug!("{}: written", outfile.display());
+        if let Some(path) = &opt.preview_out {
+            let chipset = opt
+                .preview_chipset
+                .unwrap_or_else(|| emulate::Chipset::for_image(&iff));
+            let mut png = vec![];
+            emulate::render(&iff, chipset).to_png(&mut png)?;
+            write_output(path, &png, opt, preview_previous)?;
+            debug!(
+                "{}: preview written to {}",
+                infile.display(),
+                path.display()
+            );
+        }
+    }
+    if log_enabled!(Level::Debug) {
+        let sizes = iff.packed_plane_sizes();
+        let plane_size = iff.raw_body_size() / sizes.len().max(1);
+        for (bpl, size) in sizes.into_iter().enumerate() {
+            let plane = match bpl < iff.bitplanes() as usize {
+                true => format!("bitplane {}", bpl),
+                false => "mask".to_string(),
+            };
+            debug!(
+                "{}: {}: {} of {} bytes with byterun1",
+                infile.display(),
+                plane,
+                size,
+                plane_size
+            );
+        }
+    }
+
+    Ok(report::Conversion::new(&iff, outfile, opt.dry_run))
+}
+
+/// Refuses a step losing information, `what`, under --policy strict
+fn allow_lossy(opt: &Opt, what: String) -> Result<(), exitcode::LossyOperation> {
+    match opt.policy {
+        Policy::Lossy => Ok(()),
+        Policy::Strict => Err(exitcode::LossyOperation(what)),
+    }
+}
+
+/// Lists the first --report-pixels pixels a failed conversion could not map
+/// to the palette and writes the --diagnostics-dir picture
+fn report_unmapped(infile: &Path, error: &iffimage::IffLoadError, opt: &Opt) {
+    let report = match error {
+        iffimage::IffLoadError::Convert(iffimage::IffConvertError::UnmappedPixels(report)) => {
+            report
+        }
+        _ => return,
+    };
+    for offender in report.offenders().take(opt.report_pixels) {
+        let [r, g, b] = offender.color;
+        warn!(
+            "{}: {},{} is #{:02x}{:02x}{:02x}",
+            infile.display(),
+            offender.x,
+            offender.y,
+            r,
+            g,
+            b
+        );
+    }
+    if let Some(dir) = &opt.diagnostics_dir {
+        let mut name = infile.file_stem().unwrap_or_default().to_os_string();
+        name.push(".png");
+        let path = dir.join(name);
+        let mut png = vec![];
+        let written = report
+            .highlight()
+            .to_png(&mut png)
+            .map_err(|e| e.to_string())
+            .and_then(|()| write_output(&path, &png, opt, None).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => info!(
+                "{}: offending pixels shown in {}",
+                infile.display(),
+                path.display()
+            ),
+            Err(e) => warn!(
+                "{}: can't write {}: {}",
+                infile.display(),
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Tells the user about a finished conversion and its warnings, and prints
+/// the requested reports to stdout
+fn report_conversion(opt: &Opt, infile: &Path, conversion: &report::Conversion) {
+    if conversion.dry_run {
+        info!(
+            "{} -> {} (dry run): {}x{}, {} bitplanes, {} colors, {} bytes",
+            infile.display(),
+            conversion.output.display(),
+            conversion.width,
+            conversion.height,
+            conversion.bitplanes,
+            conversion.colors,
+            conversion.output_size
+        );
+    } else if conversion.compression == iffimage::Compression::None {
+        info!(
+            "{} -> {}: BODY {} bytes uncompressed",
+            infile.display(),
+            conversion.output.display(),
+            conversion.body_size
+        );
+    } else {
+        info!(
+            "{} -> {}: BODY {} of {} bytes packed ({:.1}%)",
+            infile.display(),
+            conversion.output.display(),
+            conversion.body_size,
+            conversion.raw_body_size,
+            conversion.compression_ratio() * 100.0
+        );
+    }
+    for warning in &conversion.warnings {
+        warn!("{}: {}", infile.display(), warning);
+    }
+    if opt.json {
+        println!("{}", report::converted_json(infile, conversion));
+    }
+    if opt.report == Some(report::ReportKind::Colors) {
+        print!("{}", report::colors_table(infile, conversion));
+    }
+}
+
+/// Expands glob patterns in-process so they also work without a shell doing
+/// it (e.g. Windows cmd). Patterns have to match at least one file
+fn expand_input(input: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let pattern = input.to_string_lossy();
+    if !pattern.contains(['*', '?', '[']) || input.exists() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    let mut matches = vec![];
+    for entry in glob::glob(&pattern)? {
+        let path = entry?;
+        if path.is_file() {
+            matches.push(path);
+        }
+    }
+    if matches.is_empty() {
+        return Err(From::from(format!(
+            "Pattern {:?} matched no files",
+            pattern
+        )));
+    }
+    Ok(matches)
+}
+
+/// Recursively collects all convertible files below `dir`, sorted by path
+fn walk_dir(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    let mut files = vec![];
+    for path in entries {
+        if path.is_dir() {
+            files.extend(walk_dir(&path)?);
+        } else if iffimage::is_supported_file(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Pairs every input with the file it gets converted to
+fn jobs(opt: &Opt) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+    match &opt.out_dir {
+        Some(out_dir) => {
+            let mut jobs = vec![];
+            for input in &opt.files {
+                if input.is_dir() {
+                    // mirror the directory structure below the input directory
+                    for infile in walk_dir(input)? {
+                        let relative = infile.strip_prefix(input)?;
+                        let outfile = out_dir.join(relative).with_extension("iff");
+                        jobs.push((infile, outfile));
+                    }
+                    continue;
+                }
+                for infile in expand_input(input)? {
+                    let outfile = out_dir
+                        .join(infile.file_stem().unwrap_or_default())
+                        .with_extension("iff");
+                    jobs.push((infile, outfile));
+                }
+            }
+            Ok(jobs)
+        }
+        None => match opt.files.as_slice() {
+            [input] => expand_input(input)?
+                .into_iter()
+                .map(|infile| {
+                    let outfile = infile.with_extension("iff");
+                    if outfile == infile {
+                        return Err(From::from(format!(
+                            "Refusing to overwrite the input {:?}. Pass an output file",
+                            infile
+                        )));
+                    }
+                    Ok((infile, outfile))
+                })
+                .collect(),
+            [input, outfile] => match expand_input(input)?.as_slice() {
+                [infile] => Ok(vec![(infile.clone(), outfile.clone())]),
+                _ => Err(From::from(format!(
+                    "Pattern {:?} matched several files. Use --out-dir to convert them all",
+                    input
+                ))),
+            },
+            _ => Err(From::from(
+                "Expected an input and an output file, or --out-dir for several inputs",
+            )),
+        },
+    }
+}
+
+/// Converts every job, keeping going after failures
+fn convert_batch(opt: &Opt, jobs: &[(PathBuf, PathBuf)]) -> Result<(), Box<dyn Error>> {
+    let mut cache = match (&opt.out_dir, opt.incremental) {
+        (Some(out_dir), true) => Some(cache::ConversionCache::load(out_dir)),
+        _ => None,
+    };
+
+    // the bar would be torn apart by per-stage log lines
+    let show_progress =
+        !opt.no_progress && !opt.quiet && opt.verbose == 0 && progress::is_interactive();
+    let mut progress = progress::Progress::new(jobs.len(), show_progress);
+    let mut manifest = opt.manifest.as_ref().map(|_| manifest::Manifest::default());
+    let mut adf = match &opt.into_adf {
+        Some(target) => Some(adf::Adf::load(&target.disk)?),
+        None => None,
+    };
+
+    let mut failed = 0;
+    let mut code = exitcode::FAILURE;
+    let mut total_size = 0;
+    for (infile, outfile) in jobs {
+        progress.start(infile);
+        let result = match cache.as_mut() {
+            Some(cache) => fs::read(infile).map_err(From::from).and_then(|contents| {
+                let settings = opt.for_file(infile).settings();
+                let settings_hash = cache::fnv1a(settings.as_bytes(), cache::FNV_OFFSET);
+                let hash = cache::fnv1a(&contents, settings_hash);
+                match cache.fresh_output(infile, hash) {
+                    Some(output) if output.exists() => {
+                        progress.clear();
+                        info!("{}: unchanged", infile.display());
+                        if opt.json {
+                            println!("{}", report::unchanged_json(infile, output));
+                        }
+                        if let Some(manifest) = manifest.as_mut() {
+                            manifest.add(infile, output, settings)?;
+                        }
+                        if let (Some(adf), Some(target)) = (adf.as_mut(), &opt.into_adf) {
+                            adf.write_file(&target.path_for(output), &fs::read(output)?)?;
+                        }
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+                let conversion = convert(infile, outfile, opt, cache.output(infile), &mut |p| {
+                    progress.update(infile, p)
+                })?;
+                if !opt.dry_run {
+                    cache.update(infile, hash, &conversion.output);
+                }
+                Ok(Some(conversion))
+            }),
+            None => convert(infile, outfile, opt, None, &mut |p| {
+                progress.update(infile, p)
+            })
+            .map(Some),
+        };
+        progress.clear();
+        progress.finish_one();
+        let result = result.and_then(|conversion| {
+            if let (Some(manifest), Some(conversion)) = (manifest.as_mut(), &conversion) {
+                let settings = opt.for_file(infile).settings();
+                manifest.add(infile, &conversion.output, settings)?;
+            }
+            if let (Some(adf), Some(target), Some(conversion)) =
+                (adf.as_mut(), &opt.into_adf, &conversion)
+            {
+                let output = &conversion.output;
+                adf.write_file(&target.path_for(output), &fs::read(output)?)?;
+            }
+            Ok(conversion)
+        });
+        if let Ok(Some(conversion)) = &result {
+            total_size += conversion.output_size;
+            report_conversion(opt, infile, conversion);
+        }
+        if let Err(e) = result {
+            error!(
+                "{}: {}",
+                infile.display(),
+                report::error_message(e.as_ref())
+            );
+            if opt.json {
+                println!(
+                    "{}",
+                    report::failed_json(infile, &report::error_message(e.as_ref()))
+                );
+            }
+            if failed == 0 {
+                code = exitcode::for_error(e.as_ref());
+            }
+            failed += 1;
+        }
+    }
+    if let (Some(cache), false) = (&cache, opt.dry_run) {
+        cache.save()?;
+    }
+    if let (Some(manifest), Some(path)) = (&manifest, &opt.manifest) {
+        manifest.save(path)?;
+        info!(
+            "{}: manifest of {} files",
+            path.display(),
+            jobs.len() - failed
+        );
+    }
+    if let (Some(adf), Some(target)) = (&adf, &opt.into_adf) {
+        adf.save(&target.disk)?;
+        info!(
+            "{}: {} blocks free",
+            target.disk.display(),
+            adf.free_blocks()
+        );
+    }
+    if opt.dry_run {
+        info!(
+            "{} of {} files would be written, {} bytes in total",
+            jobs.len() - failed,
+            jobs.len(),
+            total_size
+        );
+    }
+
+    if failed > 0 {
+        return Err(From::from(exitcode::BatchError {
+            failed,
+            total: jobs.len(),
+            code,
+        }));
+    }
+    check_budget(opt, total_size)
+}
+
+/// Compares the bytes written against --max-size, failing or with
+/// --warn-size only warning when they exceed it
+fn check_budget(opt: &Opt, size: u64) -> Result<(), Box<dyn Error>> {
+    match &opt.max_size {
+        Some(ByteSize(max)) if size > *max => {
+            let over = exitcode::OverBudget { size, max: *max };
+            if !opt.warn_size {
+                return Err(From::from(over));
+            }
+            warn!("{}", over);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Converts all inputs into a single CAT, or a LIST if `list` is set
+fn bundle(output: &Path, list: bool, opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let mut inputs = vec![];
+    for input in &opt.files {
+        if input.is_dir() {
+            inputs.extend(walk_dir(input)?);
+        } else {
+            inputs.extend(expand_input(input)?);
+        }
+    }
+
+    let mut forms = vec![];
+    let mut failed = 0;
+    let mut code = exitcode::FAILURE;
+    for infile in &inputs {
+        match load(infile, opt.for_file(infile)) {
+            Ok(iff) => {
+                info!(
+                    "{}: {}x{}, {} colors",
+                    infile.display(),
+                    iff.width(),
+                    iff.height(),
+                    iff.num_colors()
+                );
+                forms.push(iff.to_form());
+            }
+            Err(e) => {
+                error!(
+                    "{}: {}",
+                    infile.display(),
+                    report::error_message(e.as_ref())
+                );
+                if failed == 0 {
+                    code = exitcode::for_error(e.as_ref());
+                }
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(From::from(exitcode::BatchError {
+            failed,
+            total: inputs.len(),
+            code,
+        }));
+    }
+
+    let container = if list {
+        iff::Form::list(b"ILBM", forms, &[*b"CMAP"])
+    } else {
+        iff::Form::cat(b"ILBM", forms)
+    };
+    check_budget(opt, container.size() as u64)?;
+    clear_output(output, opt, None)?;
+    if !opt.dry_run {
+        let mut buffer = BufWriter::new(File::create(output)?);
+        container.write(&mut buffer)?;
+        buffer.flush()?;
+    }
+    info!(
+        "{}: {} images, {} bytes{}",
+        output.display(),
+        inputs.len(),
+        container.size(),
+        if opt.dry_run { " (dry run)" } else { "" }
+    );
+    Ok(())
+}
+
+/// Polls the inputs and reconverts whenever one of them is modified. Runs
+/// until interrupted
+fn watch(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let mut seen = HashMap::new();
+    let mut written = HashMap::new();
+    info!("Watching for changes, press Ctrl-C to stop");
+    loop {
+        for (infile, outfile) in jobs(opt)? {
+            let modified = match fs::metadata(&infile).and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                // deleted or being replaced, try again next round
+                Err(_) => continue,
+            };
+            if seen.get(&infile) == Some(&modified) {
+                continue;
+            }
+            seen.insert(infile.clone(), modified);
+            let previous = written.get(&infile).map(PathBuf::as_path);
+            match convert(&infile, &outfile, opt, previous, &mut |_| {}) {
+                Ok(conversion) => {
+                    report_conversion(opt, &infile, &conversion);
+                    written.insert(infile, conversion.output);
+                }
+                Err(e) => {
+                    error!(
+                        "{}: {}",
+                        infile.display(),
+                        report::error_message(e.as_ref())
+                    );
+                    if opt.json {
+                        println!(
+                            "{}",
+                            report::failed_json(&infile, &report::error_message(e.as_ref()))
+                        );
+                    }
+                }
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Converts `file` in memory with the usual options and draws it
+fn preview(file: &Path, columns: u16, opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let iff = load(file, opt)?;
+    let (width, height) = preview::fit(iff.width(), iff.height(), columns);
+    let iff = iff.resize(width, height)?;
+    print!("{}", preview::half_blocks(&iff));
+    Ok(())
+}
+
+fn show_palettes(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    for input in &opt.files {
+        for file in expand_input(input)? {
+            let iff = load(&file, opt)?;
+            println!("{}:", file.display());
+            print!("{}", preview::palette_swatches(&iff));
+        }
+    }
+    Ok(())
+}
+
+fn extract_palette(input: &Path, output: &Path, opt: &Opt) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(input)?;
+    let cmap = if data.starts_with(b"FORM") {
+        palette::cmap_from_iff(&data)?
+    } else {
+        load(input, opt)?.palette().clone()
+    };
+    let name = input.file_stem().unwrap_or_default().to_string_lossy();
+    let format = palette::PaletteFormat::from_path(output);
+    write_output(output, &palette::encode(&cmap, format, &name), opt, None)?;
+    Ok(())
+}
+
+fn run(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    match &opt.command {
+        Some(Command::Preview { file, columns }) => return preview(file, *columns, opt),
+        Some(Command::Edit {
+            file,
+            set,
+            delete,
+            output,
+        }) => {
+            let edited = chunkedit::edit_form(&fs::read(file)?, set, delete)?;
+            // without --output the file is edited in place, as asked
+            let output = output.as_deref().unwrap_or(file);
+            write_output(output, &edited, opt, Some(file))?;
+            return Ok(());
+        }
+        Some(Command::Rebody { iff, image, output }) => {
+            let rebodied = chunkedit::rebody(&fs::read(iff)?, &load(image, opt)?)?;
+            let output = output.as_deref().unwrap_or(iff);
+            write_output(output, &rebodied, opt, Some(iff))?;
+            return Ok(());
+        }
+        Some(Command::DualPlayfield {
+            image,
+            pf1,
+            pf2,
+            playfield_colors,
+            pf2_front,
+        }) => {
+            let iff = load(image, opt)?;
+            let (front, back) = playfield::split(&iff, *playfield_colors, *pf2_front)?;
+            // both or neither
+            clear_output(pf1, opt, None)?;
+            clear_output(pf2, opt, None)?;
+            write_output(pf1, &front.to_bytes(), opt, None)?;
+            write_output(pf2, &back.to_bytes(), opt, None)?;
+            info!(
+                "{} -> {} + {}: CAMG {:#010x}, playfield 2 colors start at {}",
+                image.display(),
+                pf1.display(),
+                pf2.display(),
+                front.camg().unwrap_or(0),
+                playfield_colors
+            );
+            return Ok(());
+        }
+        Some(Command::Sprite {
+            image,
+            output,
+            sprite_width,
+            vstart,
+            hstart,
+            vstop,
+            no_terminator,
+            attached,
+        }) => {
+            let options = sprite::SpriteOptions {
+                width: *sprite_width,
+                vstart: *vstart,
+                hstart: *hstart,
+                vstop: *vstop,
+                terminate: !no_terminator,
+            };
+            let iff = load(image, opt)?;
+            let data = if *attached {
+                sprite::encode_attached(&iff, &options)?
+            } else {
+                sprite::encode(&iff, &options)?
+            };
+            write_output(output, &data, opt, None)?;
+            return Ok(());
+        }
+        Some(Command::AtariSt { image, output }) => {
+            let iff = load(image, opt)?;
+            write_output(output, &atarist::encode(&iff)?, opt, None)?;
+            info!(
+                "{} -> {}: {} bitplanes",
+                image.display(),
+                output.display(),
+                atarist::st_planes(&iff)?
+            );
+            return Ok(());
+        }
+        Some(Command::Degas {
+            image,
+            output,
+            resolution,
+        }) => {
+            let iff = load(image, opt)?;
+            let from_extension = || {
+                let ext = output.extension()?.to_str()?;
+                ext.parse::<atarist::Resolution>().ok()
+            };
+            let resolution = match resolution.or_else(from_extension) {
+                Some(resolution) => resolution,
+                None => atarist::Resolution::for_image(&iff)?,
+            };
+            write_output(output, &atarist::degas(&iff, resolution)?, opt, None)?;
+            info!(
+                "{} -> {}: {} resolution",
+                image.display(),
+                output.display(),
+                resolution
+            );
+            return Ok(());
+        }
+        Some(Command::Rgb {
+            image,
+            output,
+            format,
+        }) => {
+            let encoded = rgbn::encode(&load(image, opt)?, *format);
+            write_output(output, &encoded, opt, None)?;
+            return Ok(());
+        }
+        Some(Command::Ham {
+            image,
+            output,
+            mode,
+            ham_base,
+            heat_map,
+        }) => {
+            let source = load(image, opt)?;
+            let base = match ham_base {
+                Some(path) => {
+                    palette::decode(&fs::read(path)?, palette::PaletteFormat::from_path(path))?
+                }
+                None => ham::base_palette(&source, *mode)?,
+            };
+            let encoded = ham::encode(&source, *mode, &base)?;
+            info!(
+                "{} -> {}: {}",
+                image.display(),
+                output.display(),
+                ham::metrics(&source, &encoded.shown)
+            );
+            if let Some(path) = heat_map {
+                clear_output(path, opt, None)?;
+            }
+            write_output(output, &encoded.image.to_bytes(), opt, None)?;
+            if let Some(path) = heat_map {
+                let mut png = vec![];
+                ham::heat_map(&source, &encoded.shown).to_png(&mut png)?;
+                write_output(path, &png, opt, None)?;
+            }
+            return Ok(());
+        }
+        Some(Command::Icon {
+            image,
+            output,
+            selected,
+            icon_type,
+            default_tool,
+            tool_type,
+            stack,
+        }) => {
+            let options = icon::IconOptions {
+                icon_type: *icon_type,
+                default_tool: default_tool.clone(),
+                tool_types: tool_type.clone(),
+                stack_size: *stack,
+            };
+            let selected = selected.as_ref().map(|path| load(path, opt)).transpose()?;
+            let info = icon::encode(&load(image, opt)?, selected.as_ref(), &options)?;
+            write_output(output, &info, opt, None)?;
+            return Ok(());
+        }
+        Some(Command::Font {
+            sheet,
+            output,
+            cell,
+            chars,
+            baseline,
+            spacing,
+            fixed,
+        }) => {
+            let chars = match chars {
+                Some(path) => fs::read_to_string(path)?.replace(['\r', '\n'], ""),
+                None => font::ASCII.to_string(),
+            };
+            let name = output
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let options = font::FontOptions {
+                name: name.clone(),
+                cell_width: cell.width,
+                cell_height: cell.height,
+                chars,
+                baseline: *baseline,
+                spacing: *spacing,
+                fixed: *fixed,
+            };
+            let (contents, data) = font::encode(&load(sheet, opt)?, &options)?;
+            let dir = output.with_file_name(&name);
+            let size = dir.join(cell.height.to_string());
+            clear_output(output, opt, None)?;
+            clear_output(&size, opt, None)?;
+            if !opt.dry_run {
+                fs::create_dir_all(&dir)?;
+            }
+            write_output(output, &contents, opt, None)?;
+            write_output(&size, &data, opt, None)?;
+            return Ok(());
+        }
+        Some(Command::Diff { a, b }) => {
+            let result = diff::diff(&fs::read(a)?, &fs::read(b)?)?;
+            print!("{}", result);
+            if !result.is_identical() {
+                return Err(From::from(format!(
+                    "{} and {} differ",
+                    a.display(),
+                    b.display()
+                )));
+            }
+            return Ok(());
+        }
+        Some(Command::Palette(PaletteCommand::Extract { input, output })) => {
+            return extract_palette(input, output, opt);
+        }
+        None => {}
+    }
+    if opt.show_palette {
+        return show_palettes(opt);
+    }
+    if let Some(output) = &opt.cat {
+        return bundle(output, false, opt);
+    }
+    if let Some(output) = &opt.list {
+        return bundle(output, true, opt);
+    }
+    let jobs = jobs(opt)?;
+    if let (Some(out_dir), false) = (&opt.out_dir, opt.dry_run) {
+        fs::create_dir_all(out_dir)?;
+    }
+
+    if opt.watch {
+        watch(opt)
+    } else if opt.out_dir.is_some()
+        || opt.manifest.is_some()
+        || opt.into_adf.is_some()
+        || jobs.len() > 1
+    {
+        convert_batch(opt, &jobs)
+    } else {
+        let (infile, outfile) = &jobs[0];
+        match convert(infile, outfile, opt, None, &mut |_| {}) {
+            Ok(conversion) => {
+                report_conversion(opt, infile, &conversion);
+                check_budget(opt, conversion.output_size)
+            }
+            Err(e) => {
+                if opt.json {
+                    println!(
+                        "{}",
+                        report::failed_json(infile, &report::error_message(e.as_ref()))
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::parse().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(exitcode::FAILURE);
+    });
+    logger::init(opt.verbose, opt.quiet);
+    if let Err(e) = run(&opt) {
+        error!("{}", report::error_message(e.as_ref()));
+        process::exit(exitcode::for_error(e.as_ref()));
+    }
+}
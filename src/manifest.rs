@@ -0,0 +1,98 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Result as IoResult;
+use std::path::Path;
+
+/// Lowercase hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// One output file of a batch run
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub input: String,
+    pub output: String,
+    pub sha256: String,
+    pub size: u64,
+    /// The options the output was produced with
+    pub settings: String,
+}
+
+/// Checksums of all files a batch run produced or left unchanged, so asset
+/// pipelines can detect tampered or stale outputs
+#[derive(Debug, Default)]
+pub struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Hashes the written `output` of `input`
+    pub fn add(&mut self, input: &Path, output: &Path, settings: String) -> IoResult<()> {
+        let data = fs::read(output)?;
+        self.entries.push(Entry {
+            input: input.to_string_lossy().into_owned(),
+            output: output.to_string_lossy().into_owned(),
+            sha256: sha256_hex(&data),
+            size: data.len() as u64,
+            settings,
+        });
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Json<'a> {
+            version: &'a str,
+            files: &'a [Entry],
+        }
+        let json = Json {
+            version: env!("CARGO_PKG_VERSION"),
+            files: &self.entries,
+        };
+        let mut out =
+            serde_json::to_string_pretty(&json).expect("entries are plain strings and numbers");
+        out.push('\n');
+        out
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_reference_values() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let manifest = Manifest {
+            entries: vec![Entry {
+                input: "a.png".to_string(),
+                output: "out/a.iff".to_string(),
+                sha256: sha256_hex(b""),
+                size: 0,
+                settings: "none".to_string(),
+            }],
+        };
+        let json: serde_json::Value = serde_json::from_str(&manifest.to_json()).unwrap();
+        let file = &json["files"][0];
+        assert_eq!(file["input"], "a.png");
+        assert_eq!(file["output"], "out/a.iff");
+        assert_eq!(
+            file["sha256"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(file["size"], 0);
+        assert_eq!(file["settings"], "none");
+    }
+}
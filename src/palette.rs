@@ -0,0 +1,364 @@
+use crate::iff::form_chunks;
+use crate::iffimage::{Color, ColorMap, IffConvertError};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Palette file formats, picked by file extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaletteFormat {
+    /// GIMP palette (.gpl)
+    Gimp,
+    /// JASC / Paint Shop Pro palette (.pal)
+    Jasc,
+    /// Adobe color table, 256 RGB entries (.act)
+    Act,
+    /// Raw RGB triplets, as read by --raw-palette
+    Raw,
+}
+
+impl PaletteFormat {
+    pub fn from_path(path: &Path) -> PaletteFormat {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("gpl") => PaletteFormat::Gimp,
+            Some("pal") => PaletteFormat::Jasc,
+            Some("act") => PaletteFormat::Act,
+            _ => PaletteFormat::Raw,
+        }
+    }
+}
+
+/// Standard palettes to remap images onto
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    /// The 4 colors of Workbench 1.3: blue, white, black and orange
+    Workbench13,
+    /// The 8 colors of Workbench 3.1: gray, black, white, blue and the four
+    /// extra pens
+    Workbench31,
+}
+
+impl Preset {
+    pub fn palette(self) -> ColorMap {
+        let colors: &[[u8; 3]] = match self {
+            Preset::Workbench13 => &[
+                [0x00, 0x55, 0xaa],
+                [0xff, 0xff, 0xff],
+                [0x00, 0x00, 0x22],
+                [0xff, 0x88, 0x00],
+            ],
+            Preset::Workbench31 => &[
+                [0xaa, 0xaa, 0xaa],
+                [0x00, 0x00, 0x00],
+                [0xff, 0xff, 0xff],
+                [0x66, 0x88, 0xbb],
+                [0xee, 0x44, 0x44],
+                [0x55, 0xdd, 0x55],
+                [0x00, 0x44, 0xdd],
+                [0xee, 0x99, 0x00],
+            ],
+        };
+        ColorMap {
+            colors: colors.iter().map(|&[r, g, b]| Color { r, g, b }).collect(),
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "workbench13" => Ok(Preset::Workbench13),
+            "workbench31" => Ok(Preset::Workbench31),
+            _ => Err(format!(
+                "Unknown preset {:?}. Expected workbench13 or workbench31",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Preset::Workbench13 => "workbench13",
+            Preset::Workbench31 => "workbench31",
+        })
+    }
+}
+
+/// Colors pinned to palette indices, read from a TOML file of
+/// `"#RRGGBB" = index` lines:
+///
+/// ```toml
+/// "#000000" = 0   # background
+/// "#ffcc00" = 28  # HUD
+/// ```
+#[derive(Debug)]
+pub struct IndexMap {
+    pub path: PathBuf,
+    pub pins: Vec<(Color, u8)>,
+}
+
+impl IndexMap {
+    /// The pins of an index map file. A color can only have one index and
+    /// an index only one color
+    pub fn parse(text: &str) -> Result<Vec<(Color, u8)>, String> {
+        let table = text.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        let mut pins: Vec<(Color, u8)> = vec![];
+        for (key, value) in &table {
+            let color = key.parse::<Color>()?;
+            let index = value
+                .as_integer()
+                .and_then(|index| u8::try_from(index).ok())
+                .ok_or_else(|| format!("Index of {} is not a number from 0 to 255", key))?;
+            if let Some((other, _)) = pins.iter().find(|(_, i)| *i == index) {
+                return Err(format!(
+                    "{} and #{:02x}{:02x}{:02x} are both pinned to index {}",
+                    key, other.r, other.g, other.b, index
+                ));
+            }
+            if let Some((_, other)) = pins.iter().find(|(c, _)| *c == color) {
+                return Err(format!(
+                    "{} is pinned to both index {} and {}",
+                    key, other, index
+                ));
+            }
+            pins.push((color, index));
+        }
+        Ok(pins)
+    }
+}
+
+impl FromStr for IndexMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = fs::read_to_string(s).map_err(|e| format!("Can't read {:?}: {}", s, e))?;
+        let pins = IndexMap::parse(&text).map_err(|e| format!("{}: {}", s, e))?;
+        Ok(IndexMap {
+            path: PathBuf::from(s),
+            pins,
+        })
+    }
+}
+
+/// Reads just the CMAP of an ILBM, so palettes can be taken from images we
+/// could not decode otherwise (HAM, 24 bit)
+pub fn cmap_from_iff(data: &[u8]) -> Result<ColorMap, IffConvertError> {
+    let chunks = form_chunks(data, b"ILBM")?;
+    let cmap = chunks
+        .iter()
+        .find(|(id, _)| id == b"CMAP")
+        .ok_or(IffConvertError::NoPalette)?
+        .1;
+    if cmap.len() < 3 {
+        return Err(IffConvertError::EmptyPalette);
+    }
+    Ok(ColorMap {
+        colors: cmap
+            .chunks_exact(3)
+            .map(|c| Color {
+                r: c[0],
+                g: c[1],
+                b: c[2],
+            })
+            .collect(),
+    })
+}
+
+/// Reads a palette file in `format`, the counterpart of `encode`
+pub fn decode(data: &[u8], format: PaletteFormat) -> Result<ColorMap, IffConvertError> {
+    let color = |c: &[u8]| Color {
+        r: c[0],
+        g: c[1],
+        b: c[2],
+    };
+    let colors = match format {
+        PaletteFormat::Gimp | PaletteFormat::Jasc => {
+            let text = std::str::from_utf8(data)
+                .map_err(|_| IffConvertError::Malformed("palette is not text"))?;
+            let mut lines = text.lines().map(str::trim);
+            let header = match format {
+                PaletteFormat::Gimp => &["GIMP Palette"][..],
+                _ => &["JASC-PAL", "0100"][..],
+            };
+            if !header.iter().all(|h| lines.next() == Some(h)) {
+                return Err(IffConvertError::Malformed("unknown palette header"));
+            }
+            let mut colors = vec![];
+            for line in lines {
+                let values = line
+                    .split_whitespace()
+                    .take(3)
+                    .map(str::parse::<u8>)
+                    .collect::<Result<Vec<_>, _>>();
+                match values {
+                    Ok(rgb) if rgb.len() == 3 => colors.push(color(&rgb)),
+                    // names, comments and the JASC color count
+                    _ => continue,
+                }
+            }
+            colors
+        }
+        PaletteFormat::Act => {
+            if data.len() < 768 {
+                return Err(IffConvertError::Malformed("color table is too short"));
+            }
+            let count = match data.get(768..770) {
+                Some(&[high, low]) => u16::from_be_bytes([high, low]).min(256) as usize,
+                _ => 256,
+            };
+            data[..count * 3].chunks_exact(3).map(color).collect()
+        }
+        PaletteFormat::Raw => data.chunks_exact(3).map(color).collect(),
+    };
+    if colors.is_empty() {
+        return Err(IffConvertError::EmptyPalette);
+    }
+    Ok(ColorMap { colors })
+}
+
+/// Serializes `cmap` in `format`. `name` ends up in formats that carry one
+pub fn encode(cmap: &ColorMap, format: PaletteFormat, name: &str) -> Vec<u8> {
+    match format {
+        PaletteFormat::Gimp => {
+            let mut out = format!("GIMP Palette\nName: {}\nColumns: 16\n#\n", name);
+            for (index, c) in cmap.colors.iter().enumerate() {
+                writeln!(out, "{:3} {:3} {:3}\tIndex {}", c.r, c.g, c.b, index)
+                    .expect("writing to a String never fails");
+            }
+            out.into_bytes()
+        }
+        PaletteFormat::Jasc => {
+            let mut out = format!("JASC-PAL\r\n0100\r\n{}\r\n", cmap.colors.len());
+            for c in &cmap.colors {
+                write!(out, "{} {} {}\r\n", c.r, c.g, c.b)
+                    .expect("writing to a String never fails");
+            }
+            out.into_bytes()
+        }
+        PaletteFormat::Act => {
+            let mut out = vec![0u8; 768];
+            for (entry, c) in out.chunks_mut(3).zip(&cmap.colors) {
+                entry.copy_from_slice(&[c.r, c.g, c.b]);
+            }
+            // the optional trailer holds the number of used colors
+            out.extend_from_slice(&(cmap.colors.len() as u16).to_be_bytes());
+            out.extend_from_slice(&[0xff, 0xff]);
+            out
+        }
+        PaletteFormat::Raw => cmap.colors.iter().flat_map(|c| [c.r, c.g, c.b]).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::IffImage;
+
+    #[test]
+    fn extracts_cmap_as_gimp_palette() {
+        let image = IffImage::from_parts(
+            8,
+            1,
+            ColorMap {
+                colors: vec![Color { r: 255, g: 0, b: 0 }, Color { r: 0, g: 0, b: 16 }],
+            },
+            vec![0; 8],
+        );
+        let iff = image.to_bytes();
+        let cmap = cmap_from_iff(&iff).unwrap();
+        let gpl = String::from_utf8(encode(&cmap, PaletteFormat::Gimp, "test")).unwrap();
+        assert_eq!(
+            gpl,
+            "GIMP Palette\nName: test\nColumns: 16\n#\n255   0   0\tIndex 0\n  0   0  16\tIndex 1\n"
+        );
+        assert_eq!(
+            encode(&cmap, PaletteFormat::Raw, ""),
+            vec![255, 0, 0, 0, 0, 16]
+        );
+        assert_eq!(
+            PaletteFormat::from_path(Path::new("x.PAL")),
+            PaletteFormat::Jasc
+        );
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let cmap = ColorMap {
+            colors: vec![
+                Color { r: 1, g: 2, b: 3 },
+                Color {
+                    r: 255,
+                    g: 128,
+                    b: 0,
+                },
+            ],
+        };
+        for format in [
+            PaletteFormat::Gimp,
+            PaletteFormat::Jasc,
+            PaletteFormat::Act,
+            PaletteFormat::Raw,
+        ] {
+            let decoded = decode(&encode(&cmap, format, "test"), format).unwrap();
+            assert_eq!(decoded.colors, cmap.colors, "{:?}", format);
+        }
+        assert!(decode(b"JASC-PAL\r\n0100\r\n0\r\n", PaletteFormat::Jasc).is_err());
+        assert!(decode(b"not a palette", PaletteFormat::Gimp).is_err());
+    }
+
+    #[test]
+    fn index_map_pins_and_conflicts() {
+        let pins = IndexMap::parse("\"#000000\" = 0\n\"#FFCC00\" = 3\n").unwrap();
+        assert_eq!(
+            pins[1],
+            (
+                Color {
+                    r: 255,
+                    g: 204,
+                    b: 0
+                },
+                3
+            )
+        );
+        assert!(IndexMap::parse("\"#000000\" = 1\n\"#ffffff\" = 1\n").is_err());
+        assert!(IndexMap::parse("\"#ffffff\" = 1\n\"#FFFFFF\" = 2\n").is_err());
+        assert!(IndexMap::parse("\"#ffffff\" = 256\n").is_err());
+
+        let colors = vec![
+            Color {
+                r: 255,
+                g: 204,
+                b: 0,
+            },
+            Color { r: 9, g: 9, b: 9 },
+            Color::default(),
+        ];
+        let image = IffImage::from_parts(3, 1, ColorMap { colors }, vec![0, 1, 2]);
+        let pinned = image.pin_colors(&pins).unwrap();
+        assert_eq!(pinned.pixels, vec![3, 1, 0]);
+        assert_eq!(pinned.palette().colors.len(), 4);
+        assert_eq!(
+            pinned.palette().colors[3],
+            Color {
+                r: 255,
+                g: 204,
+                b: 0
+            }
+        );
+
+        let full = vec![(Color::default(), 255)];
+        let colors = vec![Color { r: 1, g: 1, b: 1 }; 256];
+        let image = IffImage::from_parts(1, 1, ColorMap { colors }, vec![0]);
+        assert!(image.pin_colors(&full).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Dual playfield mode shows two independent bitplane images on top of each
+//! other. Playfield 1 uses the first block of colors, playfield 2 the next
+//! one, and color 0 of either block is see-through
+
+use crate::iffimage::{ColorMap, IffConvertError, IffImage, Masking};
+
+/// CAMG flag for dual playfield mode
+pub const CAMG_DUALPF: u32 = 0x400;
+/// CAMG flag putting playfield 2 in front of playfield 1
+pub const CAMG_PF2PRI: u32 = 0x40;
+
+/// Splits `image` by palette ranges into the two playfields of a dual
+/// playfield screen with `colors` colors each, 8 on OCS/ECS and 16 on AGA.
+/// Indices below `colors` go to playfield 1, the next `colors` ones to
+/// playfield 2, and each playfield is transparent where the other one has
+/// a pixel. Both get the DUALPF CAMG, with PF2PRI if `pf2_in_front`
+pub fn split(
+    image: &IffImage,
+    colors: usize,
+    pf2_in_front: bool,
+) -> Result<(IffImage, IffImage), IffConvertError> {
+    if colors != 8 && colors != 16 {
+        return Err(IffConvertError::Unsupported(
+            "playfields have 8 or 16 colors",
+        ));
+    }
+    let width = image.width() as usize;
+    if let Some(i) = image.pixels.iter().position(|&p| p as usize >= 2 * colors) {
+        return Err(IffConvertError::InvalidIndex {
+            index: image.pixels[i],
+            x: i % width,
+            y: i / width,
+        });
+    }
+
+    let camg = CAMG_DUALPF | if pf2_in_front { CAMG_PF2PRI } else { 0 };
+    let playfield = |first: usize| {
+        let mut palette = image.palette().colors.iter().skip(first).take(colors);
+        let cmap = ColorMap {
+            colors: (0..colors)
+                .map(|_| palette.next().cloned().unwrap_or_default())
+                .collect(),
+        };
+        let pixels = image
+            .pixels
+            .iter()
+            .map(|&p| match (p as usize).checked_sub(first) {
+                Some(index) if index < colors => index as u8,
+                _ => 0,
+            })
+            .collect();
+        let mut playfield = IffImage::from_parts(image.width(), image.height(), cmap, pixels);
+        playfield.camg = Some(camg);
+        playfield.set_compression(image.compression());
+        playfield.set_transparent_color(0);
+        playfield.set_masking(Masking::TransparentColor);
+        playfield
+    };
+    Ok((playfield(0), playfield(colors)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::Color;
+
+    #[test]
+    fn splits_by_palette_range() {
+        let colors = (0..16)
+            .map(|i| Color {
+                r: i * 16,
+                g: 0,
+                b: 0,
+            })
+            .collect();
+        let pixels = vec![0, 3, 8, 15, 9, 7, 0, 0];
+        let image = IffImage::from_parts(8, 1, ColorMap { colors }, pixels);
+
+        let (pf1, pf2) = split(&image, 8, true).unwrap();
+        assert_eq!(pf1.pixels, vec![0, 3, 0, 0, 0, 7, 0, 0]);
+        assert_eq!(pf2.pixels, vec![0, 0, 0, 7, 1, 0, 0, 0]);
+        assert_eq!(pf2.palette().colors[1].r, 9 * 16);
+        assert_eq!((pf1.bitplanes(), pf2.bitplanes()), (3, 3));
+        assert_eq!(pf2.camg, Some(CAMG_DUALPF | CAMG_PF2PRI));
+        assert_eq!(pf1.masking(), Masking::TransparentColor);
+
+        assert!(split(&image, 4, false).is_err());
+        let wide = IffImage::from_parts(
+            1,
+            1,
+            ColorMap {
+                colors: vec![Color::default(); 32],
+            },
+            vec![20],
+        );
+        assert!(split(&wide, 8, false).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! PNG color space chunks. The png decoder skips gAMA, sRGB and iCCP, so
+//! the chunks in front of the image data are read here first and handed to
+//! the decoder afterwards. Colors of files with another gamma than sRGB are
+//! converted to sRGB, which is what the artist saw in their editor and what
+//! palette colors are compared in
+
+use std::io::{Read, Result as IoResult};
+
+const SIGNATURE_SIZE: usize = 8;
+/// gAMA stores the file gamma times 100000
+const GAMMA_SCALE: f64 = 100_000.0;
+/// File gammas this close to sRGB's 1/2.2 are left alone
+const GAMMA_TOLERANCE: f64 = 0.01;
+
+/// What the chunks before the image data say about the colors
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ColorInfo {
+    /// File gamma from gAMA, e.g. 0.45455
+    pub gamma: Option<f64>,
+    /// An sRGB chunk, overriding gAMA
+    pub srgb: bool,
+    /// An embedded ICC profile, which is not interpreted
+    pub icc: bool,
+}
+
+impl ColorInfo {
+    /// Lookup table converting samples to sRGB, None when they already are
+    pub fn srgb_table(&self) -> Option<[u8; 256]> {
+        let gamma = match self.gamma {
+            Some(gamma) if !self.srgb && gamma > 0.0 => gamma,
+            _ => return None,
+        };
+        if (gamma * 2.2 - 1.0).abs() < GAMMA_TOLERANCE {
+            return None;
+        }
+        let mut table = [0u8; 256];
+        for (sample, entry) in table.iter_mut().enumerate() {
+            let linear = (sample as f64 / 255.0).powf(1.0 / gamma);
+            *entry = (encode_srgb(linear) * 255.0).round() as u8;
+        }
+        Some(table)
+    }
+}
+
+/// The sRGB transfer function
+fn encode_srgb(linear: f64) -> f64 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Reads the signature and the chunks up to the first IDAT header. Returns
+/// the bytes consumed, to be put in front of the rest for the decoder.
+/// Anything that doesn't look like a PNG is left to the decoder to reject
+pub(crate) fn scan<R: Read>(r: &mut R) -> IoResult<(Vec<u8>, ColorInfo)> {
+    let mut consumed = vec![];
+    let mut info = ColorInfo::default();
+    r.by_ref()
+        .take(SIGNATURE_SIZE as u64)
+        .read_to_end(&mut consumed)?;
+    loop {
+        let start = consumed.len();
+        r.by_ref().take(8).read_to_end(&mut consumed)?;
+        let header = &consumed[start..];
+        if header.len() < 8 || &header[4..8] == b"IDAT" {
+            return Ok((consumed, info));
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let id = [header[4], header[5], header[6], header[7]];
+        let data_start = consumed.len();
+        // the data and the CRC
+        r.by_ref().take(len as u64 + 4).read_to_end(&mut consumed)?;
+        let data = &consumed[data_start..];
+        match &id {
+            b"gAMA" if data.len() >= 4 => {
+                let gamma = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                info.gamma = Some(gamma as f64 / GAMMA_SCALE);
+            }
+            b"sRGB" => info.srgb = true,
+            b"iCCP" => info.icc = true,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(id);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0; 4]);
+        out
+    }
+
+    #[test]
+    fn reads_gamma_up_to_the_image_data() {
+        let png = [
+            &b"\x89PNG\r\n\x1a\n"[..],
+            &chunk(b"IHDR", &[0; 13]),
+            &chunk(b"gAMA", &100_000u32.to_be_bytes()),
+            &chunk(b"IDAT", &[1, 2, 3]),
+        ]
+        .concat();
+        let mut reader = &png[..];
+        let (consumed, info) = scan(&mut reader).unwrap();
+        assert_eq!(consumed.len(), png.len() - 7);
+        assert_eq!([&consumed[..], reader].concat(), png);
+        assert_eq!(info.gamma, Some(1.0));
+
+        // linear samples get brighter in sRGB
+        let table = info.srgb_table().unwrap();
+        assert_eq!((table[0], table[255]), (0, 255));
+        assert_eq!(table[128], 188);
+
+        let srgb = ColorInfo {
+            srgb: true,
+            ..info.clone()
+        };
+        assert_eq!(srgb.srgb_table(), None);
+        let display = ColorInfo {
+            gamma: Some(0.45455),
+            ..info
+        };
+        assert_eq!(display.srgb_table(), None);
+    }
+}
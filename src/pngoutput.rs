@@ -0,0 +1,62 @@
+use crate::iffimage::{IffImage, MASK_HAS_TRANSPARENT_COLOR};
+use png::{BitDepth, ColorType, EncodingError};
+use std::io::Write;
+
+impl IffImage {
+    /// Writes an 8 bit indexed PNG with the palette in its original order. A
+    /// transparent color from the BMHD ends up in a tRNS chunk
+    pub fn to_png<W: Write>(&self, w: W) -> Result<(), EncodingError> {
+        let mut encoder = png::Encoder::new(w, self.bmhd.width as u32, self.bmhd.height as u32);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+
+        let palette = self
+            .cmap
+            .colors
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b])
+            .collect::<Vec<_>>();
+        writer.write_chunk(*b"PLTE", &palette)?;
+        let transparent = self.bmhd.transparent_color as usize;
+        if self.bmhd.masking == MASK_HAS_TRANSPARENT_COLOR && transparent < self.cmap.colors.len() {
+            // entries after the last one listed stay opaque
+            let mut trns = vec![0xff; transparent + 1];
+            trns[transparent] = 0;
+            writer.write_chunk(*b"tRNS", &trns)?;
+        }
+        writer.write_image_data(&self.pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn round_trips_through_png() {
+        let mut image = IffImage::from_parts(
+            3,
+            2,
+            ColorMap {
+                colors: vec![
+                    Color { r: 9, g: 9, b: 9 },
+                    Color { r: 0, g: 0, b: 0 },
+                    Color { r: 9, g: 9, b: 9 },
+                ],
+            },
+            vec![0, 1, 2, 2, 1, 0],
+        );
+        image.bmhd.masking = MASK_HAS_TRANSPARENT_COLOR;
+        image.bmhd.transparent_color = 1;
+        let mut png = vec![];
+        image.to_png(&mut png).unwrap();
+
+        let read = IffImage::from_png_reader(&png[..]).unwrap();
+        assert_eq!(read.pixels, image.pixels);
+        assert_eq!(read.cmap.colors, image.cmap.colors);
+        let (_, reader) = png::Decoder::new(&png[..]).read_info().unwrap();
+        assert_eq!(reader.info().trns, Some(vec![0xff, 0]));
+    }
+}
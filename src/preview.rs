@@ -0,0 +1,84 @@
+use crate::iffimage::IffImage;
+use std::fmt::Write;
+
+/// Renders `image` with truecolor ANSI escapes, two pixels per character
+/// cell using the upper half block. The top pixel is the foreground color,
+/// the bottom one the background
+pub fn half_blocks(image: &IffImage) -> String {
+    let width = image.bmhd.width as usize;
+    let color = |y: usize, x: usize| {
+        let c = &image.cmap.colors[image.pixels[y * width + x] as usize];
+        (c.r, c.g, c.b)
+    };
+
+    let mut out = String::new();
+    let height = image.bmhd.height as usize;
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let (r, g, b) = color(y, x);
+            write!(out, "\x1b[38;2;{};{};{}m", r, g, b).expect("writing to a String never fails");
+            if y + 1 < height {
+                let (r, g, b) = color(y + 1, x);
+                write!(out, "\x1b[48;2;{};{};{}m", r, g, b)
+                    .expect("writing to a String never fails");
+            } else {
+                out.push_str("\x1b[49m");
+            }
+            out.push('\u{2580}');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Lists the palette as colored swatches with index and hex value
+pub fn palette_swatches(image: &IffImage) -> String {
+    let mut out = String::new();
+    for (index, c) in image.cmap.colors.iter().enumerate() {
+        writeln!(
+            out,
+            "{:3} \x1b[48;2;{};{};{}m    \x1b[0m #{:02x}{:02x}{:02x}",
+            index, c.r, c.g, c.b, c.r, c.g, c.b
+        )
+        .expect("writing to a String never fails");
+    }
+    out
+}
+
+/// Size that fits `columns` character cells while keeping the aspect ratio.
+/// Images that already fit are not enlarged
+pub fn fit(width: u16, height: u16, columns: u16) -> (u16, u16) {
+    if width <= columns {
+        return (width, height);
+    }
+    let scaled = (height as u32 * columns as u32 / width as u32).max(1);
+    (columns, scaled as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn pairs_rows_into_half_blocks() {
+        let image = IffImage::from_parts(
+            1,
+            3,
+            ColorMap {
+                colors: vec![Color { r: 1, g: 2, b: 3 }, Color { r: 4, g: 5, b: 6 }],
+            },
+            vec![0, 1, 1],
+        );
+        assert_eq!(
+            half_blocks(&image),
+            "\x1b[38;2;1;2;3m\x1b[48;2;4;5;6m\u{2580}\x1b[0m\n\x1b[38;2;4;5;6m\x1b[49m\u{2580}\x1b[0m\n"
+        );
+        assert_eq!(
+            palette_swatches(&image),
+            "  0 \x1b[48;2;1;2;3m    \x1b[0m #010203\n  1 \x1b[48;2;4;5;6m    \x1b[0m #040506\n"
+        );
+        assert_eq!(fit(640, 256, 80), (80, 32));
+        assert_eq!(fit(32, 16, 80), (32, 16));
+    }
+}
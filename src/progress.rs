@@ -0,0 +1,113 @@
+use ipng2iff::iffimage;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Width of the bar itself, without counts and file name
+const BAR_WIDTH: usize = 30;
+
+/// Single line progress bar on stderr for batch runs. Does nothing when
+/// disabled, e.g. because stderr is not a terminal
+pub struct Progress {
+    total: usize,
+    done: usize,
+    started: Instant,
+    enabled: bool,
+    /// Percentage of the current file's rows last drawn
+    percent: Option<usize>,
+}
+
+/// Whether a progress bar makes sense: only on a terminal
+pub fn is_interactive() -> bool {
+    io::stderr().is_terminal()
+}
+
+impl Progress {
+    pub fn new(total: usize, enabled: bool) -> Progress {
+        Progress {
+            total,
+            done: 0,
+            started: Instant::now(),
+            enabled,
+            percent: None,
+        }
+    }
+
+    /// Shows the bar while `file` is being converted
+    pub fn start(&mut self, file: &Path) {
+        self.percent = None;
+        if self.enabled {
+            let line = render(self.done, self.total, self.started.elapsed(), file);
+            eprint!("\r\x1b[K{}", line);
+            io::stderr().flush().ok();
+        }
+    }
+
+    /// Adds how much of `file` is written to the bar. Only redraws when the
+    /// percentage changes
+    pub fn update(&mut self, file: &Path, event: iffimage::Progress) {
+        let percent = match event {
+            iffimage::Progress::Rows { done, total } => done * 100 / total.max(1),
+            _ => return,
+        };
+        if !self.enabled || self.percent == Some(percent) {
+            return;
+        }
+        self.percent = Some(percent);
+        let line = render(self.done, self.total, self.started.elapsed(), file);
+        eprint!("\r\x1b[K{} {}%", line, percent);
+        io::stderr().flush().ok();
+    }
+
+    /// Removes the bar so log output starts on a clean line
+    pub fn clear(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[K");
+            io::stderr().flush().ok();
+        }
+    }
+
+    pub fn finish_one(&mut self) {
+        self.done += 1;
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Formats the bar for `done` of `total` files. The ETA extrapolates the
+/// time taken per file so far
+fn render(done: usize, total: usize, elapsed: Duration, file: &Path) -> String {
+    let filled = BAR_WIDTH * done / total.max(1);
+    let eta = match done {
+        0 => "--:--".to_string(),
+        _ => format_duration(elapsed / done as u32 * (total - done) as u32),
+    };
+    format!(
+        "[{}{}] {}/{} ETA {} {}",
+        "=".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        done,
+        total,
+        eta,
+        file.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_bar_with_eta() {
+        let line = render(5, 10, Duration::from_secs(50), Path::new("a.png"));
+        assert_eq!(
+            line,
+            format!("[{}{}] 5/10 ETA 0:50 a.png", "=".repeat(15), " ".repeat(15))
+        );
+        let line = render(0, 3, Duration::from_secs(0), Path::new("a.png"));
+        assert!(line.ends_with("0/3 ETA --:-- a.png"));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::iffimage::{
+    CancelToken, Color, ColorMap, ColorMetric, IffConvertError, Matcher, PaletteOrder, MAX_COLORS,
+};
+use color_quant::NeuQuant;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+
+/// NeuQuant sampling factor. 1 is the slowest and best, 30 the fastest
+const SAMPLE_FACTOR: i32 = 10;
+/// Pixels mapped to the quantized palette between checks for cancellation
+const CANCEL_CHECK_PIXELS: usize = 4096;
+/// Passes moving the palette towards the colors it stands for, when
+/// quantizing by a perceptual metric
+const REFINE_PASSES: usize = 4;
+
+/// Moves every palette entry to the mean of the colors closest to it by
+/// `metric`, averaged in OKLab. NeuQuant picks its palette by RGB distance,
+/// which spends too many entries on dark shades and too few on blues
+fn refine(
+    mut cmap: ColorMap,
+    counts: &BTreeMap<[u8; 3], usize>,
+    metric: ColorMetric,
+    cancel: &CancelToken,
+) -> Result<ColorMap, IffConvertError> {
+    for _ in 0..REFINE_PASSES {
+        cancel.check()?;
+        let mut sums = vec![([0.0; 3], 0); cmap.colors.len()];
+        let mut matcher = Matcher::new(&cmap, metric);
+        for (&[r, g, b], &count) in counts {
+            let (sum, n) = &mut sums[matcher.nearest([r, g, b]) as usize];
+            let lab = Color { r, g, b }.to_oklab();
+            for (s, v) in sum.iter_mut().zip(lab) {
+                *s += v * count as f64;
+            }
+            *n += count;
+        }
+        let colors = cmap
+            .colors
+            .iter()
+            .zip(&sums)
+            .map(|(color, &(sum, n))| match n {
+                0 => color.clone(),
+                _ => Color::from_oklab(sum.map(|s| s / n as f64)),
+            })
+            .collect();
+        cmap = ColorMap { colors };
+    }
+    Ok(cmap)
+}
+
+/// Sorts the palette into `order`, remapping the pixels. Entries no pixel
+/// uses, which quantizing can leave, go last in their previous order
+fn reorder(cmap: ColorMap, pixels: Vec<u8>, order: PaletteOrder) -> (ColorMap, Vec<u8>) {
+    let len = cmap.colors.len();
+    let mut first_seen = vec![usize::MAX; len];
+    let mut counts = vec![0usize; len];
+    for (i, &p) in pixels.iter().enumerate() {
+        let index = p as usize;
+        first_seen[index] = first_seen[index].min(i);
+        counts[index] += 1;
+    }
+    let mut sorted = (0..len).collect::<Vec<_>>();
+    match order {
+        PaletteOrder::FirstAppearance => sorted.sort_by_key(|&i| (first_seen[i], i)),
+        PaletteOrder::Popularity => {
+            sorted.sort_by_key(|&i| (std::cmp::Reverse(counts[i]), first_seen[i], i))
+        }
+    }
+
+    let mut table = vec![0u8; len];
+    for (new, &old) in sorted.iter().enumerate() {
+        table[old] = new as u8;
+    }
+    let colors = sorted.iter().map(|&i| cmap.colors[i].clone()).collect();
+    let pixels = pixels.iter().map(|&p| table[p as usize]).collect();
+    (ColorMap { colors }, pixels)
+}
+
+/// Reduces RGBA pixels to at most `max_colors` palette entries, which has to
+/// be 1 to `MAX_COLORS`. Images that
+/// already fit are indexed exactly. The palette comes in `order`, so the
+/// same input always gets the same indices. Alpha is ignored. Pixels go to
+/// the closest palette color by `metric`. `cancel` is checked between the
+/// quantizing steps and while mapping pixels to the new palette
+pub(crate) fn quantize(
+    rgba: &[u8],
+    max_colors: usize,
+    order: PaletteOrder,
+    metric: ColorMetric,
+    cancel: &CancelToken,
+) -> Result<(ColorMap, Vec<u8>), IffConvertError> {
+    if !(1..=MAX_COLORS).contains(&max_colors) {
+        return Err(IffConvertError::InvalidColorCount(max_colors));
+    }
+    cancel.check()?;
+    let mut lookup = HashMap::new();
+    let mut colors = vec![];
+    let mut pixels = Vec::with_capacity(rgba.len() / 4);
+    for pixel in rgba.chunks(4) {
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+        let index = *lookup.entry(rgb).or_insert_with(|| {
+            colors.push(Color {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+            });
+            colors.len() - 1
+        });
+        if colors.len() > max_colors {
+            break;
+        }
+        pixels.push(index as u8);
+    }
+    if colors.len() <= max_colors {
+        return Ok(reorder(ColorMap { colors }, pixels, order));
+    }
+    cancel.check()?;
+
+    debug!("quantizing to {} colors", max_colors);
+    let opaque = rgba
+        .chunks(4)
+        .flat_map(|p| [p[0], p[1], p[2], 0xff])
+        .collect::<Vec<_>>();
+    let quant = NeuQuant::new(SAMPLE_FACTOR, max_colors, &opaque);
+    let colors = quant
+        .color_map_rgb()
+        .chunks(3)
+        .map(|c| Color {
+            r: c[0],
+            g: c[1],
+            b: c[2],
+        })
+        .collect();
+    let mut pixels = Vec::with_capacity(opaque.len() / 4);
+    if metric == ColorMetric::Rgb {
+        for block in opaque.chunks(CANCEL_CHECK_PIXELS * 4) {
+            cancel.check()?;
+            pixels.extend(block.chunks(4).map(|p| quant.index_of(p) as u8));
+        }
+        return Ok(reorder(ColorMap { colors }, pixels, order));
+    }
+
+    let mut counts = BTreeMap::new();
+    for p in opaque.chunks(4) {
+        *counts.entry([p[0], p[1], p[2]]).or_insert(0) += 1;
+    }
+    let cmap = refine(ColorMap { colors }, &counts, metric, cancel)?;
+    let mut matcher = Matcher::new(&cmap, metric);
+    for block in opaque.chunks(CANCEL_CHECK_PIXELS * 4) {
+        cancel.check()?;
+        pixels.extend(block.chunks(4).map(|p| matcher.nearest([p[0], p[1], p[2]])));
+    }
+    Ok(reorder(cmap, pixels, order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_palette_when_colors_fit() {
+        let rgba = [0, 0, 0, 255, 255, 0, 0, 255, 0, 0, 0, 255, 0, 255, 0, 255];
+        let cancel = CancelToken::default();
+        let (cmap, pixels) = quantize(
+            &rgba,
+            4,
+            PaletteOrder::FirstAppearance,
+            ColorMetric::Rgb,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(cmap.colors.len(), 3);
+        assert_eq!(cmap.colors[1].r, 255);
+        assert_eq!(pixels, vec![0, 1, 0, 2]);
+
+        let (cmap, pixels) = quantize(
+            &rgba,
+            4,
+            PaletteOrder::Popularity,
+            ColorMetric::Rgb,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(cmap.colors[0], Color::default());
+        assert_eq!(cmap.colors[1].r, 255);
+        assert_eq!(pixels, vec![0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn reduces_to_max_colors() {
+        let rgba = (0..64u8)
+            .flat_map(|i| [i * 4, 0, 0, 255])
+            .collect::<Vec<_>>();
+        let cancel = CancelToken::default();
+        let (cmap, pixels) = quantize(
+            &rgba,
+            8,
+            PaletteOrder::FirstAppearance,
+            ColorMetric::Rgb,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(cmap.colors.len(), 8);
+        assert_eq!(pixels.len(), 64);
+        assert!(pixels.iter().all(|&p| (p as usize) < 8));
+        // indices count up from the top left pixel
+        let mut next = 0;
+        for &p in &pixels {
+            assert!(p <= next);
+            next = next.max(p + 1);
+        }
+
+        let (_, popular) = quantize(
+            &rgba,
+            8,
+            PaletteOrder::Popularity,
+            ColorMetric::Rgb,
+            &cancel,
+        )
+        .unwrap();
+        let count = |index: u8| popular.iter().filter(|&&p| p == index).count();
+        assert!((1..8).all(|i| count(i - 1) >= count(i)));
+        for colors in [0, 257] {
+            assert!(matches!(
+                quantize(&rgba, colors, PaletteOrder::Popularity, ColorMetric::Rgb, &cancel),
+                Err(IffConvertError::InvalidColorCount(n)) if n == colors
+            ));
+        }
+
+        let oklab = ColorMetric::Oklab;
+        let (cmap, pixels) = quantize(&rgba, 8, PaletteOrder::Popularity, oklab, &cancel).unwrap();
+        assert_eq!(cmap.colors.len(), 8);
+        let nearest = |p: &[u8]| cmap.nearest_by([p[0], p[1], p[2]], oklab);
+        assert!(rgba.chunks(4).zip(&pixels).all(|(p, &i)| nearest(p) == i));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::iffimage::{check_indices, Color, ColorMap, IffConvertError, IffImage, IffLoadError};
+use std::fs;
+use std::path::Path;
+
+impl IffImage {
+    /// Builds an image from one palette index per pixel, row after row, e.g.
+    /// for generated graphics. `pixels` has to hold exactly `width * height`
+    /// indices into `palette`
+    pub fn from_indexed(
+        width: u16,
+        height: u16,
+        palette: &[[u8; 3]],
+        pixels: &[u8],
+    ) -> Result<IffImage, IffConvertError> {
+        if width == 0 || height == 0 {
+            return Err(IffConvertError::EmptyImage(width as u32, height as u32));
+        }
+        if palette.is_empty() {
+            return Err(IffConvertError::EmptyPalette);
+        }
+        if palette.len() > 256 {
+            return Err(IffConvertError::TooManyColors(palette.len()));
+        }
+        if pixels.len() != width as usize * height as usize {
+            return Err(IffConvertError::Malformed(
+                "pixel count does not match the image size",
+            ));
+        }
+        check_indices(pixels, width as usize, palette.len())?;
+
+        let cmap = ColorMap {
+            colors: palette.iter().map(|&[r, g, b]| Color { r, g, b }).collect(),
+        };
+        Ok(IffImage::from_parts(width, height, cmap, pixels.to_vec()))
+    }
+
+    /// Builds an image from headerless 8 bit chunky pixels (one palette index
+    /// per byte, row after row) and a palette of raw RGB triplets
+    pub fn from_raw(
+        chunky: &[u8],
+        palette: &[u8],
+        width: u16,
+        height: u16,
+    ) -> Result<IffImage, IffConvertError> {
+        if !palette.len().is_multiple_of(3) {
+            return Err(IffConvertError::Malformed(
+                "raw palette size is not a multiple of 3",
+            ));
+        }
+        let palette = palette
+            .chunks(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>();
+        let num_pixels = width as usize * height as usize;
+        let pixels = chunky
+            .get(..num_pixels)
+            .ok_or(IffConvertError::Malformed("raw pixel data is too short"))?;
+        IffImage::from_indexed(width, height, &palette, pixels)
+    }
+
+    pub fn from_raw_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        palette_path: Q,
+        width: u16,
+        height: u16,
+    ) -> Result<IffImage, IffLoadError> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|source| IffLoadError::File {
+                path: path.to_path_buf(),
+                source,
+            })
+        };
+        let chunky = read(path.as_ref())?;
+        let palette = read(palette_path.as_ref())?;
+        Ok(IffImage::from_raw(&chunky, &palette, width, height)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_chunky_with_palette() {
+        let palette = [0, 0, 0, 255, 255, 255, 255, 0, 0];
+        let image = IffImage::from_raw(&[0, 1, 2, 1, 0, 0], &palette, 3, 2).unwrap();
+        assert_eq!(image.bmhd.bitplanes, 2);
+        assert_eq!(image.pixels, vec![0, 1, 2, 1, 0, 0]);
+        assert!(IffImage::from_raw(&[0, 1], &palette, 3, 2).is_err());
+        let indexed = IffImage::from_indexed(2, 1, &[[1, 2, 3], [4, 5, 6]], &[1, 0]).unwrap();
+        assert_eq!(indexed.palette().colors[1], Color { r: 4, g: 5, b: 6 });
+        assert!(IffImage::from_indexed(2, 2, &[[0, 0, 0]], &[0, 0]).is_err());
+        assert!(matches!(
+            IffImage::from_raw(&[0, 0, 0, 0, 3, 0], &palette, 3, 2),
+            Err(IffConvertError::InvalidIndex {
+                index: 3,
+                x: 1,
+                y: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn from_indexed_validates_its_input() {
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        assert!(matches!(
+            IffImage::from_indexed(2, 2, &palette, &[0, 1, 0]),
+            Err(IffConvertError::Malformed(_))
+        ));
+        assert!(matches!(
+            IffImage::from_indexed(2, 2, &palette, &[0, 1, 0, 1, 0]),
+            Err(IffConvertError::Malformed(_))
+        ));
+        assert!(matches!(
+            IffImage::from_indexed(2, 1, &palette, &[1, 2]),
+            Err(IffConvertError::InvalidIndex {
+                index: 2,
+                x: 1,
+                y: 0
+            })
+        ));
+        assert!(matches!(
+            IffImage::from_indexed(1, 1, &[[0, 0, 0]; 257], &[0]),
+            Err(IffConvertError::TooManyColors(257))
+        ));
+        assert!(IffImage::from_indexed(1, 1, &[[0, 0, 0]; 256], &[255]).is_ok());
+        assert!(matches!(
+            IffImage::from_indexed(1, 1, &[], &[0]),
+            Err(IffConvertError::EmptyPalette)
+        ));
+        assert!(matches!(
+            IffImage::from_indexed(0, 1, &palette, &[]),
+            Err(IffConvertError::EmptyImage(0, 1))
+        ));
+    }
+}
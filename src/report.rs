@@ -0,0 +1,303 @@
+use crate::iffimage::{Compression, IffImage, Warning};
+use std::error::Error;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Colors whose channels all differ by at most this much count as near-duplicates
+const NEAR_DUPLICATE_TOLERANCE: u8 = 8;
+
+/// Human readable reports selectable with --report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportKind {
+    Colors,
+}
+
+impl FromStr for ReportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colors" => Ok(ReportKind::Colors),
+            _ => Err(format!("Invalid report {:?}. Expected colors", s)),
+        }
+    }
+}
+
+/// How often one palette entry is used
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ColorUsage {
+    pub rgb: [u8; 3],
+    pub pixels: usize,
+    /// An earlier palette entry that looks almost the same
+    pub near_duplicate_of: Option<usize>,
+}
+
+fn color_usage(image: &IffImage) -> Vec<ColorUsage> {
+    let histogram = image.histogram();
+    let colors = image
+        .cmap
+        .colors
+        .iter()
+        .map(|c| [c.r, c.g, c.b])
+        .collect::<Vec<_>>();
+    colors
+        .iter()
+        .enumerate()
+        .map(|(index, rgb)| ColorUsage {
+            rgb: *rgb,
+            pixels: histogram[index],
+            near_duplicate_of: colors[..index].iter().position(|other| {
+                other
+                    .iter()
+                    .zip(rgb)
+                    .all(|(a, b)| a.abs_diff(*b) <= NEAR_DUPLICATE_TOLERANCE)
+            }),
+        })
+        .collect()
+}
+
+/// Facts about one converted file
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Conversion {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_path"))]
+    pub output: PathBuf,
+    pub width: u16,
+    pub height: u16,
+    pub bitplanes: u8,
+    pub colors: usize,
+    pub compression: Compression,
+    pub body_size: usize,
+    pub raw_body_size: usize,
+    pub output_size: u64,
+    pub warnings: Vec<Warning>,
+    pub palette: Vec<ColorUsage>,
+    /// Nothing was written, the sizes are what would have been produced
+    pub dry_run: bool,
+}
+
+impl Conversion {
+    /// Gathers the statistics of `image`, which ends up (or would end up with
+    /// `dry_run`) in `output`
+    pub fn new(image: &IffImage, output: PathBuf, dry_run: bool) -> Conversion {
+        let body_size = image.body_size();
+        let output_size = 8 + image.form_size(body_size) as u64;
+        let raw_body_size = image.raw_body_size();
+        let mut warnings = image.warnings().to_vec();
+        if image.compression() != Compression::None && body_size > raw_body_size {
+            warnings.push(Warning::CompressionGrewBody {
+                compression: image.compression(),
+                body_size,
+                raw_body_size,
+            });
+        }
+        Conversion {
+            output,
+            width: image.width(),
+            height: image.height(),
+            bitplanes: image.bitplanes(),
+            colors: image.num_colors(),
+            compression: image.compression(),
+            body_size,
+            raw_body_size,
+            output_size,
+            warnings,
+            palette: color_usage(image),
+            dry_run,
+        }
+    }
+
+    /// Compressed BODY size relative to the uncompressed one
+    pub fn compression_ratio(&self) -> f64 {
+        if self.raw_body_size == 0 {
+            return 1.0;
+        }
+        self.body_size as f64 / self.raw_body_size as f64
+    }
+}
+
+/// The message of `error` followed by those of all its sources
+pub fn error_message(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        write!(message, ": {}", error).expect("writing to a String never fails");
+        source = error.source();
+    }
+    message
+}
+
+/// One line of --json output
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum JsonLine<'a> {
+    Converted {
+        input: String,
+        #[serde(flatten)]
+        conversion: &'a Conversion,
+        compression_ratio: f64,
+    },
+    DryRun {
+        input: String,
+        #[serde(flatten)]
+        conversion: &'a Conversion,
+        compression_ratio: f64,
+    },
+    Unchanged {
+        input: String,
+        output: String,
+    },
+    Failed {
+        input: String,
+        error: &'a str,
+    },
+}
+
+#[cfg(feature = "serde")]
+impl JsonLine<'_> {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("report lines have no fallible fields")
+    }
+}
+
+/// Serializes a path as a string, replacing what isn't valid UTF-8
+#[cfg(feature = "serde")]
+fn serialize_path<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// One line of --json output for a converted file
+#[cfg(feature = "serde")]
+pub fn converted_json(input: &Path, conversion: &Conversion) -> String {
+    let input = input.to_string_lossy().into_owned();
+    let compression_ratio = conversion.compression_ratio();
+    let line = if conversion.dry_run {
+        JsonLine::DryRun {
+            input,
+            conversion,
+            compression_ratio,
+        }
+    } else {
+        JsonLine::Converted {
+            input,
+            conversion,
+            compression_ratio,
+        }
+    };
+    line.to_json()
+}
+
+/// One line of --json output for an input skipped by --incremental
+#[cfg(feature = "serde")]
+pub fn unchanged_json(input: &Path, output: &Path) -> String {
+    JsonLine::Unchanged {
+        input: input.to_string_lossy().into_owned(),
+        output: output.to_string_lossy().into_owned(),
+    }
+    .to_json()
+}
+
+/// One line of --json output for an input that failed to convert
+#[cfg(feature = "serde")]
+pub fn failed_json(input: &Path, error: &str) -> String {
+    JsonLine::Failed {
+        input: input.to_string_lossy().into_owned(),
+        error,
+    }
+    .to_json()
+}
+
+/// The --report colors table for one file
+pub fn colors_table(input: &Path, conversion: &Conversion) -> String {
+    let total = conversion.palette.iter().map(|c| c.pixels).sum::<usize>();
+    let mut out = format!(
+        "{}: {} colors, {} pixels\n",
+        input.display(),
+        conversion.palette.len(),
+        total
+    );
+    for (index, usage) in conversion.palette.iter().enumerate() {
+        let percent = match total {
+            0 => 0.0,
+            _ => usage.pixels as f64 * 100.0 / total as f64,
+        };
+        write!(
+            out,
+            "{:5}  #{:02x}{:02x}{:02x} {:9} {:6.2}%",
+            index, usage.rgb[0], usage.rgb[1], usage.rgb[2], usage.pixels, percent
+        )
+        .expect("writing to a String never fails");
+        if usage.pixels == 0 {
+            out.push_str("  unused");
+        }
+        if let Some(other) = usage.near_duplicate_of {
+            write!(out, "  near duplicate of {}", other).expect("writing to a String never fails");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn flags_unused_and_near_duplicate_colors() {
+        let image = IffImage::from_parts(
+            4,
+            1,
+            ColorMap {
+                colors: vec![
+                    Color { r: 0, g: 0, b: 0 },
+                    Color {
+                        r: 255,
+                        g: 255,
+                        b: 255,
+                    },
+                    Color { r: 4, g: 2, b: 0 },
+                ],
+            },
+            vec![0, 0, 0, 2],
+        );
+        let usage = color_usage(&image);
+        assert_eq!(usage[0].pixels, 3);
+        assert_eq!(usage[1].pixels, 0);
+        assert_eq!(usage[0].near_duplicate_of, None);
+        assert_eq!(usage[2].near_duplicate_of, Some(0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_lines_carry_the_status() {
+        let image = IffImage::from_parts(1, 1, ColorMap { colors: vec![] }, vec![0]);
+        let conversion = Conversion::new(&image, PathBuf::from("out/a\".iff"), true);
+        let line: serde_json::Value =
+            serde_json::from_str(&converted_json(Path::new("a.png"), &conversion)).unwrap();
+        assert_eq!(line["status"], "dry-run");
+        assert_eq!(line["input"], "a.png");
+        assert_eq!(line["output"], "out/a\".iff");
+        assert_eq!(line["width"], 1);
+        assert_eq!(line["compression"], "none");
+        let line: serde_json::Value =
+            serde_json::from_str(&failed_json(Path::new("b.png"), "Can't read b.png")).unwrap();
+        assert_eq!(line["status"], "failed");
+        assert_eq!(line["error"], "Can't read b.png");
+    }
+
+    #[test]
+    fn error_message_includes_sources() {
+        let error = crate::iffimage::IffLoadError::File {
+            path: PathBuf::from("pal.raw"),
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        };
+        assert_eq!(
+            error_message(&error),
+            "Can't read pal.raw: entity not found"
+        );
+    }
+}
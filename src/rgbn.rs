@@ -0,0 +1,181 @@
+//! Impulse Turbo Silver true color images. FORM RGBN stores 12 bit colors
+//! as one word per run, RRRRGGGGBBBBNCCC, FORM RGB8 24 bit colors as a long
+//! word RRRRRRRRGGGGGGGGBBBBBBBBNCCCCCCC. N is the genlock bit, set for
+//! see-through pixels, and C the repeat count. A count of 0 is followed by a
+//! count byte, and a count byte of 0 by a count word
+
+use crate::iff::Form;
+use crate::iffimage::{IffImage, Masking};
+use std::fmt;
+use std::str::FromStr;
+
+/// BMHD compression byte of the RGBN and RGB8 run-length encoding
+const COMPRESSION_RGBN: u8 = 4;
+
+/// The two true color FORMs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RgbFormat {
+    /// 4 bits per channel
+    Rgbn,
+    /// 8 bits per channel
+    Rgb8,
+}
+
+impl RgbFormat {
+    fn form_type(self) -> &'static [u8; 4] {
+        match self {
+            RgbFormat::Rgbn => b"RGBN",
+            RgbFormat::Rgb8 => b"RGB8",
+        }
+    }
+
+    /// BMHD bitplanes: the color bits plus the genlock bit
+    fn bitplanes(self) -> u8 {
+        match self {
+            RgbFormat::Rgbn => 13,
+            RgbFormat::Rgb8 => 25,
+        }
+    }
+
+    /// Largest count that fits into the pixel itself
+    fn short_count(self) -> usize {
+        match self {
+            RgbFormat::Rgbn => 7,
+            RgbFormat::Rgb8 => 127,
+        }
+    }
+}
+
+impl FromStr for RgbFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgbn" => Ok(RgbFormat::Rgbn),
+            "rgb8" => Ok(RgbFormat::Rgb8),
+            _ => Err(format!("Unknown format {}, expected rgbn or rgb8", s)),
+        }
+    }
+}
+
+impl fmt::Display for RgbFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RgbFormat::Rgbn => "rgbn",
+            RgbFormat::Rgb8 => "rgb8",
+        })
+    }
+}
+
+/// Appends a run of `count` pixels, `pixel` having a zero count field
+fn push_run(out: &mut Vec<u8>, pixel: &[u8], count: usize, format: RgbFormat) {
+    let last = pixel.len() - 1;
+    out.extend_from_slice(&pixel[..last]);
+    if count <= format.short_count() {
+        out.push(pixel[last] | count as u8);
+    } else if count <= u8::MAX as usize {
+        out.extend_from_slice(&[pixel[last], count as u8]);
+    } else {
+        out.extend_from_slice(&[pixel[last], 0]);
+        out.extend_from_slice(&(count as u16).to_be_bytes());
+    }
+}
+
+/// The run-length encoded BODY. Runs end with the line they start in
+fn body(image: &IffImage, format: RgbFormat) -> Vec<u8> {
+    let width = image.width() as usize;
+    let colors = &image.palette().colors;
+    let transparent = image.bmhd.transparent_color as usize;
+    let genlock = |i: usize| match image.mask() {
+        Some(mask) => mask[i] == 0,
+        None => {
+            image.masking() == Masking::TransparentColor && image.pixels[i] as usize == transparent
+        }
+    };
+    let pixel = |i: usize| {
+        let color = &colors[image.pixels[i] as usize];
+        let n = genlock(i) as u8;
+        match format {
+            RgbFormat::Rgbn => vec![color.r & 0xf0 | color.g >> 4, color.b & 0xf0 | n << 3],
+            RgbFormat::Rgb8 => vec![color.r, color.g, color.b, n << 7],
+        }
+    };
+
+    let mut out = vec![];
+    if width == 0 {
+        return out;
+    }
+    for row in 0..image.height() as usize {
+        let mut x = 0;
+        while x < width {
+            let current = pixel(row * width + x);
+            let mut count = 1;
+            while x + count < width
+                && count < u16::MAX as usize
+                && pixel(row * width + x + count) == current
+            {
+                count += 1;
+            }
+            push_run(&mut out, &current, count, format);
+            x += count;
+        }
+    }
+    out
+}
+
+/// Writes `image` as FORM RGBN or RGB8. Pixels are stored with their
+/// palette colors, masked out ones or those in the transparent color get
+/// the genlock bit
+pub fn encode(image: &IffImage, format: RgbFormat) -> Vec<u8> {
+    let mut bmhd = image.get_bmhd();
+    bmhd[8] = format.bitplanes();
+    bmhd[9] = 0;
+    bmhd[10] = COMPRESSION_RGBN;
+    let mut form = Form::new(format.form_type()).chunk(b"BMHD", bmhd);
+    if let Some(camg) = image.camg() {
+        form = form.chunk(b"CAMG", camg.to_be_bytes());
+    }
+    form.chunk(b"BODY", body(image, format)).to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn runs_with_short_and_long_counts() {
+        let colors = vec![
+            Color {
+                r: 0x12,
+                g: 0x34,
+                b: 0x56,
+            },
+            Color {
+                r: 0xff,
+                g: 0,
+                b: 0x80,
+            },
+        ];
+        let mut pixels = vec![0; 300];
+        pixels[0] = 1;
+        pixels[1..9].fill(1);
+        let mut image = IffImage::from_parts(300, 1, ColorMap { colors }, pixels);
+
+        let rgbn = encode(&image, RgbFormat::Rgbn);
+        assert_eq!(&rgbn[8..12], b"RGBN");
+        assert_eq!(rgbn[20 + 8], 13);
+        assert_eq!(rgbn[20 + 10], 4);
+        assert_eq!(&rgbn[40..44], b"BODY");
+        assert_eq!(&rgbn[48..], &[0xf0, 0x80, 9, 0x13, 0x50, 0, 1, 35]);
+
+        image.set_transparent_color(1);
+        image.set_masking(Masking::TransparentColor);
+        let rgb8 = encode(&image, RgbFormat::Rgb8);
+        assert_eq!(rgb8[20 + 8], 25);
+        assert_eq!(
+            &rgb8[48..],
+            &[0xff, 0, 0x80, 0x89, 0x12, 0x34, 0x56, 0, 0, 1, 35, 0]
+        );
+    }
+}
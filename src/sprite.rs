@@ -0,0 +1,245 @@
+//! Raw hardware sprite data, ready to be pointed at by the sprite DMA. Every
+//! sprite starts with its POS and CTL control words, followed by two words
+//! (plane 0 and plane 1) per line and two null control words at the end.
+//! With the AGA fetch modes for 32 and 64 pixel wide sprites every word
+//! grows to 32 or 64 bits, control words being padded with zeros
+//!
+//! Attached sprites pair an even and an odd sprite into one with 15 colors,
+//! shown with color registers 17 to 31. The odd sprite holds planes 2 and 3
+//! and has the attach bit set in its CTL word
+
+use crate::iffimage::{IffConvertError, IffImage};
+
+/// Sprites have 3 colors plus transparency
+const SPRITE_COLORS: usize = 4;
+/// Attached sprite pairs have 15 colors plus transparency
+const ATTACHED_COLORS: usize = 16;
+/// First color register of attached sprites, which is see-through
+pub const ATTACHED_FIRST_REGISTER: usize = 16;
+/// CTL bit attaching an odd sprite to the even one before it
+const CTL_ATTACH: u16 = 0x80;
+
+/// Where and how wide the sprite is shown
+#[derive(Clone, Debug)]
+pub struct SpriteOptions {
+    /// 16 on OCS/ECS, 32 or 64 with the AGA sprite fetch modes
+    pub width: usize,
+    /// First display line
+    pub vstart: u16,
+    /// Horizontal position in low resolution pixels
+    pub hstart: u16,
+    /// Line after the last one shown. Defaults to right below the image,
+    /// later stops pad the sprite with transparent lines
+    pub vstop: Option<u16>,
+    /// End the data with null control words, which stops the sprite DMA.
+    /// Leave them out to chain further sprites for the same channel
+    pub terminate: bool,
+}
+
+impl Default for SpriteOptions {
+    /// Top left corner of a standard PAL/NTSC display
+    fn default() -> Self {
+        SpriteOptions {
+            width: 16,
+            vstart: 0x2c,
+            hstart: 0x80,
+            vstop: None,
+            terminate: true,
+        }
+    }
+}
+
+/// POS and CTL for a sprite shown from `vstart` up to `vstop`
+fn control_words(vstart: u16, vstop: u16, hstart: u16) -> [u16; 2] {
+    let pos = (vstart & 0xff) << 8 | (hstart >> 1) & 0xff;
+    let ctl = (vstop & 0xff) << 8 | (vstart >> 8 & 1) << 2 | (vstop >> 8 & 1) << 1 | hstart & 1;
+    [pos, ctl]
+}
+
+/// Checks the sprite size and position, and that all pixels are below
+/// `colors` once `offset` is taken off. Pixels below `offset` have to be 0
+fn validate(
+    image: &IffImage,
+    options: &SpriteOptions,
+    colors: usize,
+    offset: usize,
+) -> Result<(), IffConvertError> {
+    if ![16, 32, 64].contains(&options.width) {
+        return Err(IffConvertError::Unsupported(
+            "sprites are 16, 32 or 64 pixels wide",
+        ));
+    }
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let vstop = options.vstart as usize + height;
+    let too_short = options.vstop.is_some_and(|stop| (stop as usize) < vstop);
+    let vstop = options.vstop.map_or(vstop, |stop| stop as usize);
+    if width > options.width || too_short || vstop > 0x1ff || options.hstart > 0x1ff {
+        return Err(IffConvertError::InvalidSize(width as u32, height as u32));
+    }
+    let fits = |p: usize| p == 0 || (offset..offset + colors).contains(&p);
+    if let Some(i) = image.pixels.iter().position(|&p| !fits(p as usize)) {
+        return Err(IffConvertError::InvalidIndex {
+            index: image.pixels[i],
+            x: i % width,
+            y: i / width,
+        });
+    }
+    Ok(())
+}
+
+/// Sprite data from bitplanes `first_plane` and `first_plane + 1` of
+/// `pixels`
+fn sprite_data(
+    pixels: &[u8],
+    width: usize,
+    options: &SpriteOptions,
+    first_plane: u8,
+    attach: bool,
+) -> Vec<u8> {
+    let height = pixels.len() / width.max(1);
+    let vstop = options.vstop.unwrap_or(options.vstart + height as u16);
+
+    // bytes per control word and per line of one plane
+    let word_size = options.width / 8;
+    let control = |out: &mut Vec<u8>, word: u16| {
+        out.extend_from_slice(&word.to_be_bytes());
+        out.resize(out.len() + word_size - 2, 0);
+    };
+    let mut out = Vec::with_capacity((height * 2 + 4) * word_size);
+    let [pos, ctl] = control_words(options.vstart, vstop, options.hstart);
+    control(&mut out, pos);
+    control(&mut out, if attach { ctl | CTL_ATTACH } else { ctl });
+    for row in pixels.chunks(width) {
+        for plane in first_plane..first_plane + 2 {
+            let mut bits = vec![0u8; word_size];
+            for (x, &p) in row.iter().enumerate() {
+                bits[x / 8] |= (p >> plane & 1) << (7 - x % 8);
+            }
+            out.extend_from_slice(&bits);
+        }
+    }
+    // transparent lines up to the requested stop
+    let lines = (vstop - options.vstart) as usize;
+    out.resize(out.len() + lines.saturating_sub(height) * 2 * word_size, 0);
+    if options.terminate {
+        control(&mut out, 0);
+        control(&mut out, 0);
+    }
+    out
+}
+
+/// Encodes `image`, which may use palette indices 0 to 3 with 0 being
+/// transparent, as one sprite. Narrower images are padded on the right
+pub fn encode(image: &IffImage, options: &SpriteOptions) -> Result<Vec<u8>, IffConvertError> {
+    validate(image, options, SPRITE_COLORS, 0)?;
+    let width = image.width() as usize;
+    Ok(sprite_data(&image.pixels, width, options, 0, false))
+}
+
+/// Encodes `image` as an attached sprite pair, the even sprite followed by
+/// the odd one. Images with up to 16 colors use indices 0 to 15. Larger
+/// palettes are taken as a screen palette, where the sprite colors sit in
+/// registers 16 to 31 and pixels have to use those or 0
+pub fn encode_attached(
+    image: &IffImage,
+    options: &SpriteOptions,
+) -> Result<Vec<u8>, IffConvertError> {
+    let offset = match image.num_colors() {
+        0..=ATTACHED_COLORS => 0,
+        _ => ATTACHED_FIRST_REGISTER,
+    };
+    validate(image, options, ATTACHED_COLORS, offset)?;
+    let pixels = image
+        .pixels
+        .iter()
+        .map(|&p| (p as usize).saturating_sub(offset) as u8)
+        .collect::<Vec<_>>();
+    let width = image.width() as usize;
+    let mut out = sprite_data(&pixels, width, options, 0, false);
+    out.extend(sprite_data(&pixels, width, options, 2, true));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn control_words_and_plane_data() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let mut pixels = vec![0; 8];
+        pixels[0] = 1;
+        pixels[1] = 3;
+        pixels[7] = 2;
+        let image = IffImage::from_parts(4, 2, colors, pixels);
+
+        let data = encode(&image, &SpriteOptions::default()).unwrap();
+        assert_eq!(
+            data,
+            vec![0x2c, 0x40, 0x2e, 0x00, 0xc0, 0, 0x40, 0, 0, 0, 0x10, 0, 0, 0, 0, 0]
+        );
+
+        let options = SpriteOptions {
+            width: 64,
+            vstart: 0x100,
+            hstart: 0x81,
+            ..Default::default()
+        };
+        let data = encode(&image, &options).unwrap();
+        assert_eq!(data.len(), 8 * (2 + 2 * 2 + 2));
+        assert_eq!(&data[..2], &[0x00, 0x40]);
+        assert_eq!(&data[8..10], &[0x02, 0b111]);
+        assert_eq!(&data[16..18], &[0xc0, 0]);
+        assert_eq!(&data[24..26], &[0x40, 0]);
+
+        let options = SpriteOptions {
+            width: 24,
+            ..Default::default()
+        };
+        assert!(encode(&image, &options).is_err());
+    }
+
+    #[test]
+    fn custom_stop_without_terminator() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 4],
+        };
+        let image = IffImage::from_parts(1, 1, colors, vec![1]);
+        let options = SpriteOptions {
+            width: 16,
+            vstart: 0x40,
+            hstart: 0x90,
+            vstop: Some(0x43),
+            terminate: false,
+        };
+        let data = encode(&image, &options).unwrap();
+        assert_eq!(&data[..4], &[0x40, 0x48, 0x43, 0x00]);
+        assert_eq!(data.len(), 4 + 3 * 4);
+        assert_eq!(&data[4..], &[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let options = SpriteOptions {
+            vstop: Some(0x40),
+            ..options
+        };
+        assert!(encode(&image, &options).is_err());
+    }
+
+    #[test]
+    fn attached_pairs_split_the_planes() {
+        let colors = ColorMap {
+            colors: vec![Color::default(); 32],
+        };
+        let image = IffImage::from_parts(2, 1, colors.clone(), vec![0b1_0110, 0]);
+        let data = encode_attached(&image, &SpriteOptions::default()).unwrap();
+        assert_eq!(data.len(), 2 * 12);
+        // even sprite: planes 0 and 1, odd sprite: planes 2 and 3 attached
+        assert_eq!(&data[2..8], &[0x2d, 0x00, 0x00, 0, 0x80, 0]);
+        assert_eq!(&data[14..20], &[0x2d, 0x80, 0x80, 0, 0x00, 0]);
+
+        let mismatched = IffImage::from_parts(2, 1, colors, vec![3, 0]);
+        assert!(encode_attached(&mismatched, &SpriteOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::iffimage::IffImage;
+use std::str::FromStr;
+
+/// Values that can be placed into an output file name
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Stem,
+    Width,
+    Height,
+    Bitplanes,
+    Colors,
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// Output file name pattern like `{stem}_{bitplanes}bpl.iff`. Placeholders
+/// are filled in after the image has been converted
+#[derive(Debug)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in template {:?}", s))?;
+            let field = match &rest[start + 1..start + end] {
+                "stem" => Field::Stem,
+                "width" => Field::Width,
+                "height" => Field::Height,
+                "bitplanes" => Field::Bitplanes,
+                "colors" => Field::Colors,
+                name => {
+                    return Err(format!(
+                    "Unknown placeholder {{{}}}. Expected stem, width, height, bitplanes or colors",
+                    name
+                ))
+                }
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(OutputTemplate { parts })
+    }
+}
+
+impl OutputTemplate {
+    /// Builds the file name for the converted `image` read from a file named `stem`
+    pub fn render(&self, stem: &str, image: &IffImage) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.clone(),
+                Part::Field(Field::Stem) => stem.to_string(),
+                Part::Field(Field::Width) => image.bmhd.width.to_string(),
+                Part::Field(Field::Height) => image.bmhd.height.to_string(),
+                Part::Field(Field::Bitplanes) => image.bmhd.bitplanes.to_string(),
+                Part::Field(Field::Colors) => image.cmap.colors.len().to_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iffimage::{Color, ColorMap};
+
+    #[test]
+    fn renders_placeholders() {
+        let image = IffImage::from_parts(
+            16,
+            8,
+            ColorMap {
+                colors: vec![Color::default(); 5],
+            },
+            vec![0; 128],
+        );
+        let template = "{stem}_{bitplanes}bpl_{width}x{height}.iff"
+            .parse::<OutputTemplate>()
+            .unwrap();
+        assert_eq!(template.render("ship", &image), "ship_3bpl_16x8.iff");
+        assert!("{stem".parse::<OutputTemplate>().is_err());
+        assert!("{name}.iff".parse::<OutputTemplate>().is_err());
+    }
+}
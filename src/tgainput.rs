@@ -0,0 +1,173 @@
+use crate::iffimage::{check_indices, Color, ColorMap, IffConvertError, IffImage, IffLoadError};
+use std::io::Read;
+
+const COLOR_MAPPED: u8 = 1;
+const COLOR_MAPPED_RLE: u8 = 9;
+const HEADER_SIZE: usize = 18;
+
+/// Expands TGA run-length packets of 8 bit pixels
+fn decode_rle(data: &[u8], num_pixels: usize) -> Result<Vec<u8>, IffConvertError> {
+    let mut pixels = Vec::with_capacity(num_pixels);
+    let mut i = 0;
+    while pixels.len() < num_pixels {
+        let header = *data
+            .get(i)
+            .ok_or(IffConvertError::Malformed("truncated TGA pixel data"))?;
+        let count = (header & 0x7f) as usize + 1;
+        i += 1;
+        if header & 0x80 != 0 {
+            let value = *data
+                .get(i)
+                .ok_or(IffConvertError::Malformed("truncated TGA pixel data"))?;
+            pixels.extend(std::iter::repeat_n(value, count));
+            i += 1;
+        } else {
+            let raw = data
+                .get(i..i + count)
+                .ok_or(IffConvertError::Malformed("truncated TGA pixel data"))?;
+            pixels.extend_from_slice(raw);
+            i += count;
+        }
+    }
+    pixels.truncate(num_pixels);
+    Ok(pixels)
+}
+
+fn read_color(entry: &[u8]) -> Color {
+    match entry.len() {
+        // A1R5G5B5, little endian
+        2 => {
+            let v = u16::from_le_bytes([entry[0], entry[1]]);
+            let scale = |c: u16| ((c & 0x1f) * 255 / 31) as u8;
+            Color {
+                r: scale(v >> 10),
+                g: scale(v >> 5),
+                b: scale(v),
+            }
+        }
+        // BGR(A)
+        _ => Color {
+            r: entry[2],
+            g: entry[1],
+            b: entry[0],
+        },
+    }
+}
+
+impl IffImage {
+    /// Converts color-mapped TGAs, uncompressed or run-length encoded
+    pub fn from_tga_reader<R: Read>(mut r: R) -> Result<IffImage, IffLoadError> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        IffImage::from_tga_bytes(&data)
+    }
+
+    pub fn from_tga_bytes(data: &[u8]) -> Result<IffImage, IffLoadError> {
+        let header = data
+            .get(..HEADER_SIZE)
+            .ok_or(IffConvertError::Malformed("truncated TGA header"))?;
+        let le_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+
+        let id_length = header[0] as usize;
+        let colormap_type = header[1];
+        let image_type = header[2];
+        let first_entry = le_u16(3) as usize;
+        let num_entries = le_u16(5) as usize;
+        let entry_bits = header[7];
+        let width = le_u16(12);
+        let height = le_u16(14);
+        let pixel_depth = header[16];
+        let descriptor = header[17];
+
+        if colormap_type != 1 || (image_type != COLOR_MAPPED && image_type != COLOR_MAPPED_RLE) {
+            return Err(From::from(IffConvertError::NoPalette));
+        }
+        if pixel_depth != 8 {
+            return Err(From::from(IffConvertError::UnsupportedBitDepth(
+                pixel_depth,
+            )));
+        }
+        if width == 0 || height == 0 {
+            return Err(From::from(IffConvertError::EmptyImage(
+                width as u32,
+                height as u32,
+            )));
+        }
+        if num_entries == 0 {
+            return Err(From::from(IffConvertError::EmptyPalette));
+        }
+        if first_entry + num_entries > 256 {
+            return Err(From::from(IffConvertError::TooManyColors(
+                first_entry + num_entries,
+            )));
+        }
+
+        let entry_size = (entry_bits as usize).div_ceil(8);
+        if !(2..=4).contains(&entry_size) {
+            return Err(From::from(IffConvertError::Malformed(
+                "unsupported TGA color map entry size",
+            )));
+        }
+        let palette_offset = HEADER_SIZE + id_length;
+        let palette_end = palette_offset + num_entries * entry_size;
+        let palette = data
+            .get(palette_offset..palette_end)
+            .ok_or(IffConvertError::Malformed("truncated TGA color map"))?;
+        // pixel values address the color map including its first entry offset,
+        // so keep the leading unused entries to preserve the indices
+        let cmap = ColorMap {
+            colors: std::iter::repeat_n(Color::default(), first_entry)
+                .chain(palette.chunks(entry_size).map(read_color))
+                .collect::<Vec<_>>(),
+        };
+
+        let num_pixels = width as usize * height as usize;
+        let mut pixels = if image_type == COLOR_MAPPED_RLE {
+            decode_rle(&data[palette_end..], num_pixels)?
+        } else {
+            data.get(palette_end..palette_end + num_pixels)
+                .ok_or(IffConvertError::Malformed("truncated TGA pixel data"))?
+                .to_vec()
+        };
+
+        // bit 5 set means top-down, bit 4 right-to-left
+        if descriptor & 0x20 == 0 {
+            pixels = pixels
+                .chunks(width as usize)
+                .rev()
+                .flat_map(|row| row.iter().cloned())
+                .collect();
+        }
+        if descriptor & 0x10 != 0 {
+            pixels
+                .chunks_mut(width as usize)
+                .for_each(|row| row.reverse());
+        }
+        check_indices(&pixels, width as usize, cmap.colors.len())?;
+
+        Ok(IffImage::from_parts(width, height, cmap, pixels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_packets() {
+        // run of 3 x 7, raw packet 1 2
+        let data = [0x82, 7, 0x01, 1, 2];
+        assert_eq!(decode_rle(&data, 5).unwrap(), vec![7, 7, 7, 1, 2]);
+        assert!(decode_rle(&data, 6).is_err());
+    }
+
+    #[test]
+    fn bottom_up_rle_tga() {
+        let image =
+            IffImage::from_tga_reader(&include_bytes!("../testdata/indexed.tga")[..]).unwrap();
+        assert_eq!(image.bmhd.width, 3);
+        assert_eq!(image.bmhd.height, 2);
+        assert_eq!(image.pixels, vec![0, 1, 2, 2, 2, 2]);
+        assert_eq!(image.cmap.colors[1].r, 0xff);
+    }
+}
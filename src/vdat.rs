@@ -0,0 +1,167 @@
+//! VDAT vertical run-length compression, BMHD compression 2 as written by
+//! Atari ST and TT programs. The BODY holds one VDAT chunk per plane (the
+//! mask being the last one). Planes are packed column by column, every
+//! column one word wide and running from the top line to the bottom one.
+//! A chunk starts with a word counting itself and the command bytes after
+//! it, followed by the data words the commands consume:
+//!
+//! - 0: a count word n, then n literal words
+//! - 1: a count word n, then one word repeated n times
+//! - -128..=-1: -cmd literal words
+//! - 2..=127: one word repeated cmd times
+
+use crate::iff::chunks;
+use crate::iffimage::IffConvertError;
+
+/// The command count word is 16 bits and counts itself
+const MAX_COMMANDS: usize = u16::MAX as usize - 2;
+
+const TRUNCATED_VDAT: IffConvertError = IffConvertError::MalformedChunk {
+    chunk: *b"VDAT",
+    reason: "truncated",
+};
+
+/// Packs the column ordered words of one plane into VDAT chunk data
+pub(crate) fn pack(words: &[u16]) -> Vec<u8> {
+    let mut commands = vec![];
+    let mut data = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        // near the command limit everything left goes into long literals
+        let long_literals = (words.len() - i).div_ceil(u16::MAX as usize);
+        if commands.len() + long_literals + 1 >= MAX_COMMANDS {
+            for literal in words[i..].chunks(u16::MAX as usize) {
+                commands.push(0);
+                data.push(literal.len() as u16);
+                data.extend_from_slice(literal);
+            }
+            break;
+        }
+
+        let run = words[i..]
+            .iter()
+            .take(u16::MAX as usize)
+            .take_while(|&&w| w == words[i])
+            .count();
+        if run > 1 {
+            if run <= 127 {
+                commands.push(run as u8);
+            } else {
+                commands.push(1);
+                data.push(run as u16);
+            }
+            data.push(words[i]);
+            i += run;
+            continue;
+        }
+
+        // literal until the next run of at least 2 equal words
+        let start = i;
+        while i < words.len()
+            && i - start < u16::MAX as usize
+            && (i + 1 >= words.len() || words[i] != words[i + 1])
+        {
+            i += 1;
+        }
+        let literal = &words[start..i];
+        if literal.len() <= 128 {
+            commands.push((-(literal.len() as i16)) as u8);
+        } else {
+            commands.push(0);
+            data.push(literal.len() as u16);
+        }
+        data.extend_from_slice(literal);
+    }
+
+    let mut out = Vec::with_capacity(2 + commands.len() + data.len() * 2);
+    out.extend_from_slice(&(commands.len() as u16 + 2).to_be_bytes());
+    out.extend(commands);
+    out.extend(data.iter().flat_map(|w| w.to_be_bytes()));
+    out
+}
+
+/// Unpacks VDAT chunk data into the `len` column ordered words of a plane
+pub(crate) fn unpack(data: &[u8], len: usize) -> Result<Vec<u16>, IffConvertError> {
+    let count = data.get(..2).ok_or(TRUNCATED_VDAT)?;
+    let count = u16::from_be_bytes([count[0], count[1]]) as usize;
+    let commands = data.get(2..count).ok_or(TRUNCATED_VDAT)?;
+    let mut words = data[count..]
+        .chunks_exact(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]));
+    let mut next = || words.next().ok_or(TRUNCATED_VDAT);
+
+    let mut out = Vec::with_capacity(len);
+    for &command in commands {
+        match command as i8 {
+            0 => {
+                for _ in 0..next()? {
+                    out.push(next()?);
+                }
+            }
+            1 => {
+                let n = next()? as usize;
+                out.extend(std::iter::repeat_n(next()?, n));
+            }
+            n if n < 0 => {
+                for _ in 0..-(n as i16) {
+                    out.push(next()?);
+                }
+            }
+            n => out.extend(std::iter::repeat_n(next()?, n as usize)),
+        }
+    }
+    if out.len() < len {
+        return Err(TRUNCATED_VDAT);
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// Unpacks the VDAT chunks of a BODY into `height` lines of `planes`
+/// interleaved rows, `row_bytes` (an even number) each
+pub(crate) fn unpack_body(
+    body: &[u8],
+    row_bytes: usize,
+    planes: usize,
+    height: usize,
+) -> Result<Vec<u8>, IffConvertError> {
+    let vdats = chunks(body)?
+        .into_iter()
+        .filter(|(id, _)| id == b"VDAT")
+        .collect::<Vec<_>>();
+    if vdats.len() < planes {
+        return Err(IffConvertError::MalformedChunk {
+            chunk: *b"BODY",
+            reason: "missing VDAT chunks",
+        });
+    }
+    let mut out = vec![0u8; row_bytes * planes * height];
+    for (plane, (_, data)) in vdats.iter().take(planes).enumerate() {
+        let words = unpack(data, row_bytes / 2 * height)?;
+        for (i, word) in words.iter().enumerate() {
+            let (column, y) = (i / height, i % height);
+            let offset = (y * planes + plane) * row_bytes + column * 2;
+            out[offset..offset + 2].copy_from_slice(&word.to_be_bytes());
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_runs_and_literals() {
+        let mut words = vec![7, 7, 7, 1, 2, 3, 5, 5];
+        words.extend(vec![9; 300]);
+        words.extend(1..=200);
+        let packed = pack(&words);
+        assert_eq!(&packed[..7], &[0, 7, 3, 0xfd, 2, 1, 0]);
+        assert_eq!(&packed[7..13], &[0, 7, 0, 1, 0, 2]);
+        assert_eq!(unpack(&packed, words.len()).unwrap(), words);
+
+        assert!(unpack(&packed[..packed.len() - 2], words.len()).is_err());
+        assert!(unpack(&packed, words.len() + 1).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! JavaScript bindings for the WebAssembly build:
+//!
+//! ```sh
+//! cargo build --release --lib --target wasm32-unknown-unknown --no-default-features --features wasm
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/ipng2iff.wasm
+//! ```
+
+use crate::iffimage::{Compression, IffImage, LoadOptions};
+use wasm_bindgen::prelude::*;
+
+/// Options of `convert`, named after the command line options
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct ConvertOptions {
+    /// Pack the BODY with ByteRun1
+    pub compress: bool,
+    /// Maximum number of colors when truecolor input needs quantizing
+    pub colors: usize,
+    /// Frame to convert from Aseprite files
+    pub frame: usize,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        let defaults = LoadOptions::default();
+        ConvertOptions {
+            compress: false,
+            colors: defaults.max_colors,
+            frame: defaults.frame,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ConvertOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> ConvertOptions {
+        ConvertOptions::default()
+    }
+}
+
+/// Converts the contents of an image file to the bytes of an ILBM
+#[wasm_bindgen]
+pub fn convert(input: &[u8], options: Option<ConvertOptions>) -> Result<Vec<u8>, JsError> {
+    let options = options.unwrap_or_default();
+    let load_options = LoadOptions {
+        frame: options.frame,
+        max_colors: options.colors,
+        ..Default::default()
+    };
+    let mut image = IffImage::from_bytes(input, &load_options)?;
+    if options.compress {
+        image.set_compression(Compression::ByteRun1);
+    }
+    Ok(image.to_bytes())
+}
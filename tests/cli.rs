@@ -0,0 +1,39 @@
+use ipng2iff::IffImage;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// An empty directory for one test
+fn work_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn converts_files_named_like_subcommands() {
+    let dir = work_dir("subcommand_names");
+    let image = IffImage::from_indexed(2, 1, &[[0, 0, 0], [255, 255, 255]], &[0, 1]).unwrap();
+    let mut png = vec![];
+    image.to_png(&mut png).unwrap();
+    fs::write(dir.join("sprite.png"), png).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_ipng2iff"))
+        .current_dir(&dir)
+        .args(["sprite.png", "icon.iff"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let converted = IffImage::from_iff_bytes(&fs::read(dir.join("icon.iff")).unwrap()).unwrap();
+    assert_eq!(converted.width(), 2);
+
+    // conversion options still go before a subcommand
+    let status = Command::new(env!("CARGO_BIN_EXE_ipng2iff"))
+        .current_dir(&dir)
+        .args(["--colors", "2", "sprite", "sprite.png", "sprite.spr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(dir.join("sprite.spr").exists());
+}